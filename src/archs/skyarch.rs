@@ -6,7 +6,7 @@ use bitflags::bitflags_match;
 use crate::{AsRawId, instr::{Address, AddressKind, Instruction, Operand, RegisterKind, RelocSym}, mach::{FeatureSet, MachineSpec, ONE_MACHINE, OneMachine, Opcode, Register, RegisterSpec, Regset, TargetFeatureSpec}, traits::{AsId, BitfieldEncodable, IdType, IntoId, Name}};
 
 #[cfg(feature = "xva")]
-use crate::{compiler::{CompilerSpec, CompilerContext}, xva::{XvaCategory, BinaryOp, RightShiftMode, XvaOperand, XvaRegister, XvaStatement}};
+use crate::{compiler::{callconv::{ArgLocation, CallLayout, CallSignature, CallingConvention, StackArgs}, CompilerSpec, CompilerContext, StackGuard, StackProbeKind}, mach::MachineMode, xva::{dwarf::DwarfCie, opt::{peephole::PeepholeRule, sched::SchedInfo}, XvaCategory, XvaConst, XvaExpr, XvaFrameProperties, XvaOpcode, XvaType, BinaryOp, RightShiftMode, ShiftBehaviour, XvaCfi, XvaOperand, XvaRegister, XvaStatement}};
 
 pub type SkyarchMachine = OneMachine;
 
@@ -169,7 +169,9 @@ macro_rules! skyarch_regno {
 #[allow(non_upper_case_globals)]
 impl SkyarchRegno {
     pub const r0: SkyarchRegno = skyarch_regno!(0);
+    pub const r14: SkyarchRegno = skyarch_regno!(14);
     pub const r15: SkyarchRegno = skyarch_regno!(15);
+    pub const r29: SkyarchRegno = skyarch_regno!(29);
     pub const r30: SkyarchRegno = skyarch_regno!(30);
     pub const r31: SkyarchRegno = skyarch_regno!(31);
 }
@@ -212,6 +214,8 @@ pub struct SkyarchRegister(pub u64);
 impl SkyarchRegister {
     pub const r0: SkyarchRegister = SkyarchRegister(0);
     pub const r15: SkyarchRegister = SkyarchRegister(15);
+    pub const r29: SkyarchRegister = SkyarchRegister(29);
+    pub const r30: SkyarchRegister = SkyarchRegister(30);
     pub const r31: SkyarchRegister = SkyarchRegister(31);
 }
 
@@ -419,6 +423,12 @@ impl RegisterSpec for SkyarchRegister {
             _ => None,
         }
     }
+    fn dwarf_regno(&self, _: Self::MachineMode) -> Option<u16> {
+        match self.map() {
+            Map::GeneralPurpose => Some(self.regno().get() as u16),
+            _ => None,
+        }
+    }
 }
 
 impl SkyarchRegister {
@@ -1271,6 +1281,259 @@ impl MachineSpec for Skyarch {
 const GPRS: [SkyarchRegister; 31] = core::array::from_fn(const |v| SkyarchRegister((v as u64) + 1));
 
 
+#[cfg(feature = "xva")]
+impl Skyarch {
+    /// The size of the registers saved by the prologue, which are not part of the allocated frame
+    fn saved_size(frame: &crate::xva::XvaFrameProperties) -> usize {
+        4 * (!frame.is_leaf as usize) + 4 * (frame.use_frame_pointer as usize)
+    }
+
+    /// Adds `delta` to `reg`, using a second instruction for the upper half if it does not fit in a signed 16-bit immediate
+    fn emit_add_imm(stmts: &mut Vec<XvaStatement>, reg: SkyarchRegno, delta: i32) {
+        match i16::try_from(delta) {
+            Ok(imm) => stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Addi { dest: reg, signed: true, supress_flags: true, higher_half: false, imm: imm as u16 }))),
+            Err(_) => {
+                let val = delta as u32;
+                stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Addi { dest: reg, signed: false, supress_flags: true, higher_half: false, imm: val as u16 })));
+                stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Addi { dest: reg, signed: false, supress_flags: true, higher_half: true, imm: (val >> 16) as u16 })));
+            }
+        }
+    }
+
+    /// Allocates `size` bytes of stack, probing each page according to [`CompilerContext::stack_probes`].
+    ///
+    /// `cfa_offset` is the offset of the CFA from the stack pointer before the allocation, or [`None`] if the CFA is computed from the frame pointer.
+    /// The inline loop uses `r15` as a scratch register.
+    /// The probe function of [`StackProbeKind::Call`] is called with the size in `r14`, and does not adjust the stack pointer itself.
+    fn emit_stack_alloc(stmts: &mut Vec<XvaStatement>, size: usize, cfa_offset: Option<i64>, context: &CompilerContext) {
+        let page_size = context.stack_probe_size();
+
+        let probe = |stmts: &mut Vec<XvaStatement>| {
+            stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::St { dest: SkyarchRegno::r30, src: SkyarchRegno::r0, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default })));
+        };
+        let def_cfa_offset = |stmts: &mut Vec<XvaStatement>, allocated: usize| {
+            if let Some(off) = cfa_offset {
+                stmts.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(off + allocated as i64)));
+            }
+        };
+
+        match context.stack_probes() {
+            StackProbeKind::Inline if size > page_size && size <= 8 * page_size => {
+                let mut allocated = 0;
+                while size - allocated >= page_size {
+                    Self::emit_add_imm(stmts, SkyarchRegno::r30, -(page_size as i32));
+                    allocated += page_size;
+                    def_cfa_offset(stmts, allocated);
+                    probe(stmts);
+                }
+
+                if allocated < size {
+                    Self::emit_add_imm(stmts, SkyarchRegno::r30, -((size - allocated) as i32));
+                    def_cfa_offset(stmts, size);
+                }
+            }
+            StackProbeKind::Inline if size > page_size => {
+                let rounded = size - (size % page_size);
+                let probe_loop = context.local_label("probe");
+
+                stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Mov { dest: SkyarchRegno::r15, ssrc: SkyarchRegno::r30, latency: false, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose })));
+                Self::emit_add_imm(stmts, SkyarchRegno::r15, -(rounded as i32));
+
+                // The stack pointer changes in the loop, so the CFA is computed from the loop bound until it is done
+                if let Some(off) = cfa_offset {
+                    stmts.push(XvaStatement::Cfi(XvaCfi::DefCfa(Register::new(SkyarchRegister::r15), off + rounded as i64)));
+                }
+
+                stmts.push(XvaStatement::Label(probe_loop));
+                Self::emit_add_imm(stmts, SkyarchRegno::r30, -(page_size as i32));
+                probe(stmts);
+                stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Sub { dest: SkyarchRegno::r0, src1: SkyarchRegno::r30, src2: SkyarchRegno::r15, supress_flags: false, shift: 0, shift_polarity: false })));
+                // `jmpw` would clobber the loop bound in r15, so the short form is used for the backwards branch
+                stmts.push(XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::Jmp { cond: SkyarchConditionCode::NotZero, link: SkyarchRegno::r0, offset: 0 }, vec![Operand::RelSymbol(RelocSym { sym: probe_loop, kind: AddressKind::Default }, None)])));
+
+                if rounded < size {
+                    Self::emit_add_imm(stmts, SkyarchRegno::r30, -((size - rounded) as i32));
+                }
+
+                if let Some(off) = cfa_offset {
+                    stmts.push(XvaStatement::Cfi(XvaCfi::DefCfa(Register::new(SkyarchRegister::r30), off + size as i64)));
+                }
+            }
+            StackProbeKind::Call(sym) if size > page_size => {
+                let size = size as u32;
+                stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ldi { dest: SkyarchRegno::r14, signed: false, imm: size as u16 as i16 })));
+                if size > (u16::MAX as u32) {
+                    stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Addi { dest: SkyarchRegno::r14, signed: false, supress_flags: true, higher_half: true, imm: (size >> 16) as u16 })));
+                }
+                let target = Operand::RelSymbol(RelocSym { sym, kind: context.global_call_address_kind }, None);
+                stmts.push(XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::JmpW { cond: SkyarchConditionCode::Always, link: SkyarchRegno::r31, dest: SkyarchRegno::r15 }, vec![target])));
+                stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Sub { dest: SkyarchRegno::r30, src1: SkyarchRegno::r30, src2: SkyarchRegno::r14, supress_flags: true, shift: 0, shift_polarity: false })));
+                def_cfa_offset(stmts, size as usize);
+            }
+            _ => {
+                Self::emit_add_imm(stmts, SkyarchRegno::r30, -(size as i32));
+                def_cfa_offset(stmts, size);
+            }
+        }
+    }
+
+    /// Loads the stack protector guard value into `dest`.
+    /// Skyarch has no segment registers, so the guard must be [`StackGuard::Global`]
+    fn load_stack_guard(stmts: &mut Vec<XvaStatement>, dest: SkyarchRegno, context: &CompilerContext) {
+        let StackGuard::Global(sym) = context.stack_guard else {
+            panic!("The Skyarch stack guard must be a global symbol");
+        };
+
+        if matches!(context.global_address_kind, AddressKind::GotRel | AddressKind::GotAbs) {
+            let got = Operand::RelSymbol(RelocSym { sym, kind: AddressKind::GotRel }, None);
+            stmts.push(XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::LraW { dest, signed: false }, vec![got])));
+            stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ld { dest, src: dest, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default })));
+        } else {
+            let addr = XvaConst::Global(sym, 0).to_direct_rel(context.local_address_kind, context.global_address_kind);
+            stmts.push(XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::LraW { dest, signed: false }, vec![addr])));
+        }
+        stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ld { dest, src: dest, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default })));
+    }
+
+    /// Computes the address of the stack protector canary slot `slot` of `frame` into `dest`
+    fn canary_address(stmts: &mut Vec<XvaStatement>, frame: &crate::xva::XvaFrameProperties, slot: i32, dest: SkyarchRegno) {
+        let base = if frame.use_frame_pointer { SkyarchRegno::r29 } else { SkyarchRegno::r30 };
+        stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Mov { dest, ssrc: base, latency: false, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose })));
+        Self::emit_add_imm(stmts, dest, slot);
+    }
+
+    /// Computes the address of the stack argument `opr` into `dest`.
+    /// Incoming arguments start at the stack pointer on entry, and outgoing arguments at the stack pointer after the prologue
+    fn stack_arg_address(stmts: &mut Vec<XvaStatement>, opr: XvaOperand, frame: &crate::xva::XvaFrameProperties, dest: SkyarchRegno) {
+        let (base, disp) = match opr {
+            XvaOperand::IncomingArg(off) if frame.use_frame_pointer => (SkyarchRegno::r29, Self::saved_size(frame) + off as usize),
+            XvaOperand::IncomingArg(off) => (SkyarchRegno::r30, frame.frame_size + off as usize),
+            XvaOperand::OutgoingArg(off) => (SkyarchRegno::r30, off as usize),
+            _ => panic!("Not a stack argument"),
+        };
+        stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Mov { dest, ssrc: base, latency: false, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose })));
+        if disp != 0 {
+            Self::emit_add_imm(stmts, dest, disp as i32);
+        }
+    }
+
+    /// The width of a load or store of `size` bytes
+    fn access_width(size: u64) -> SkyarchByteSize {
+        match size {
+            1 => SkyarchByteSize::Byte,
+            2 => SkyarchByteSize::Half,
+            _ => SkyarchByteSize::Word,
+        }
+    }
+
+    const PEEPHOLE_RULES: &[PeepholeRule] = &[
+        PeepholeRule { name: "mov-self", window: 1, rewrite: Self::peephole_mov_self },
+        PeepholeRule { name: "addi-zero", window: 1, rewrite: Self::peephole_addi_zero },
+        PeepholeRule { name: "push-pop", window: 2, rewrite: Self::peephole_push_pop },
+    ];
+
+    /// Decodes `instr`, if it has no operands, prefixes, or mode override
+    fn peephole_decode(instr: &Instruction) -> Option<SkyarchInstruction> {
+        (instr.operands().is_empty() && instr.prefixes().is_empty() && instr.mode_override().is_none())
+            .then(|| instr.opcode().downcast::<SkyarchOpcode>())
+            .flatten()
+            .map(|op| op.decode())
+    }
+
+    /// An unconditional `mov r, r`
+    fn peephole_mov_self(instrs: &[Instruction], _: MachineMode) -> Option<(usize, Vec<Instruction>)> {
+        let SkyarchInstruction::Mov { dest, ssrc, latency: false, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose } = Self::peephole_decode(&instrs[0])? else {
+            return None;
+        };
+        (dest == ssrc).then(|| (1, vec![]))
+    }
+
+    /// `addi r, 0` that does not set the flags
+    fn peephole_addi_zero(instrs: &[Instruction], _: MachineMode) -> Option<(usize, Vec<Instruction>)> {
+        let SkyarchInstruction::Addi { supress_flags: true, imm: 0, .. } = Self::peephole_decode(&instrs[0])? else {
+            return None;
+        };
+        Some((1, vec![]))
+    }
+
+    /// `push a; pop b` on the stack pointer
+    fn peephole_push_pop(instrs: &[Instruction], _: MachineMode) -> Option<(usize, Vec<Instruction>)> {
+        let [push, pop, ..] = instrs else {
+            return None;
+        };
+        let SkyarchInstruction::St { dest: SkyarchRegno::r30, src, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PreDec } = Self::peephole_decode(push)? else {
+            return None;
+        };
+        let SkyarchInstruction::Ld { dest, src: SkyarchRegno::r30, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PostInc } = Self::peephole_decode(pop)? else {
+            return None;
+        };
+        if src == SkyarchRegno::r30 || dest == SkyarchRegno::r30 {
+            return None;
+        }
+
+        if src == dest {
+            Some((2, vec![]))
+        } else {
+            Some((2, vec![Instruction::new_nullary(SkyarchInstruction::Mov { dest, ssrc: src, latency: false, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose })]))
+        }
+    }
+
+    const SCHED_ALU_PORTS: u32 = 0b01;
+    const SCHED_MEM_PORTS: u32 = 0b10;
+    const SCHED_LOAD_LATENCY: u32 = 3;
+
+    fn sched_info_impl(instr: &Instruction) -> Option<SchedInfo> {
+        let reg = |regno: SkyarchRegno| Register::new(regno.gpr());
+        let alu = |dest: SkyarchRegno, srcs: &[SkyarchRegno], supress_flags: bool| {
+            let mut info = SchedInfo::new(1, Self::SCHED_ALU_PORTS);
+            info.reads.extend(srcs.iter().map(|&src| reg(src)));
+            info.writes.push(reg(dest));
+            info.writes_flags = !supress_flags;
+            info
+        };
+
+        let info = match Self::peephole_decode(instr)? {
+            // The timing of `movl` is explicit, so it is never moved
+            SkyarchInstruction::Mov { dest, ssrc, latency: false, cond, dir: false, map: Map::GeneralPurpose } => {
+                let mut info = alu(dest, &[ssrc], true);
+                if cond != SkyarchConditionCode::Always {
+                    info.reads.push(reg(dest));
+                    info.reads_flags = true;
+                }
+                info
+            }
+            SkyarchInstruction::Ldi { dest, .. } => alu(dest, &[], true),
+            SkyarchInstruction::Addi { dest, supress_flags, .. } => alu(dest, &[dest], supress_flags),
+            SkyarchInstruction::Add { dest, src1, src2, supress_flags, .. }
+            | SkyarchInstruction::Sub { dest, src1, src2, supress_flags, .. }
+            | SkyarchInstruction::And { dest, src1, src2, supress_flags, .. }
+            | SkyarchInstruction::Or { dest, src1, src2, supress_flags, .. }
+            | SkyarchInstruction::Xor { dest, src1, src2, supress_flags, .. } => alu(dest, &[src1, src2], supress_flags),
+            SkyarchInstruction::Ld { dest, src, width: _, mode } => {
+                let mut info = SchedInfo::new(Self::SCHED_LOAD_LATENCY, Self::SCHED_MEM_PORTS);
+                info.reads.push(reg(src));
+                info.writes.push(reg(dest));
+                if mode != SkyarchLoadStoreMode::Default {
+                    info.writes.push(reg(src));
+                }
+                info.loads = true;
+                info
+            }
+            SkyarchInstruction::St { dest, src, width: _, mode } => {
+                let mut info = SchedInfo::new(1, Self::SCHED_MEM_PORTS);
+                info.reads.extend([reg(dest), reg(src)]);
+                if mode != SkyarchLoadStoreMode::Default {
+                    info.writes.push(reg(dest));
+                }
+                info.stores = true;
+                info
+            }
+            _ => return None,
+        };
+        Some(info)
+    }
+}
+
 #[cfg(feature = "xva")]
 impl CompilerSpec for Skyarch {
     type Machine = Self;
@@ -1315,7 +1578,7 @@ impl CompilerSpec for Skyarch {
         }
     }
 
-    fn lower_mce(&self, stmt: &mut crate::xva::XvaStatement, _: Self::MachineMode, _: &CompilerContext, _: &FeatureSet) {
+    fn lower_mce(&self, stmt: &mut crate::xva::XvaStatement, _: Self::MachineMode, context: &CompilerContext, frame: &crate::xva::XvaFrameProperties) {
         let mut preamble = Vec::new();
         match &*stmt {
             crate::xva::XvaStatement::Expr(expr) => {
@@ -1333,8 +1596,21 @@ impl CompilerSpec for Skyarch {
                             crate::xva::XvaConst::Bits(v) => {
                                 Instruction::new(SkyarchInstruction::LdiW { dest: dest.regno(), signed: false }, vec![Operand::Immediate(v as u128)])
                             },
+                            crate::xva::XvaConst::Global(sym, disp) if matches!(context.global_address_kind, AddressKind::GotRel | AddressKind::GotAbs) => {
+                                // Load the address from the GOT entry, then add the displacement, which cannot be applied to the entry
+                                let got = Operand::RelSymbol(RelocSym { sym, kind: AddressKind::GotRel }, None);
+                                let mut stmts = vec![
+                                    XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::LraW { dest: dest.regno(), signed: false }, vec![got])),
+                                    XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ld { dest: dest.regno(), src: dest.regno(), width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default })),
+                                ];
+                                if disp != 0 {
+                                    Self::emit_add_imm(&mut stmts, dest.regno(), disp as i32);
+                                }
+                                *stmt = XvaStatement::Elaborated(stmts);
+                                return;
+                            }
                             xva_const => {
-                                let addr = xva_const.to_direct_rel(crate::instr::AddressKind::Default, crate::instr::AddressKind::Default);
+                                let addr = xva_const.to_direct_rel(context.local_address_kind, context.global_address_kind);
                                 Instruction::new(SkyarchInstruction::LraW { dest: dest.regno(), signed: false }, vec![addr])
                             }
                         }
@@ -1343,6 +1619,11 @@ impl CompilerSpec for Skyarch {
                         let src = Self::areg(src);
                         Instruction::new_nullary(SkyarchInstruction::Mov { dest: dest.regno(), ssrc: src.regno(), latency: false, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose})
                     },
+                    crate::xva::XvaOpcode::ComputeAddr { base: XvaOperand::Register(base), size, index: XvaOperand::Register(index) } if size.is_power_of_two() => {
+                        let base = Self::areg(base);
+                        let index = Self::areg(index);
+                        Instruction::new_nullary(SkyarchInstruction::Add { dest: dest.regno(), src1: base.regno(), src2: index.regno(), supress_flags: true, shift: size.trailing_zeros(), shift_polarity: false })
+                    },
                     crate::xva::XvaOpcode::ComputeAddr { base, size, index } => todo!(),
                     crate::xva::XvaOpcode::GetFrameAddr(_) => todo!(),
                     crate::xva::XvaOpcode::BinaryOp { op, left, right } => {
@@ -1351,7 +1632,7 @@ impl CompilerSpec for Skyarch {
                             let src2 = match right {
                                 crate::xva::XvaOperand::Register(src) => Self::areg(src),
                                 crate::xva::XvaOperand::Const(xva_const) => {
-                                    let opr = xva_const.to_readable(AddressKind::Default, AddressKind::Default, true, None);
+                                    let opr = xva_const.to_readable(context.local_address_kind, context.global_address_kind, true, None);
                                     let (vval, signext) = match xva_const {
                                         crate::xva::XvaConst::Bits(v) => {
                                             if v < u16::MAX as u64 {
@@ -1383,6 +1664,11 @@ impl CompilerSpec for Skyarch {
                                     preamble.push(XvaStatement::RawInstr(instr));
                                     SkyarchRegister::r15
                                 },
+                                opr @ (crate::xva::XvaOperand::IncomingArg(_) | crate::xva::XvaOperand::OutgoingArg(_)) => {
+                                    Self::stack_arg_address(&mut preamble, opr, frame, SkyarchRegno::r15);
+                                    preamble.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ld { dest: SkyarchRegno::r15, src: SkyarchRegno::r15, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default })));
+                                    SkyarchRegister::r15
+                                }
                                 crate::xva::XvaOperand::FrameAddr(_) => todo!(),
                             };
 
@@ -1403,9 +1689,32 @@ impl CompilerSpec for Skyarch {
                     },
                     crate::xva::XvaOpcode::CheckedBinaryOp { op, mode, left, right } => todo!(),
                     crate::xva::XvaOpcode::UnaryOp { op, left } => todo!(),
+                    crate::xva::XvaOpcode::Read(opr @ (XvaOperand::IncomingArg(_) | XvaOperand::OutgoingArg(_))) => {
+                        Self::stack_arg_address(&mut preamble, opr, frame, dest.regno());
+                        Instruction::new_nullary(SkyarchInstruction::Ld { dest: dest.regno(), src: dest.regno(), width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default })
+                    }
                     crate::xva::XvaOpcode::Read(xva_operand) => todo!(),
-                    crate::xva::XvaOpcode::UMul { left, right } => todo!(),
-                    crate::xva::XvaOpcode::SMul { left, right } => todo!(),
+                    crate::xva::XvaOpcode::UMul { .. } | crate::xva::XvaOpcode::SMul { .. } | crate::xva::XvaOpcode::UDiv { .. } | crate::xva::XvaOpcode::SDiv { .. } => {
+                        unreachable!("multiplications and divisions are expanded into calls by expand_runtime_calls")
+                    }
+                    crate::xva::XvaOpcode::Select { cond, left, right } => {
+                        let cond = Self::areg(cond);
+                        let left = Self::areg(left);
+                        let right = Self::areg(right);
+
+                        // Set the flags from `cond`, discarding the result
+                        preamble.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Or { dest: SkyarchRegno::r0, src1: cond.regno(), src2: SkyarchRegno::r0, supress_flags: false, shift: 0, shift_polarity: false, invert: 0 })));
+
+                        if dest == left {
+                            Instruction::new_nullary(SkyarchInstruction::Mov { dest: dest.regno(), ssrc: right.regno(), latency: false, cond: SkyarchConditionCode::Zero, dir: false, map: Map::GeneralPurpose })
+                        } else {
+                            if dest != right {
+                                preamble.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Mov { dest: dest.regno(), ssrc: right.regno(), latency: false, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose })));
+                            }
+                            Instruction::new_nullary(SkyarchInstruction::Mov { dest: dest.regno(), ssrc: left.regno(), latency: false, cond: SkyarchConditionCode::NotZero, dir: false, map: Map::GeneralPurpose })
+                        }
+                    },
+                    crate::xva::XvaOpcode::TlsAddr { .. } | crate::xva::XvaOpcode::TlsIndex { .. } => panic!("Thread-local storage is not supported on Skyarch, which has no thread pointer"),
                 };
 
                 let nstat = XvaStatement::RawInstr(instr);
@@ -1418,6 +1727,13 @@ impl CompilerSpec for Skyarch {
                     *stmt = XvaStatement::Elaborated(preamble)
                 }
             },
+            &crate::xva::XvaStatement::Write(opr @ (XvaOperand::IncomingArg(_) | XvaOperand::OutgoingArg(_)), ty, reg) => {
+                let src = Self::areg(reg);
+                let mut stmts = Vec::new();
+                Self::stack_arg_address(&mut stmts, opr, frame, SkyarchRegno::r15);
+                stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::St { dest: SkyarchRegno::r15, src: src.regno(), width: Self::access_width(ty.size), mode: SkyarchLoadStoreMode::Default })));
+                *stmt = XvaStatement::Elaborated(stmts);
+            }
             crate::xva::XvaStatement::Write(xva_operand, xva_type, xva_register) => todo!(),
             crate::xva::XvaStatement::Jump(symbol) => {
                 let op = Operand::RelSymbol(RelocSym{sym: *symbol, kind: AddressKind::Default}, None);
@@ -1426,6 +1742,16 @@ impl CompilerSpec for Skyarch {
 
                 *stmt = XvaStatement::RawInstr(instr);
             },
+            crate::xva::XvaStatement::JumpIf(cond, symbol) => {
+                let cond = Self::areg(*cond);
+                let op = Operand::RelSymbol(RelocSym{sym: *symbol, kind: AddressKind::Default}, None);
+
+                // Set the flags from `cond`, discarding the result
+                let test = Instruction::new_nullary(SkyarchInstruction::Or { dest: SkyarchRegno::r0, src1: cond.regno(), src2: SkyarchRegno::r0, supress_flags: false, shift: 0, shift_polarity: false, invert: 0 });
+                let instr = Instruction::new(SkyarchInstruction::JmpW { cond: SkyarchConditionCode::NotZero, link: SkyarchRegno::r0, dest: SkyarchRegno::r15 }, vec![op]);
+
+                *stmt = XvaStatement::Elaborated(vec![XvaStatement::RawInstr(test), XvaStatement::RawInstr(instr)]);
+            },
             rstmt @ (crate::xva::XvaStatement::Tailcall { dest,  .. } |
             crate::xva::XvaStatement::Call { dest, .. }) => {
                 let link = match rstmt {
@@ -1438,10 +1764,20 @@ impl CompilerSpec for Skyarch {
                         Instruction::new_nullary(SkyarchInstruction::Jmpr { cond: SkyarchConditionCode::Always, link, dest: reg.regno() })
                     },
                     crate::xva::XvaOperand::Const(xva_const) => {
-                        let opr = xva_const.to_direct_rel(AddressKind::Default, AddressKind::Default);
+                        let opr = xva_const.to_direct_rel(context.local_address_kind, context.global_call_address_kind);
                         Instruction::new(SkyarchInstruction::JmpW { cond: SkyarchConditionCode::Always, link, dest: SkyarchRegno::r15 }, vec![opr])
                     },
-                    crate::xva::XvaOperand::FrameAddr(_) => todo!(),
+                    crate::xva::XvaOperand::FrameAddr(_) => unreachable!("Cannot call the stack"),
+                    // A tail call loads its target before the epilogue, into r13 since the stack protector check of the epilogue uses r14 and r15
+                    &opr @ (crate::xva::XvaOperand::IncomingArg(_) | crate::xva::XvaOperand::OutgoingArg(_)) => {
+                        let target = if link == SkyarchRegno::r0 { SkyarchRegno::r13 } else { SkyarchRegno::r15 };
+                        let mut stmts = Vec::new();
+                        Self::stack_arg_address(&mut stmts, opr, frame, target);
+                        stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ld { dest: target, src: target, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default })));
+                        stmts.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Jmpr { cond: SkyarchConditionCode::Always, link, dest: target })));
+                        *stmt = XvaStatement::Elaborated(stmts);
+                        return;
+                    }
                 };
 
                 *stmt = XvaStatement::RawInstr(instr);
@@ -1458,60 +1794,680 @@ impl CompilerSpec for Skyarch {
             crate::xva::XvaStatement::EndOptGate(_) |
             crate::xva::XvaStatement::Elaborated(..) |
             crate::xva::XvaStatement::Use(..) |
-            crate::xva::XvaStatement::Fallthrough(..) => unimplemented!(),
+            crate::xva::XvaStatement::Fallthrough(..) |
+            crate::xva::XvaStatement::InlineAsm(..) |
+            crate::xva::XvaStatement::Loc(..) |
+            crate::xva::XvaStatement::Cfi(..) |
+            crate::xva::XvaStatement::Label(..) => unimplemented!(),
         }
     }
 
-    fn lower_epilogue(&self, frame: &crate::xva::XvaFrameProperties, _: Self::MachineMode) -> Vec<crate::xva::XvaStatement> {
+    fn lower_epilogue(&self, frame: &crate::xva::XvaFrameProperties, _: Self::MachineMode, context: &CompilerContext) -> Vec<crate::xva::XvaStatement> {
         if !frame.has_prologue {
             return Vec::new();
         }
 
         let mut ret = Vec::new();
 
-        let mut save_frame_size = -((frame.frame_size - 8 * (frame.is_leaf as usize)) as isize);
+        // r14 and r15 hold neither an argument nor a return value
+        if let Some(slot) = frame.stack_protector_slot {
+            let ok = context.local_label("canary_ok");
+            Self::load_stack_guard(&mut ret, SkyarchRegno::r15, context);
+            Self::canary_address(&mut ret, frame, slot, SkyarchRegno::r14);
+            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ld { dest: SkyarchRegno::r14, src: SkyarchRegno::r14, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default })));
+            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Sub { dest: SkyarchRegno::r0, src1: SkyarchRegno::r14, src2: SkyarchRegno::r15, supress_flags: false, shift: 0, shift_polarity: false })));
+            ret.push(XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::Jmp { cond: SkyarchConditionCode::Zero, link: SkyarchRegno::r0, offset: 0 }, vec![Operand::RelSymbol(RelocSym { sym: ok, kind: AddressKind::Default }, None)])));
+            let target = Operand::RelSymbol(RelocSym { sym: context.stack_chk_fail(), kind: context.global_call_address_kind }, None);
+            ret.push(XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::JmpW { cond: SkyarchConditionCode::Always, link: SkyarchRegno::r31, dest: SkyarchRegno::r15 }, vec![target])));
+            ret.push(XvaStatement::Label(ok));
+        }
 
-        if save_frame_size > 0 {
-            let extended = save_frame_size < (i16::MIN as isize);
-            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Addi { dest: SkyarchRegno::r30, signed: !extended, supress_flags: true, higher_half: false, imm: (save_frame_size as u16) })));
-            if extended {
-                ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Addi { dest: SkyarchRegno::r30, signed: false, supress_flags: true, higher_half: true, imm: ((save_frame_size >> 16) as u16) })));
-            }
+        let saved_size = Self::saved_size(frame);
+
+        if frame.use_frame_pointer {
+            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Mov { dest: SkyarchRegno::r30, ssrc: SkyarchRegno::r29, latency: false, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose })));
+            ret.push(XvaStatement::Cfi(XvaCfi::DefCfaRegister(Register::new(SkyarchRegister::r30))));
+            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ld { dest: SkyarchRegno::r29, src: SkyarchRegno::r30, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PostInc })));
+            ret.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(saved_size as i64 - 4)));
+            ret.push(XvaStatement::Cfi(XvaCfi::Restore(Register::new(SkyarchRegister::r29))));
+        } else if frame.frame_size > saved_size {
+            Self::emit_add_imm(&mut ret, SkyarchRegno::r30, (frame.frame_size - saved_size) as i32);
+            ret.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(saved_size as i64)));
         }
 
         if !frame.is_leaf {
             ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ld { dest: SkyarchRegno::r31, src: SkyarchRegno::r30, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PostInc })));
+            ret.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(0)));
+            ret.push(XvaStatement::Cfi(XvaCfi::Restore(Register::new(SkyarchRegister::r31))));
         }
 
         ret
 
     }
 
-    fn emit_prologue(&self, frame: &mut crate::xva::XvaFrameProperties, _: Self::MachineMode) -> Vec<crate::instr::Instruction> {
+    fn emit_prologue(&self, frame: &mut crate::xva::XvaFrameProperties, _: Self::MachineMode, context: &CompilerContext) -> Vec<XvaStatement> {
+        let protect = context.stack_protector.protects(frame);
         let mut ret = Vec::new();
         frame.has_prologue = false;
+        if protect {
+            frame.frame_size += 4;
+        }
+        frame.frame_size += frame.outgoing_args_size;
         frame.frame_size = (frame.frame_size + (frame.frame_align - 1)) & !(frame.frame_align - 1);
-        let save_frame_size = frame.frame_size;
+        let alloc_size = frame.frame_size;
+
+        // Calling the probe function clobbers the link register, so it needs to be saved like any other call
+        if alloc_size > context.stack_probe_size() && matches!(context.stack_probes(), StackProbeKind::Call(_)) {
+            frame.is_leaf = false;
+        }
+
         if !frame.is_leaf {
             frame.frame_size += 4;
             frame.has_prologue = true;
-            ret.push(Instruction::new_nullary(SkyarchInstruction::St { dest: SkyarchRegno::r30, src: SkyarchRegno::r31, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PreDec }));
+            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::St { dest: SkyarchRegno::r30, src: SkyarchRegno::r31, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PreDec })));
+            ret.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(4)));
+            ret.push(XvaStatement::Cfi(XvaCfi::Offset(Register::new(SkyarchRegister::r31), -4)));
         }
 
         if frame.frame_align > frame.call_align {
-            todo!("Dynamic alignment")
+            // The stack pointer is realigned, so the frame is addressed and unwound from the frame pointer (r29)
+            frame.use_frame_pointer = true;
+            frame.frame_size += 4;
+            frame.has_prologue = true;
+            let saved_size = frame.frame_size - alloc_size;
+            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::St { dest: SkyarchRegno::r30, src: SkyarchRegno::r29, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PreDec })));
+            ret.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(saved_size as i64)));
+            ret.push(XvaStatement::Cfi(XvaCfi::Offset(Register::new(SkyarchRegister::r29), -(saved_size as i64))));
+            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Mov { dest: SkyarchRegno::r29, ssrc: SkyarchRegno::r30, latency: false, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose })));
+            ret.push(XvaStatement::Cfi(XvaCfi::DefCfaRegister(Register::new(SkyarchRegister::r29))));
+
+            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ldi { dest: SkyarchRegno::r15, signed: true, imm: (frame.frame_align as i32).wrapping_neg() as i16 })));
+            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::And { dest: SkyarchRegno::r30, src1: SkyarchRegno::r30, src2: SkyarchRegno::r15, supress_flags: true, shift: 0, shift_polarity: false, invert: 0 })));
         } else {
             frame.frame_align = frame.call_align;
         }
 
-        if save_frame_size > 0 {
+        if alloc_size > 0 {
             frame.has_prologue = true;
-            ret.push(Instruction::new_nullary(SkyarchInstruction::Addi { dest: SkyarchRegno::r30, signed: false, supress_flags: true, higher_half: false, imm: (save_frame_size as u16) }));
-            if save_frame_size > (u16::MAX as usize) {
-                ret.push(Instruction::new_nullary(SkyarchInstruction::Addi { dest: SkyarchRegno::r30, signed: false, supress_flags: true, higher_half: true, imm: ((save_frame_size >> 16) as u16) }));
-            }
+            let cfa_offset = (!frame.use_frame_pointer).then_some((frame.frame_size - alloc_size) as i64);
+            Self::emit_stack_alloc(&mut ret, alloc_size, cfa_offset, context);
+        }
+
+        if protect {
+            // The canary is the highest slot of the allocated frame, directly below the saved frame pointer or link register
+            let slot = if frame.use_frame_pointer { -4 } else { alloc_size as i32 - 4 };
+            frame.stack_protector_slot = Some(slot);
+            Self::load_stack_guard(&mut ret, SkyarchRegno::r15, context);
+            Self::canary_address(&mut ret, frame, slot, SkyarchRegno::r14);
+            ret.push(XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::St { dest: SkyarchRegno::r14, src: SkyarchRegno::r15, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default })));
         }
 
         ret
     }
-}
\ No newline at end of file
+
+    fn cfi_entry_state(&self, _: Self::MachineMode) -> Option<DwarfCie> {
+        Some(DwarfCie {
+            code_align: 4,
+            data_align: -4,
+            return_address: 31,
+            cfa_register: Register::new(SkyarchRegister(30)),
+            cfa_offset: 0,
+            return_address_offset: None,
+        })
+    }
+
+    fn calling_convention(&self, name: &str, _: Self::MachineMode) -> Option<&'static dyn CallingConvention> {
+        match name {
+            "skyarch" => Some(&SkyarchAbi),
+            _ => None,
+        }
+    }
+
+    fn default_calling_convention(&self, _: &CompilerContext, _: Self::MachineMode) -> Option<&'static dyn CallingConvention> {
+        Some(&SkyarchAbi)
+    }
+
+    fn expand_runtime_calls(&self, expr: &XvaExpr, _: Self::MachineMode, context: &CompilerContext, frame: &XvaFrameProperties) -> Option<Vec<XvaStatement>> {
+        // Skyarch has no multiply or divide instructions, so these call the routines of the support library with the standard calling convention
+        let (left, right, signed, div) = match expr.op {
+            XvaOpcode::UMul { left, right } => (left, right, false, false),
+            XvaOpcode::SMul { left, right } => (left, right, true, false),
+            XvaOpcode::UDiv { left, right } => (left, right, false, true),
+            XvaOpcode::SDiv { left, right } => (left, right, true, true),
+            _ => return None,
+        };
+        let size = expr.dest.ty(self, context.mode).size;
+        assert!(size <= 4, "Skyarch cannot multiply or divide values wider than its registers");
+        let bits = size * 8;
+
+        let reg = |n| XvaRegister::Physical(Register::new(SkyarchRegister(n)));
+        let expr_stmt = |dest, op| XvaStatement::Expr(XvaExpr { dest, dest2: None, op });
+        let shift = |r, op, quantity| expr_stmt(r, XvaOpcode::BinaryOp { op, left: r, right: XvaOperand::Const(XvaConst::Bits(quantity)) });
+        let shift_right = BinaryOp::ShiftRight(ShiftBehaviour::AssumeQuantity, if signed { RightShiftMode::Signed } else { RightShiftMode::Unsigned });
+        let clobbers = SkyarchAbi.layout(&CallSignature::new(vec![], None), &frame.features).clobber_regs;
+        let call = |name: &str, params: u64, rets: u64| XvaStatement::Call {
+            dest: XvaOperand::Const(XvaConst::Global(crate::intern::Symbol::intern(name), 0)),
+            params: Regset::from_registers((1..=params).map(SkyarchRegister)),
+            ret_val: Regset::from_registers((1..=rets).map(SkyarchRegister)),
+            call_clobber_regs: clobbers,
+            callee_pop: 0,
+            indirect_ret: false,
+        };
+
+        // Moves `left` and `right` into the argument registers `first` and `second`.
+        // The routines operate on whole registers, so narrower values are extended first
+        let args = |stmts: &mut Vec<XvaStatement>, first: u64, second: u64, extend: bool| {
+            let moves = vec![(Register::new(SkyarchRegister(first)), left), (Register::new(SkyarchRegister(second)), right)];
+            stmts.extend(crate::compiler::parallel_copy(moves, self, context.mode).into_iter().map(XvaStatement::Expr));
+            if extend && bits < 32 {
+                for n in [first, second] {
+                    stmts.push(shift(reg(n), BinaryOp::ShiftLeft(ShiftBehaviour::AssumeQuantity), 32 - bits));
+                    stmts.push(shift(reg(n), shift_right, 32 - bits));
+                }
+            }
+        };
+
+        let mut stmts = Vec::new();
+        match (div, expr.dest2) {
+            // The low half of the product does not depend on the upper bits of the operands
+            (false, None) => {
+                args(&mut stmts, 1, 2, false);
+                stmts.push(call("__mulsi3", 2, 1));
+                stmts.push(expr_stmt(expr.dest, XvaOpcode::Move(reg(1))));
+            }
+            // The whole product of narrower values fits in a register
+            (false, Some(dest2)) if bits < 32 => {
+                args(&mut stmts, 1, 2, true);
+                stmts.push(call("__mulsi3", 2, 1));
+                let high = expr_stmt(dest2, XvaOpcode::BinaryOp { op: shift_right, left: reg(1), right: XvaOperand::Const(XvaConst::Bits(bits)) });
+                let low = expr_stmt(expr.dest, XvaOpcode::Move(reg(1)));
+                stmts.extend(if dest2 == reg(1) { [low, high] } else { [high, low] });
+            }
+            // `__muldi3` multiplies 64-bit values, passed and returned with the low word in the lower register, so the operands are extended to 64 bits
+            (false, Some(dest2)) => {
+                args(&mut stmts, 1, 3, false);
+                for n in [2, 4] {
+                    let ext = if signed {
+                        XvaOpcode::BinaryOp { op: shift_right, left: reg(n - 1), right: XvaOperand::Const(XvaConst::Bits(31)) }
+                    } else {
+                        XvaOpcode::Const(XvaConst::Bits(0))
+                    };
+                    stmts.push(expr_stmt(reg(n), ext));
+                }
+                stmts.push(call("__muldi3", 4, 2));
+                // The results are moved through r3 if each is in the other's register
+                let moves = match (expr.dest, dest2) {
+                    (dest, dest2) if dest == reg(2) && dest2 == reg(1) => vec![(reg(3), reg(1)), (dest2, reg(2)), (dest, reg(3))],
+                    (dest, dest2) if dest2 == reg(1) => vec![(dest, reg(1)), (dest2, reg(2))],
+                    (dest, dest2) => vec![(dest2, reg(2)), (dest, reg(1))],
+                };
+                stmts.extend(moves.into_iter().map(|(dest, src)| expr_stmt(dest, XvaOpcode::Move(src))));
+            }
+            (true, dest2) => {
+                let (quotient, remainder) = if signed { ("__divsi3", "__modsi3") } else { ("__udivsi3", "__umodsi3") };
+                let mut results = vec![(quotient, expr.dest)];
+                results.extend(dest2.map(|dest2| (remainder, dest2)));
+                // Each call needs the operands, so a result that replaces one of them is computed last
+                let replaces_operand = |dest: XvaRegister| dest == left || dest == right;
+                if results.iter().filter(|&&(_, dest)| replaces_operand(dest)).count() > 1 {
+                    panic!("The quotient and the remainder of a division cannot both replace its operands on Skyarch");
+                }
+                results.sort_by_key(|&(_, dest)| replaces_operand(dest));
+                for (routine, dest) in results {
+                    args(&mut stmts, 1, 2, true);
+                    stmts.push(call(routine, 2, 1));
+                    stmts.push(expr_stmt(dest, XvaOpcode::Move(reg(1))));
+                }
+            }
+        }
+        Some(stmts)
+    }
+
+    fn expr_cost(&self, op: &XvaOpcode, ty: XvaType, _: Self::MachineMode) -> Option<u32> {
+        if ty.size > 4 {
+            return None;
+        }
+        match op {
+            XvaOpcode::ZeroInit | XvaOpcode::Const(_) | XvaOpcode::Move(_) => Some(1),
+            XvaOpcode::BinaryOp { right: XvaOperand::Register(_), .. } | XvaOpcode::BinaryOp { op: BinaryOp::Add, right: XvaOperand::Const(XvaConst::Bits(_)), .. } => Some(1),
+            // The constant is loaded into a scratch register first
+            XvaOpcode::BinaryOp { right: XvaOperand::Const(XvaConst::Bits(_)), .. } => Some(2),
+            // `add` shifts its second source for free
+            XvaOpcode::ComputeAddr { base: XvaOperand::Register(_), size, index: XvaOperand::Register(_) } if size.is_power_of_two() => Some(1),
+            // These call the support library, and division loops over each bit of the quotient
+            XvaOpcode::UMul { .. } | XvaOpcode::SMul { .. } => Some(20),
+            XvaOpcode::UDiv { .. } | XvaOpcode::SDiv { .. } => Some(60),
+            _ => None,
+        }
+    }
+
+    fn peephole_rules(&self, _: Self::MachineMode) -> &'static [PeepholeRule] {
+        Self::PEEPHOLE_RULES
+    }
+
+    fn sched_info(&self, instr: &Instruction, _: Self::MachineMode) -> Option<SchedInfo> {
+        Self::sched_info_impl(instr)
+    }
+}
+
+/// The standard Skyarch calling convention.
+///
+/// Arguments are passed in `r1..r8`, with 8-byte values using two consecutive registers, and the rest on the stack in 4-byte slots.
+/// Values (including aggregates) larger than 8 bytes are passed by reference.
+/// Values are returned in `r1` and `r2`, or through a hidden pointer passed in `r1` (and returned in it) if they are larger than 8 bytes.
+/// `r16..r30` are preserved by the callee, and `r29` is used as the frame pointer.
+#[cfg(feature = "xva")]
+pub struct SkyarchAbi;
+
+#[cfg(feature = "xva")]
+impl CallingConvention for SkyarchAbi {
+    fn name(&self) -> &'static str {
+        "skyarch"
+    }
+
+    fn layout(&self, sig: &CallSignature, _: &FeatureSet) -> CallLayout {
+        const PTR: XvaType = XvaType { size: 4, align: 4, category: XvaCategory::Int };
+
+        let preserve = Regset::from_registers((16..=30).map(SkyarchRegister));
+        let clobbers = Regset::from_registers((1..16).map(SkyarchRegister).chain([SkyarchRegister::r31]));
+
+        let mut layout = CallLayout::new(preserve, clobbers);
+        let mut next_reg = 1;
+        let mut stack = StackArgs::new(0, 4);
+
+        if let Some(ret) = &sig.ret {
+            let ty = &ret.ty;
+            let loc = match ty.category {
+                XvaCategory::Null => ArgLocation::Registers(Vec::new()),
+                XvaCategory::Custom(_) => panic!("Unsupported return category {}", ty.category),
+                _ if ty.size <= 8 => ArgLocation::Registers((1..=ty.size.div_ceil(4)).map(|r| Register::new(SkyarchRegister(r))).collect()),
+                _ => {
+                    next_reg = 2;
+                    layout.return_regs.insert_register(SkyarchRegister(1));
+                    ArgLocation::Indirect(Box::new(ArgLocation::Registers(vec![Register::new(SkyarchRegister(1))])))
+                }
+            };
+            layout.set_ret(loc, &Skyarch);
+        }
+
+        for arg in &sig.params {
+            let ty = &arg.ty;
+            let by_ref = ty.size > 8;
+            let slot = if by_ref { &PTR } else { ty };
+            let loc = match ty.category {
+                XvaCategory::Null => ArgLocation::Registers(Vec::new()),
+                XvaCategory::Custom(_) => panic!("Unsupported argument category {}", ty.category),
+                _ => {
+                    let count = slot.size.div_ceil(4);
+                    if next_reg + count <= 9 {
+                        let regs = (next_reg..next_reg + count).map(|r| Register::new(SkyarchRegister(r))).collect();
+                        next_reg += count;
+                        ArgLocation::Registers(regs)
+                    } else {
+                        stack.alloc(slot)
+                    }
+                }
+            };
+
+            let loc = if by_ref { ArgLocation::Indirect(Box::new(loc)) } else { loc };
+            layout.push_param(loc, &Skyarch);
+        }
+
+        layout.stack_size = stack.size(4);
+        layout
+    }
+}
+
+#[cfg(all(test, feature = "xva"))]
+mod tests {
+    use std::{
+        cell::Cell,
+        collections::{HashMap, HashSet},
+    };
+
+    use super::*;
+    use crate::{
+        compiler::{callconv::{AggregateField, CallArg}, RelocationModel, StackGuard, StackProtectorLevel},
+        intern::Symbol,
+        target::{PropertyValue, TargetInfo, TargetProperties},
+        xva::{XvaExpr, XvaFrameProperties, XvaOpcode, XvaType},
+    };
+
+    fn context() -> CompilerContext {
+        context_with(&[])
+    }
+
+    /// A context with the given property overrides
+    fn context_with(overrides: &[(&str, PropertyValue)]) -> CompilerContext {
+        let properties = TargetProperties { global_properties: HashMap::new() };
+        let overrides = overrides.iter().map(|(key, val)| (Symbol::intern(key), *val)).collect();
+        CompilerContext {
+            mode: OneMachine::Singleton.into_id(),
+            properties: TargetInfo { properties, ptr_width: 32 },
+            property_overrides: TargetProperties { global_properties: overrides },
+            target_features: HashSet::new(),
+            global_address_kind: AddressKind::Default,
+            global_call_address_kind: AddressKind::Default,
+            local_address_kind: AddressKind::Default,
+            global_tls_kind: AddressKind::Default,
+            local_tls_kind: AddressKind::Default,
+            relocation_model: RelocationModel::Static,
+            stack_protector: StackProtectorLevel::None,
+            stack_guard: StackGuard::Global(Symbol::intern("__stack_chk_guard")),
+            label_counter: Cell::new(0),
+        }
+    }
+
+    fn reg(n: u64) -> XvaRegister {
+        XvaRegister::Physical(Register::new(SkyarchRegister(n)))
+    }
+
+    fn lower(stmt: XvaStatement) -> XvaStatement {
+        let mut stmt = stmt;
+        Skyarch.lower_mce(&mut stmt, OneMachine::Singleton, &context(), &XvaFrameProperties::new());
+        stmt
+    }
+
+    fn mov(dest: u8, src: u8, cond: SkyarchConditionCode) -> XvaStatement {
+        let [dest, ssrc] = [dest, src].map(|n| SkyarchRegno::new(n).unwrap());
+        XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Mov { dest, ssrc, latency: false, cond, dir: false, map: Map::GeneralPurpose }))
+    }
+
+    /// Sets the flags from `cond`
+    fn test(cond: u8) -> XvaStatement {
+        let src1 = SkyarchRegno::new(cond).unwrap();
+        XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Or { dest: SkyarchRegno::r0, src1, src2: SkyarchRegno::r0, supress_flags: false, shift: 0, shift_polarity: false, invert: 0 }))
+    }
+
+    fn select(dest: u64, cond: u64, left: u64, right: u64) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest: reg(dest), dest2: None, op: XvaOpcode::Select { cond: reg(cond), left: reg(left), right: reg(right) } })
+    }
+
+    #[test]
+    fn select_uses_conditional_mov() {
+        assert_eq!(
+            lower(select(1, 4, 2, 3)),
+            XvaStatement::Elaborated(vec![test(4), mov(1, 3, SkyarchConditionCode::Always), mov(1, 2, SkyarchConditionCode::NotZero)])
+        );
+        assert_eq!(lower(select(1, 4, 1, 3)), XvaStatement::Elaborated(vec![test(4), mov(1, 3, SkyarchConditionCode::Zero)]));
+        assert_eq!(lower(select(1, 4, 2, 1)), XvaStatement::Elaborated(vec![test(4), mov(1, 2, SkyarchConditionCode::NotZero)]));
+    }
+
+    #[test]
+    fn stack_arguments_are_addressed_from_the_stack_pointer() {
+        let frame = XvaFrameProperties { frame_size: 16, ..XvaFrameProperties::new() };
+        let lower_in = |stmt| {
+            let mut stmt = stmt;
+            Skyarch.lower_mce(&mut stmt, OneMachine::Singleton, &context(), &frame);
+            stmt
+        };
+        let raw = |instr| XvaStatement::RawInstr(Instruction::new_nullary(instr));
+        let addi = |dest, imm| raw(SkyarchInstruction::Addi { dest, signed: true, supress_flags: true, higher_half: false, imm });
+
+        // Incoming arguments are above the frame
+        let read = XvaStatement::Expr(XvaExpr { dest: reg(1), dest2: None, op: XvaOpcode::Read(XvaOperand::IncomingArg(4)) });
+        assert_eq!(lower_in(read), XvaStatement::Elaborated(vec![
+            raw(gpr_mov(SkyarchRegno::r1, SkyarchRegno::r30)),
+            addi(SkyarchRegno::r1, 20),
+            raw(SkyarchInstruction::Ld { dest: SkyarchRegno::r1, src: SkyarchRegno::r1, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default }),
+        ]));
+
+        // Outgoing arguments are at the bottom of the frame, and are stored with the width of their type
+        let half = XvaType { size: 2, align: 2, category: XvaCategory::Int };
+        assert_eq!(lower_in(XvaStatement::Write(XvaOperand::OutgoingArg(0), half, reg(2))), XvaStatement::Elaborated(vec![
+            raw(gpr_mov(SkyarchRegno::r15, SkyarchRegno::r30)),
+            raw(SkyarchInstruction::St { dest: SkyarchRegno::r15, src: SkyarchRegno::r2, width: SkyarchByteSize::Half, mode: SkyarchLoadStoreMode::Default }),
+        ]));
+
+        // The target of a tail call is loaded before the epilogue, into a register that the epilogue does not use
+        let tailcall = XvaStatement::Tailcall { dest: XvaOperand::IncomingArg(0), params: Regset::new() };
+        assert_eq!(lower_in(tailcall), XvaStatement::Elaborated(vec![
+            raw(gpr_mov(SkyarchRegno::r13, SkyarchRegno::r30)),
+            addi(SkyarchRegno::r13, 16),
+            raw(SkyarchInstruction::Ld { dest: SkyarchRegno::r13, src: SkyarchRegno::r13, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default }),
+            raw(SkyarchInstruction::Jmpr { cond: SkyarchConditionCode::Always, link: SkyarchRegno::r0, dest: SkyarchRegno::r13 }),
+        ]));
+    }
+
+    fn addi_sp(imm: i16) -> XvaStatement {
+        XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Addi { dest: SkyarchRegno::r30, signed: true, supress_flags: true, higher_half: false, imm: imm as u16 }))
+    }
+
+    fn probe() -> XvaStatement {
+        XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::St { dest: SkyarchRegno::r30, src: SkyarchRegno::r0, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default }))
+    }
+
+    fn probed_prologue(frame: &mut XvaFrameProperties, probes: &str) -> Vec<XvaStatement> {
+        let context = context_with(&[("stack-probes", PropertyValue::String(Symbol::intern(probes)))]);
+        Skyarch.emit_prologue(frame, OneMachine::Singleton, &context)
+    }
+
+    fn leaf_frame(frame_size: usize) -> XvaFrameProperties {
+        XvaFrameProperties { frame_size, frame_align: 4, call_align: 4, is_leaf: true, ..XvaFrameProperties::new() }
+    }
+
+    #[test]
+    fn unrolled_stack_probes() {
+        assert_eq!(probed_prologue(&mut leaf_frame(2 * 4096 + 8), "inline"), [
+            addi_sp(-4096),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(4096)),
+            probe(),
+            addi_sp(-4096),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(2 * 4096)),
+            probe(),
+            addi_sp(-8),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(2 * 4096 + 8)),
+        ]);
+    }
+
+    #[test]
+    fn stack_probe_call_saves_link_register() {
+        let mut frame = leaf_frame(3 * 4096);
+        let target = Operand::RelSymbol(RelocSym { sym: Symbol::intern("__probe"), kind: AddressKind::Default }, None);
+        assert_eq!(probed_prologue(&mut frame, "call:__probe"), [
+            XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::St { dest: SkyarchRegno::r30, src: SkyarchRegno::r31, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PreDec })),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(4)),
+            XvaStatement::Cfi(XvaCfi::Offset(Register::new(SkyarchRegister::r31), -4)),
+            XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ldi { dest: SkyarchRegno::r14, signed: false, imm: 3 * 4096 })),
+            XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::JmpW { cond: SkyarchConditionCode::Always, link: SkyarchRegno::r31, dest: SkyarchRegno::r15 }, vec![target])),
+            XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Sub { dest: SkyarchRegno::r30, src1: SkyarchRegno::r30, src2: SkyarchRegno::r14, supress_flags: true, shift: 0, shift_polarity: false })),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(4 + 3 * 4096)),
+        ]);
+        assert!(!frame.is_leaf);
+
+        // A frame that fits in a page stays a leaf
+        let mut frame = leaf_frame(64);
+        assert_eq!(probed_prologue(&mut frame, "call:__probe"), [addi_sp(-64), XvaStatement::Cfi(XvaCfi::DefCfaOffset(64))]);
+        assert!(frame.is_leaf);
+    }
+
+    #[test]
+    fn stack_protector_canary() {
+        let mut context = context();
+        context.stack_protector = StackProtectorLevel::All;
+        let raw = |instr| XvaStatement::RawInstr(Instruction::new_nullary(instr));
+        let guard = XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::LraW { dest: SkyarchRegno::r15, signed: false }, vec![Operand::RelSymbol(RelocSym { sym: Symbol::intern("__stack_chk_guard"), kind: AddressKind::Default }, None)]));
+        let load = |dest, src| raw(SkyarchInstruction::Ld { dest, src, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default });
+        let slot = [raw(gpr_mov(SkyarchRegno::r14, SkyarchRegno::r30)), raw(SkyarchInstruction::Addi { dest: SkyarchRegno::r14, signed: true, supress_flags: true, higher_half: false, imm: 8 })];
+
+        // The canary takes the top 4 bytes of the 12 byte frame
+        let mut frame = leaf_frame(8);
+        let mut prologue = vec![addi_sp(-12), XvaStatement::Cfi(XvaCfi::DefCfaOffset(12)), guard.clone(), load(SkyarchRegno::r15, SkyarchRegno::r15)];
+        prologue.extend(slot.clone());
+        prologue.push(raw(SkyarchInstruction::St { dest: SkyarchRegno::r14, src: SkyarchRegno::r15, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default }));
+        assert_eq!(Skyarch.emit_prologue(&mut frame, OneMachine::Singleton, &context), prologue);
+        assert_eq!(frame.stack_protector_slot, Some(8));
+
+        let ok = Symbol::intern(".Lcanary_ok.0");
+        let fail = Operand::RelSymbol(RelocSym { sym: Symbol::intern("__stack_chk_fail"), kind: AddressKind::Default }, None);
+        let mut check = vec![guard, load(SkyarchRegno::r15, SkyarchRegno::r15)];
+        check.extend(slot);
+        check.extend([
+            load(SkyarchRegno::r14, SkyarchRegno::r14),
+            raw(SkyarchInstruction::Sub { dest: SkyarchRegno::r0, src1: SkyarchRegno::r14, src2: SkyarchRegno::r15, supress_flags: false, shift: 0, shift_polarity: false }),
+            XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::Jmp { cond: SkyarchConditionCode::Zero, link: SkyarchRegno::r0, offset: 0 }, vec![Operand::RelSymbol(RelocSym { sym: ok, kind: AddressKind::Default }, None)])),
+            XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::JmpW { cond: SkyarchConditionCode::Always, link: SkyarchRegno::r31, dest: SkyarchRegno::r15 }, vec![fail])),
+            XvaStatement::Label(ok),
+        ]);
+        assert_eq!(Skyarch.lower_epilogue(&frame, OneMachine::Singleton, &context)[..check.len()], check);
+    }
+
+    fn signature(params: &[XvaType], ret: Option<XvaType>) -> CallSignature {
+        CallSignature::new(params.iter().map(|&ty| CallArg::from(ty)).collect(), ret.map(CallArg::from))
+    }
+
+    #[test]
+    fn abi_argument_locations() {
+        let i32 = XvaType { size: 4, align: 4, category: XvaCategory::Int };
+        let i64 = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+        let regs = |regs: &[u64]| ArgLocation::Registers(regs.iter().map(|&n| Register::new(SkyarchRegister(n))).collect());
+
+        let sig = signature(&[i32, i64, i32, i32, i32, i32, i32, i32, i64], Some(i64));
+        let layout = SkyarchAbi.layout(&sig, &FeatureSet::new());
+        assert_eq!(layout.params, [
+            regs(&[1]),
+            regs(&[2, 3]),
+            regs(&[4]),
+            regs(&[5]),
+            regs(&[6]),
+            regs(&[7]),
+            regs(&[8]),
+            ArgLocation::Stack(0),
+            ArgLocation::Stack(8),
+        ]);
+        assert_eq!(layout.ret, Some(regs(&[1, 2])));
+        assert_eq!(layout.stack_size, 16);
+        assert!(layout.preserve_regs.contains_regid(SkyarchRegister::r29, &Skyarch));
+        assert!(layout.clobber_regs.contains_regid(SkyarchRegister::r31, &Skyarch));
+    }
+
+    #[test]
+    fn abi_aggregates_larger_than_8_bytes_are_passed_by_reference() {
+        let i32 = XvaType { size: 4, align: 4, category: XvaCategory::Int };
+        let fields = |n: u64| (0..n).map(|i| AggregateField { offset: 4 * i, ty: i32 }).collect();
+        let pair = CallArg::aggregate(8, 4, fields(2));
+        let triple = CallArg::aggregate(12, 4, fields(3));
+        let reg = |n: u64| Register::new(SkyarchRegister(n));
+
+        let sig = CallSignature::new(vec![triple.clone(), pair], Some(triple));
+        let layout = SkyarchAbi.layout(&sig, &FeatureSet::new());
+        // The hidden return pointer is passed in r1, and also returned in it
+        assert_eq!(layout.ret, Some(ArgLocation::Indirect(Box::new(ArgLocation::Registers(vec![reg(1)])))));
+        assert_eq!(layout.params, [
+            ArgLocation::Indirect(Box::new(ArgLocation::Registers(vec![reg(2)]))),
+            ArgLocation::Registers(vec![reg(3), reg(4)]),
+        ]);
+        assert!(layout.param_regs.contains_regid(SkyarchRegister(1), &Skyarch));
+        assert!(layout.return_regs.contains_regid(SkyarchRegister(1), &Skyarch));
+    }
+
+    #[test]
+    fn pic_loads_global_addresses_from_the_got() {
+        let mut context = context();
+        context.set_relocation_model(RelocationModel::Pic);
+        let lra = |kind, disp| XvaStatement::RawInstr(Instruction::new(SkyarchInstruction::LraW { dest: SkyarchRegno::r1, signed: false }, vec![Operand::RelSymbol(RelocSym { sym: Symbol::intern("x"), kind }, core::num::NonZeroI64::new(disp))]));
+        let load = |c| {
+            let mut stmt = XvaStatement::Expr(XvaExpr { dest: reg(1), dest2: None, op: XvaOpcode::Const(c) });
+            Skyarch.lower_mce(&mut stmt, OneMachine::Singleton, &context, &XvaFrameProperties::new());
+            stmt
+        };
+
+        // The displacement is added after the address is loaded from the GOT entry
+        assert_eq!(load(crate::xva::XvaConst::Global(Symbol::intern("x"), 8)), XvaStatement::Elaborated(vec![
+            lra(AddressKind::GotRel, 0),
+            XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Ld { dest: SkyarchRegno::r1, src: SkyarchRegno::r1, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::Default })),
+            XvaStatement::RawInstr(Instruction::new_nullary(SkyarchInstruction::Addi { dest: SkyarchRegno::r1, signed: true, supress_flags: true, higher_half: false, imm: 8 })),
+        ]));
+
+        // Local symbols are addressed relative to the code
+        assert_eq!(load(crate::xva::XvaConst::Label(Symbol::intern("x"))), lra(AddressKind::Default, 0));
+    }
+
+    /// Applies the peephole rules to `instrs`
+    fn peephole(instrs: Vec<SkyarchInstruction>) -> Vec<Instruction> {
+        let mut instrs = instrs.into_iter().map(Instruction::new_nullary).collect();
+        crate::xva::opt::peephole::apply_rules(&mut instrs, Skyarch::PEEPHOLE_RULES, OneMachine::Singleton.into_id());
+        instrs
+    }
+
+    fn gpr_mov(dest: SkyarchRegno, ssrc: SkyarchRegno) -> SkyarchInstruction {
+        SkyarchInstruction::Mov { dest, ssrc, latency: false, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose }
+    }
+
+    #[test]
+    fn peephole_removes_no_op_moves_and_adds() {
+        assert_eq!(peephole(vec![gpr_mov(SkyarchRegno::r1, SkyarchRegno::r1)]), []);
+        assert_eq!(peephole(vec![gpr_mov(SkyarchRegno::r1, SkyarchRegno::r2)]), [Instruction::new_nullary(gpr_mov(SkyarchRegno::r1, SkyarchRegno::r2))]);
+
+        let addi = |supress_flags, imm| SkyarchInstruction::Addi { dest: SkyarchRegno::r1, signed: true, supress_flags, higher_half: false, imm };
+        assert_eq!(peephole(vec![addi(true, 0)]), []);
+        // Adding zero still sets the flags
+        assert_eq!(peephole(vec![addi(false, 0)]), [Instruction::new_nullary(addi(false, 0))]);
+        assert_eq!(peephole(vec![addi(true, 1)]), [Instruction::new_nullary(addi(true, 1))]);
+    }
+
+    #[test]
+    fn peephole_turns_push_pop_into_a_move() {
+        let push = |src| SkyarchInstruction::St { dest: SkyarchRegno::r30, src, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PreDec };
+        let pop = |dest| SkyarchInstruction::Ld { dest, src: SkyarchRegno::r30, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PostInc };
+        assert_eq!(peephole(vec![push(SkyarchRegno::r1), pop(SkyarchRegno::r2)]), [Instruction::new_nullary(gpr_mov(SkyarchRegno::r2, SkyarchRegno::r1))]);
+        assert_eq!(peephole(vec![push(SkyarchRegno::r1), push(SkyarchRegno::r2), pop(SkyarchRegno::r2), pop(SkyarchRegno::r1)]), []);
+        // Pushing the stack pointer observes the adjustment
+        let push_sp = vec![push(SkyarchRegno::r30), pop(SkyarchRegno::r1)];
+        assert_eq!(peephole(push_sp.clone()), push_sp.into_iter().map(Instruction::new_nullary).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sched_info_models_loads_and_flags() {
+        let regs = |regs: &[SkyarchRegno]| regs.iter().map(|&reg| Register::new(reg.gpr())).collect::<Vec<_>>();
+        let sched = |instr: SkyarchInstruction| Skyarch.sched_info(&Instruction::new_nullary(instr), OneMachine::Singleton);
+
+        let pop = sched(SkyarchInstruction::Ld { dest: SkyarchRegno::r1, src: SkyarchRegno::r30, width: SkyarchByteSize::Word, mode: SkyarchLoadStoreMode::PostInc }).unwrap();
+        assert_eq!((pop.latency, pop.reads, pop.writes, pop.loads), (Skyarch::SCHED_LOAD_LATENCY, regs(&[SkyarchRegno::r30]), regs(&[SkyarchRegno::r1, SkyarchRegno::r30]), true));
+
+        let add = |supress_flags| sched(SkyarchInstruction::Add { dest: SkyarchRegno::r1, src1: SkyarchRegno::r2, src2: SkyarchRegno::r3, supress_flags, shift: 0, shift_polarity: false }).unwrap();
+        assert_eq!((add(true).reads, add(true).writes, add(true).writes_flags), (regs(&[SkyarchRegno::r2, SkyarchRegno::r3]), regs(&[SkyarchRegno::r1]), false));
+        assert!(add(false).writes_flags);
+
+        // A conditional move reads the flags and the old value of its destination
+        let cmov = sched(SkyarchInstruction::Mov { dest: SkyarchRegno::r1, ssrc: SkyarchRegno::r2, latency: false, cond: SkyarchConditionCode::Zero, dir: false, map: Map::GeneralPurpose }).unwrap();
+        assert!(cmov.reads_flags);
+        assert_eq!(cmov.reads, regs(&[SkyarchRegno::r2, SkyarchRegno::r1]));
+
+        // The timing of movl is explicit
+        assert_eq!(sched(SkyarchInstruction::Mov { dest: SkyarchRegno::r1, ssrc: SkyarchRegno::r2, latency: true, cond: SkyarchConditionCode::Always, dir: false, map: Map::GeneralPurpose }), None);
+    }
+
+    #[test]
+    fn multiplications_and_divisions_call_the_support_library() {
+        let expand = |dest2, op| Skyarch.expand_runtime_calls(&XvaExpr { dest: reg(18), dest2, op }, OneMachine::Singleton, &context(), &XvaFrameProperties::new()).unwrap();
+        let mov = |dest, src| XvaStatement::Expr(XvaExpr { dest: reg(dest), dest2: None, op: XvaOpcode::Move(reg(src)) });
+        let call = |name: &str, params, rets| XvaStatement::Call {
+            dest: XvaOperand::Const(XvaConst::Global(Symbol::intern(name), 0)),
+            params: Regset::from_registers((1..=params).map(SkyarchRegister)),
+            ret_val: Regset::from_registers((1..=rets).map(SkyarchRegister)),
+            call_clobber_regs: Regset::from_registers((1..16).map(SkyarchRegister).chain([SkyarchRegister::r31])),
+            callee_pop: 0,
+            indirect_ret: false,
+        };
+
+        assert_eq!(expand(None, XvaOpcode::UMul { left: reg(16), right: reg(17) }), vec![mov(1, 16), mov(2, 17), call("__mulsi3", 2, 1), mov(18, 1)]);
+
+        // The remainder is computed first, since the quotient replaces the dividend
+        assert_eq!(
+            expand(Some(reg(19)), XvaOpcode::UDiv { left: reg(18), right: reg(17) }),
+            vec![mov(1, 18), mov(2, 17), call("__umodsi3", 2, 1), mov(19, 1), mov(1, 18), mov(2, 17), call("__udivsi3", 2, 1), mov(18, 1)]
+        );
+
+        // The high half of a full product comes from a 64-bit multiplication of the sign extended operands
+        let sign = |dest, src| XvaStatement::Expr(XvaExpr {
+            dest: reg(dest),
+            dest2: None,
+            op: XvaOpcode::BinaryOp { op: BinaryOp::ShiftRight(ShiftBehaviour::AssumeQuantity, RightShiftMode::Signed), left: reg(src), right: XvaOperand::Const(XvaConst::Bits(31)) },
+        });
+        assert_eq!(
+            expand(Some(reg(19)), XvaOpcode::SMul { left: reg(16), right: reg(17) }),
+            vec![mov(1, 16), mov(3, 17), sign(2, 1), sign(4, 3), call("__muldi3", 4, 2), mov(19, 2), mov(18, 1)]
+        );
+    }
+}
@@ -5,11 +5,11 @@
 
 
 use crate::{
-    instr::{AddressKind, Instruction, Operand, RelocSym}, mach::{FeatureSet, MachineMode, MachineSpec, Opcode, Register, RegisterSpec, Regset, TargetFeatureSpec}, traits::{AsId, AsRawId, IdType, Name},
+    instr::{Address, AddressKind, Instruction, MemoryOperand, Operand, RelocSym}, mach::{FeatureSet, MachineMode, MachineSpec, Opcode, Register, RegisterSpec, Regset, TargetFeatureSpec}, traits::{AsId, AsRawId, IdType, Name},
 };
 
 #[cfg(feature = "xva")]
-use crate::{compiler::{CompilerSpec, CompilerContext}, xva::{XvaCategory, BinaryOp, RightShiftMode, XvaOperand, XvaRegister, XvaStatement, XvaOpcode}};
+use crate::{compiler::{callconv::{is_fp_category, ArgLocation, CallArg, CallLayout, CallSignature, CallingConvention, StackArgs}, CompilerSpec, CompilerContext, StackGuard, StackProbeKind, TlsModel}, xva::{dwarf::DwarfCie, opt::{peephole::PeepholeRule, sched::SchedInfo}, XvaBasicBlock, XvaBlockBody, XvaCategory, XvaConst, XvaExpr, XvaFrameProperties, XvaFunction, XvaFunctionDef, XvaSection, Linkage, XvaType, BinaryOp, RightShiftMode, XvaCfi, XvaOperand, XvaRegister, XvaStatement, XvaOpcode}};
 
 use crate::instr::RegisterKind;
 
@@ -686,6 +686,55 @@ impl RegisterSpec for X86Register {
 
         base_regs
     }
+
+    fn dwarf_regno(&self, mode: Self::MachineMode) -> Option<u16> {
+        if mode == X86Mode::Long {
+            match *self {
+                X86Register::ByteLegacy(4..8) => None,
+                X86Register::ByteLegacy(n)
+                | X86Register::Byte(n)
+                | X86Register::ByteRex(n)
+                | X86Register::Word(n)
+                | X86Register::Double(n)
+                | X86Register::Quad(n) => match n {
+                    0..8 => Some([0, 2, 1, 3, 7, 6, 4, 5][n as usize]),
+                    8..16 => Some(n as u16),
+                    n => Some(114 + n as u16),
+                },
+                X86Register::Xmm(n) | X86Register::Ymm(n) | X86Register::Zmm(n) => match n {
+                    0..16 => Some(17 + n as u16),
+                    n => Some(51 + n as u16),
+                },
+                X86Register::St(n) => Some(33 + n as u16),
+                X86Register::Mmx(n) => Some(41 + n as u16),
+                X86Register::Segment(n) => Some(50 + n as u16),
+                X86Register::SegmentBase(4) => Some(58),
+                X86Register::SegmentBase(5) => Some(59),
+                X86Register::SseSysReg(_) => Some(64),
+                X86Register::X87SysReg(0) => Some(65),
+                X86Register::X87SysReg(1) => Some(66),
+                X86Register::Kreg(n @ 0..8) => Some(118 + n as u16),
+                _ => None,
+            }
+        } else {
+            match *self {
+                X86Register::ByteLegacy(4..8) => None,
+                X86Register::ByteLegacy(n)
+                | X86Register::Byte(n)
+                | X86Register::Word(n @ 0..8)
+                | X86Register::Double(n @ 0..8) => Some(n as u16),
+                X86Register::St(n) => Some(11 + n as u16),
+                X86Register::Xmm(n @ 0..8) | X86Register::Ymm(n @ 0..8) | X86Register::Zmm(n @ 0..8) => Some(21 + n as u16),
+                X86Register::Mmx(n) => Some(29 + n as u16),
+                X86Register::X87SysReg(0) => Some(37),
+                X86Register::X87SysReg(1) => Some(38),
+                X86Register::SseSysReg(_) => Some(39),
+                X86Register::Segment(n) => Some(40 + n as u16),
+                X86Register::Kreg(n @ 0..8) => Some(93 + n as u16),
+                _ => None,
+            }
+        }
+    }
 }
 
 /// Expands to a constant array of [`X86Register`]s with the specified name
@@ -780,6 +829,8 @@ x86_instructions! {
         Rep ("rep") = 0xF3;
         !prefix
         Wait ("fwait") = 0x9B;
+        !prefix
+        Rex64 ("rex64") = 0x48;
         Add ("add") {
             [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte), _ @ Register(X86RegisterClass::Byte)] => 0x00,
         }
@@ -795,22 +846,77 @@ x86_instructions! {
         Xor ("xor") {
             [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte), _ @ Register(X86RegisterClass::Byte)] => 0x30,
         }
+        Cmp ("cmp") {
+            [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte), _ @ Register(X86RegisterClass::Byte)] => 0x38,
+        }
         Mov ("mov") {
             [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte), _ @ Register(X86RegisterClass::Byte)] => 0x88,
         }
         Lea ("lea") {
             [_ @ Register(X86RegisterClass::Word | X86RegisterClass::Double | X86RegisterClass::Quad), _ @  Memory(_)] => 0x8D,
         }
+        Shl ("shl") {
+            [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte), _ @ Immediate] => 0xC0,
+        }
+        Shr ("shr") {
+            [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte), _ @ Immediate] => 0xC0,
+        }
+        Sar ("sar") {
+            [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte), _ @ Immediate] => 0xC0,
+        }
+        Test ("test") {
+            [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte), _ @ Register(X86RegisterClass::Byte)] => 0x84,
+        }
+        Cmovz ("cmovz") {
+            [_ @ Register(X86RegisterClass::Word | X86RegisterClass::Double | X86RegisterClass::Quad), _ @ Memory(_) | Register(X86RegisterClass::Word | X86RegisterClass::Double | X86RegisterClass::Quad)] => 0x0F44,
+        }
+        Cmovnz ("cmovnz") {
+            [_ @ Register(X86RegisterClass::Word | X86RegisterClass::Double | X86RegisterClass::Quad), _ @ Memory(_) | Register(X86RegisterClass::Word | X86RegisterClass::Double | X86RegisterClass::Quad)] => 0x0F45,
+        }
         Call ("call") {
             [_ @ RelAddr] => 0xE8,
         }
         Jump ("jmp") {
             [_ @ RelAddr] => 0xE9,
         }
+        Jz ("jz") {
+            [_ @ RelAddr] => 0x0F84,
+        }
+        Jnz ("jnz") {
+            [_ @ RelAddr] => 0x0F85,
+        }
 
         Ud2 ("ud2") {
             [] => 0x0F0B
         }
+        Mul ("mul") {
+            [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte)] => 0xF6,
+        }
+        Imul ("imul") {
+            [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte)] => 0xF6,
+            [_ @ Register(X86RegisterClass::Word | X86RegisterClass::Double | X86RegisterClass::Quad), _ @ Memory(_) | Register(X86RegisterClass::Word | X86RegisterClass::Double | X86RegisterClass::Quad)] => 0x0FAF,
+        }
+        Div ("div") {
+            [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte)] => 0xF6,
+        }
+        Idiv ("idiv") {
+            [_ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte)] => 0xF6,
+        }
+        Cwd ("cwd") {
+            [] => 0x99
+        }
+        Cdq ("cdq") {
+            [] => 0x99
+        }
+        Cqo ("cqo") {
+            [] => 0x99
+        }
+        Movzx ("movzx") {
+            [_ @ Register(X86RegisterClass::Word | X86RegisterClass::Double | X86RegisterClass::Quad), _ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte)] => 0x0FB6,
+        }
+        Movsx ("movsx") {
+            [_ @ Register(X86RegisterClass::Word | X86RegisterClass::Double | X86RegisterClass::Quad), _ @ Memory(X86RegisterClass::Byte) | Register(X86RegisterClass::Byte)] => 0x0FBE,
+        }
         Push ("push") {
             [dest @ Register(X86RegisterClass::Word | X86RegisterClass::Double | X86RegisterClass::Quad)] => 0x50 + (dest.regno() & 7),
         }
@@ -1056,6 +1162,7 @@ impl X86 {
                     X86Register::X87SysReg(_) | X86Register::SseSysReg(_) => panic!("Cannot move to a fsw/fcw/ftw/mxcsr (need to use read)"),
                 }
             },
+            XvaOpcode::ComputeAddr { base: XvaOperand::Register(_), size: 1 | 2 | 4 | 8, index: XvaOperand::Register(_) } => Some(X86Opcode::Lea),
             XvaOpcode::ComputeAddr { base, size, index } => todo!(),
             XvaOpcode::GetFrameAddr(_) => todo!(),
             XvaOpcode::BinaryOp { op, left, right } => {
@@ -1105,15 +1212,687 @@ impl X86 {
                     ) => {
                         Some(X86Opcode::Xor)
                     }
+                    // Shifts by a register need the quantity in `cl`, which is not supported yet
+                    (BinaryOp::ShiftLeft(_), X86Register::Byte(_) |
+                        X86Register::ByteLegacy(_) |
+                        X86Register::ByteRex(_) |
+                        X86Register::Word(_) |
+                        X86Register::Double(_) |
+                        X86Register::Quad(_)
+                    ) if matches!(right, XvaOperand::Const(XvaConst::Bits(_))) => {
+                        Some(X86Opcode::Shl)
+                    }
+                    (BinaryOp::ShiftRight(_, mode), X86Register::Byte(_) |
+                        X86Register::ByteLegacy(_) |
+                        X86Register::ByteRex(_) |
+                        X86Register::Word(_) |
+                        X86Register::Double(_) |
+                        X86Register::Quad(_)
+                    ) if matches!(right, XvaOperand::Const(XvaConst::Bits(_))) => {
+                        match mode {
+                            RightShiftMode::Unsigned => Some(X86Opcode::Shr),
+                            RightShiftMode::Signed => Some(X86Opcode::Sar),
+                        }
+                    }
                     _ => todo!("Combination")
                 }
             },
             XvaOpcode::CheckedBinaryOp { op, mode, left, right } => todo!(),
             XvaOpcode::UnaryOp { op, left } => todo!(),
+            XvaOpcode::Read(XvaOperand::FrameAddr(_) | XvaOperand::IncomingArg(_) | XvaOperand::OutgoingArg(_)) => Some(X86Opcode::Mov),
             XvaOpcode::Read(xva_operand) => todo!(),
-            XvaOpcode::UMul { left, right } => todo!(),
-            XvaOpcode::SMul { left, right } => todo!(),
+            XvaOpcode::UMul { .. } | XvaOpcode::SMul { .. } | XvaOpcode::UDiv { .. } | XvaOpcode::SDiv { .. } => unreachable!("multiplications and divisions are handled by lower_mul_div"),
+            XvaOpcode::Select { .. } => Some(X86Opcode::Cmovnz),
+            XvaOpcode::TlsAddr { .. } => Some(X86Opcode::Lea),
+            XvaOpcode::TlsIndex { .. } => Some(X86Opcode::Lea),
+        }
+    }
+
+    /// Lowers [`XvaOpcode::UMul`], [`XvaOpcode::SMul`], [`XvaOpcode::UDiv`], and [`XvaOpcode::SDiv`].
+    ///
+    /// A multiplication without a high half uses the two-operand `imul`. The others use the one-operand forms, which take the left operand in rAX and write rAX and rDX,
+    /// so rAX and rDX are saved on the stack around them unless they receive a result
+    fn lower_mul_div(&self, dest: X86Register, dest2: Option<X86Register>, op: &XvaOpcode, mode: X86Mode, frame: &XvaFrameProperties) -> XvaStatement {
+        let (left, right, signed, div) = match *op {
+            XvaOpcode::UMul { left, right } => (left, right, false, false),
+            XvaOpcode::SMul { left, right } => (left, right, true, false),
+            XvaOpcode::UDiv { left, right } => (left, right, false, true),
+            XvaOpcode::SDiv { left, right } => (left, right, true, true),
+            _ => unreachable!(),
+        };
+        let left = Self::areg(left);
+        let right = Self::areg(right);
+        let size = dest.gpr_size().expect("multiplication or division into a non-integer register");
+
+        let reg = |reg: X86Register| Operand::Register(Register::new(reg));
+        let raw = |op: X86Opcode, oprs: Vec<Operand>| XvaStatement::RawInstr(Instruction::new(Opcode::new(op), oprs));
+        let mut stmts = Vec::new();
+
+        if !div && dest2.is_none() {
+            // The low half of the product is the same for signed and unsigned values. `imul` has no two-operand byte form, so bytes are multiplied as doublewords
+            let size = if size == GprSize::Byte { GprSize::Double } else { size };
+            let (dest, left, right) = (dest.promote_gpr(size), left.promote_gpr(size), right.promote_gpr(size));
+            let (left, right) = if dest == right { (right, left) } else { (left, right) };
+            if dest != left {
+                stmts.push(raw(X86Opcode::Mov, vec![reg(dest), reg(left)]));
+            }
+            stmts.push(raw(X86Opcode::Imul, vec![reg(dest), reg(right)]));
+            return XvaStatement::Elaborated(stmts);
+        }
+
+        let mode_gpr = mode.largest_gpr();
+        let ptr_size = mode_gpr.size() as i64;
+        let sp = GprName::sp.as_reg(mode_gpr);
+        let (ax, dx) = (GprName::ax.as_reg(mode_gpr), GprName::dx.as_reg(mode_gpr));
+        let results: Vec<X86Register> = [Some(dest), dest2].into_iter().flatten().map(|reg| reg.promote_gpr(mode_gpr)).collect();
+
+        // The registers pushed before the instruction, and whether each is restored afterwards.
+        // The right operand is read from the stack if the instruction would overwrite it first, which the byte forms never do to rDX
+        let mut pushed: Vec<(X86Register, bool)> = [dx, ax].into_iter().filter(|reg| !results.contains(reg)).map(|reg| (reg, true)).collect();
+        let right_full = right.promote_gpr(mode_gpr);
+        let overwritten = right_full == ax || (div && size != GprSize::Byte && right_full == dx);
+        if overwritten && !pushed.iter().any(|&(reg, _)| reg == right_full) {
+            pushed.push((right_full, false));
+        }
+
+        let cfa_offset = (!frame.use_frame_pointer).then_some(ptr_size + frame.frame_size as i64);
+        for (n, &(saved, _)) in pushed.iter().enumerate() {
+            stmts.push(raw(X86Opcode::Push, vec![reg(saved)]));
+            if let Some(cfa_offset) = cfa_offset {
+                stmts.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(cfa_offset + (n as i64 + 1) * ptr_size)));
+            }
+        }
+
+        let right = match pushed.iter().position(|&(saved, _)| overwritten && saved == right_full) {
+            Some(n) => {
+                let disp = (pushed.len() - 1 - n) as i64 * ptr_size;
+                let addr = Address { segment: None, base: Some(Register::new(sp)), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(disp), rel: false };
+                Operand::Memory(MemoryOperand { value_size: Some(size.size() as usize), addr })
+            }
+            None => reg(right),
+        };
+
+        let (a, d) = (GprName::ax.as_reg(size), GprName::dx.as_reg(size));
+        if size == GprSize::Byte && div {
+            // The byte forms divide all of ax
+            let extend = if signed { X86Opcode::Movsx } else { X86Opcode::Movzx };
+            stmts.push(raw(extend, vec![reg(GprName::ax.as_reg(GprSize::Word)), reg(left)]));
+        } else if left != a {
+            stmts.push(raw(X86Opcode::Mov, vec![reg(a), reg(left)]));
+        }
+        if div && size != GprSize::Byte {
+            if signed {
+                let extend = match size {
+                    GprSize::Word => X86Opcode::Cwd,
+                    GprSize::Double => X86Opcode::Cdq,
+                    _ => X86Opcode::Cqo,
+                };
+                stmts.push(raw(extend, vec![]));
+            } else {
+                stmts.push(raw(X86Opcode::Xor, vec![reg(d), reg(d)]));
+            }
+        }
+
+        let opcode = match (div, signed) {
+            (false, false) => X86Opcode::Mul,
+            (false, true) => X86Opcode::Imul,
+            (true, false) => X86Opcode::Div,
+            (true, true) => X86Opcode::Idiv,
+        };
+        stmts.push(raw(opcode, vec![right]));
+
+        if size == GprSize::Byte && dest2.is_some() {
+            // The byte forms leave the high half or the remainder in ah, which cannot be moved to every byte register, so it is moved to dl instead
+            let (eax, edx) = (GprName::ax.as_reg(GprSize::Double), GprName::dx.as_reg(GprSize::Double));
+            stmts.push(raw(X86Opcode::Mov, vec![reg(edx), reg(eax)]));
+            stmts.push(raw(X86Opcode::Shr, vec![reg(edx), Operand::Immediate(8)]));
+        }
+
+        // The result in rAX goes to `dest` and the one in rDX to `dest2`, swapping them if each is in the other's register
+        match dest2 {
+            Some(dest2) if dest == d && dest2 == a => {
+                stmts.extend([raw(X86Opcode::Xor, vec![reg(a), reg(d)]), raw(X86Opcode::Xor, vec![reg(d), reg(a)]), raw(X86Opcode::Xor, vec![reg(a), reg(d)])]);
+            }
+            Some(dest2) if dest == d => {
+                stmts.push(raw(X86Opcode::Mov, vec![reg(dest2), reg(d)]));
+                stmts.push(raw(X86Opcode::Mov, vec![reg(dest), reg(a)]));
+            }
+            _ => {
+                if dest != a {
+                    stmts.push(raw(X86Opcode::Mov, vec![reg(dest), reg(a)]));
+                }
+                if let Some(dest2) = dest2.filter(|&dest2| dest2 != d) {
+                    stmts.push(raw(X86Opcode::Mov, vec![reg(dest2), reg(d)]));
+                }
+            }
+        }
+
+        for (n, &(saved, restore)) in pushed.iter().enumerate().rev() {
+            if restore {
+                stmts.push(raw(X86Opcode::Pop, vec![reg(saved)]));
+            } else {
+                stmts.push(raw(X86Opcode::Add, vec![reg(sp), Operand::Immediate(ptr_size as u128)]));
+            }
+            if let Some(cfa_offset) = cfa_offset {
+                stmts.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(cfa_offset + n as i64 * ptr_size)));
+            }
+        }
+
+        XvaStatement::Elaborated(stmts)
+    }
+
+    /// Lowers [`XvaOpcode::Select`]. Uses `cmov` if available, and otherwise branches around the move of `left`
+    fn lower_select(&self, dest: X86Register, cond: XvaRegister, left: XvaRegister, right: XvaRegister, context: &CompilerContext, features: &FeatureSet) -> XvaStatement {
+        let cond = Self::areg(cond);
+        let mut left = Self::areg(left);
+        let mut right = Self::areg(right);
+        let mut dest = dest;
+
+        let mut stmts = Vec::new();
+
+        stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Test), vec![Operand::Register(Register::new(cond)), Operand::Register(Register::new(cond))])));
+
+        if features.contains_feature(&X86TargetFeature::Cmov) {
+            // cmov has no byte form, so byte selects are performed on the whole register
+            if dest.gpr_size() == Some(GprSize::Byte) {
+                dest = dest.promote_gpr(GprSize::Double);
+                left = left.promote_gpr(GprSize::Double);
+                right = right.promote_gpr(GprSize::Double);
+            }
+
+            if dest == left {
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Cmovz), vec![Operand::Register(Register::new(dest)), Operand::Register(Register::new(right))])));
+            } else {
+                if dest != right {
+                    stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(Register::new(dest)), Operand::Register(Register::new(right))])));
+                }
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Cmovnz), vec![Operand::Register(Register::new(dest)), Operand::Register(Register::new(left))])));
+            }
+        } else {
+            let skip = context.local_label("select");
+            let skip_target = Operand::RelSymbol(RelocSym { sym: skip, kind: AddressKind::Default }, None);
+
+            // `mov` does not modify flags, so the moves can be placed after the `test`
+            let (jcc, src) = if dest == left {
+                (X86Opcode::Jnz, right)
+            } else {
+                if dest != right {
+                    stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(Register::new(dest)), Operand::Register(Register::new(right))])));
+                }
+                (X86Opcode::Jz, left)
+            };
+
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(jcc), vec![skip_target])));
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(Register::new(dest)), Operand::Register(Register::new(src))])));
+            stmts.push(XvaStatement::Label(skip));
+        }
+
+        XvaStatement::Elaborated(stmts)
+    }
+
+    /// Allocates `size` bytes of stack, probing each page according to [`CompilerContext::stack_probes`].
+    ///
+    /// `cfa_offset` is the offset of the CFA from the stack pointer before the allocation, or [`None`] if the CFA is computed from the frame pointer.
+    /// The inline loop uses `r11` in 64-bit mode and `eax` otherwise as a scratch register. `eax` may hold an argument, so it is saved on the stack around the loop.
+    /// The probe function of [`StackProbeKind::Call`] is called with the size in `eax`/`rax`, which is saved the same way, and does not adjust the stack pointer itself.
+    fn emit_stack_alloc(&self, stmts: &mut Vec<XvaStatement>, size: usize, cfa_offset: Option<i64>, mode: X86Mode, context: &CompilerContext) {
+        let mode_gpr = mode.largest_gpr();
+        let sp = Register::new(GprName::sp.as_reg(mode_gpr));
+        let page_size = context.stack_probe_size();
+
+        let sub = |stmts: &mut Vec<XvaStatement>, size: usize| {
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Sub), vec![Operand::Register(sp), Operand::Immediate(size as u128)])));
+        };
+        let probe = |stmts: &mut Vec<XvaStatement>| {
+            let addr = Address { segment: None, base: Some(sp), index: None, scale: nzlit!(1), sym: None, disp: None, rel: false };
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Memory(MemoryOperand { value_size: Some(mode_gpr.size() as usize), addr }), Operand::Immediate(0)])));
+        };
+        let def_cfa_offset = |stmts: &mut Vec<XvaStatement>, allocated: usize| {
+            if let Some(off) = cfa_offset {
+                stmts.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(off + allocated as i64)));
+            }
+        };
+
+        match context.stack_probes() {
+            StackProbeKind::Inline if size > page_size && size <= 8 * page_size => {
+                let mut allocated = 0;
+                while size - allocated >= page_size {
+                    sub(stmts, page_size);
+                    allocated += page_size;
+                    def_cfa_offset(stmts, allocated);
+                    probe(stmts);
+                }
+
+                if allocated < size {
+                    sub(stmts, size - allocated);
+                    def_cfa_offset(stmts, size);
+                }
+            }
+            StackProbeKind::Inline if size > page_size => {
+                let save = mode != X86Mode::Long;
+                let scratch = if save { GprName::ax } else { GprName::r11 };
+                let scratch = Register::new(scratch.as_reg(mode_gpr));
+                let rounded = size - (size % page_size);
+                let probe_loop = context.local_label("probe");
+
+                // The number of bytes below the stack pointer on entry when the loop starts
+                let saved = if save { mode_gpr.size() as usize } else { 0 };
+                if save {
+                    stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Push), vec![Operand::Register(scratch)])));
+                    def_cfa_offset(stmts, saved);
+                }
+
+                let addr = Address { segment: None, base: Some(sp), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(-(rounded as i64)), rel: false };
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Lea), vec![Operand::Register(scratch), Operand::Memory(MemoryOperand { value_size: None, addr })])));
+
+                // The stack pointer changes in the loop, so the CFA is computed from the loop bound until it is done
+                if let Some(off) = cfa_offset {
+                    stmts.push(XvaStatement::Cfi(XvaCfi::DefCfa(scratch, off + (saved + rounded) as i64)));
+                }
+
+                stmts.push(XvaStatement::Label(probe_loop));
+                sub(stmts, page_size);
+                probe(stmts);
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Cmp), vec![Operand::Register(sp), Operand::Register(scratch)])));
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Jnz), vec![Operand::RelSymbol(RelocSym { sym: probe_loop, kind: AddressKind::Default }, None)])));
+
+                if save {
+                    if let Some(off) = cfa_offset {
+                        stmts.push(XvaStatement::Cfi(XvaCfi::DefCfa(sp, off + (saved + rounded) as i64)));
+                    }
+
+                    // The saved register is just above the probed pages, and is left below the stack pointer once it is restored
+                    let addr = Address { segment: None, base: Some(sp), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(rounded as i64), rel: false };
+                    stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(scratch), Operand::Memory(MemoryOperand { value_size: Some(mode_gpr.size() as usize), addr })])));
+                    let addr = Address { segment: None, base: Some(sp), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new((saved + rounded) as i64 - size as i64), rel: false };
+                    stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Lea), vec![Operand::Register(sp), Operand::Memory(MemoryOperand { value_size: None, addr })])));
+                    def_cfa_offset(stmts, size);
+                } else {
+                    if rounded < size {
+                        sub(stmts, size - rounded);
+                    }
+
+                    if let Some(off) = cfa_offset {
+                        stmts.push(XvaStatement::Cfi(XvaCfi::DefCfa(sp, off + size as i64)));
+                    }
+                }
+            }
+            StackProbeKind::Call(sym) if size > page_size => {
+                let ax = Register::new(GprName::ax.as_reg(mode_gpr));
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Push), vec![Operand::Register(ax)])));
+                def_cfa_offset(stmts, mode_gpr.size() as usize);
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(ax), Operand::Immediate(size as u128)])));
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Call), vec![Operand::RelSymbol(RelocSym { sym, kind: context.global_call_address_kind }, None)])));
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Pop), vec![Operand::Register(ax)])));
+                def_cfa_offset(stmts, 0);
+                sub(stmts, size);
+                def_cfa_offset(stmts, size);
+            }
+            _ => {
+                sub(stmts, size);
+                def_cfa_offset(stmts, size);
+            }
+        }
+    }
+
+    /// The scratch register used to store and check the stack protector canary.
+    /// This is `r11` in 64-bit mode, which holds neither an argument nor a return value.
+    /// Every caller-saved register of the other modes may hold one of them, so `eax` is used, which holds no argument in the prologue, and is saved around the check in the epilogue
+    fn stack_protector_scratch(&self, mode: X86Mode) -> Register {
+        let scratch = if mode == X86Mode::Long { GprName::r11 } else { GprName::ax };
+        Register::new(scratch.as_reg(mode.largest_gpr()))
+    }
+
+    /// Loads the stack protector guard value into `scratch`
+    fn load_stack_guard(&self, stmts: &mut Vec<XvaStatement>, scratch: Register, mode: X86Mode, context: &CompilerContext) {
+        let value_size = Some(mode.largest_gpr().size() as usize);
+        let addr = match context.stack_guard {
+            StackGuard::Global(sym) => match self.symbol_address(XvaConst::Global(sym, 0), mode, context) {
+                (addr, true) => {
+                    stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(scratch), Operand::Memory(MemoryOperand { value_size, addr })])));
+                    Address { segment: None, base: Some(scratch), index: None, scale: nzlit!(1), sym: None, disp: None, rel: false }
+                }
+                (addr, false) => addr,
+            },
+            StackGuard::Segment(seg, off) => Address { segment: Some(seg), base: None, index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(off as i64), rel: false },
+        };
+        stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(scratch), Operand::Memory(MemoryOperand { value_size, addr })])));
+    }
+
+    /// Whether position-independent code in `mode` addresses symbols relative to the GOT, whose address is kept in `ebx`.
+    /// This is the case for i386, which has no pc-relative addressing
+    fn uses_got_base(&self, mode: X86Mode, context: &CompilerContext) -> bool {
+        mode == X86Mode::Protected && context.relocation_model.is_pic()
+    }
+
+    /// Whether the prologue of the function with `frame` loads the GOT into `ebx`, which is only done if the function refers to a symbol
+    fn needs_got_base(&self, frame: &XvaFrameProperties, mode: X86Mode, context: &CompilerContext) -> bool {
+        let guard_symbol = context.stack_protector.protects(frame) && matches!(context.stack_guard, StackGuard::Global(_));
+        self.uses_got_base(mode, context) && !frame.naked && (frame.references_symbols || guard_symbol)
+    }
+
+    /// The address of the symbol of `c`, which is either [`XvaConst::Label`] or [`XvaConst::Global`], and whether it is the address of the GOT entry of the symbol rather than of the symbol itself.
+    /// The displacement of a symbol that is accessed through the GOT is not included in the address, and must be added after loading the entry
+    fn symbol_address(&self, c: XvaConst, mode: X86Mode, context: &CompilerContext) -> (Address, bool) {
+        let (sym, disp, kind) = match c {
+            XvaConst::Label(sym) => (sym, 0, context.local_address_kind),
+            XvaConst::Global(sym, disp) => (sym, disp, context.global_address_kind),
+            XvaConst::Bits(_) => panic!("Not a symbol"),
+        };
+
+        let got = matches!(kind, AddressKind::GotRel | AddressKind::GotAbs);
+        let disp = if got { None } else { core::num::NonZeroI64::new(disp) };
+
+        let addr = if self.uses_got_base(mode, context) {
+            let kind = if got { AddressKind::GotAbs } else { AddressKind::GotOff };
+            Address { segment: None, base: Some(Register::new(crate::x86_register!(ebx))), index: None, scale: nzlit!(1), sym: Some(RelocSym { sym, kind }), disp, rel: false }
+        } else {
+            Address { segment: None, base: None, index: None, scale: nzlit!(1), sym: Some(RelocSym { sym, kind }), disp, rel: mode.supports_rel_addr() }
+        };
+
+        (addr, got)
+    }
+
+    /// Lowers an [`XvaOpcode::Const`] of a symbol, loading the address from the GOT if the symbol is accessed through it
+    fn lower_symbol_addr(&self, dest: X86Register, c: XvaConst, mode: X86Mode, context: &CompilerContext) -> XvaStatement {
+        let dest = Register::new(dest);
+        let mut stmts = Vec::new();
+
+        match self.symbol_address(c, mode, context) {
+            (addr, true) => {
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(dest), Operand::Memory(MemoryOperand { value_size: Some(mode.largest_gpr().size() as usize), addr })])));
+                if let XvaConst::Global(_, disp) = c
+                    && let Some(disp) = core::num::NonZeroI64::new(disp)
+                {
+                    let addr = Address { segment: None, base: Some(dest), index: None, scale: nzlit!(1), sym: None, disp: Some(disp), rel: false };
+                    stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Lea), vec![Operand::Register(dest), Operand::Memory(MemoryOperand { value_size: None, addr })])));
+                }
+            }
+            (addr, false) => {
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Lea), vec![Operand::Register(dest), Operand::Memory(MemoryOperand { value_size: None, addr })])));
+            }
+        }
+
+        XvaStatement::Elaborated(stmts)
+    }
+
+    /// The symbol of the thunk that loads its return address into `ebx`, used to find the GOT in i386 position-independent code
+    fn pc_thunk(&self) -> crate::intern::Symbol {
+        crate::intern::Symbol::intern("__x86.get_pc_thunk.bx")
+    }
+
+    /// The register that holds the target of a tail call that is loaded before the epilogue.
+    /// This is `r10` in 64-bit mode and `eax` otherwise, which hold no argument and are not used by the epilogue without being saved
+    fn tail_call_scratch(&self, mode: X86Mode) -> X86Register {
+        let scratch = if mode == X86Mode::Long { GprName::r10 } else { GprName::ax };
+        scratch.as_reg(mode.largest_gpr())
+    }
+
+    /// The TLS runtime function of x86-64, which returns the address of the variable of a `tls_index`
+    fn tls_get_addr(&self) -> crate::intern::Symbol {
+        crate::intern::Symbol::intern("__tls_get_addr")
+    }
+
+    /// The address of the stack slot `opr`, which is an [`XvaOperand::IncomingArg`], [`XvaOperand::OutgoingArg`], or [`XvaOperand::FrameAddr`].
+    ///
+    /// Incoming arguments start just above the return address, outgoing arguments at the stack pointer after the prologue, and the slots of the frame just above the outgoing arguments.
+    /// 16-bit modes cannot address relative to `sp`, so the prologue always sets up a frame pointer when stack arguments are used
+    fn stack_slot_address(&self, opr: XvaOperand, frame: &XvaFrameProperties, mode: X86Mode) -> Address {
+        let mode_gpr = mode.largest_gpr();
+        let ptr_size = mode_gpr.size() as i64;
+        let sixteen_bit = mode_gpr == GprSize::Word;
+        let (base, disp) = match opr {
+            XvaOperand::IncomingArg(off) if frame.use_frame_pointer => (GprName::bp, 2 * ptr_size + off as i64),
+            XvaOperand::IncomingArg(off) => (GprName::sp, frame.frame_size as i64 + ptr_size + off as i64),
+            XvaOperand::OutgoingArg(off) if sixteen_bit => (GprName::bp, off as i64 - (frame.frame_size as i64 - ptr_size)),
+            XvaOperand::OutgoingArg(off) => (GprName::sp, off as i64),
+            XvaOperand::FrameAddr(off) if frame.use_frame_pointer => (GprName::bp, (frame.outgoing_args_size as i64 + off as i64) - (frame.frame_size as i64 - ptr_size)),
+            XvaOperand::FrameAddr(off) => (GprName::sp, frame.outgoing_args_size as i64 + off as i64),
+            _ => panic!("Not a stack slot"),
+        };
+
+        Address { segment: None, base: Some(Register::new(base.as_reg(mode_gpr))), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(disp), rel: false }
+    }
+
+    /// Lowers [`XvaOpcode::TlsAddr`] using the static TLS models, or relative to `module_base` after [`XvaFile::expand_runtime_calls`][crate::xva::XvaFile::expand_runtime_calls].
+    ///
+    /// The thread pointer is read from `fs:0` in 64-bit mode and `gs:0` in 32-bit mode
+    fn lower_tls_addr(&self, dest: X86Register, sym: crate::intern::Symbol, disp: i64, local: bool, module_base: Option<XvaRegister>, mode: X86Mode, context: &CompilerContext) -> XvaStatement {
+        let mode_gpr = mode.largest_gpr();
+        let dest = Register::new(dest);
+        let disp = core::num::NonZeroI64::new(disp);
+        let rel = mode == X86Mode::Long;
+
+        let mut stmts = Vec::new();
+
+        if let Some(base) = module_base {
+            let base = Register::new(Self::areg(base));
+            let addr = Address { segment: None, base: Some(base), index: None, scale: nzlit!(1), sym: Some(RelocSym { sym, kind: AddressKind::DTpoff }), disp, rel: false };
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Lea), vec![Operand::Register(dest), Operand::Memory(MemoryOperand { value_size: None, addr })])));
+            return XvaStatement::Elaborated(stmts);
+        }
+
+        let segment = match mode {
+            X86Mode::Long => Register::new(crate::x86_register!(fs)),
+            X86Mode::Protected => Register::new(crate::x86_register!(gs)),
+            X86Mode::Real | X86Mode::Protected16 => panic!("Thread-local storage is not supported in 16-bit modes"),
+        };
+
+        // The first word of the thread control block points to itself
+        let tp = Address { segment: Some(segment), base: None, index: None, scale: nzlit!(1), sym: None, disp: None, rel: false };
+        stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(dest), Operand::Memory(MemoryOperand { value_size: Some(mode_gpr.size() as usize), addr: tp })])));
+
+        match context.tls_model(local) {
+            TlsModel::LocalExec => {
+                // The variables are below the thread pointer, and i386 uses the negated offset so that it can be added like on x86-64
+                let kind = if mode == X86Mode::Long { AddressKind::Tpoff } else { AddressKind::NTpoff };
+                let addr = Address { segment: None, base: Some(dest), index: None, scale: nzlit!(1), sym: Some(RelocSym { sym, kind }), disp, rel: false };
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Lea), vec![Operand::Register(dest), Operand::Memory(MemoryOperand { value_size: None, addr })])));
+            }
+            TlsModel::InitialExec => {
+                let base = self.uses_got_base(mode, context).then(|| Register::new(crate::x86_register!(ebx)));
+                let kind = match (mode, base) {
+                    (X86Mode::Long, _) => AddressKind::GotTpoff,
+                    (_, Some(_)) => AddressKind::GotNTpoff,
+                    (_, None) => AddressKind::IndNTpoff,
+                };
+                let addr = Address { segment: None, base, index: None, scale: nzlit!(1), sym: Some(RelocSym { sym, kind }), disp: None, rel };
+                stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Add), vec![Operand::Register(dest), Operand::Memory(MemoryOperand { value_size: Some(mode_gpr.size() as usize), addr })])));
+                if disp.is_some() {
+                    let addr = Address { segment: None, base: Some(dest), index: None, scale: nzlit!(1), sym: None, disp, rel: false };
+                    stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Lea), vec![Operand::Register(dest), Operand::Memory(MemoryOperand { value_size: None, addr })])));
+                }
+            }
+            model @ (TlsModel::GeneralDynamic | TlsModel::LocalDynamic) => {
+                panic!("Access to {sym} with the {model:?} TLS model must be expanded before register allocation")
+            }
+        }
+
+        XvaStatement::Elaborated(stmts)
+    }
+
+    /// The memory operand of the stack protector canary slot in `frame`
+    fn canary_operand(&self, frame: &XvaFrameProperties, slot: i32, mode: X86Mode) -> Operand {
+        let mode_gpr = mode.largest_gpr();
+        let base = if frame.use_frame_pointer { GprName::bp } else { GprName::sp };
+        let addr = Address { segment: None, base: Some(Register::new(base.as_reg(mode_gpr))), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(slot as i64), rel: false };
+        Operand::Memory(MemoryOperand { value_size: Some(mode_gpr.size() as usize), addr })
+    }
+
+    const PEEPHOLE_RULES: &[PeepholeRule] = &[
+        PeepholeRule { name: "mov-self", window: 1, rewrite: Self::peephole_mov_self },
+        PeepholeRule { name: "add-zero", window: 4, rewrite: Self::peephole_add_zero },
+        PeepholeRule { name: "push-pop", window: 2, rewrite: Self::peephole_push_pop },
+        PeepholeRule { name: "lea-mov", window: 1, rewrite: Self::peephole_lea_mov },
+    ];
+
+    /// The operands of `instr` if it is `op`, without prefixes or a mode override
+    fn peephole_match(instr: &Instruction, op: X86Opcode) -> Option<&[Operand]> {
+        (instr.opcode().downcast::<X86Opcode>() == Some(op) && instr.prefixes().is_empty() && instr.mode_override().is_none()).then(|| instr.operands())
+    }
+
+    /// Whether writing `reg` in `mode` also clears the upper half of the full register, so that writing it to itself is not a no-op
+    fn zero_extends(reg: Register, mode: MachineMode) -> bool {
+        reg.downcast::<X86Register>().and_then(|reg| reg.gpr_size()) == Some(GprSize::Double) && mode.downcast::<X86Mode>() == Some(X86Mode::Long)
+    }
+
+    /// `mov r, r`
+    fn peephole_mov_self(instrs: &[Instruction], mode: MachineMode) -> Option<(usize, Vec<Instruction>)> {
+        let &[Operand::Register(dest), Operand::Register(src)] = Self::peephole_match(&instrs[0], X86Opcode::Mov)? else {
+            return None;
+        };
+        (dest == src && !Self::zero_extends(dest, mode)).then(|| (1, vec![]))
+    }
+
+    /// `add r, 0` or `sub r, 0`, where the flags are overwritten before they are read
+    fn peephole_add_zero(instrs: &[Instruction], mode: MachineMode) -> Option<(usize, Vec<Instruction>)> {
+        let op = instrs[0].opcode().downcast::<X86Opcode>()?;
+        if !matches!(op, X86Opcode::Add | X86Opcode::Sub) {
+            return None;
+        }
+        let &[Operand::Register(dest), Operand::Immediate(0)] = Self::peephole_match(&instrs[0], op)? else {
+            return None;
+        };
+        if Self::zero_extends(dest, mode) {
+            return None;
+        }
+
+        for instr in &instrs[1..] {
+            match instr.opcode().downcast::<X86Opcode>()? {
+                X86Opcode::Add | X86Opcode::Sub | X86Opcode::Or | X86Opcode::And | X86Opcode::Xor | X86Opcode::Cmp | X86Opcode::Test | X86Opcode::Call | X86Opcode::Ret => {
+                    return Some((1, vec![]));
+                }
+                X86Opcode::Mov | X86Opcode::Lea | X86Opcode::Push | X86Opcode::Pop => {}
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    /// `push a; pop b`
+    fn peephole_push_pop(instrs: &[Instruction], _: MachineMode) -> Option<(usize, Vec<Instruction>)> {
+        let [push, pop, ..] = instrs else {
+            return None;
+        };
+        let &[Operand::Register(src)] = Self::peephole_match(push, X86Opcode::Push)? else {
+            return None;
+        };
+        let &[Operand::Register(dest)] = Self::peephole_match(pop, X86Opcode::Pop)? else {
+            return None;
+        };
+        let src_size = src.downcast::<X86Register>()?.gpr_size()?;
+        let dest_size = dest.downcast::<X86Register>()?.gpr_size()?;
+        // Pushing or popping the stack pointer observes the adjustment
+        if src == Register::new(GprName::sp.as_reg(src_size)) || dest == Register::new(GprName::sp.as_reg(dest_size)) {
+            return None;
+        }
+
+        if src == dest {
+            Some((2, vec![]))
+        } else if src_size == dest_size {
+            Some((2, vec![Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(dest), Operand::Register(src)])]))
+        } else {
+            None
+        }
+    }
+
+    /// `lea r, [base]`
+    fn peephole_lea_mov(instrs: &[Instruction], _: MachineMode) -> Option<(usize, Vec<Instruction>)> {
+        let &[Operand::Register(dest), Operand::Memory(MemoryOperand { addr: Address { segment: None, base: Some(base), index: None, sym: None, disp: None, rel: false, .. }, .. })] = Self::peephole_match(&instrs[0], X86Opcode::Lea)? else {
+            return None;
+        };
+        (dest.downcast::<X86Register>()?.gpr_size()? == base.downcast::<X86Register>()?.gpr_size()?)
+            .then(|| (1, vec![Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(dest), Operand::Register(base)])]))
+    }
+
+    // Issue ports, modelled on a recent out-of-order core
+    const SCHED_ALU_PORTS: u32 = 0b0110_0011;
+    const SCHED_SHIFT_PORTS: u32 = 0b0100_0001;
+    const SCHED_LEA_PORTS: u32 = 0b0010_0010;
+    const SCHED_LOAD_PORTS: u32 = 0b0000_1100;
+    const SCHED_STORE_PORTS: u32 = 0b0001_0000;
+    /// The latency of a load that hits the L1 cache
+    const SCHED_LOAD_LATENCY: u32 = 4;
+
+    /// Records the registers and memory accessed by `operand` in `info`.
+    /// Returns [`None`] if the operand cannot be modelled
+    fn sched_operand(info: &mut SchedInfo, operand: &Operand, read: bool, write: bool) -> Option<()> {
+        match *operand {
+            Operand::Register(reg) => {
+                if read {
+                    info.reads.push(reg);
+                }
+                if write {
+                    info.writes.push(reg);
+                }
+            }
+            Operand::Memory(MemoryOperand { addr, .. }) => {
+                info.reads.extend(addr.segment.into_iter().chain(addr.base).chain(addr.index));
+                info.loads |= read;
+                info.stores |= write;
+            }
+            Operand::Immediate(_) | Operand::AbsSymbol(..) => {}
+            Operand::RelSymbol(..) | Operand::Placeholder(_) => return None,
+        }
+        Some(())
+    }
+
+    fn sched_info_impl(instr: &Instruction, mode: X86Mode) -> Option<SchedInfo> {
+        if !instr.prefixes().is_empty() || instr.mode_override().is_some() {
+            return None;
+        }
+        let op = instr.opcode().downcast::<X86Opcode>()?;
+        let sp = Register::new(GprName::sp.as_reg(mode.largest_gpr()));
+
+        let mut info = match (op, instr.operands()) {
+            (X86Opcode::Push, [src]) => {
+                let mut info = SchedInfo::new(1, Self::SCHED_STORE_PORTS);
+                Self::sched_operand(&mut info, src, true, false)?;
+                info.reads.push(sp);
+                info.writes.push(sp);
+                info.stores = true;
+                info
+            }
+            (X86Opcode::Pop, [dest]) => {
+                let mut info = SchedInfo::new(Self::SCHED_LOAD_LATENCY, Self::SCHED_LOAD_PORTS);
+                Self::sched_operand(&mut info, dest, false, true)?;
+                info.reads.push(sp);
+                info.writes.push(sp);
+                info.loads = true;
+                return Some(info);
+            }
+            (X86Opcode::Lea, [Operand::Register(dest), Operand::Memory(MemoryOperand { addr, .. })]) => {
+                let mut info = SchedInfo::new(1, Self::SCHED_LEA_PORTS);
+                info.writes.push(*dest);
+                info.reads.extend(addr.segment.into_iter().chain(addr.base).chain(addr.index));
+                return Some(info);
+            }
+            (_, [dest, src]) => {
+                let (ports, read_dest, write_dest) = match op {
+                    X86Opcode::Mov => (Self::SCHED_ALU_PORTS, false, true),
+                    X86Opcode::Add | X86Opcode::Sub | X86Opcode::Or | X86Opcode::And | X86Opcode::Xor => (Self::SCHED_ALU_PORTS, true, true),
+                    X86Opcode::Shl | X86Opcode::Shr | X86Opcode::Sar => (Self::SCHED_SHIFT_PORTS, true, true),
+                    X86Opcode::Cmp | X86Opcode::Test => (Self::SCHED_ALU_PORTS, true, false),
+                    X86Opcode::Cmovz | X86Opcode::Cmovnz => (Self::SCHED_ALU_PORTS, true, true),
+                    _ => return None,
+                };
+                let mut info = SchedInfo::new(1, ports);
+                info.writes_flags = !matches!(op, X86Opcode::Mov | X86Opcode::Cmovz | X86Opcode::Cmovnz);
+                info.reads_flags = matches!(op, X86Opcode::Cmovz | X86Opcode::Cmovnz);
+                Self::sched_operand(&mut info, dest, read_dest, write_dest)?;
+                Self::sched_operand(&mut info, src, true, false)?;
+                info
+            }
+            _ => return None,
+        };
+
+        if info.stores {
+            info.ports = Self::SCHED_STORE_PORTS;
+        } else if info.loads {
+            info.ports = Self::SCHED_LOAD_PORTS;
+            info.latency += Self::SCHED_LOAD_LATENCY;
         }
+        Some(info)
     }
 }
 
@@ -1164,7 +1943,7 @@ impl CompilerSpec for X86 {
         }
     }
 
-    fn lower_mce(&self, stmt: &mut XvaStatement, mode: X86Mode, context: &CompilerContext, features: &FeatureSet) {
+    fn lower_mce(&self, stmt: &mut XvaStatement, mode: X86Mode, context: &CompilerContext, frame: &XvaFrameProperties) {
         let instr = match stmt {
             XvaStatement::Expr(xva_expr) => {
                 let XvaRegister::Physical(dest) = xva_expr.dest else {
@@ -1180,12 +1959,33 @@ impl CompilerSpec for X86 {
                     v2.downcast::<X86Register>().expect("Non x86-register encountered")
                 });
 
+                if let XvaOpcode::Select { cond, left, right } = xva_expr.op {
+                    *stmt = self.lower_select(dest, cond, left, right, context, &frame.features);
+                    return;
+                }
+
+                if let XvaOpcode::UMul { .. } | XvaOpcode::SMul { .. } | XvaOpcode::UDiv { .. } | XvaOpcode::SDiv { .. } = xva_expr.op {
+                    *stmt = self.lower_mul_div(dest, dest2, &xva_expr.op, mode, frame);
+                    return;
+                }
+
+                if let XvaOpcode::Const(c @ (XvaConst::Label(_) | XvaConst::Global(_, _))) = xva_expr.op {
+                    *stmt = self.lower_symbol_addr(dest, c, mode, context);
+                    return;
+                }
+
+                if let XvaOpcode::TlsAddr { sym, disp, local, module_base } = xva_expr.op {
+                    *stmt = self.lower_tls_addr(dest, sym, disp, local, module_base, mode, context);
+                    return;
+                }
+
                 let Some(opcode) = self.opcode_for_expr(dest, dest2, &xva_expr.op) else {
                     *stmt = XvaStatement::Elaborated(vec![]); 
                     return;
                 };
 
                 let mut oprs = Vec::with_capacity(2);
+                let mut prefixes = Vec::new();
                 oprs.push(Operand::Register(Register::new(dest)));
 
                 match &xva_expr.op {
@@ -1203,6 +2003,15 @@ impl CompilerSpec for X86 {
 
                         oprs.push(Operand::Register(reg))
                     },
+                    XvaOpcode::ComputeAddr { base: XvaOperand::Register(base), size, index: XvaOperand::Register(index) } => {
+                        let (XvaRegister::Physical(base), XvaRegister::Physical(index)) = (*base, *index) else {
+                            panic!("Virtual Register during mce")
+                        };
+
+                        let scale = core::num::NonZeroU32::new(*size).expect("compaddr with a size of 0");
+                        let addr = Address { segment: None, base: Some(base), index: Some(index), scale, sym: None, disp: None, rel: false };
+                        oprs.push(Operand::Memory(MemoryOperand { value_size: None, addr }));
+                    },
                     XvaOpcode::ComputeAddr { base, size, index } => todo!(),
                     XvaOpcode::GetFrameAddr(_) => todo!(),
                     XvaOpcode::BinaryOp { op, left, right } => {
@@ -1223,23 +2032,64 @@ impl CompilerSpec for X86 {
                                 };
                                 oprs.push(Operand::Register(reg));
                             },
-                            crate::xva::XvaOperand::Const(xva_const) => oprs.push(xva_const.to_readable(context.local_address_kind, context.global_address_kind, mode.supports_rel_addr(), Some(size as usize))),
-                            crate::xva::XvaOperand::FrameAddr(_) => todo!(),
+                            crate::xva::XvaOperand::Const(XvaConst::Bits(n)) => oprs.push(Operand::Immediate(*n as u128)),
+                            crate::xva::XvaOperand::Const(xva_const) => match self.symbol_address(*xva_const, mode, context) {
+                                (addr, false) => oprs.push(Operand::Memory(MemoryOperand { value_size: Some(size as usize), addr })),
+                                (_, true) => panic!("Symbols accessed through the GOT must be loaded into a register before use"),
+                            },
+                            opr @ (crate::xva::XvaOperand::FrameAddr(_) | crate::xva::XvaOperand::IncomingArg(_) | crate::xva::XvaOperand::OutgoingArg(_)) => {
+                                oprs.push(Operand::Memory(MemoryOperand { value_size: Some(size as usize), addr: self.stack_slot_address(*opr, frame, mode) }));
+                            },
                         }
                     },
                     XvaOpcode::CheckedBinaryOp { op, mode, left, right } => todo!(),
                     XvaOpcode::UnaryOp { op, left } => todo!(),
+                    XvaOpcode::Read(opr @ (XvaOperand::FrameAddr(_) | XvaOperand::IncomingArg(_) | XvaOperand::OutgoingArg(_))) => {
+                        oprs.push(Operand::Memory(MemoryOperand { value_size: Some(dest.size(mode) as usize), addr: self.stack_slot_address(*opr, frame, mode) }));
+                    },
                     XvaOpcode::Read(xva_operand) => todo!(),
-                    XvaOpcode::UMul { left, right } => todo!(),
-                    XvaOpcode::SMul { left, right } => todo!(),
+                    XvaOpcode::UMul { .. } | XvaOpcode::SMul { .. } | XvaOpcode::UDiv { .. } | XvaOpcode::SDiv { .. } => unreachable!("multiplications and divisions are handled by lower_mul_div"),
+                    XvaOpcode::Select { .. } => unreachable!("select is handled by lower_select"),
+                    XvaOpcode::TlsAddr { .. } => unreachable!("tls addresses are handled by lower_tls_addr"),
+                    XvaOpcode::TlsIndex { sym, module } => {
+                        // These are the sequences the linker expects when it relaxes the access to a static model:
+                        // `data16 lea rdi, [rip + sym@tlsgd]` on x86-64, and `lea eax, [sym@tlsgd + ebx*1]` or `lea eax, [ebx + sym@tlsldm]` on i386.
+                        // i386 addresses the GOT through ebx, which `___tls_get_addr` also expects to hold the GOT
+                        let ebx = Register::new(crate::x86_register!(ebx));
+                        let (base, index, kind) = match (mode, *module) {
+                            (X86Mode::Long, false) => (None, None, AddressKind::TlsGd),
+                            (X86Mode::Long, true) => (None, None, AddressKind::TlsLd),
+                            (_, false) => (None, Some(ebx), AddressKind::TlsGd),
+                            (_, true) => (Some(ebx), None, AddressKind::TlsLdm),
+                        };
+                        let addr = Address { segment: None, base, index, scale: nzlit!(1), sym: Some(RelocSym { sym: *sym, kind }), disp: None, rel: mode == X86Mode::Long };
+                        oprs.push(Operand::Memory(MemoryOperand { value_size: None, addr }));
+                        if mode == X86Mode::Long && !*module {
+                            prefixes.push(Opcode::new(X86Opcode::DataOverride));
+                        }
+                    },
                 }
 
-                Instruction::new(Opcode::new(opcode), oprs)
+                Instruction::new(Opcode::new(opcode), oprs).with_prefixes(prefixes)
+            },
+            XvaStatement::Write(opr @ (XvaOperand::FrameAddr(_) | XvaOperand::IncomingArg(_) | XvaOperand::OutgoingArg(_)), ty, reg) => {
+                let reg = Self::areg(*reg);
+                Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Memory(MemoryOperand { value_size: Some(ty.size as usize), addr: self.stack_slot_address(*opr, frame, mode) }), Operand::Register(Register::new(reg))])
             },
             XvaStatement::Write(xva_operand, ty, xva_register) => todo!("write"),
             XvaStatement::Jump(symbol) => {
                 Instruction::new(Opcode::new(X86Opcode::Jump), vec![Operand::RelSymbol(RelocSym { sym: *symbol, kind: AddressKind::Default }, None)])
             },
+            XvaStatement::JumpIf(cond, symbol) => {
+                let cond = Register::new(Self::areg(*cond));
+                let target = Operand::RelSymbol(RelocSym { sym: *symbol, kind: AddressKind::Default }, None);
+
+                *stmt = XvaStatement::Elaborated(vec![
+                    XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Test), vec![Operand::Register(cond), Operand::Register(cond)])),
+                    XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Jnz), vec![target])),
+                ]);
+                return;
+            },
             XvaStatement::Tailcall { dest, .. } => {
                 let mut oprs = Vec::with_capacity(1);
                 match *dest {
@@ -1249,14 +2099,35 @@ impl CompilerSpec for X86 {
                         };
                         oprs.push(Operand::Register(reg))
                     },
+                    // The PLT of i386 needs the GOT in ebx, which the epilogue restores to the caller's value, so the target is loaded from the GOT while it is still there
+                    crate::xva::XvaOperand::Const(xva_const @ XvaConst::Global(..)) if context.global_call_address_kind == AddressKind::Plt && self.needs_got_base(frame, mode, context) => {
+                        let scratch = self.tail_call_scratch(mode);
+                        let load = self.lower_symbol_addr(scratch, xva_const, mode, context);
+                        *stmt = XvaStatement::Elaborated(vec![load, XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Jump), vec![Operand::Register(Register::new(scratch))]))]);
+                        return;
+                    },
                     crate::xva::XvaOperand::Const(xva_const) => {
                         oprs.push(xva_const.to_direct_rel(context.local_address_kind, context.global_call_address_kind));
                     },
                     crate::xva::XvaOperand::FrameAddr(_) => unreachable!("Cannot call the stack"),
+                    opr @ (crate::xva::XvaOperand::IncomingArg(_) | crate::xva::XvaOperand::OutgoingArg(_)) => {
+                        let scratch = Operand::Register(Register::new(self.tail_call_scratch(mode)));
+                        let addr = self.stack_slot_address(opr, frame, mode);
+                        let load = Instruction::new(Opcode::new(X86Opcode::Mov), vec![scratch, Operand::Memory(MemoryOperand { value_size: Some(mode.largest_gpr().size() as usize), addr })]);
+                        *stmt = XvaStatement::Elaborated(vec![XvaStatement::RawInstr(load), XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Jump), vec![scratch]))]);
+                        return;
+                    },
                 }
                 Instruction::new(Opcode::new(X86Opcode::Jump), oprs)
             },
-            XvaStatement::Call { dest, .. } => {
+            XvaStatement::Call { dest: XvaOperand::Const(XvaConst::Global(sym, 0)), .. } if mode == X86Mode::Long && *sym == self.tls_get_addr() => {
+                // The padded call of the general-dynamic sequence, which directly follows the `data16 lea` of `XvaOpcode::TlsIndex`
+                let target = Operand::RelSymbol(RelocSym { sym: *sym, kind: AddressKind::Plt }, None);
+                let prefixes = [X86Opcode::DataOverride, X86Opcode::DataOverride, X86Opcode::Rex64].map(Opcode::new);
+                Instruction::new(Opcode::new(X86Opcode::Call), vec![target]).with_prefixes(prefixes)
+            },
+            XvaStatement::Call { dest, callee_pop, .. } => {
+                let callee_pop = *callee_pop;
                 let mut oprs = Vec::with_capacity(1);
                 match *dest {
                     crate::xva::XvaOperand::Register(reg) => {
@@ -1269,10 +2140,34 @@ impl CompilerSpec for X86 {
                         oprs.push(xva_const.to_direct_rel(context.local_address_kind, context.global_call_address_kind));
                     },
                     crate::xva::XvaOperand::FrameAddr(_) => unreachable!("Cannot call the stack"),
+                    opr @ (crate::xva::XvaOperand::IncomingArg(_) | crate::xva::XvaOperand::OutgoingArg(_)) => {
+                        oprs.push(Operand::Memory(MemoryOperand { value_size: Some(mode.largest_gpr().size() as usize), addr: self.stack_slot_address(opr, frame, mode) }));
+                    },
                 }
 
-                Instruction::new(Opcode::new(X86Opcode::Call), oprs)
+                let call = Instruction::new(Opcode::new(X86Opcode::Call), oprs);
+                if callee_pop == 0 {
+                    call
+                } else {
+                    // The outgoing argument area is allocated by the prologue, so the part popped by the callee is reserved again
+                    let mode_gpr = mode.largest_gpr();
+                    let sp = Register::new(GprName::sp.as_reg(mode_gpr));
+                    let cfa_offset = mode_gpr.size() as i64 + frame.frame_size as i64;
+                    let mut stmts = vec![XvaStatement::RawInstr(call)];
+                    if !frame.use_frame_pointer {
+                        stmts.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(cfa_offset - callee_pop as i64)));
+                    }
+                    stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Sub), vec![Operand::Register(sp), Operand::Immediate(callee_pop as u128)])));
+                    if !frame.use_frame_pointer {
+                        stmts.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(cfa_offset)));
+                    }
+                    *stmt = XvaStatement::Elaborated(stmts);
+                    return;
+                }
             },
+            XvaStatement::Return if frame.callee_pop != 0 => {
+                Instruction::new(Opcode::new(X86Opcode::Ret), vec![Operand::Immediate(frame.callee_pop as u128)])
+            }
             XvaStatement::Return => {
                 Instruction::new_nullary(X86Opcode::Ret)
             }
@@ -1285,65 +2180,1791 @@ impl CompilerSpec for X86 {
         *stmt = XvaStatement::RawInstr(instr);
     }
 
-    fn lower_epilogue(&self, frame: &crate::xva::XvaFrameProperties, mode: X86Mode) -> Vec<XvaStatement> {
+    fn lower_epilogue(&self, frame: &crate::xva::XvaFrameProperties, mode: X86Mode, context: &CompilerContext) -> Vec<XvaStatement> {
         let mode_gpr = mode.largest_gpr();
         let sp = GprName::sp.as_reg(mode_gpr);
+        let ptr_size = mode_gpr.size() as i64;
         let mut epilogue = Vec::new();
+
+        if let Some(slot) = frame.stack_protector_slot {
+            let scratch = self.stack_protector_scratch(mode);
+            let ok = context.local_label("canary_ok");
+            // The return value is already in the scratch register, and `pop` leaves the flags of the comparison intact
+            let save = mode != X86Mode::Long;
+            let mut slot = slot;
+            if save {
+                epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Push), vec![Operand::Register(scratch)])));
+                if !frame.use_frame_pointer {
+                    slot += ptr_size as i32;
+                    epilogue.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(2 * ptr_size + frame.frame_size as i64)));
+                }
+            }
+            self.load_stack_guard(&mut epilogue, scratch, mode, context);
+            epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Cmp), vec![Operand::Register(scratch), self.canary_operand(frame, slot, mode)])));
+            if save {
+                epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Pop), vec![Operand::Register(scratch)])));
+                if !frame.use_frame_pointer {
+                    epilogue.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(ptr_size + frame.frame_size as i64)));
+                }
+            }
+            epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Jz), vec![Operand::RelSymbol(RelocSym { sym: ok, kind: AddressKind::Default }, None)])));
+            epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Call), vec![Operand::RelSymbol(RelocSym { sym: context.stack_chk_fail(), kind: context.global_call_address_kind }, None)])));
+            epilogue.push(XvaStatement::Label(ok));
+        }
+
+        let got_base = self.needs_got_base(frame, mode, context);
+        let bx = Register::new(crate::x86_register!(ebx));
+
         if frame.use_frame_pointer {
             let bp = GprName::bp.as_reg(mode_gpr);
+            if got_base {
+                let addr = Address { segment: None, base: Some(Register::new(bp)), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(-ptr_size), rel: false };
+                epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(bx), Operand::Memory(MemoryOperand { value_size: Some(ptr_size as usize), addr })])));
+                epilogue.push(XvaStatement::Cfi(XvaCfi::Restore(bx)));
+            }
             epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(Register::new(sp)), Operand::Register(Register::new(bp))])));
             epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Pop), vec![Operand::Register(Register::new(bp))])));
+            epilogue.push(XvaStatement::Cfi(XvaCfi::DefCfa(Register::new(sp), ptr_size)));
+            epilogue.push(XvaStatement::Cfi(XvaCfi::Restore(Register::new(bp))));
         } else if frame.has_prologue {
-            let size = frame.frame_size;
-            epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Add), vec![Operand::Register(Register::new(sp)), Operand::Immediate(size as u128)])));
+            let size = if got_base { frame.frame_size - ptr_size as usize } else { frame.frame_size };
+            if size > 0 {
+                epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Add), vec![Operand::Register(Register::new(sp)), Operand::Immediate(size as u128)])));
+            }
+            if got_base {
+                epilogue.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(2 * ptr_size)));
+                epilogue.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Pop), vec![Operand::Register(bx)])));
+                epilogue.push(XvaStatement::Cfi(XvaCfi::Restore(bx)));
+            }
+            epilogue.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(ptr_size)));
         }
         epilogue
     }
 
-    fn emit_prologue(&self, frame: &mut crate::xva::XvaFrameProperties, mode: X86Mode) -> Vec<Instruction> {
+    fn emit_prologue(&self, frame: &mut crate::xva::XvaFrameProperties, mode: X86Mode, context: &CompilerContext) -> Vec<XvaStatement> {
         let mode_gpr = mode.largest_gpr();
         let sp = GprName::sp.as_reg(mode_gpr);
+        let ptr_size = mode_gpr.size() as i64;
         
+        let protect = context.stack_protector.protects(frame);
+        if protect {
+            frame.frame_size += ptr_size as usize;
+        }
+
+        frame.frame_size += frame.outgoing_args_size;
+
         let mut used_size = 0;
         let mut align_frame = false;
         if frame.call_align < frame.frame_align {
             frame.use_frame_pointer = true;
             align_frame = true;
         }
-        let mut instrs = Vec::new();
+
+        if mode.largest_gpr() == GprSize::Word && (frame.incoming_args_size > 0 || frame.outgoing_args_size > 0) {
+            frame.use_frame_pointer = true;
+        }
+        let mut stmts = Vec::new();
         if frame.use_frame_pointer {
             let bp = GprName::bp.as_reg(mode_gpr);
             let fptr_size = mode_gpr.size() as usize;
             frame.frame_size += fptr_size;
-            used_size += 8;
-            instrs.push(Instruction::new(Opcode::new(X86Opcode::Push), vec![Operand::Register(Register::new(bp))]));
-            instrs.push(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(Register::new(bp)), Operand::Register(Register::new(sp))]));
+            used_size += fptr_size;
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Push), vec![Operand::Register(Register::new(bp))])));
+            stmts.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(2 * ptr_size)));
+            stmts.push(XvaStatement::Cfi(XvaCfi::Offset(Register::new(bp), -2 * ptr_size)));
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(Register::new(bp)), Operand::Register(Register::new(sp))])));
+            stmts.push(XvaStatement::Cfi(XvaCfi::DefCfaRegister(Register::new(bp))));
+        }
+
+        // ebx is callee-saved, so it is saved below the frame pointer before it is loaded with the GOT
+        let got_base = self.needs_got_base(frame, mode, context);
+        if got_base {
+            let bx = Register::new(crate::x86_register!(ebx));
+            frame.frame_size += ptr_size as usize;
+            used_size += ptr_size as usize;
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Push), vec![Operand::Register(bx)])));
+            if !frame.use_frame_pointer {
+                stmts.push(XvaStatement::Cfi(XvaCfi::DefCfaOffset(ptr_size + used_size as i64)));
+            }
+            stmts.push(XvaStatement::Cfi(XvaCfi::Offset(bx, -(ptr_size + used_size as i64))));
         }
 
         let mut align_offset = frame.call_align_offset;
 
         if align_frame {
             let align = !(frame.frame_align - 1) as u32;
-            instrs.push(Instruction::new(Opcode::new(X86Opcode::And), vec![Operand::Register(Register::new(sp)), Operand::Immediate(align as u128)]));
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::And), vec![Operand::Register(Register::new(sp)), Operand::Immediate(align as u128)])));
 
             align_offset = 0;
         }
         let total_size = frame.frame_size + align_offset;
 
-        let disp = total_size & (frame.frame_align - 1);
+        // Calls made by the function need the stack aligned to `call_align`, since that is what the callee assumes
+        let align = if frame.is_leaf { frame.frame_align } else { frame.frame_align.max(frame.call_align) };
+        let disp = total_size & (align - 1);
 
         if disp != 0 {
-            frame.frame_size += frame.frame_align - disp;
+            frame.frame_size += align - disp;
         }
 
         let sub_size = frame.frame_size - used_size;
 
         if sub_size > 0 {
-            instrs.push(Instruction::new(Opcode::new(X86Opcode::Sub), vec![Operand::Register(Register::new(sp)), Operand::Immediate(sub_size as u128)]));
+            let cfa_offset = (!frame.use_frame_pointer).then_some(ptr_size + used_size as i64);
+            self.emit_stack_alloc(&mut stmts, sub_size, cfa_offset, mode, context);
+        }
+
+        if got_base {
+            let bx = Register::new(crate::x86_register!(ebx));
+            let got = crate::intern::Symbol::intern("_GLOBAL_OFFSET_TABLE_");
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Call), vec![Operand::RelSymbol(RelocSym { sym: self.pc_thunk(), kind: AddressKind::Default }, None)])));
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Add), vec![Operand::Register(bx), Operand::AbsSymbol(RelocSym { sym: got, kind: AddressKind::Default }, None)])));
+        }
+
+        if protect {
+            // The canary is the highest slot of the frame, directly below the registers saved by the prologue or the return address
+            let slot = if frame.use_frame_pointer { -(used_size as i64) } else { sub_size as i64 - ptr_size } as i32;
+            frame.stack_protector_slot = Some(slot);
+
+            let scratch = self.stack_protector_scratch(mode);
+            self.load_stack_guard(&mut stmts, scratch, mode, context);
+            stmts.push(XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![self.canary_operand(frame, slot, mode), Operand::Register(scratch)])));
         }
         
-        frame.has_prologue = !instrs.is_empty();
+        frame.has_prologue = !stmts.is_empty();
+
+        stmts
+    }
+
+    fn cfi_entry_state(&self, mode: X86Mode) -> Option<DwarfCie> {
+        let return_address = match mode {
+            X86Mode::Long => 16,
+            X86Mode::Protected => 8,
+            X86Mode::Real | X86Mode::Protected16 => return None,
+        };
+        let mode_gpr = mode.largest_gpr();
+        let ptr_size = mode_gpr.size() as i64;
+
+        Some(DwarfCie {
+            code_align: 1,
+            data_align: -ptr_size,
+            return_address,
+            cfa_register: Register::new(GprName::sp.as_reg(mode_gpr)),
+            cfa_offset: ptr_size,
+            return_address_offset: Some(-ptr_size),
+        })
+    }
+
+    fn calling_convention(&self, name: &str, mode: X86Mode) -> Option<&'static dyn CallingConvention> {
+        match (mode, name) {
+            (X86Mode::Long, "sysv64") => Some(&SysV64),
+            (X86Mode::Long, "win64") => Some(&Win64),
+            (X86Mode::Protected, "cdecl") => Some(&I386CallConv::Cdecl),
+            (X86Mode::Protected, "stdcall") => Some(&I386CallConv::Stdcall),
+            (X86Mode::Protected, "fastcall") => Some(&I386CallConv::Fastcall),
+            (X86Mode::Real | X86Mode::Protected16, "cdecl") => Some(&Cdecl16),
+            _ => None,
+        }
+    }
+
+    fn default_calling_convention(&self, _: &CompilerContext, mode: X86Mode) -> Option<&'static dyn CallingConvention> {
+        match mode {
+            X86Mode::Long => Some(&SysV64),
+            X86Mode::Protected => Some(&I386CallConv::Cdecl),
+            X86Mode::Real | X86Mode::Protected16 => Some(&Cdecl16),
+        }
+    }
+
+    fn expr_cost(&self, op: &XvaOpcode, ty: XvaType, _: X86Mode) -> Option<u32> {
+        match op {
+            XvaOpcode::ZeroInit | XvaOpcode::Const(_) | XvaOpcode::Move(_) => Some(1),
+            XvaOpcode::BinaryOp { op: BinaryOp::Add | BinaryOp::Sub | BinaryOp::And | BinaryOp::Or | BinaryOp::Xor, .. } => Some(1),
+            XvaOpcode::BinaryOp { op: BinaryOp::ShiftLeft(_) | BinaryOp::ShiftRight(..), right: XvaOperand::Const(XvaConst::Bits(_)), .. } => Some(1),
+            XvaOpcode::UMul { .. } | XvaOpcode::SMul { .. } => Some(3),
+            // `div` takes tens of cycles, more for wider values
+            XvaOpcode::UDiv { .. } | XvaOpcode::SDiv { .. } => Some(if ty.size == 8 { 40 } else { 25 }),
+            // `lea` has no 8-bit form, and 16-bit addressing cannot scale its index
+            XvaOpcode::ComputeAddr { base: XvaOperand::Register(_), size: 1 | 2 | 4 | 8, index: XvaOperand::Register(_) } if ty.size >= 4 => Some(1),
+            _ => None,
+        }
+    }
+
+    fn peephole_rules(&self, _: X86Mode) -> &'static [PeepholeRule] {
+        Self::PEEPHOLE_RULES
+    }
+
+    fn sched_info(&self, instr: &Instruction, mode: X86Mode) -> Option<SchedInfo> {
+        Self::sched_info_impl(instr, mode)
+    }
+
+    fn needs_prologue(&self, frame: &XvaFrameProperties, mode: X86Mode, context: &CompilerContext) -> bool {
+        frame.has_prologue || context.stack_protector.protects(frame) || self.needs_got_base(frame, mode, context)
+    }
+
+    fn support_functions(&self, mode: X86Mode, context: &CompilerContext) -> Vec<XvaFunctionDef> {
+        if !self.uses_got_base(mode, context) {
+            return Vec::new();
+        }
+
+        // mov ebx, [esp]; ret
+        let sp = Register::new(crate::x86_register!(esp));
+        let ret_addr = Address { segment: None, base: Some(sp), index: None, scale: nzlit!(1), sym: None, disp: None, rel: false };
+        let body = vec![
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(Register::new(crate::x86_register!(ebx))), Operand::Memory(MemoryOperand { value_size: Some(4), addr: ret_addr })])),
+            XvaStatement::Return,
+        ];
+
+        let label = self.pc_thunk();
+        let mut frame_properties = XvaFrameProperties::new();
+        frame_properties.is_leaf = true;
+        frame_properties.naked = true;
+
+        let func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::from_registers(crate::x86_registers!(esp, ebp, esi, edi)),
+            clobber_regs: Regset::from_registers(crate::x86_registers!(ebx)),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: vec![XvaBasicBlock { label, live_at_start: Vec::new(), body: XvaBlockBody::Statement(body) }],
+            frame_properties,
+        };
+
+        // Each object file that uses the thunk has its own copy, which the linker is free to merge
+        vec![XvaFunctionDef { body: func, linkage: Linkage::Weak, label, section: XvaSection::Text, debug: None }]
+    }
+
+    fn expand_runtime_calls(&self, expr: &XvaExpr, mode: X86Mode, context: &CompilerContext, frame: &XvaFrameProperties) -> Option<Vec<XvaStatement>> {
+        let XvaOpcode::TlsAddr { sym, disp, local, module_base: None } = expr.op else {
+            return None;
+        };
+
+        // x86-64 uses the general-dynamic sequence for the local-dynamic model as well, so that every call to `__tls_get_addr` can be lowered to the padded call of that sequence
+        let module = match context.tls_model(local) {
+            TlsModel::GeneralDynamic => false,
+            TlsModel::LocalDynamic => mode != X86Mode::Long,
+            TlsModel::InitialExec | TlsModel::LocalExec => return None,
+        };
+
+        // `__tls_get_addr` takes its argument in the first parameter register, but i386 uses `___tls_get_addr`, which takes it in eax
+        let (arg, ret, get_addr, layout) = match mode {
+            X86Mode::Long => (crate::x86_register!(rdi), crate::x86_register!(rax), self.tls_get_addr(), SysV64.layout(&CallSignature::new(vec![], None), &frame.features)),
+            X86Mode::Protected => (crate::x86_register!(eax), crate::x86_register!(eax), crate::intern::Symbol::intern("___tls_get_addr"), I386CallConv::Cdecl.layout(&CallSignature::new(vec![], None), &frame.features)),
+            X86Mode::Real | X86Mode::Protected16 => panic!("Thread-local storage is not supported in 16-bit modes"),
+        };
+
+        let arg = XvaRegister::Physical(Register::new(arg));
+        let ret = XvaRegister::Physical(Register::new(ret));
+
+        let mut stmts = Vec::new();
+        stmts.push(XvaStatement::Expr(XvaExpr { dest: arg, dest2: None, op: XvaOpcode::TlsIndex { sym, module } }));
+        stmts.push(XvaStatement::Call {
+            dest: XvaOperand::Const(XvaConst::Global(get_addr, 0)),
+            params: Regset::from_registers([Self::areg(arg)]),
+            ret_val: Regset::from_registers([Self::areg(ret)]),
+            call_clobber_regs: layout.clobber_regs,
+            callee_pop: 0,
+            indirect_ret: false,
+        });
+
+        if module {
+            stmts.push(XvaStatement::Expr(XvaExpr { dest: expr.dest, dest2: None, op: XvaOpcode::TlsAddr { sym, disp, local, module_base: Some(ret) } }));
+        } else {
+            stmts.push(XvaStatement::Expr(XvaExpr { dest: expr.dest, dest2: None, op: XvaOpcode::Move(ret) }));
+            if disp != 0 {
+                stmts.push(XvaStatement::Expr(XvaExpr { dest: expr.dest, dest2: None, op: XvaOpcode::BinaryOp { op: BinaryOp::Add, left: expr.dest, right: XvaOperand::Const(XvaConst::Bits(disp as u64)) } }));
+            }
+        }
+
+        Some(stmts)
+    }
+}
+
+/// The vector register numbered `n` that holds a value of `size` bytes, if one is available
+#[cfg(feature = "xva")]
+fn vector_arg_reg(n: u8, size: u64, features: &FeatureSet) -> Option<X86Register> {
+    match size {
+        ..=16 if features.contains_feature(&X86TargetFeature::Sse) => Some(X86Register::Xmm(n)),
+        32 if features.contains_feature(&X86TargetFeature::Avx) => Some(X86Register::Ymm(n)),
+        64 if features.contains_feature(&X86TargetFeature::Avx512f) => Some(X86Register::Zmm(n)),
+        _ => None,
+    }
+}
+
+/// The vector and x87 registers that are not preserved by any x86 calling convention, other than `xmm0..xmm{first_preserved}`
+#[cfg(feature = "xva")]
+fn vector_clobbers(clobbers: &mut Regset, first_preserved: u8, max_sse: u8, features: &FeatureSet) {
+    if features.contains_feature(&X86TargetFeature::X87) || features.contains_feature(&X86TargetFeature::Mmx) {
+        clobbers.insert_registers((0..8).map(X86Register::St));
+    }
+
+    if features.contains_feature(&X86TargetFeature::Sse) {
+        clobbers.insert_registers((0..first_preserved).map(X86Register::Xmm));
+        if features.contains_feature(&X86TargetFeature::Avx512f) && max_sse > 16 {
+            clobbers.insert_registers((16..32).map(X86Register::Xmm));
+        }
+    }
+
+    if features.contains_feature(&X86TargetFeature::Avx512f) {
+        clobbers.insert_registers((0..8).map(X86Register::Kreg));
+    }
+}
+
+/// The type of the hidden pointers used by x86 calling conventions in [`X86Mode::Long`]
+#[cfg(feature = "xva")]
+const PTR64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+
+/// The class of an eightbyte of a value under the System V x86-64 ABI
+#[cfg(feature = "xva")]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+enum SysVClass {
+    Integer,
+    Sse,
+    /// An x87 `long double`, which is returned in `st0` and always passed in memory
+    X87,
+}
+
+/// Classifies each eightbyte of `arg` under the System V x86-64 ABI, or returns [`None`] if it is passed in memory
+#[cfg(feature = "xva")]
+fn sysv64_classify(arg: &CallArg, features: &FeatureSet) -> Option<Vec<SysVClass>> {
+    let ty = &arg.ty;
+    match ty.category {
+        XvaCategory::Null => Some(Vec::new()),
+        XvaCategory::Int | XvaCategory::Condition if ty.size <= 16 => Some(vec![SysVClass::Integer; ty.size.div_ceil(8) as usize]),
+        XvaCategory::Float if ty.size > 8 && ty.size <= 16 => Some(vec![SysVClass::X87]),
+        cat if is_fp_category(cat) && vector_arg_reg(0, ty.size, features).is_some() => Some(vec![SysVClass::Sse]),
+        XvaCategory::Aggregate if ty.size == 0 => Some(Vec::new()),
+        XvaCategory::Aggregate if ty.size <= 16 && !arg.is_packed() => {
+            // An aggregate of a single wide field, such as `__m128` or `__int128`, is passed like the field
+            if let [field] = &arg.fields[..] && field.ty.size > 8 {
+                return sysv64_classify(&CallArg::from(field.ty), features).filter(|_| field.ty.size == ty.size);
+            }
+
+            Some((0..ty.size.div_ceil(8)).map(|n| if arg.is_fp_range(8 * n..8 * n + 8) { SysVClass::Sse } else { SysVClass::Integer }).collect())
+        }
+        XvaCategory::Custom(_) => panic!("Unsupported argument category {}", ty.category),
+        _ => None,
+    }
+}
+
+/// The System V x86-64 calling convention, used by most non-Windows targets in [`X86Mode::Long`].
+///
+/// Aggregates of up to 16 bytes are split into eightbytes, each of which is passed in a vector register if it only contains floating-point fields, and an integer register otherwise.
+/// Larger aggregates are copied onto the stack, or returned through a hidden pointer passed in `rdi` and returned in `rax`.
+/// `long double` (a [`XvaCategory::Float`] larger than 8 bytes) is passed on the stack and returned in `st0`.
+/// Variadic calls also take the number of vector registers used in `al`, which is included in [`CallLayout::param_regs`]
+#[cfg(feature = "xva")]
+pub struct SysV64;
+
+#[cfg(feature = "xva")]
+impl CallingConvention for SysV64 {
+    fn name(&self) -> &'static str {
+        "sysv64"
+    }
+
+    fn layout(&self, sig: &CallSignature, features: &FeatureSet) -> CallLayout {
+        const INT_PARAMS: [X86Register; 6] = crate::x86_registers!(rdi, rsi, rdx, rcx, r8, r9);
+        const INT_RETURNS: [X86Register; 2] = crate::x86_registers!(rax, rdx);
+
+        let preserve = Regset::from_registers(crate::x86_registers!(rbx, rsp, rbp, r12, r13, r14, r15));
+        let mut clobbers = Regset::from_registers(crate::x86_registers!(rax, rcx, rdx, rsi, rdi, r8, r9, r10, r11));
+        vector_clobbers(&mut clobbers, 16, 32, features);
+
+        let mut layout = CallLayout::new(preserve, clobbers);
+        let mut next_int = 0;
+        let mut next_sse = 0;
+        let mut stack = StackArgs::new(0, 8);
+
+        if let Some(ret) = &sig.ret {
+            match sysv64_classify(ret, features) {
+                Some(classes) => {
+                    let (mut int, mut sse) = (0, 0);
+                    let sse_size = if classes.len() == 1 { ret.ty.size } else { 8 };
+                    let regs = classes
+                        .iter()
+                        .map(|class| match class {
+                            SysVClass::Integer => {
+                                int += 1;
+                                Register::new(INT_RETURNS[int - 1])
+                            }
+                            SysVClass::Sse => {
+                                sse += 1;
+                                Register::new(vector_arg_reg(sse - 1, sse_size, features).unwrap())
+                            }
+                            SysVClass::X87 => Register::new(X86Register::St(0)),
+                        })
+                        .collect();
+                    layout.set_ret(ArgLocation::Registers(regs), &X86);
+                }
+                None => {
+                    next_int = 1;
+                    layout.set_ret(ArgLocation::Indirect(Box::new(ArgLocation::Registers(vec![Register::new(INT_PARAMS[0])]))), &X86);
+                    layout.return_regs.insert_register(crate::x86_register!(rax));
+                }
+            }
+        }
+
+        for arg in &sig.params {
+            let loc = match sysv64_classify(arg, features) {
+                Some(classes) if classes.contains(&SysVClass::X87) => stack.alloc(&arg.ty),
+                Some(classes) => {
+                    let ints = classes.iter().filter(|&&class| class == SysVClass::Integer).count();
+                    let sses = classes.len() - ints;
+                    let sse_size = if classes.len() == 1 { arg.ty.size } else { 8 };
+
+                    // An argument is never split between registers and the stack
+                    if next_int + ints <= INT_PARAMS.len() && next_sse as usize + sses <= 8 {
+                        let regs = classes
+                            .iter()
+                            .map(|class| match class {
+                                SysVClass::Integer => {
+                                    next_int += 1;
+                                    Register::new(INT_PARAMS[next_int - 1])
+                                }
+                                SysVClass::Sse => {
+                                    next_sse += 1;
+                                    Register::new(vector_arg_reg(next_sse - 1, sse_size, features).unwrap())
+                                }
+                                SysVClass::X87 => unreachable!(),
+                            })
+                            .collect();
+                        ArgLocation::Registers(regs)
+                    } else {
+                        stack.alloc(&arg.ty)
+                    }
+                }
+                None => stack.alloc(&arg.ty),
+            };
+            layout.push_param(loc, &X86);
+        }
+
+        if sig.fixed_params.is_some() {
+            layout.param_regs.insert_register(crate::x86_register!(rax));
+        }
+
+        layout.stack_size = stack.size(16);
+        layout
+    }
+}
+
+/// Whether a value of `ty` is passed by reference under the Microsoft x64 calling convention, which is the case for any size other than 1, 2, 4, or 8 bytes
+#[cfg(feature = "xva")]
+fn win64_by_ref(ty: &XvaType) -> bool {
+    ty.category != XvaCategory::Null && !matches!(ty.size, 1 | 2 | 4 | 8)
+}
+
+/// The Microsoft x64 calling convention, used by Windows targets in [`X86Mode::Long`].
+///
+/// The first four arguments are assigned to `rcx`, `rdx`, `r8`, and `r9` or `xmm0..xmm3` by position, and the caller reserves 32 bytes of shadow space for them.
+/// Values (including aggregates) that are not 1, 2, 4, or 8 bytes are passed by reference, and returned through a hidden pointer that is passed as the first argument and returned in `rax`.
+/// Other aggregates are passed like integers of the same size
+#[cfg(feature = "xva")]
+pub struct Win64;
+
+#[cfg(feature = "xva")]
+impl CallingConvention for Win64 {
+    fn name(&self) -> &'static str {
+        "win64"
+    }
+
+    fn layout(&self, sig: &CallSignature, features: &FeatureSet) -> CallLayout {
+        const INT_PARAMS: [X86Register; 4] = crate::x86_registers!(rcx, rdx, r8, r9);
+
+        let mut preserve = Regset::from_registers(crate::x86_registers!(rbx, rsp, rbp, rsi, rdi, r12, r13, r14, r15));
+        if features.contains_feature(&X86TargetFeature::Sse) {
+            preserve.insert_registers((6..16).map(X86Register::Xmm));
+        }
+        let mut clobbers = Regset::from_registers(crate::x86_registers!(rax, rcx, rdx, r8, r9, r10, r11));
+        vector_clobbers(&mut clobbers, 6, 32, features);
+
+        let mut layout = CallLayout::new(preserve, clobbers);
+        let mut stack = StackArgs::new(32, 8);
+        let mut first_param = 0;
+
+        if let Some(ret) = &sig.ret {
+            let ty = &ret.ty;
+            let loc = match ty.category {
+                XvaCategory::Null => ArgLocation::Registers(Vec::new()),
+                XvaCategory::Custom(_) => panic!("Unsupported return category {}", ty.category),
+                cat if is_fp_category(cat) && ty.size <= 16 => ArgLocation::Registers(vec![Register::new(crate::x86_register!(xmm0))]),
+                _ if !win64_by_ref(ty) => ArgLocation::Registers(vec![Register::new(crate::x86_register!(rax))]),
+                _ => {
+                    first_param = 1;
+                    layout.return_regs.insert_register(crate::x86_register!(rax));
+                    ArgLocation::Indirect(Box::new(ArgLocation::Registers(vec![Register::new(INT_PARAMS[0])])))
+                }
+            };
+            layout.set_ret(loc, &X86);
+        }
+
+        for (n, arg) in sig.params.iter().enumerate() {
+            let ty = &arg.ty;
+            let pos = n + first_param;
+            let by_ref = win64_by_ref(ty);
+            let loc = match ty.category {
+                XvaCategory::Null => ArgLocation::Registers(Vec::new()),
+                XvaCategory::Custom(_) => panic!("Unsupported argument category {}", ty.category),
+                // Variadic floating-point arguments are read from the integer registers by the callee
+                cat if pos < 4 && is_fp_category(cat) && !by_ref && !sig.is_variadic_param(n) => ArgLocation::Registers(vec![Register::new(X86Register::Xmm(pos as u8))]),
+                _ if pos < 4 => ArgLocation::Registers(vec![Register::new(INT_PARAMS[pos])]),
+                _ if by_ref => stack.alloc(&PTR64),
+                _ => stack.alloc(ty),
+            };
+
+            let loc = if by_ref { ArgLocation::Indirect(Box::new(loc)) } else { loc };
+            layout.push_param(loc, &X86);
+        }
+
+        layout.stack_size = stack.size(16);
+        layout
+    }
+}
+
+/// The calling conventions of [`X86Mode::Protected`], which pass most arguments on the stack.
+///
+/// Aggregates are passed on the stack by value, and are always returned through a hidden pointer that is passed as the first stack argument, popped by the callee, and returned in `eax`
+#[cfg(feature = "xva")]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum I386CallConv {
+    /// The caller pops the arguments
+    Cdecl,
+    /// The callee pops the arguments, except for variadic functions
+    Stdcall,
+    /// Like [`I386CallConv::Stdcall`], but the first two integer arguments of at most 4 bytes are passed in `ecx` and `edx`
+    Fastcall,
+}
+
+#[cfg(feature = "xva")]
+impl CallingConvention for I386CallConv {
+    fn name(&self) -> &'static str {
+        match self {
+            I386CallConv::Cdecl => "cdecl",
+            I386CallConv::Stdcall => "stdcall",
+            I386CallConv::Fastcall => "fastcall",
+        }
+    }
+
+    fn layout(&self, sig: &CallSignature, features: &FeatureSet) -> CallLayout {
+        const FASTCALL_PARAMS: [X86Register; 2] = crate::x86_registers!(ecx, edx);
+        const PTR32: XvaType = XvaType { size: 4, align: 4, category: XvaCategory::Int };
+
+        let preserve = Regset::from_registers(crate::x86_registers!(ebx, esp, ebp, esi, edi));
+        let mut clobbers = Regset::from_registers(crate::x86_registers!(eax, ecx, edx));
+        vector_clobbers(&mut clobbers, 8, 8, features);
+
+        let mut layout = CallLayout::new(preserve, clobbers);
+        let mut next_int = 0;
+        let mut stack = StackArgs::new(0, 4);
+        let mut sret = false;
+
+        if let Some(ret) = &sig.ret {
+            let ty = &ret.ty;
+            let loc = match ty.category {
+                XvaCategory::Null => ArgLocation::Registers(Vec::new()),
+                XvaCategory::Custom(_) => panic!("Unsupported return category {}", ty.category),
+                XvaCategory::Int | XvaCategory::Condition if ty.size <= 4 => ArgLocation::Registers(vec![Register::new(crate::x86_register!(eax))]),
+                XvaCategory::Int if ty.size <= 8 => ArgLocation::Registers(vec![Register::new(crate::x86_register!(eax)), Register::new(crate::x86_register!(edx))]),
+                XvaCategory::Float if ty.size <= 16 => ArgLocation::Registers(vec![Register::new(X86Register::St(0))]),
+                cat if is_fp_category(cat) && ty.size == 16 => ArgLocation::Registers(vec![Register::new(crate::x86_register!(xmm0))]),
+                _ => {
+                    sret = true;
+                    layout.return_regs.insert_register(crate::x86_register!(eax));
+                    ArgLocation::Indirect(Box::new(stack.alloc(&PTR32)))
+                }
+            };
+            layout.set_ret(loc, &X86);
+        }
+
+        for arg in &sig.params {
+            let ty = &arg.ty;
+            let loc = match ty.category {
+                XvaCategory::Null => ArgLocation::Registers(Vec::new()),
+                XvaCategory::Int | XvaCategory::Condition if *self == I386CallConv::Fastcall && ty.size <= 4 && next_int < FASTCALL_PARAMS.len() => {
+                    next_int += 1;
+                    ArgLocation::Registers(vec![Register::new(FASTCALL_PARAMS[next_int - 1])])
+                }
+                XvaCategory::Custom(_) => panic!("Unsupported argument category {}", ty.category),
+                _ => stack.alloc(ty),
+            };
+            layout.push_param(loc, &X86);
+        }
+
+        layout.stack_size = stack.size(4);
+        if *self != I386CallConv::Cdecl && sig.fixed_params.is_none() {
+            layout.callee_pop = layout.stack_size;
+        } else if sret {
+            layout.callee_pop = PTR32.size;
+        }
+        layout
+    }
+}
+
+/// The calling convention of the 16-bit modes, which passes every argument on the stack in 2-byte slots and is popped by the caller.
+///
+/// Values are returned in `ax`, or `dx:ax` if they are 4 bytes, and floating-point values in `st0`.
+/// Other values are returned through a hidden pointer that is passed as the first stack argument and returned in `ax`.
+/// `si`, `di`, and `bp` are preserved by the callee
+#[cfg(feature = "xva")]
+pub struct Cdecl16;
+
+#[cfg(feature = "xva")]
+impl CallingConvention for Cdecl16 {
+    fn name(&self) -> &'static str {
+        "cdecl"
+    }
+
+    fn layout(&self, sig: &CallSignature, features: &FeatureSet) -> CallLayout {
+        const PTR16: XvaType = XvaType { size: 2, align: 2, category: XvaCategory::Int };
+
+        let preserve = Regset::from_registers(crate::x86_registers!(sp, bp, si, di));
+        let mut clobbers = Regset::from_registers(crate::x86_registers!(ax, bx, cx, dx));
+        vector_clobbers(&mut clobbers, 8, 8, features);
+
+        let mut layout = CallLayout::new(preserve, clobbers);
+        let mut stack = StackArgs::new(0, 2);
+
+        if let Some(ret) = &sig.ret {
+            let ty = &ret.ty;
+            let loc = match ty.category {
+                XvaCategory::Null => ArgLocation::Registers(Vec::new()),
+                XvaCategory::Custom(_) => panic!("Unsupported return category {}", ty.category),
+                XvaCategory::Int | XvaCategory::Condition if ty.size <= 2 => ArgLocation::Registers(vec![Register::new(crate::x86_register!(ax))]),
+                XvaCategory::Int if ty.size <= 4 => ArgLocation::Registers(vec![Register::new(crate::x86_register!(ax)), Register::new(crate::x86_register!(dx))]),
+                XvaCategory::Float if ty.size <= 16 => ArgLocation::Registers(vec![Register::new(X86Register::St(0))]),
+                _ => {
+                    layout.return_regs.insert_register(crate::x86_register!(ax));
+                    ArgLocation::Indirect(Box::new(stack.alloc(&PTR16)))
+                }
+            };
+            layout.set_ret(loc, &X86);
+        }
+
+        for arg in &sig.params {
+            let ty = &arg.ty;
+            let loc = match ty.category {
+                XvaCategory::Null => ArgLocation::Registers(Vec::new()),
+                XvaCategory::Custom(_) => panic!("Unsupported argument category {}", ty.category),
+                _ => stack.alloc(ty),
+            };
+            layout.push_param(loc, &X86);
+        }
+
+        layout.stack_size = stack.size(2);
+        layout
+    }
+}
+
+#[cfg(all(test, feature = "xva"))]
+mod tests {
+    use std::{
+        cell::Cell,
+        collections::{HashMap, HashSet},
+    };
+
+    use super::*;
+    use crate::{
+        compiler::{callconv::AggregateField, RelocationModel, StackProtectorLevel},
+        intern::Symbol,
+        target::{PropertyValue, TargetInfo, TargetProperties},
+        traits::IntoId,
+        xva::XvaExpr,
+    };
+
+    fn context(mode: X86Mode) -> CompilerContext {
+        context_with(mode, &[])
+    }
+
+    /// A context with the given property overrides
+    fn context_with(mode: X86Mode, overrides: &[(&str, PropertyValue)]) -> CompilerContext {
+        let properties = TargetProperties { global_properties: HashMap::new() };
+        let overrides = overrides.iter().map(|(key, val)| (Symbol::intern(key), val.clone())).collect();
+        CompilerContext {
+            mode: mode.into_id(),
+            properties: TargetInfo { properties, ptr_width: mode.largest_gpr().size() as u16 * 8 },
+            property_overrides: TargetProperties { global_properties: overrides },
+            target_features: HashSet::new(),
+            global_address_kind: AddressKind::Default,
+            global_call_address_kind: AddressKind::Default,
+            local_address_kind: AddressKind::Default,
+            global_tls_kind: AddressKind::Default,
+            local_tls_kind: AddressKind::Default,
+            relocation_model: RelocationModel::Static,
+            stack_protector: StackProtectorLevel::None,
+            stack_guard: StackGuard::Global(Symbol::intern("__stack_chk_guard")),
+            label_counter: Cell::new(0),
+        }
+    }
+
+    fn reg(reg: X86Register) -> XvaRegister {
+        XvaRegister::Physical(Register::new(reg))
+    }
+
+    fn raw(op: X86Opcode, oprs: &[X86Register]) -> XvaStatement {
+        XvaStatement::RawInstr(Instruction::new(Opcode::new(op), oprs.iter().map(|&reg| Operand::Register(Register::new(reg))).collect()))
+    }
+
+    fn lower(stmt: XvaStatement, mode: X86Mode, features: &FeatureSet) -> XvaStatement {
+        let frame = XvaFrameProperties { features: *features, ..XvaFrameProperties::new() };
+        lower_in(stmt, mode, &frame)
+    }
+
+    fn lower_in(stmt: XvaStatement, mode: X86Mode, frame: &XvaFrameProperties) -> XvaStatement {
+        let mut stmt = stmt;
+        X86.lower_mce(&mut stmt, mode, &context(mode), frame);
+        stmt
+    }
+
+    fn select(dest: X86Register, cond: X86Register, left: X86Register, right: X86Register) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest: reg(dest), dest2: None, op: XvaOpcode::Select { cond: reg(cond), left: reg(left), right: reg(right) } })
+    }
+
+    const RAX: X86Register = crate::x86_register!(rax);
+    const RBX: X86Register = crate::x86_register!(rbx);
+    const RCX: X86Register = crate::x86_register!(rcx);
+    const RDX: X86Register = crate::x86_register!(rdx);
+
+    #[test]
+    fn select_uses_cmov() {
+        let cmov = FeatureSet::from_iter([X86TargetFeature::Cmov]);
+        assert_eq!(
+            lower(select(RAX, RDX, RBX, RCX), X86Mode::Long, &cmov),
+            XvaStatement::Elaborated(vec![raw(X86Opcode::Test, &[RDX, RDX]), raw(X86Opcode::Mov, &[RAX, RCX]), raw(X86Opcode::Cmovnz, &[RAX, RBX])])
+        );
+
+        // The destination already holds `left`, so only `right` is moved in when the condition is zero
+        assert_eq!(
+            lower(select(RAX, RDX, RAX, RCX), X86Mode::Long, &cmov),
+            XvaStatement::Elaborated(vec![raw(X86Opcode::Test, &[RDX, RDX]), raw(X86Opcode::Cmovz, &[RAX, RCX])])
+        );
+    }
+
+    #[test]
+    fn select_widens_byte_cmov() {
+        let cmov = FeatureSet::from_iter([X86TargetFeature::Cmov]);
+        let [al, bl, cl, dl] = crate::x86_registers![al, bl, cl, dl];
+        let [eax, ebx, ecx] = crate::x86_registers![eax, ebx, ecx];
+        assert_eq!(
+            lower(select(al, dl, bl, cl), X86Mode::Long, &cmov),
+            XvaStatement::Elaborated(vec![raw(X86Opcode::Test, &[dl, dl]), raw(X86Opcode::Mov, &[eax, ecx]), raw(X86Opcode::Cmovnz, &[eax, ebx])])
+        );
+    }
+
+    #[test]
+    fn select_branches_without_cmov() {
+        let skip = Symbol::intern(".Lselect.0");
+        let jump = |op| XvaStatement::RawInstr(Instruction::new(Opcode::new(op), vec![Operand::RelSymbol(RelocSym { sym: skip, kind: AddressKind::Default }, None)]));
+        assert_eq!(
+            lower(select(RAX, RDX, RBX, RCX), X86Mode::Long, &FeatureSet::new()),
+            XvaStatement::Elaborated(vec![
+                raw(X86Opcode::Test, &[RDX, RDX]),
+                raw(X86Opcode::Mov, &[RAX, RCX]),
+                jump(X86Opcode::Jz),
+                raw(X86Opcode::Mov, &[RAX, RBX]),
+                XvaStatement::Label(skip),
+            ])
+        );
+        assert_eq!(
+            lower(select(RAX, RDX, RAX, RCX), X86Mode::Long, &FeatureSet::new()),
+            XvaStatement::Elaborated(vec![raw(X86Opcode::Test, &[RDX, RDX]), jump(X86Opcode::Jnz), raw(X86Opcode::Mov, &[RAX, RCX]), XvaStatement::Label(skip)])
+        );
+    }
+
+    #[test]
+    fn jump_if_tests_the_condition() {
+        let target = Symbol::intern("taken");
+        assert_eq!(
+            lower(XvaStatement::JumpIf(reg(RDX), target), X86Mode::Long, &FeatureSet::new()),
+            XvaStatement::Elaborated(vec![
+                raw(X86Opcode::Test, &[RDX, RDX]),
+                XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Jnz), vec![Operand::RelSymbol(RelocSym { sym: target, kind: AddressKind::Default }, None)])),
+            ])
+        );
+    }
+
+    #[test]
+    fn dwarf_register_numbers() {
+        let [rdx, rsp, r12, xmm3, st1] = crate::x86_registers![rdx, rsp, r12, xmm3, st1];
+        assert_eq!(RAX.dwarf_regno(X86Mode::Long), Some(0));
+        assert_eq!(rdx.dwarf_regno(X86Mode::Long), Some(1));
+        assert_eq!(rsp.dwarf_regno(X86Mode::Long), Some(7));
+        assert_eq!(r12.dwarf_regno(X86Mode::Long), Some(12));
+        assert_eq!(xmm3.dwarf_regno(X86Mode::Long), Some(20));
+        assert_eq!(st1.dwarf_regno(X86Mode::Long), Some(34));
+
+        let [ecx, esp, ebp, ah] = crate::x86_registers![ecx, esp, ebp, ah];
+        assert_eq!(ecx.dwarf_regno(X86Mode::Protected), Some(1));
+        assert_eq!(esp.dwarf_regno(X86Mode::Protected), Some(4));
+        assert_eq!(ebp.dwarf_regno(X86Mode::Protected), Some(5));
+        assert_eq!(xmm3.dwarf_regno(X86Mode::Protected), Some(24));
+        assert_eq!(ah.dwarf_regno(X86Mode::Protected), None);
+    }
+
+    const RSP: X86Register = crate::x86_register!(rsp);
+    const RBP: X86Register = crate::x86_register!(rbp);
+
+    fn frame(frame_size: usize, use_frame_pointer: bool) -> XvaFrameProperties {
+        XvaFrameProperties { frame_size, frame_align: 16, call_align: 16, call_align_offset: 8, use_frame_pointer, ..XvaFrameProperties::new() }
+    }
+
+    fn sp_imm(op: X86Opcode, size: u128) -> XvaStatement {
+        XvaStatement::RawInstr(Instruction::new(Opcode::new(op), vec![Operand::Register(Register::new(RSP)), Operand::Immediate(size)]))
+    }
+
+    #[test]
+    fn frame_pointer_prologue_cfi() {
+        let mut frame = frame(0, true);
+        assert_eq!(X86.emit_prologue(&mut frame, X86Mode::Long, &context(X86Mode::Long)), [
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Push), vec![Operand::Register(Register::new(RBP))])),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(16)),
+            XvaStatement::Cfi(XvaCfi::Offset(Register::new(RBP), -16)),
+            raw(X86Opcode::Mov, &[RBP, RSP]),
+            XvaStatement::Cfi(XvaCfi::DefCfaRegister(Register::new(RBP))),
+        ]);
+        assert!(frame.has_prologue);
+        assert_eq!(X86.lower_epilogue(&frame, X86Mode::Long, &context(X86Mode::Long)), [
+            raw(X86Opcode::Mov, &[RSP, RBP]),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Pop), vec![Operand::Register(Register::new(RBP))])),
+            XvaStatement::Cfi(XvaCfi::DefCfa(Register::new(RSP), 8)),
+            XvaStatement::Cfi(XvaCfi::Restore(Register::new(RBP))),
+        ]);
+    }
+
+    #[test]
+    fn stack_pointer_prologue_cfi() {
+        // 16 bytes of locals, plus 8 to realign the stack after the return address
+        let mut frame = frame(16, false);
+        assert_eq!(X86.emit_prologue(&mut frame, X86Mode::Long, &context(X86Mode::Long)), [sp_imm(X86Opcode::Sub, 24), XvaStatement::Cfi(XvaCfi::DefCfaOffset(32))]);
+        assert_eq!(X86.lower_epilogue(&frame, X86Mode::Long, &context(X86Mode::Long)), [sp_imm(X86Opcode::Add, 24), XvaStatement::Cfi(XvaCfi::DefCfaOffset(8))]);
+
+        let mut leaf = XvaFrameProperties::new();
+        assert!(X86.emit_prologue(&mut leaf, X86Mode::Long, &context(X86Mode::Long)).is_empty());
+        assert!(X86.lower_epilogue(&leaf, X86Mode::Long, &context(X86Mode::Long)).is_empty());
+    }
+
+    #[test]
+    fn cfi_entry_state() {
+        let cie = X86.cfi_entry_state(X86Mode::Long).unwrap();
+        assert_eq!((cie.code_align, cie.data_align, cie.return_address), (1, -8, 16));
+        assert_eq!((cie.cfa_register, cie.cfa_offset, cie.return_address_offset), (Register::new(RSP), 8, Some(-8)));
+        assert_eq!(X86.cfi_entry_state(X86Mode::Protected).unwrap().return_address, 8);
+        assert_eq!(X86.cfi_entry_state(X86Mode::Real), None);
+    }
+
+    fn probe() -> XvaStatement {
+        let addr = Address { segment: None, base: Some(Register::new(RSP)), index: None, scale: nzlit!(1), sym: None, disp: None, rel: false };
+        XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Memory(MemoryOperand { value_size: Some(8), addr }), Operand::Immediate(0)]))
+    }
+
+    fn probed_prologue(frame_size: usize, probes: &str) -> Vec<XvaStatement> {
+        let context = context_with(X86Mode::Long, &[("stack-probes", PropertyValue::String(Symbol::intern(probes)))]);
+        X86.emit_prologue(&mut frame(frame_size, false), X86Mode::Long, &context)
+    }
+
+    #[test]
+    fn unrolled_stack_probes() {
+        assert_eq!(probed_prologue(2 * 4096 + 8, "inline"), [
+            sp_imm(X86Opcode::Sub, 4096),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(8 + 4096)),
+            probe(),
+            sp_imm(X86Opcode::Sub, 4096),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(8 + 2 * 4096)),
+            probe(),
+            sp_imm(X86Opcode::Sub, 8),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(16 + 2 * 4096)),
+        ]);
+
+        // Frames that fit in a page are never probed
+        assert_eq!(probed_prologue(4088, "inline"), [sp_imm(X86Opcode::Sub, 4088), XvaStatement::Cfi(XvaCfi::DefCfaOffset(4096))]);
+    }
+
+    #[test]
+    fn stack_probe_loop() {
+        let r11 = Register::new(crate::x86_register!(r11));
+        let bound = Address { segment: None, base: Some(Register::new(RSP)), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(-16 * 4096), rel: false };
+        let label = Symbol::intern(".Lprobe.0");
+        assert_eq!(probed_prologue(16 * 4096 + 8, "inline"), [
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Lea), vec![Operand::Register(r11), Operand::Memory(MemoryOperand { value_size: None, addr: bound })])),
+            XvaStatement::Cfi(XvaCfi::DefCfa(r11, 8 + 16 * 4096)),
+            XvaStatement::Label(label),
+            sp_imm(X86Opcode::Sub, 4096),
+            probe(),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Cmp), vec![Operand::Register(Register::new(RSP)), Operand::Register(r11)])),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Jnz), vec![Operand::RelSymbol(RelocSym { sym: label, kind: AddressKind::Default }, None)])),
+            sp_imm(X86Opcode::Sub, 8),
+            XvaStatement::Cfi(XvaCfi::DefCfa(Register::new(RSP), 16 + 16 * 4096)),
+        ]);
+    }
+
+    #[test]
+    fn stack_probe_call() {
+        let chkstk = RelocSym { sym: Symbol::intern("__chkstk"), kind: AddressKind::Default };
+        // rax may hold an argument, so it is saved around the call
+        assert_eq!(probed_prologue(2 * 4096 + 8, "call:__chkstk"), [
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Push), vec![Operand::Register(Register::new(RAX))])),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(16)),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Register(Register::new(RAX)), Operand::Immediate(2 * 4096 + 8)])),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Call), vec![Operand::RelSymbol(chkstk, None)])),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Pop), vec![Operand::Register(Register::new(RAX))])),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(8)),
+            sp_imm(X86Opcode::Sub, 2 * 4096 + 8),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(16 + 2 * 4096)),
+        ]);
+
+        // Without probes, the whole frame is allocated at once
+        assert_eq!(probed_prologue(2 * 4096 + 8, "none"), [sp_imm(X86Opcode::Sub, 2 * 4096 + 8), XvaStatement::Cfi(XvaCfi::DefCfaOffset(16 + 2 * 4096))]);
+    }
+
+    #[test]
+    fn callee_pop_calls_and_returns() {
+        let frame = XvaFrameProperties { frame_size: 12, callee_pop: 8, ..XvaFrameProperties::new() };
+        assert_eq!(
+            lower_in(XvaStatement::Return, X86Mode::Protected, &frame),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Ret), vec![Operand::Immediate(8)]))
+        );
+
+        // The callee pops the arguments from the outgoing argument area, which is reserved again
+        let g = RelocSym { sym: Symbol::intern("g"), kind: AddressKind::Default };
+        let call = XvaStatement::Call {
+            dest: XvaOperand::Const(XvaConst::Global(Symbol::intern("g"), 0)),
+            params: Regset::new(),
+            ret_val: Regset::new(),
+            call_clobber_regs: Regset::new(),
+            callee_pop: 8,
+            indirect_ret: false,
+        };
+        let esp = Register::new(crate::x86_register!(esp));
+        assert_eq!(lower_in(call, X86Mode::Protected, &frame), XvaStatement::Elaborated(vec![
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Call), vec![Operand::RelSymbol(g, None)])),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(8)),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Sub), vec![Operand::Register(esp), Operand::Immediate(8)])),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(16)),
+        ]));
+    }
+
+    fn mem(base: X86Register, disp: i64) -> Operand {
+        let addr = Address { segment: None, base: Some(Register::new(base)), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(disp), rel: false };
+        Operand::Memory(MemoryOperand { value_size: Some(8), addr })
+    }
+
+    fn mov(dest: Operand, src: Operand) -> XvaStatement {
+        XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Mov), vec![dest, src]))
+    }
+
+    /// The instructions that check the canary at `slot` against `guard`, before the frame is torn down
+    fn canary_check(slot: Operand, guard: Operand) -> Vec<XvaStatement> {
+        let r11 = Operand::Register(Register::new(crate::x86_register!(r11)));
+        let ok = Symbol::intern(".Lcanary_ok.0");
+        vec![
+            mov(r11, guard),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Cmp), vec![r11, slot])),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Jz), vec![Operand::RelSymbol(RelocSym { sym: ok, kind: AddressKind::Default }, None)])),
+            XvaStatement::RawInstr(Instruction::new(Opcode::new(X86Opcode::Call), vec![Operand::RelSymbol(RelocSym { sym: Symbol::intern("__stack_chk_fail"), kind: AddressKind::Default }, None)])),
+            XvaStatement::Label(ok),
+        ]
+    }
+
+    #[test]
+    fn stack_protector_canary() {
+        let mut context = context(X86Mode::Long);
+        context.stack_protector = StackProtectorLevel::All;
+        context.stack_guard = StackGuard::Segment(Register::new(crate::x86_register!(fs)), 0x28);
+        let fs = Address { segment: Some(Register::new(crate::x86_register!(fs))), base: None, index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(0x28), rel: false };
+        let guard = Operand::Memory(MemoryOperand { value_size: Some(8), addr: fs });
+        let r11 = Operand::Register(Register::new(crate::x86_register!(r11)));
+
+        // The canary takes the top 8 bytes of the 24 byte frame
+        let mut frame = frame(16, false);
+        assert_eq!(X86.emit_prologue(&mut frame, X86Mode::Long, &context), [
+            sp_imm(X86Opcode::Sub, 24),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(32)),
+            mov(r11, guard),
+            mov(mem(RSP, 16), r11),
+        ]);
+        assert_eq!(frame.stack_protector_slot, Some(16));
+
+        let mut epilogue = canary_check(mem(RSP, 16), guard);
+        epilogue.extend([sp_imm(X86Opcode::Add, 24), XvaStatement::Cfi(XvaCfi::DefCfaOffset(8))]);
+        assert_eq!(X86.lower_epilogue(&frame, X86Mode::Long, &context), epilogue);
+    }
+
+    #[test]
+    fn stack_protector_canary_below_frame_pointer() {
+        let mut context = context(X86Mode::Long);
+        context.stack_protector = StackProtectorLevel::Strong;
+        let guard_sym = Address { segment: None, base: None, index: None, scale: nzlit!(1), sym: Some(RelocSym { sym: Symbol::intern("__stack_chk_guard"), kind: AddressKind::Default }), disp: None, rel: true };
+        let guard = Operand::Memory(MemoryOperand { value_size: Some(8), addr: guard_sym });
+
+        // Functions without local buffers are only protected by StackProtectorLevel::All
+        let mut unprotected = frame(0, true);
+        assert!(!X86.emit_prologue(&mut unprotected, X86Mode::Long, &context).contains(&mov(mem(RBP, -8), Operand::Register(Register::new(crate::x86_register!(r11))))));
+        assert_eq!(unprotected.stack_protector_slot, None);
+
+        let mut frame = frame(0, true);
+        frame.has_local_buffers = true;
+        let prologue = X86.emit_prologue(&mut frame, X86Mode::Long, &context);
+        assert_eq!(frame.stack_protector_slot, Some(-8));
+        assert_eq!(prologue[prologue.len() - 2..], [
+            mov(Operand::Register(Register::new(crate::x86_register!(r11))), guard),
+            mov(mem(RBP, -8), Operand::Register(Register::new(crate::x86_register!(r11)))),
+        ]);
+        assert_eq!(X86.lower_epilogue(&frame, X86Mode::Long, &context)[..5], canary_check(mem(RBP, -8), guard));
+    }
+
+    fn ty(category: XvaCategory, size: u64, align: u64) -> XvaType {
+        XvaType { size, align, category }
+    }
+
+    const I32: XvaType = XvaType { size: 4, align: 4, category: XvaCategory::Int };
+    const I64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+    const F64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Float };
+
+    fn signature(params: &[XvaType], ret: Option<XvaType>) -> CallSignature {
+        CallSignature::new(params.iter().map(|&ty| CallArg::from(ty)).collect(), ret.map(CallArg::from))
+    }
+
+    fn regs(regs: &[X86Register]) -> ArgLocation {
+        ArgLocation::Registers(regs.iter().map(|&reg| Register::new(reg)).collect())
+    }
+
+    #[test]
+    fn sysv64_argument_locations() {
+        let sse = FeatureSet::from_iter([X86TargetFeature::Sse]);
+        let i128 = ty(XvaCategory::Int, 16, 16);
+        let sig = signature(&[I64, F64, i128, I64, I64, I64, I64, i128, F64], Some(I64));
+        let layout = SysV64.layout(&sig, &sse);
+
+        assert_eq!(layout.params, [
+            regs(&[crate::x86_register!(rdi)]),
+            regs(&[crate::x86_register!(xmm0)]),
+            regs(&crate::x86_registers!(rsi, rdx)),
+            regs(&[crate::x86_register!(rcx)]),
+            regs(&[crate::x86_register!(r8)]),
+            regs(&[crate::x86_register!(r9)]),
+            ArgLocation::Stack(0),
+            // 128-bit integers are aligned to 16 bytes on the stack
+            ArgLocation::Stack(16),
+            regs(&[crate::x86_register!(xmm1)]),
+        ]);
+        assert_eq!(layout.ret, Some(regs(&[RAX])));
+        assert_eq!(layout.stack_size, 32);
+        assert_eq!(layout.callee_pop, 0);
+        assert!(layout.param_regs.contains_regid(crate::x86_register!(xmm1), &X86));
+        assert!(!layout.param_regs.contains_regid(RAX, &X86));
+        assert!(layout.return_regs.contains_regid(RAX, &X86));
+        assert!(layout.preserve_regs.contains_regid(RBX, &X86));
+        assert!(layout.clobber_regs.contains_regid(crate::x86_register!(xmm8), &X86));
+
+        // Variadic calls pass the number of vector registers in al
+        let variadic = CallSignature { fixed_params: Some(1), ..signature(&[I64, F64], None) };
+        assert!(SysV64.layout(&variadic, &sse).param_regs.contains_regid(RAX, &X86));
+    }
+
+    #[test]
+    fn sysv64_long_double() {
+        let sse = FeatureSet::from_iter([X86TargetFeature::Sse]);
+        let f80 = ty(XvaCategory::Float, 16, 16);
+        let layout = SysV64.layout(&signature(&[I64, f80, F64], Some(f80)), &sse);
+
+        // long double is passed in memory, without taking a vector register, and returned in st0
+        assert_eq!(layout.params, [regs(&[crate::x86_register!(rdi)]), ArgLocation::Stack(0), regs(&[crate::x86_register!(xmm0)])]);
+        assert_eq!(layout.stack_size, 16);
+        assert_eq!(layout.ret, Some(regs(&[crate::x86_register!(st0)])));
+    }
+
+    #[test]
+    fn win64_argument_locations() {
+        let sse = FeatureSet::from_iter([X86TargetFeature::Sse]);
+        let i128 = ty(XvaCategory::Int, 16, 16);
+        let sig = signature(&[I64, F64, I64, F64, I32, i128], Some(F64));
+        let layout = Win64.layout(&sig, &sse);
+
+        // Registers are assigned by position, and stack arguments start after the shadow space
+        assert_eq!(layout.params, [
+            regs(&[RCX]),
+            regs(&[crate::x86_register!(xmm1)]),
+            regs(&[crate::x86_register!(r8)]),
+            regs(&[crate::x86_register!(xmm3)]),
+            ArgLocation::Stack(32),
+            ArgLocation::Indirect(Box::new(ArgLocation::Stack(40))),
+        ]);
+        assert_eq!(layout.ret, Some(regs(&[crate::x86_register!(xmm0)])));
+        assert_eq!(layout.stack_size, 48);
+        assert!(layout.preserve_regs.contains_regid(crate::x86_register!(xmm6), &X86));
+        assert!(!layout.preserve_regs.contains_regid(crate::x86_register!(xmm5), &X86));
+
+        // Variadic floating-point arguments use the integer registers
+        let variadic = CallSignature { fixed_params: Some(1), ..signature(&[I64, F64], None) };
+        assert_eq!(Win64.layout(&variadic, &sse).params[1], regs(&[RDX]));
+
+        // An argument that is not a power of two in size is passed by reference, even in a register
+        let odd = signature(&[ty(XvaCategory::Int, 3, 1)], None);
+        assert_eq!(Win64.layout(&odd, &sse).params, [ArgLocation::Indirect(Box::new(regs(&[RCX])))]);
+    }
+
+    #[test]
+    fn i386_argument_locations() {
+        let none = FeatureSet::new();
+        // 64-bit integers are only aligned to 4 bytes on i386
+        let i64 = ty(XvaCategory::Int, 8, 4);
+        let sig = signature(&[I32, i64, I32, I32], Some(i64));
+
+        let cdecl = I386CallConv::Cdecl.layout(&sig, &none);
+        assert_eq!(cdecl.params, [ArgLocation::Stack(0), ArgLocation::Stack(4), ArgLocation::Stack(12), ArgLocation::Stack(16)]);
+        assert_eq!(cdecl.ret, Some(regs(&crate::x86_registers!(eax, edx))));
+        assert_eq!((cdecl.stack_size, cdecl.callee_pop), (20, 0));
+
+        let stdcall = I386CallConv::Stdcall.layout(&sig, &none);
+        assert_eq!(stdcall.params, cdecl.params);
+        assert_eq!(stdcall.callee_pop, 20);
+
+        // Only integers of at most 4 bytes are passed in registers
+        let fastcall = I386CallConv::Fastcall.layout(&sig, &none);
+        assert_eq!(fastcall.params, [regs(&[crate::x86_register!(ecx)]), ArgLocation::Stack(0), regs(&[crate::x86_register!(edx)]), ArgLocation::Stack(8)]);
+        assert_eq!((fastcall.stack_size, fastcall.callee_pop), (12, 12));
+
+        // The caller pops the arguments of variadic functions
+        let variadic = CallSignature { fixed_params: Some(1), ..sig.clone() };
+        assert_eq!(I386CallConv::Stdcall.layout(&variadic, &none).callee_pop, 0);
+
+        let float = signature(&[], Some(F64));
+        assert_eq!(I386CallConv::Cdecl.layout(&float, &none).ret, Some(regs(&[X86Register::St(0)])));
+    }
+
+    #[test]
+    fn calling_convention_lookup() {
+        let long = context(X86Mode::Long);
+        let compiler: &dyn crate::compiler::Compiler = &X86;
+        assert_eq!(compiler.calling_convention(&long, None).map(|cc| cc.name()), Some("sysv64"));
+        assert_eq!(compiler.calling_convention(&long, Some("win64")).map(|cc| cc.name()), Some("win64"));
+        assert!(compiler.calling_convention(&long, Some("stdcall")).is_none());
+
+        let windows = context_with(X86Mode::Long, &[("calling-convention", PropertyValue::String(Symbol::intern("win64")))]);
+        assert_eq!(compiler.calling_convention(&windows, None).map(|cc| cc.name()), Some("win64"));
+
+        let protected = context(X86Mode::Protected);
+        assert_eq!(compiler.calling_convention(&protected, None).map(|cc| cc.name()), Some("cdecl"));
+        assert_eq!(compiler.calling_convention(&context(X86Mode::Real), None).map(|cc| cc.name()), Some("cdecl"));
+        assert!(compiler.calling_convention(&context(X86Mode::Real), Some("stdcall")).is_none());
+    }
+
+    #[test]
+    fn cdecl16_argument_locations() {
+        let i16 = ty(XvaCategory::Int, 2, 2);
+        let long = ty(XvaCategory::Int, 4, 2);
+        let layout = Cdecl16.layout(&signature(&[i16, long, i16], Some(long)), &FeatureSet::new());
+
+        // Every argument is passed on the stack, and 4-byte values are returned in dx:ax
+        assert_eq!(layout.params, [ArgLocation::Stack(0), ArgLocation::Stack(2), ArgLocation::Stack(6)]);
+        assert_eq!(layout.ret, Some(regs(&crate::x86_registers!(ax, dx))));
+        assert_eq!((layout.stack_size, layout.callee_pop), (8, 0));
+        assert!(layout.preserve_regs.contains_regid(crate::x86_register!(si), &X86));
+
+        // Larger values are returned through a pointer passed as the first argument
+        let layout = Cdecl16.layout(&signature(&[i16], Some(I64)), &FeatureSet::new());
+        assert_eq!(layout.ret, Some(ArgLocation::Indirect(Box::new(ArgLocation::Stack(0)))));
+        assert_eq!(layout.params, [ArgLocation::Stack(2)]);
+        assert!(layout.return_regs.contains_regid(crate::x86_register!(ax), &X86));
+    }
+
+    fn read(dest: X86Register, opr: XvaOperand) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest: reg(dest), dest2: None, op: XvaOpcode::Read(opr) })
+    }
+
+    #[test]
+    fn stack_argument_addresses() {
+        // After a prologue that allocated 24 bytes, incoming arguments start above the frame and the return address
+        let no_fp = XvaFrameProperties { frame_size: 24, ..XvaFrameProperties::new() };
+        assert_eq!(lower_in(read(RAX, XvaOperand::IncomingArg(8)), X86Mode::Long, &no_fp), mov(Operand::Register(Register::new(RAX)), mem(RSP, 40)));
+        assert_eq!(lower_in(read(RAX, XvaOperand::OutgoingArg(8)), X86Mode::Long, &no_fp), mov(Operand::Register(Register::new(RAX)), mem(RSP, 8)));
+        assert_eq!(
+            lower_in(XvaStatement::Write(XvaOperand::OutgoingArg(0), I64, reg(RCX)), X86Mode::Long, &no_fp),
+            mov(mem(RSP, 0), Operand::Register(Register::new(RCX)))
+        );
+
+        // With a frame pointer, they are addressed above the saved frame pointer
+        let fp = XvaFrameProperties { frame_size: 24, use_frame_pointer: true, ..XvaFrameProperties::new() };
+        assert_eq!(lower_in(read(RAX, XvaOperand::IncomingArg(8)), X86Mode::Long, &fp), mov(Operand::Register(Register::new(RAX)), mem(RBP, 24)));
+
+        // Stack slots are memory operands of arithmetic
+        let add = |right| XvaStatement::Expr(XvaExpr { dest: reg(RAX), dest2: None, op: XvaOpcode::BinaryOp { op: BinaryOp::Add, left: reg(RAX), right } });
+        let rax = Operand::Register(Register::new(RAX));
+        assert_eq!(lower_in(add(XvaOperand::IncomingArg(8)), X86Mode::Long, &no_fp), instr(X86Opcode::Add, vec![rax.clone(), mem(RSP, 40)]));
+        assert_eq!(lower_in(add(XvaOperand::OutgoingArg(0)), X86Mode::Long, &fp), instr(X86Opcode::Add, vec![rax.clone(), mem(RSP, 0)]));
+
+        // The slots of the frame are above the outgoing arguments
+        let outgoing = XvaFrameProperties { outgoing_args_size: 16, ..no_fp };
+        assert_eq!(lower_in(add(XvaOperand::FrameAddr(8)), X86Mode::Long, &outgoing), instr(X86Opcode::Add, vec![rax.clone(), mem(RSP, 24)]));
+        assert_eq!(lower_in(read(RAX, XvaOperand::FrameAddr(0)), X86Mode::Long, &fp), mov(rax.clone(), mem(RBP, -16)));
+
+        // Calls through a stack argument use it as a memory operand
+        let call = |dest| XvaStatement::Call { dest, params: Regset::new(), ret_val: Regset::new(), call_clobber_regs: Regset::new(), callee_pop: 0, indirect_ret: false };
+        let indirect = |op, opr| XvaStatement::RawInstr(Instruction::new(Opcode::new(op), vec![opr]));
+        assert_eq!(lower_in(call(XvaOperand::OutgoingArg(8)), X86Mode::Long, &no_fp), indirect(X86Opcode::Call, mem(RSP, 8)));
+        // A tail call loads its target before the epilogue, into a register that the epilogue does not restore
+        let r10 = Operand::Register(Register::new(crate::x86_register!(r10)));
+        let tailcall = XvaStatement::Tailcall { dest: XvaOperand::IncomingArg(8), params: Regset::new() };
+        assert_eq!(
+            lower_in(tailcall, X86Mode::Long, &no_fp),
+            XvaStatement::Elaborated(vec![mov(r10.clone(), mem(RSP, 40)), indirect(X86Opcode::Jump, r10)])
+        );
+    }
+
+    #[test]
+    fn outgoing_argument_area() {
+        // The outgoing area is added to the frame, which is kept aligned for the calls that use it
+        let mut frame = frame(16, false);
+        frame.reserve_outgoing_args(16);
+        frame.reserve_outgoing_args(8);
+        assert_eq!(frame.outgoing_args_size, 16);
+        assert_eq!(X86.emit_prologue(&mut frame, X86Mode::Long, &context(X86Mode::Long)), [sp_imm(X86Opcode::Sub, 40), XvaStatement::Cfi(XvaCfi::DefCfaOffset(48))]);
+
+        // 16-bit modes address stack arguments from the frame pointer
+        let mut frame = XvaFrameProperties { incoming_args_size: 2, ..XvaFrameProperties::new() };
+        X86.emit_prologue(&mut frame, X86Mode::Real, &context(X86Mode::Real));
+        assert!(frame.use_frame_pointer);
+    }
+
+    const F32: XvaType = XvaType { size: 4, align: 4, category: XvaCategory::Float };
+
+    /// An aggregate with the fields of `tys`, each at its natural alignment
+    fn aggregate(tys: &[XvaType]) -> CallArg {
+        let mut fields = Vec::new();
+        let mut size = 0;
+        for &ty in tys {
+            let offset = size.next_multiple_of(ty.align);
+            fields.push(AggregateField { offset, ty });
+            size = offset + ty.size;
+        }
+        let align = tys.iter().map(|ty| ty.align).max().unwrap_or(1);
+        CallArg::aggregate(size.next_multiple_of(align), align, fields)
+    }
+
+    #[test]
+    fn sysv64_classifies_eightbytes() {
+        let sse = FeatureSet::from_iter([X86TargetFeature::Sse]);
+        let classify = |arg: CallArg| sysv64_classify(&arg, &sse);
+
+        assert_eq!(classify(aggregate(&[F64, F64])), Some(vec![SysVClass::Sse, SysVClass::Sse]));
+        assert_eq!(classify(aggregate(&[I64, F64])), Some(vec![SysVClass::Integer, SysVClass::Sse]));
+        // Two floats share the first eightbyte, and the int makes the second one INTEGER
+        assert_eq!(classify(aggregate(&[F32, F32, I32])), Some(vec![SysVClass::Sse, SysVClass::Integer]));
+        assert_eq!(classify(aggregate(&[I32, F32])), Some(vec![SysVClass::Integer]));
+        // Larger aggregates are passed in memory
+        assert_eq!(classify(aggregate(&[I64, I64, I64])), None);
+        // As are packed ones
+        let byte = ty(XvaCategory::Int, 1, 1);
+        let packed = CallArg::aggregate(5, 1, vec![AggregateField { offset: 0, ty: byte }, AggregateField { offset: 1, ty: I32 }]);
+        assert_eq!(classify(packed), None);
+        // A single vector field is passed like the vector itself
+        let m128 = ty(XvaCategory::VectorFloat, 16, 16);
+        assert_eq!(classify(aggregate(&[m128])), Some(vec![SysVClass::Sse]));
+        assert_eq!(classify(aggregate(&[])), Some(Vec::new()));
+    }
+
+    #[test]
+    fn sysv64_aggregate_locations() {
+        let sse = FeatureSet::from_iter([X86TargetFeature::Sse]);
+        let large = aggregate(&[I64, I64, I64]);
+        let mixed = aggregate(&[F64, I64]);
+
+        let sig = CallSignature::new(vec![mixed.clone(), large.clone(), aggregate(&[F32, F32, I32])], Some(large));
+        let layout = SysV64.layout(&sig, &sse);
+        // The hidden return pointer takes rdi, so the integer eightbyte of the first argument is in rsi
+        assert_eq!(layout.ret, Some(ArgLocation::Indirect(Box::new(regs(&[crate::x86_register!(rdi)])))));
+        assert_eq!(layout.params, [
+            regs(&[crate::x86_register!(xmm0), crate::x86_register!(rsi)]),
+            ArgLocation::Stack(0),
+            regs(&[crate::x86_register!(xmm1), RDX]),
+        ]);
+        assert!(layout.param_regs.contains_regid(crate::x86_register!(rdi), &X86));
+        assert!(layout.return_regs.contains_regid(RAX, &X86));
+
+        let ret = |arg: CallArg| SysV64.layout(&CallSignature::new(Vec::new(), Some(arg)), &sse).ret;
+        assert_eq!(ret(aggregate(&[I64, I64])), Some(regs(&[RAX, RDX])));
+        assert_eq!(ret(mixed), Some(regs(&[crate::x86_register!(xmm0), RAX])));
+
+        // An aggregate that does not fit in the remaining registers is passed entirely on the stack, but later arguments can still use them
+        let ints = aggregate(&[I64, I64]);
+        let sig = CallSignature::new(vec![I64.into(), I64.into(), I64.into(), I64.into(), I64.into(), ints, I64.into()], None);
+        let layout = SysV64.layout(&sig, &sse);
+        assert_eq!(layout.params[5], ArgLocation::Stack(0));
+        assert_eq!(layout.params[6], regs(&[crate::x86_register!(r9)]));
+    }
+
+    #[test]
+    fn win64_aggregate_locations() {
+        let sse = FeatureSet::from_iter([X86TargetFeature::Sse]);
+        let odd = aggregate(&[I32, I32, I32]);
+
+        // The hidden return pointer is the first argument
+        let sig = CallSignature::new(vec![aggregate(&[I32, I32]), odd.clone()], Some(odd));
+        let layout = Win64.layout(&sig, &sse);
+        assert_eq!(layout.ret, Some(ArgLocation::Indirect(Box::new(regs(&[RCX])))));
+        assert_eq!(layout.params, [regs(&[RDX]), ArgLocation::Indirect(Box::new(regs(&[crate::x86_register!(r8)])))]);
+        assert!(layout.return_regs.contains_regid(RAX, &X86));
+    }
+
+    #[test]
+    fn i386_aggregate_locations() {
+        let none = FeatureSet::new();
+        let pair = aggregate(&[I32, I32]);
+
+        let sig = CallSignature::new(vec![pair.clone(), I32.into()], Some(pair));
+        let layout = I386CallConv::Cdecl.layout(&sig, &none);
+        assert_eq!(layout.ret, Some(ArgLocation::Indirect(Box::new(ArgLocation::Stack(0)))));
+        assert_eq!(layout.params, [ArgLocation::Stack(4), ArgLocation::Stack(12)]);
+        assert_eq!(layout.stack_size, 16);
+        // The callee pops the hidden pointer, even under cdecl
+        assert_eq!(layout.callee_pop, 4);
+        assert!(layout.return_regs.contains_regid(crate::x86_register!(eax), &X86));
+    }
+
+    fn mul_div(dest: X86Register, dest2: Option<X86Register>, op: XvaOpcode) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest: reg(dest), dest2: dest2.map(reg), op })
+    }
+
+    #[test]
+    fn multiplications_use_two_operand_imul() {
+        let [rsi, rdi] = crate::x86_registers![rsi, rdi];
+        let [al, bl, cl] = crate::x86_registers![al, bl, cl];
+        let [eax, ebx, ecx] = crate::x86_registers![eax, ebx, ecx];
+        let lower = |stmt| lower_in(stmt, X86Mode::Long, &frame(0, false));
+        assert_eq!(
+            lower(mul_div(RAX, None, XvaOpcode::UMul { left: reg(rdi), right: reg(rsi) })),
+            XvaStatement::Elaborated(vec![raw(X86Opcode::Mov, &[RAX, rdi]), raw(X86Opcode::Imul, &[RAX, rsi])])
+        );
+        // Multiplication commutes, so a destination that holds the right operand needs no move
+        assert_eq!(lower(mul_div(RCX, None, XvaOpcode::SMul { left: reg(rdi), right: reg(RCX) })), XvaStatement::Elaborated(vec![raw(X86Opcode::Imul, &[RCX, rdi])]));
+        assert_eq!(
+            lower(mul_div(al, None, XvaOpcode::UMul { left: reg(bl), right: reg(cl) })),
+            XvaStatement::Elaborated(vec![raw(X86Opcode::Mov, &[eax, ebx]), raw(X86Opcode::Imul, &[eax, ecx])])
+        );
+    }
+
+    #[test]
+    fn divisions_save_rax_and_rdx() {
+        let [rsi, rdi] = crate::x86_registers![rsi, rdi];
+        let cfa = |offset| XvaStatement::Cfi(XvaCfi::DefCfaOffset(offset));
+        assert_eq!(
+            lower_in(mul_div(RCX, None, XvaOpcode::UDiv { left: reg(rdi), right: reg(rsi) }), X86Mode::Long, &frame(0, false)),
+            XvaStatement::Elaborated(vec![
+                raw(X86Opcode::Push, &[RDX]),
+                cfa(16),
+                raw(X86Opcode::Push, &[RAX]),
+                cfa(24),
+                raw(X86Opcode::Mov, &[RAX, rdi]),
+                raw(X86Opcode::Xor, &[RDX, RDX]),
+                raw(X86Opcode::Div, &[rsi]),
+                raw(X86Opcode::Mov, &[RCX, RAX]),
+                raw(X86Opcode::Pop, &[RAX]),
+                cfa(16),
+                raw(X86Opcode::Pop, &[RDX]),
+                cfa(8),
+            ])
+        );
+
+        // The divisor in rdx is overwritten by the sign extension, so it is read from a copy on the stack
+        assert_eq!(
+            lower_in(mul_div(RAX, Some(RDX), XvaOpcode::SDiv { left: reg(RCX), right: reg(RDX) }), X86Mode::Long, &frame(0, true)),
+            XvaStatement::Elaborated(vec![
+                raw(X86Opcode::Push, &[RDX]),
+                raw(X86Opcode::Mov, &[RAX, RCX]),
+                raw(X86Opcode::Cqo, &[]),
+                instr(X86Opcode::Idiv, vec![mem(RSP, 0)]),
+                sp_imm(X86Opcode::Add, 8),
+            ])
+        );
+    }
+
+    fn tls_context(mode: X86Mode, model: &str) -> CompilerContext {
+        context_with(mode, &[("tls-model", PropertyValue::String(Symbol::intern(model)))])
+    }
+
+    fn tls_addr(dest: X86Register, sym: &str, disp: i64, local: bool) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest: reg(dest), dest2: None, op: XvaOpcode::TlsAddr { sym: Symbol::intern(sym), disp, local, module_base: None } })
+    }
+
+    fn reloc_mem(value_size: Option<usize>, base: Option<X86Register>, sym: &str, kind: AddressKind, disp: i64, rel: bool) -> Operand {
+        let sym = Some(RelocSym { sym: Symbol::intern(sym), kind });
+        let addr = Address { segment: None, base: base.map(Register::new), index: None, scale: nzlit!(1), sym, disp: core::num::NonZeroI64::new(disp), rel };
+        Operand::Memory(MemoryOperand { value_size, addr })
+    }
+
+    fn thread_pointer(dest: X86Register, segment: X86Register, size: usize) -> XvaStatement {
+        let addr = Address { segment: Some(Register::new(segment)), base: None, index: None, scale: nzlit!(1), sym: None, disp: None, rel: false };
+        mov(Operand::Register(Register::new(dest)), Operand::Memory(MemoryOperand { value_size: Some(size), addr }))
+    }
+
+    fn instr(op: X86Opcode, oprs: Vec<Operand>) -> XvaStatement {
+        XvaStatement::RawInstr(Instruction::new(Opcode::new(op), oprs))
+    }
+
+    #[test]
+    fn tls_model_selection() {
+        // Without the property, the model follows the address kinds
+        let mut context = context(X86Mode::Long);
+        assert_eq!(context.tls_model(false), TlsModel::LocalExec);
+        context.global_tls_kind = AddressKind::GotTpoff;
+        context.local_tls_kind = AddressKind::TlsLd;
+        assert_eq!(context.tls_model(false), TlsModel::InitialExec);
+        assert_eq!(context.tls_model(true), TlsModel::LocalDynamic);
+
+        // Local-dynamic only applies to variables defined in the current module
+        let context = tls_context(X86Mode::Long, "local-dynamic");
+        assert_eq!(context.tls_model(true), TlsModel::LocalDynamic);
+        assert_eq!(context.tls_model(false), TlsModel::GeneralDynamic);
+    }
+
+    #[test]
+    fn local_exec_tls() {
+        let rax = Operand::Register(Register::new(RAX));
+        let mut stmt = tls_addr(RAX, "x", 8, true);
+        X86.lower_mce(&mut stmt, X86Mode::Long, &tls_context(X86Mode::Long, "local-exec"), &XvaFrameProperties::new());
+        assert_eq!(
+            stmt,
+            XvaStatement::Elaborated(vec![
+                thread_pointer(RAX, crate::x86_register!(fs), 8),
+                instr(X86Opcode::Lea, vec![rax, reloc_mem(None, Some(RAX), "x", AddressKind::Tpoff, 8, false)]),
+            ])
+        );
+
+        // 32-bit mode uses gs for the thread pointer, and adds the negated offset
+        let eax = crate::x86_register!(eax);
+        let mut stmt = tls_addr(eax, "x", 0, false);
+        X86.lower_mce(&mut stmt, X86Mode::Protected, &tls_context(X86Mode::Protected, "local-exec"), &XvaFrameProperties::new());
+        assert_eq!(
+            stmt,
+            XvaStatement::Elaborated(vec![
+                thread_pointer(eax, crate::x86_register!(gs), 4),
+                instr(X86Opcode::Lea, vec![Operand::Register(Register::new(eax)), reloc_mem(None, Some(eax), "x", AddressKind::NTpoff, 0, false)]),
+            ])
+        );
+    }
+
+    #[test]
+    fn initial_exec_tls() {
+        let rax = Operand::Register(Register::new(RAX));
+        let displaced = Address { segment: None, base: Some(Register::new(RAX)), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(8), rel: false };
+        let mut stmt = tls_addr(RAX, "x", 8, false);
+        X86.lower_mce(&mut stmt, X86Mode::Long, &tls_context(X86Mode::Long, "initial-exec"), &XvaFrameProperties::new());
+        assert_eq!(
+            stmt,
+            XvaStatement::Elaborated(vec![
+                thread_pointer(RAX, crate::x86_register!(fs), 8),
+                instr(X86Opcode::Add, vec![rax, reloc_mem(Some(8), None, "x", AddressKind::GotTpoff, 0, true)]),
+                instr(X86Opcode::Lea, vec![rax, Operand::Memory(MemoryOperand { value_size: None, addr: displaced })]),
+            ])
+        );
+
+        // i386 position-dependent code reads the negated offset from an absolute GOT entry
+        let eax = crate::x86_register!(eax);
+        let mut stmt = tls_addr(eax, "x", 0, false);
+        X86.lower_mce(&mut stmt, X86Mode::Protected, &tls_context(X86Mode::Protected, "initial-exec"), &XvaFrameProperties::new());
+        assert_eq!(
+            stmt,
+            XvaStatement::Elaborated(vec![
+                thread_pointer(eax, crate::x86_register!(gs), 4),
+                instr(X86Opcode::Add, vec![Operand::Register(Register::new(eax)), reloc_mem(Some(4), None, "x", AddressKind::IndNTpoff, 0, false)]),
+            ])
+        );
+    }
+
+    #[test]
+    fn general_dynamic_tls() {
+        let context = tls_context(X86Mode::Long, "global-dynamic");
+        let frame = XvaFrameProperties::new();
+        let XvaStatement::Expr(expr) = tls_addr(RBX, "x", 8, false) else { unreachable!() };
+        let stmts = X86.expand_runtime_calls(&expr, X86Mode::Long, &context, &frame).unwrap();
+
+        let rdi = crate::x86_register!(rdi);
+        assert_eq!(stmts[0], XvaStatement::Expr(XvaExpr { dest: reg(rdi), dest2: None, op: XvaOpcode::TlsIndex { sym: Symbol::intern("x"), module: false } }));
+        let XvaStatement::Call { dest, params, ret_val, call_clobber_regs, .. } = &stmts[1] else { panic!("expected a call, got {:?}", stmts[1]) };
+        assert_eq!(*dest, XvaOperand::Const(XvaConst::Global(Symbol::intern("__tls_get_addr"), 0)));
+        assert!(params.contains_regid(rdi, &X86));
+        assert!(ret_val.contains_regid(RAX, &X86));
+        assert!(call_clobber_regs.contains_regid(crate::x86_register!(r11), &X86));
+        assert_eq!(stmts[2], XvaStatement::Expr(XvaExpr { dest: reg(RBX), dest2: None, op: XvaOpcode::Move(reg(RAX)) }));
+        assert_eq!(
+            stmts[3],
+            XvaStatement::Expr(XvaExpr { dest: reg(RBX), dest2: None, op: XvaOpcode::BinaryOp { op: BinaryOp::Add, left: reg(RBX), right: XvaOperand::Const(XvaConst::Bits(8)) } })
+        );
+        assert_eq!(stmts.len(), 4);
+
+        // The tls_index is addressed relative to rip, in the padded sequence that the linker can relax
+        let mut index = stmts[0].clone();
+        X86.lower_mce(&mut index, X86Mode::Long, &context, &frame);
+        let lea = Instruction::new(Opcode::new(X86Opcode::Lea), vec![Operand::Register(Register::new(rdi)), reloc_mem(None, None, "x", AddressKind::TlsGd, 0, true)]);
+        assert_eq!(index, XvaStatement::RawInstr(lea.with_prefixes([Opcode::new(X86Opcode::DataOverride)])));
+        let mut call = stmts[1].clone();
+        X86.lower_mce(&mut call, X86Mode::Long, &context, &frame);
+        let target = Operand::RelSymbol(RelocSym { sym: Symbol::intern("__tls_get_addr"), kind: AddressKind::Plt }, None);
+        let padded = Instruction::new(Opcode::new(X86Opcode::Call), vec![target]).with_prefixes([X86Opcode::DataOverride, X86Opcode::DataOverride, X86Opcode::Rex64].map(Opcode::new));
+        assert_eq!(call, XvaStatement::RawInstr(padded));
+
+        // x86-64 uses the same sequence for the local-dynamic model
+        let XvaStatement::Expr(expr) = tls_addr(RBX, "x", 8, true) else { unreachable!() };
+        let local = X86.expand_runtime_calls(&expr, X86Mode::Long, &tls_context(X86Mode::Long, "local-dynamic"), &frame).unwrap();
+        assert_eq!(local[0], stmts[0]);
+
+        // The static models are not expanded
+        let XvaStatement::Expr(expr) = tls_addr(RBX, "x", 8, false) else { unreachable!() };
+        assert_eq!(X86.expand_runtime_calls(&expr, X86Mode::Long, &tls_context(X86Mode::Long, "initial-exec"), &frame), None);
+    }
+
+    #[test]
+    fn local_dynamic_tls() {
+        let mut context = tls_context(X86Mode::Protected, "local-dynamic");
+        context.set_relocation_model(RelocationModel::Pic);
+        let frame = XvaFrameProperties::new();
+        let eax = crate::x86_register!(eax);
+        let ecx = crate::x86_register!(ecx);
+        let XvaStatement::Expr(expr) = tls_addr(ecx, "x", 4, true) else { unreachable!() };
+        let stmts = X86.expand_runtime_calls(&expr, X86Mode::Protected, &context, &frame).unwrap();
+
+        // i386 passes the module's tls_index in eax to ___tls_get_addr
+        assert_eq!(stmts[0], XvaStatement::Expr(XvaExpr { dest: reg(eax), dest2: None, op: XvaOpcode::TlsIndex { sym: Symbol::intern("x"), module: true } }));
+        let XvaStatement::Call { dest, .. } = &stmts[1] else { panic!("expected a call, got {:?}", stmts[1]) };
+        assert_eq!(*dest, XvaOperand::Const(XvaConst::Global(Symbol::intern("___tls_get_addr"), 0)));
+        let base = XvaStatement::Expr(XvaExpr { dest: reg(ecx), dest2: None, op: XvaOpcode::TlsAddr { sym: Symbol::intern("x"), disp: 4, local: true, module_base: Some(reg(eax)) } });
+        assert_eq!(stmts[2..], [base.clone()]);
+
+        // The GOT is addressed through ebx, and the variable relative to the module's TLS block
+        let mut index = stmts[0].clone();
+        X86.lower_mce(&mut index, X86Mode::Protected, &context, &frame);
+        assert_eq!(index, instr(X86Opcode::Lea, vec![Operand::Register(Register::new(eax)), reloc_mem(None, Some(crate::x86_register!(ebx)), "x", AddressKind::TlsLdm, 0, false)]));
+
+        let mut addr = base;
+        X86.lower_mce(&mut addr, X86Mode::Protected, &context, &frame);
+        assert_eq!(
+            addr,
+            XvaStatement::Elaborated(vec![instr(X86Opcode::Lea, vec![Operand::Register(Register::new(ecx)), reloc_mem(None, Some(eax), "x", AddressKind::DTpoff, 4, false)])])
+        );
+    }
+
+    fn pic_context(mode: X86Mode, model: RelocationModel) -> CompilerContext {
+        let mut context = context(mode);
+        context.set_relocation_model(model);
+        context
+    }
+
+    fn symbol_const(dest: X86Register, c: XvaConst) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest: reg(dest), dest2: None, op: XvaOpcode::Const(c) })
+    }
+
+    #[test]
+    fn pie_symbol_addresses() {
+        let context = pic_context(X86Mode::Long, RelocationModel::Pie);
+        let frame = XvaFrameProperties::new();
+        let rax = Operand::Register(Register::new(RAX));
+
+        // Global symbols are loaded from the GOT, and the displacement is added afterwards
+        let mut stmt = symbol_const(RAX, XvaConst::Global(Symbol::intern("x"), 8));
+        X86.lower_mce(&mut stmt, X86Mode::Long, &context, &frame);
+        let displaced = Address { segment: None, base: Some(Register::new(RAX)), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(8), rel: false };
+        assert_eq!(
+            stmt,
+            XvaStatement::Elaborated(vec![
+                mov(rax, reloc_mem(Some(8), None, "x", AddressKind::GotRel, 0, true)),
+                instr(X86Opcode::Lea, vec![rax, Operand::Memory(MemoryOperand { value_size: None, addr: displaced })]),
+            ])
+        );
+
+        // Local symbols are addressed relative to rip
+        let mut stmt = symbol_const(RAX, XvaConst::Label(Symbol::intern("l")));
+        X86.lower_mce(&mut stmt, X86Mode::Long, &context, &frame);
+        assert_eq!(stmt, XvaStatement::Elaborated(vec![instr(X86Opcode::Lea, vec![rax, reloc_mem(None, None, "l", AddressKind::Default, 0, true)])]));
+
+        // Static code references the symbol directly
+        let mut stmt = symbol_const(RAX, XvaConst::Global(Symbol::intern("x"), 8));
+        X86.lower_mce(&mut stmt, X86Mode::Long, &pic_context(X86Mode::Long, RelocationModel::Static), &frame);
+        assert_eq!(stmt, XvaStatement::Elaborated(vec![instr(X86Opcode::Lea, vec![rax, reloc_mem(None, None, "x", AddressKind::Default, 8, true)])]));
+    }
+
+    #[test]
+    fn i386_pic_addresses_symbols_from_the_got() {
+        let context = pic_context(X86Mode::Protected, RelocationModel::Pic);
+        let frame = XvaFrameProperties::new();
+        let eax = crate::x86_register!(eax);
+        let ebx = crate::x86_register!(ebx);
+
+        let mut stmt = symbol_const(eax, XvaConst::Global(Symbol::intern("x"), 0));
+        X86.lower_mce(&mut stmt, X86Mode::Protected, &context, &frame);
+        assert_eq!(stmt, XvaStatement::Elaborated(vec![mov(Operand::Register(Register::new(eax)), reloc_mem(Some(4), Some(ebx), "x", AddressKind::GotAbs, 0, false))]));
+
+        let mut stmt = symbol_const(eax, XvaConst::Label(Symbol::intern("l")));
+        X86.lower_mce(&mut stmt, X86Mode::Protected, &context, &frame);
+        assert_eq!(
+            stmt,
+            XvaStatement::Elaborated(vec![instr(X86Opcode::Lea, vec![Operand::Register(Register::new(eax)), reloc_mem(None, Some(ebx), "l", AddressKind::GotOff, 0, false)])])
+        );
+    }
+
+    #[test]
+    fn i386_pic_prologue_loads_the_got() {
+        let context = pic_context(X86Mode::Protected, RelocationModel::Pic);
+        let esp = Register::new(crate::x86_register!(esp));
+        let ebx = Register::new(crate::x86_register!(ebx));
+        let esp_imm = |op, size| XvaStatement::RawInstr(Instruction::new(Opcode::new(op), vec![Operand::Register(esp), Operand::Immediate(size)]));
+
+        // Functions that refer to symbols need the GOT, even if they have no frame
+        let mut frame = XvaFrameProperties { frame_size: 8, frame_align: 4, call_align: 4, call_align_offset: 4, ..XvaFrameProperties::new() };
+        assert!(!X86.needs_prologue(&frame, X86Mode::Protected, &context));
+        frame.references_symbols = true;
+        assert!(X86.needs_prologue(&frame, X86Mode::Protected, &context));
+        assert!(!X86.needs_prologue(&frame, X86Mode::Long, &pic_context(X86Mode::Long, RelocationModel::Pic)));
+
+        let got = RelocSym { sym: Symbol::intern("_GLOBAL_OFFSET_TABLE_"), kind: AddressKind::Default };
+        let thunk = RelocSym { sym: Symbol::intern("__x86.get_pc_thunk.bx"), kind: AddressKind::Default };
+        assert_eq!(X86.emit_prologue(&mut frame, X86Mode::Protected, &context), [
+            instr(X86Opcode::Push, vec![Operand::Register(ebx)]),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(8)),
+            XvaStatement::Cfi(XvaCfi::Offset(ebx, -8)),
+            esp_imm(X86Opcode::Sub, 8),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(16)),
+            instr(X86Opcode::Call, vec![Operand::RelSymbol(thunk, None)]),
+            instr(X86Opcode::Add, vec![Operand::Register(ebx), Operand::AbsSymbol(got, None)]),
+        ]);
+        assert_eq!(X86.lower_epilogue(&frame, X86Mode::Protected, &context), [
+            esp_imm(X86Opcode::Add, 8),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(8)),
+            instr(X86Opcode::Pop, vec![Operand::Register(ebx)]),
+            XvaStatement::Cfi(XvaCfi::Restore(ebx)),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(4)),
+        ]);
+
+        // The epilogue restores the caller's ebx, so the target of a tail call through the PLT is loaded from the GOT before it
+        let eax = Register::new(crate::x86_register!(eax));
+        let mut stmt = XvaStatement::Tailcall { dest: XvaOperand::Const(XvaConst::Global(Symbol::intern("g"), 0)), params: Regset::new() };
+        crate::compiler::Compiler::mce_lower(&X86, &mut stmt, &frame, &context);
+        assert_eq!(stmt, XvaStatement::Elaborated(vec![
+            XvaStatement::Elaborated(vec![mov(Operand::Register(eax), reloc_mem(Some(4), Some(crate::x86_register!(ebx)), "g", AddressKind::GotAbs, 0, false))]),
+            XvaStatement::Cfi(XvaCfi::RememberState),
+            esp_imm(X86Opcode::Add, 8),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(8)),
+            instr(X86Opcode::Pop, vec![Operand::Register(ebx)]),
+            XvaStatement::Cfi(XvaCfi::Restore(ebx)),
+            XvaStatement::Cfi(XvaCfi::DefCfaOffset(4)),
+            instr(X86Opcode::Jump, vec![Operand::Register(eax)]),
+            XvaStatement::Cfi(XvaCfi::RestoreState),
+        ]));
+    }
+
+    #[test]
+    fn jumps_to_local_labels_are_not_symbol_references() {
+        let jump = |op, name| instr(op, vec![Operand::RelSymbol(RelocSym { sym: Symbol::intern(name), kind: AddressKind::Default }, None)]);
+        let func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: vec![XvaBasicBlock {
+                label: Symbol::intern("f"),
+                live_at_start: Vec::new(),
+                body: XvaBlockBody::Statement(vec![XvaStatement::Label(Symbol::intern(".Lloop")), jump(X86Opcode::Jz, ".Lloop"), jump(X86Opcode::Jump, "f")]),
+            }],
+            frame_properties: XvaFrameProperties::new(),
+        };
+        assert!(crate::xva::used_symbols(&func).is_empty());
+
+        // Calls refer to other functions, which the GOT base is needed for
+        let mut func = func;
+        let XvaBlockBody::Statement(stmts) = &mut func.body[0].body;
+        stmts.push(jump(X86Opcode::Call, "g"));
+        assert_eq!(crate::xva::used_symbols(&func), [Symbol::intern("g")].into());
+    }
+
+    #[test]
+    fn i386_pic_thunk() {
+        let context = pic_context(X86Mode::Protected, RelocationModel::Pic);
+        let [thunk] = &X86.support_functions(X86Mode::Protected, &context)[..] else { panic!("expected the get-PC thunk") };
+        assert_eq!(thunk.label, Symbol::intern("__x86.get_pc_thunk.bx"));
+        assert_eq!(thunk.linkage, Linkage::Weak);
+        // It does not refer to symbols itself, and must not load the GOT
+        assert!(thunk.body.frame_properties.naked);
+
+        // It is only needed for i386 position-independent code
+        assert!(X86.support_functions(X86Mode::Protected, &pic_context(X86Mode::Protected, RelocationModel::Static)).is_empty());
+        assert!(X86.support_functions(X86Mode::Long, &pic_context(X86Mode::Long, RelocationModel::Pic)).is_empty());
+    }
+
+    /// Applies the peephole rules to the raw instructions in `stmts`
+    fn peephole(mode: X86Mode, stmts: Vec<XvaStatement>) -> Vec<XvaStatement> {
+        let mut instrs: Vec<Instruction> = stmts.into_iter().map(|stmt| match stmt {
+            XvaStatement::RawInstr(instr) => instr,
+            _ => panic!("expected a raw instruction"),
+        }).collect();
+        crate::xva::opt::peephole::apply_rules(&mut instrs, X86::PEEPHOLE_RULES, mode.into_id());
+        instrs.into_iter().map(XvaStatement::RawInstr).collect()
+    }
+
+    fn reg_imm(op: X86Opcode, reg: X86Register, imm: u128) -> XvaStatement {
+        instr(op, vec![Operand::Register(Register::new(reg)), Operand::Immediate(imm)])
+    }
+
+    #[test]
+    fn peephole_removes_moves_to_self() {
+        let eax = crate::x86_register!(eax);
+        assert_eq!(peephole(X86Mode::Long, vec![raw(X86Opcode::Mov, &[RAX, RAX]), raw(X86Opcode::Mov, &[RBX, RAX])]), [raw(X86Opcode::Mov, &[RBX, RAX])]);
+        // Writing a 32-bit register clears the upper half of the 64-bit register
+        assert_eq!(peephole(X86Mode::Long, vec![raw(X86Opcode::Mov, &[eax, eax])]), [raw(X86Opcode::Mov, &[eax, eax])]);
+        assert_eq!(peephole(X86Mode::Protected, vec![raw(X86Opcode::Mov, &[eax, eax])]), []);
+    }
+
+    #[test]
+    fn peephole_removes_adding_zero_only_when_the_flags_are_overwritten() {
+        let overwritten = vec![reg_imm(X86Opcode::Add, RAX, 0), raw(X86Opcode::Mov, &[RBX, RAX]), reg_imm(X86Opcode::Cmp, RBX, 1)];
+        assert_eq!(peephole(X86Mode::Long, overwritten), [raw(X86Opcode::Mov, &[RBX, RAX]), reg_imm(X86Opcode::Cmp, RBX, 1)]);
+
+        // The flags may be read after the end of the run
+        let read = vec![reg_imm(X86Opcode::Sub, RAX, 0), raw(X86Opcode::Mov, &[RBX, RAX])];
+        assert_eq!(peephole(X86Mode::Long, read.clone()), read);
+        let nonzero = vec![reg_imm(X86Opcode::Add, RAX, 1), reg_imm(X86Opcode::Cmp, RAX, 1)];
+        assert_eq!(peephole(X86Mode::Long, nonzero.clone()), nonzero);
+    }
+
+    #[test]
+    fn peephole_turns_push_pop_into_a_move() {
+        assert_eq!(peephole(X86Mode::Long, vec![raw(X86Opcode::Push, &[RBX]), raw(X86Opcode::Pop, &[RCX])]), [raw(X86Opcode::Mov, &[RCX, RBX])]);
+        // Removing a pair exposes the one around it
+        assert_eq!(peephole(X86Mode::Long, vec![raw(X86Opcode::Push, &[RAX]), raw(X86Opcode::Push, &[RBX]), raw(X86Opcode::Pop, &[RBX]), raw(X86Opcode::Pop, &[RAX])]), []);
+        // Pushing the stack pointer observes the adjustment
+        let rsp = crate::x86_register!(rsp);
+        let push_sp = vec![raw(X86Opcode::Push, &[rsp]), raw(X86Opcode::Pop, &[RAX])];
+        assert_eq!(peephole(X86Mode::Long, push_sp.clone()), push_sp);
+    }
+
+    #[test]
+    fn peephole_turns_lea_of_a_register_into_a_move() {
+        let base = |disp| Operand::Memory(MemoryOperand { value_size: None, addr: Address { segment: None, base: Some(Register::new(RBX)), index: None, scale: nzlit!(1), sym: None, disp: core::num::NonZeroI64::new(disp), rel: false } });
+        assert_eq!(peephole(X86Mode::Long, vec![instr(X86Opcode::Lea, vec![Operand::Register(Register::new(RAX)), base(0)])]), [raw(X86Opcode::Mov, &[RAX, RBX])]);
+        let displaced = vec![instr(X86Opcode::Lea, vec![Operand::Register(Register::new(RAX)), base(8)])];
+        assert_eq!(peephole(X86Mode::Long, displaced.clone()), displaced);
+    }
+
+    #[test]
+    fn sched_info_models_registers_memory_and_flags() {
+        let mode = X86Mode::Long;
+        let sched = |stmt: XvaStatement| match stmt {
+            XvaStatement::RawInstr(instr) => X86.sched_info(&instr, mode),
+            _ => unreachable!(),
+        };
+        let regs = |regs: &[X86Register]| regs.iter().map(|&reg| Register::new(reg)).collect::<Vec<_>>();
+
+        let add = sched(raw(X86Opcode::Add, &[RAX, RBX])).unwrap();
+        assert_eq!((add.latency, add.reads, add.writes, add.writes_flags, add.loads), (1, regs(&[RAX, RBX]), regs(&[RAX]), true, false));
+
+        // A load from memory takes longer and uses the load ports, and a store reads the address registers
+        let load = sched(mov(Operand::Register(Register::new(RAX)), mem(RBX, 8))).unwrap();
+        assert_eq!((load.latency, load.ports, load.reads, load.writes, load.loads), (1 + X86::SCHED_LOAD_LATENCY, X86::SCHED_LOAD_PORTS, regs(&[RBX]), regs(&[RAX]), true));
+        let store = sched(mov(mem(RBX, 8), Operand::Register(Register::new(RAX)))).unwrap();
+        assert_eq!((store.ports, store.reads, store.writes, store.stores), (X86::SCHED_STORE_PORTS, regs(&[RBX, RAX]), regs(&[]), true));
+
+        // Push and pop use the stack pointer
+        let push = sched(raw(X86Opcode::Push, &[RBX])).unwrap();
+        assert_eq!((push.reads, push.writes, push.stores), (regs(&[RBX, RSP]), regs(&[RSP]), true));
 
-        instrs
+        // Instructions without a model are never moved
+        assert_eq!(sched(raw(X86Opcode::Ret, &[])), None);
+        assert_eq!(sched(instr(X86Opcode::Call, vec![Operand::RelSymbol(RelocSym { sym: Symbol::intern("f"), kind: AddressKind::Default }, None)])), None);
     }
 }
@@ -1,12 +1,14 @@
 //! Compilation support for architectures
-use std::{collections::HashSet, num::NonZeroU64};
+use std::{cell::Cell, collections::HashSet, num::NonZeroU64};
 
 use crate::{
-    instr::{Address, AddressKind, Instruction}, mach::{FeatureSet, Machine, MachineMode, MachineSpec, Register, RegisterSpec}, target::{PropertyValue, TargetInfo, TargetProperties}, traits::{AsId, IdType, Name}, xva::{NoopKind, XvaCategory, XvaFrameProperties, XvaRegister, XvaStatement}
+    compiler::callconv::CallingConvention, instr::{Address, AddressKind, Instruction, Operand}, intern::Symbol, mach::{Machine, MachineMode, MachineSpec, Register, RegisterSpec}, target::{PropertyValue, TargetInfo, TargetProperties}, traits::{AsId, IdType, Name}, xva::{dwarf::DwarfCie, opt::{peephole::PeepholeRule, sched::SchedInfo}, BinaryOp, NoopKind, XvaType, XvaAsmConstraint, XvaCategory, XvaCfi, XvaExpr, XvaFrameProperties, XvaFunctionDef, XvaInlineAsm, XvaOpcode, XvaOperand, XvaRegister, XvaStatement}
 };
 
 
 
+pub mod callconv;
+
 pub trait CompilerSpec: MachineSpec {
     type Machine: MachineSpec<
             Opcode = Self::Opcode,
@@ -30,10 +32,62 @@ pub trait CompilerSpec: MachineSpec {
         size: u32,
     ) -> Option<u32>;
 
-    fn lower_mce(&self, stmt: &mut XvaStatement, mode: Self::MachineMode, context: &CompilerContext, features: &FeatureSet);
+    /// Lowers `stmt` to machine instructions. `frame` is the frame of the function after the prologue has been emitted
+    fn lower_mce(&self, stmt: &mut XvaStatement, mode: Self::MachineMode, context: &CompilerContext, frame: &XvaFrameProperties);
+
+    fn lower_epilogue(&self, frame: &XvaFrameProperties, mode: Self::MachineMode, context: &CompilerContext) -> Vec<XvaStatement>;
+    fn emit_prologue(&self, frame: &mut XvaFrameProperties, mode: Self::MachineMode, context: &CompilerContext) -> Vec<XvaStatement>;
+
+    /// Describes the call frame on entry to a function, used to build the CIE of unwind information, or [`None`] if unwind information is not supported
+    fn cfi_entry_state(&self, _mode: Self::MachineMode) -> Option<DwarfCie> {
+        None
+    }
+
+    /// Expands an expression that needs to call into the runtime, such as [`XvaOpcode::TlsAddr`] with a dynamic [`TlsModel`] or a division on a machine without a divide instruction, into XVA statements.
+    /// Returns [`None`] if `expr` does not need to be expanded
+    fn expand_runtime_calls(&self, _expr: &XvaExpr, _mode: Self::MachineMode, _context: &CompilerContext, _frame: &XvaFrameProperties) -> Option<Vec<XvaStatement>> {
+        None
+    }
+
+    /// Whether the function with `frame` needs [`Self::emit_prologue`] to be called, such as when it is protected by [`CompilerContext::stack_protector`]
+    fn needs_prologue(&self, frame: &XvaFrameProperties, _mode: Self::MachineMode, context: &CompilerContext) -> bool {
+        frame.has_prologue || context.stack_protector.protects(frame)
+    }
+
+    /// Functions that are called by the code generated for `mode`, and that must be emitted into each file that uses them, such as the i386 get-PC thunk
+    fn support_functions(&self, _mode: Self::MachineMode, _context: &CompilerContext) -> Vec<XvaFunctionDef> {
+        Vec::new()
+    }
 
-    fn lower_epilogue(&self, frame: &XvaFrameProperties, mode: Self::MachineMode) -> Vec<XvaStatement>;
-    fn emit_prologue(&self, frame: &mut XvaFrameProperties, mode: Self::MachineMode) -> Vec<Instruction>;
+    /// Looks up the calling convention named `name`, such as `sysv64`, that is available in `mode`
+    fn calling_convention(&self, _name: &str, _mode: Self::MachineMode) -> Option<&'static dyn CallingConvention> {
+        None
+    }
+
+    /// The calling convention used by functions that do not specify one
+    fn default_calling_convention(&self, _context: &CompilerContext, _mode: Self::MachineMode) -> Option<&'static dyn CallingConvention> {
+        None
+    }
+
+    /// The relative cost of computing `op` into a register of type `ty` in `mode`, or [`None`] if [`Self::lower_mce`] cannot lower it
+    fn expr_cost(&self, op: &XvaOpcode, _ty: XvaType, _mode: Self::MachineMode) -> Option<u32> {
+        match op {
+            XvaOpcode::ZeroInit | XvaOpcode::Const(_) | XvaOpcode::Move(_) | XvaOpcode::BinaryOp { .. } | XvaOpcode::UnaryOp { .. } => Some(1),
+            XvaOpcode::UMul { .. } | XvaOpcode::SMul { .. } => Some(4),
+            XvaOpcode::UDiv { .. } | XvaOpcode::SDiv { .. } => Some(20),
+            _ => None,
+        }
+    }
+
+    /// The peephole rules applied to the machine code emitted by [`Self::lower_mce`] in `mode`
+    fn peephole_rules(&self, _mode: Self::MachineMode) -> &'static [PeepholeRule] {
+        &[]
+    }
+
+    /// The scheduling model of `instr`, emitted by [`Self::lower_mce`] in `mode`, or [`None`] if it must not be reordered
+    fn sched_info(&self, _instr: &Instruction, _mode: Self::MachineMode) -> Option<SchedInfo> {
+        None
+    }
 
     /// Helper function for implementing [`Self::lower_mce`]
     /// 
@@ -63,6 +117,81 @@ impl<C: CompilerSpec> CheckCompiler for C {
     }
 }
 
+/// How the prologue ensures that each page of a large stack frame is touched in order, so that guard pages are not skipped
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum StackProbeKind {
+    /// The probes are emitted inline, either unrolled or as a loop
+    Inline,
+    /// The named function is called to probe the stack. The convention used to call it is machine specific
+    Call(Symbol),
+    /// The stack is not probed
+    None,
+}
+
+/// Which functions have a stack protector canary inserted
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Default)]
+pub enum StackProtectorLevel {
+    #[default]
+    None,
+    /// Only functions with [`XvaFrameProperties::has_local_buffers`] set
+    Strong,
+    All,
+}
+
+impl StackProtectorLevel {
+    /// Whether or not the function with `frame` is protected at this level
+    pub fn protects(self, frame: &XvaFrameProperties) -> bool {
+        match self {
+            StackProtectorLevel::None => false,
+            StackProtectorLevel::Strong => frame.has_local_buffers,
+            StackProtectorLevel::All => true,
+        }
+    }
+}
+
+/// How the address of a thread-local variable is computed
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum TlsModel {
+    /// Calls the TLS runtime (`__tls_get_addr`) for each variable. Works for any variable from any module
+    GeneralDynamic,
+    /// Calls the TLS runtime once for the module's TLS block, and adds the offset of the variable within it. Only works for variables defined in the current module
+    LocalDynamic,
+    /// Loads the offset of the variable from the thread pointer from the GOT. Only works for modules loaded at startup
+    InitialExec,
+    /// Adds the link-time constant offset of the variable to the thread pointer. Only works for variables defined in the executable
+    LocalExec,
+}
+
+/// How symbols are addressed, which determines whether the generated code can be loaded at any address
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Default)]
+pub enum RelocationModel {
+    /// Position-dependent code, which references every symbol directly
+    #[default]
+    Static,
+    /// A position-independent executable. Local symbols are addressed relative to the code, and global symbols through the GOT, since they may be defined in a shared library.
+    /// Thread-local variables use the initial-exec or local-exec models
+    Pie,
+    /// Position-independent code for shared libraries. Global symbols may be preempted, so they are addressed through the GOT, and called through the PLT.
+    /// Thread-local variables use the general-dynamic or local-dynamic models
+    Pic,
+}
+
+impl RelocationModel {
+    /// Whether code generated with this model can be loaded at any address
+    pub fn is_pic(self) -> bool {
+        !matches!(self, RelocationModel::Static)
+    }
+}
+
+/// Where the stack protector loads the guard value from
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum StackGuard {
+    /// The value of a global symbol, typically `__stack_chk_guard`
+    Global(Symbol),
+    /// An offset from the base of a segment register, such as `fs:0x28` on x86-64 Linux
+    Segment(Register, i32),
+}
+
 pub struct CompilerContext {
     pub mode: MachineMode,
     pub properties: TargetInfo,
@@ -73,6 +202,12 @@ pub struct CompilerContext {
     pub local_address_kind: AddressKind,
     pub global_tls_kind: AddressKind,
     pub local_tls_kind: AddressKind,
+    /// Set with [`CompilerContext::set_relocation_model`], which also sets the address kinds of the context
+    pub relocation_model: RelocationModel,
+    pub stack_protector: StackProtectorLevel,
+    pub stack_guard: StackGuard,
+    /// Counter used by [`CompilerContext::local_label`]
+    pub label_counter: Cell<u32>,
 }
 
 impl CompilerContext {
@@ -84,6 +219,110 @@ impl CompilerContext {
             self.properties.properties.global_properties.get(st)
         }
     }
+
+    /// The stack probe mode, from the `stack-probes` property.
+    /// This is either `inline`, `none`, or `call:` followed by the name of the function to call. Defaults to [`StackProbeKind::None`]
+    pub fn stack_probes(&self) -> StackProbeKind {
+        match self.property("stack-probes") {
+            None | Some(PropertyValue::Bool(false)) => StackProbeKind::None,
+            Some(PropertyValue::Bool(true)) => StackProbeKind::Inline,
+            Some(PropertyValue::String(val)) => match &**val {
+                "inline" => StackProbeKind::Inline,
+                "none" => StackProbeKind::None,
+                val => match val.strip_prefix("call:") {
+                    Some(name) => StackProbeKind::Call(Symbol::intern(name)),
+                    None => panic!("Invalid value for stack-probes: {val}"),
+                },
+            },
+            Some(val) => panic!("Invalid value for stack-probes: {val:?}"),
+        }
+    }
+
+    /// The distance between stack probes, from the `stack-probe-size` property, which must be a power of two. Defaults to 4096
+    pub fn stack_probe_size(&self) -> usize {
+        match self.property("stack-probe-size") {
+            Some(&PropertyValue::Int(size)) if size > 0 && size.count_ones() == 1 => size as usize,
+            None => 4096,
+            Some(val) => panic!("Invalid value for stack-probe-size: {val:?}"),
+        }
+    }
+
+    /// The TLS model used to access a thread-local variable, which is `local` if it is defined in the current module.
+    ///
+    /// This is the `tls-model` property (`global-dynamic`, `local-dynamic`, `initial-exec`, or `local-exec`) if set,
+    /// and otherwise is chosen from [`Self::local_tls_kind`] or [`Self::global_tls_kind`].
+    /// The local-dynamic model is only used for local variables, and the general-dynamic model is used instead for other variables
+    pub fn tls_model(&self, local: bool) -> TlsModel {
+        let model = match self.property("tls-model") {
+            Some(PropertyValue::String(val)) => match &**val {
+                "global-dynamic" => TlsModel::GeneralDynamic,
+                "local-dynamic" => TlsModel::LocalDynamic,
+                "initial-exec" => TlsModel::InitialExec,
+                "local-exec" => TlsModel::LocalExec,
+                val => panic!("Invalid value for tls-model: {val}"),
+            },
+            None => match if local { self.local_tls_kind } else { self.global_tls_kind } {
+                AddressKind::Default | AddressKind::GotOff | AddressKind::Tpoff | AddressKind::NTpoff => TlsModel::LocalExec,
+                AddressKind::GotRel | AddressKind::GotAbs | AddressKind::GotTpoff | AddressKind::GotNTpoff | AddressKind::IndNTpoff => TlsModel::InitialExec,
+                AddressKind::DTpoff | AddressKind::LTlsDesc | AddressKind::TlsLd | AddressKind::TlsLdm => TlsModel::LocalDynamic,
+                AddressKind::Plt | AddressKind::TlsDesc | AddressKind::TlsGd => TlsModel::GeneralDynamic,
+            },
+            Some(val) => panic!("Invalid value for tls-model: {val:?}"),
+        };
+
+        match model {
+            TlsModel::LocalDynamic if !local => TlsModel::GeneralDynamic,
+            model => model,
+        }
+    }
+
+    /// Sets [`Self::relocation_model`], along with the address kinds used for symbols and thread-local variables under `model`
+    pub fn set_relocation_model(&mut self, model: RelocationModel) {
+        self.relocation_model = model;
+        self.local_address_kind = AddressKind::Default;
+        match model {
+            RelocationModel::Static => {
+                self.global_address_kind = AddressKind::Default;
+                self.global_call_address_kind = AddressKind::Default;
+                self.local_tls_kind = AddressKind::Tpoff;
+                self.global_tls_kind = AddressKind::Tpoff;
+            }
+            RelocationModel::Pie => {
+                self.global_address_kind = AddressKind::GotRel;
+                self.global_call_address_kind = AddressKind::Plt;
+                self.local_tls_kind = AddressKind::Tpoff;
+                self.global_tls_kind = AddressKind::GotTpoff;
+            }
+            RelocationModel::Pic => {
+                self.global_address_kind = AddressKind::GotRel;
+                self.global_call_address_kind = AddressKind::Plt;
+                self.local_tls_kind = AddressKind::TlsLd;
+                self.global_tls_kind = AddressKind::TlsGd;
+            }
+        }
+    }
+
+    /// The name of the default calling convention, from the `calling-convention` property
+    pub fn calling_convention_name(&self) -> Option<&str> {
+        match self.property("calling-convention") {
+            Some(PropertyValue::String(name)) => Some(&**name),
+            None => None,
+            Some(val) => panic!("Invalid value for calling-convention: {val:?}"),
+        }
+    }
+
+    /// The function called when the stack protector detects that the canary was overwritten
+    pub fn stack_chk_fail(&self) -> Symbol {
+        Symbol::intern("__stack_chk_fail")
+    }
+
+    /// Generates a fresh local label for use by machine code lowering, such as with [`XvaStatement::Label`]
+    pub fn local_label(&self, prefix: &str) -> Symbol {
+        let n = self.label_counter.get();
+        self.label_counter.set(n + 1);
+
+        Symbol::intern(&format!(".L{prefix}.{n}"))
+    }
 }
 
 pub trait Compiler {
@@ -105,7 +344,24 @@ pub trait Compiler {
 
     fn mce_lower(&self, xva: &mut XvaStatement, frame: &XvaFrameProperties, mode: &CompilerContext);
 
-    fn emit_prologue(&self, frame: &mut XvaFrameProperties, mode: MachineMode) -> Vec<Instruction>;
+    fn emit_prologue(&self, frame: &mut XvaFrameProperties, context: &CompilerContext) -> Vec<XvaStatement>;
+
+    fn cfi_entry_state(&self, mode: MachineMode) -> Option<DwarfCie>;
+
+    fn expand_runtime_calls(&self, expr: &XvaExpr, context: &CompilerContext, frame: &XvaFrameProperties) -> Option<Vec<XvaStatement>>;
+
+    fn needs_prologue(&self, frame: &XvaFrameProperties, context: &CompilerContext) -> bool;
+
+    fn support_functions(&self, context: &CompilerContext) -> Vec<XvaFunctionDef>;
+
+    /// Looks up the calling convention named `name`, or the one from [`CompilerContext::calling_convention_name`] (or the machine's default) if `name` is [`None`]
+    fn calling_convention(&self, context: &CompilerContext, name: Option<&str>) -> Option<&'static dyn CallingConvention>;
+
+    fn expr_cost(&self, op: &XvaOpcode, ty: XvaType, mode: MachineMode) -> Option<u32>;
+
+    fn peephole_rules(&self, mode: MachineMode) -> &'static [PeepholeRule];
+
+    fn sched_info(&self, instr: &Instruction, mode: MachineMode) -> Option<SchedInfo>;
 }
 
 impl<C: CompilerSpec> Compiler for C {
@@ -162,16 +418,19 @@ impl<C: CompilerSpec> Compiler for C {
 
             XvaStatement::Write(_, ty, _) => {
                 if ty.size > 0 {
-                    self.lower_mce(xva, mmode, context, &frame.features);
+                    self.lower_mce(xva, mmode, context, frame);
                 }
             }
             
             XvaStatement::Expr(expr) => {
                 if expr.dest.size(self.machine(), mode) > 0 {
-                    self.lower_mce(xva, mmode, context, &frame.features);
+                    self.lower_mce(xva, mmode, context, frame);
                 }
             }
             XvaStatement::RawInstr(_) |
+            XvaStatement::Label(_) |
+            XvaStatement::Loc(_) |
+            XvaStatement::Cfi(_) |
             XvaStatement::OptGate(_, _) |
              XvaStatement::Use(_, _) |
             XvaStatement::EndOptGate(_) => {},
@@ -180,29 +439,444 @@ impl<C: CompilerSpec> Compiler for C {
             },
 
             XvaStatement::Return | XvaStatement::Tailcall { .. } => {
-                let mut stmts = if frame.has_prologue { 
-                    self.lower_epilogue(frame, mmode) 
+                self.lower_mce(xva, mmode, context, frame);
+
+                // The target of a tail call may be loaded while the frame still exists, so the epilogue goes right before the final instruction
+                let mut stmts = match core::mem::take(xva) {
+                    XvaStatement::Elaborated(stmts) => stmts,
+                    stmt => vec![stmt],
+                };
+                let exit = stmts.pop();
+
+                let epilogue = if frame.has_prologue {
+                    self.lower_epilogue(frame, mmode, context)
                 } else {
                     Vec::new()
                 };
 
-                self.lower_mce(xva, mmode, context, &frame.features);
+                // The epilogue only unwinds the frame for this exit, so code after it uses the rules from before it
+                let restore_state = !epilogue.is_empty();
+                if restore_state {
+                    stmts.push(XvaStatement::Cfi(XvaCfi::RememberState));
+                }
+                stmts.extend(epilogue);
+                stmts.extend(exit);
 
-                stmts.push(core::mem::take(xva));
+                if restore_state {
+                    stmts.push(XvaStatement::Cfi(XvaCfi::RestoreState));
+                }
 
                 *xva = XvaStatement::Elaborated(stmts);
             }
 
-            
+            XvaStatement::InlineAsm(asm) => {
+                let stmts = lower_inline_asm(self, asm, mmode, context, frame);
+                *xva = XvaStatement::Elaborated(stmts);
+            }
 
             stmt => {
-                self.lower_mce(stmt, mmode, context, &frame.features);
+                self.lower_mce(stmt, mmode, context, frame);
             }
         }
     }
 
-    fn emit_prologue(&self, frame: &mut XvaFrameProperties, mode: MachineMode) -> Vec<Instruction> {
+    fn emit_prologue(&self, frame: &mut XvaFrameProperties, context: &CompilerContext) -> Vec<XvaStatement> {
+        let mmode = context.mode.downcast::<<C as MachineSpec>::MachineMode>().unwrap();
+        <Self as CompilerSpec>::emit_prologue(self, frame, mmode, context)
+    }
+
+    fn cfi_entry_state(&self, mode: MachineMode) -> Option<DwarfCie> {
+        let mmode = mode.downcast::<<C as MachineSpec>::MachineMode>().unwrap();
+        <Self as CompilerSpec>::cfi_entry_state(self, mmode)
+    }
+
+    fn expand_runtime_calls(&self, expr: &XvaExpr, context: &CompilerContext, frame: &XvaFrameProperties) -> Option<Vec<XvaStatement>> {
+        let mmode = context.mode.downcast::<<C as MachineSpec>::MachineMode>().unwrap();
+        <Self as CompilerSpec>::expand_runtime_calls(self, expr, mmode, context, frame)
+    }
+
+    fn needs_prologue(&self, frame: &XvaFrameProperties, context: &CompilerContext) -> bool {
+        let mmode = context.mode.downcast::<<C as MachineSpec>::MachineMode>().unwrap();
+        <Self as CompilerSpec>::needs_prologue(self, frame, mmode, context)
+    }
+
+    fn support_functions(&self, context: &CompilerContext) -> Vec<XvaFunctionDef> {
+        let mmode = context.mode.downcast::<<C as MachineSpec>::MachineMode>().unwrap();
+        <Self as CompilerSpec>::support_functions(self, mmode, context)
+    }
+
+    fn calling_convention(&self, context: &CompilerContext, name: Option<&str>) -> Option<&'static dyn CallingConvention> {
+        let mode = context.mode.downcast::<<C as MachineSpec>::MachineMode>().unwrap();
+        match name.or(context.calling_convention_name()) {
+            Some(name) => CompilerSpec::calling_convention(self, name, mode),
+            None => CompilerSpec::default_calling_convention(self, context, mode),
+        }
+    }
+
+    fn expr_cost(&self, op: &XvaOpcode, ty: XvaType, mode: MachineMode) -> Option<u32> {
+        let mmode = mode.downcast::<<C as MachineSpec>::MachineMode>().unwrap();
+        <Self as CompilerSpec>::expr_cost(self, op, ty, mmode)
+    }
+
+    fn peephole_rules(&self, mode: MachineMode) -> &'static [PeepholeRule] {
         let mmode = mode.downcast::<<C as MachineSpec>::MachineMode>().unwrap();
-        <Self as CompilerSpec>::emit_prologue(self, frame, mmode)
+        <Self as CompilerSpec>::peephole_rules(self, mmode)
+    }
+
+    fn sched_info(&self, instr: &Instruction, mode: MachineMode) -> Option<SchedInfo> {
+        let mmode = mode.downcast::<<C as MachineSpec>::MachineMode>().unwrap();
+        <Self as CompilerSpec>::sched_info(self, instr, mmode)
+    }
+}
+
+/// Orders the moves `dest <- src` so that each source is read before any move overwrites it, as if they were all done at once.
+///
+/// Cycles of moves are broken by swapping registers with three xors, so they must be between general purpose registers
+pub(crate) fn parallel_copy(mut moves: Vec<(Register, XvaRegister)>, mach: &dyn Machine, mode: MachineMode) -> Vec<XvaExpr> {
+    let overlaps = |a: Register, b: XvaRegister| match b {
+        XvaRegister::Physical(b) => a == b || mach.registers().register_overlaps(a, b),
+        XvaRegister::Virtual(_) => false,
+    };
+    let xor = |dest: Register, src: Register| XvaExpr {
+        dest: XvaRegister::Physical(dest),
+        dest2: None,
+        op: XvaOpcode::BinaryOp { op: BinaryOp::Xor, left: XvaRegister::Physical(dest), right: XvaOperand::Register(XvaRegister::Physical(src)) },
+    };
+
+    moves.retain(|&(dest, src)| src != XvaRegister::Physical(dest));
+    let mut exprs = Vec::with_capacity(moves.len());
+    while !moves.is_empty() {
+        let ready = (0..moves.len()).find(|&n| moves.iter().enumerate().all(|(m, &(_, src))| m == n || !overlaps(moves[n].0, src)));
+        if let Some(n) = ready {
+            let (dest, src) = moves.remove(n);
+            exprs.push(XvaExpr { dest: XvaRegister::Physical(dest), dest2: None, op: XvaOpcode::Move(src) });
+            continue;
+        }
+
+        // Every remaining destination is the source of another move, so the first move is part of a cycle.
+        // Swapping its registers completes it, and leaves the old value of `dest` in `src`
+        let (dest, src) = moves.remove(0);
+        let XvaRegister::Physical(src) = src else {
+            unreachable!()
+        };
+        assert!(
+            [dest, src].iter().all(|&reg| XvaRegister::Physical(reg).ty(mach, mode).category == XvaCategory::Int),
+            "Cannot swap registers that are not general purpose in a parallel copy"
+        );
+        exprs.extend([xor(dest, src), xor(src, dest), xor(dest, src)]);
+        for (_, other) in &mut moves {
+            if *other == XvaRegister::Physical(dest) {
+                *other = XvaRegister::Physical(src);
+            }
+        }
+        moves.retain(|&(dest, src)| src != XvaRegister::Physical(dest));
+    }
+    exprs
+}
+
+/// Lowers an inline assembly statement into the template instructions, with each placeholder replaced by the register assigned to the operand.
+///
+/// Operands constrained to a specific register that were allocated elsewhere are moved into (or out of) that register around the template.
+/// Tied inputs use the register of their output
+fn lower_inline_asm<C: CompilerSpec>(
+    compiler: &C,
+    asm: &XvaInlineAsm,
+    mode: C::MachineMode,
+    context: &CompilerContext,
+    frame: &XvaFrameProperties,
+) -> Vec<XvaStatement> {
+    let mut moves = Vec::new();
+    let mut post = Vec::new();
+    let mut locations = Vec::with_capacity(asm.outputs.len() + asm.inputs.len());
+
+    for output in &asm.outputs {
+        let loc = match output.constraint {
+            XvaAsmConstraint::Register(preg) => {
+                let XvaRegister::Physical(reg) = output.reg else {
+                    panic!("Encountered virtual register")
+                };
+                post.push((reg, XvaRegister::Physical(preg)));
+                preg
+            }
+            XvaAsmConstraint::Class(_) => {
+                let XvaRegister::Physical(reg) = output.reg else {
+                    panic!("Encountered virtual register")
+                };
+                reg
+            }
+            XvaAsmConstraint::Tied(_) => panic!("Tied constraint on an inline assembly output"),
+        };
+        locations.push(loc);
+    }
+
+    for input in &asm.inputs {
+        let loc = match input.constraint {
+            XvaAsmConstraint::Register(preg) => preg,
+            XvaAsmConstraint::Tied(n) => *locations
+                .get(n as usize)
+                .expect("Tied constraint refers to a nonexistant output"),
+            XvaAsmConstraint::Class(_) => {
+                let XvaRegister::Physical(reg) = input.reg else {
+                    panic!("Encountered virtual register")
+                };
+                reg
+            }
+        };
+
+        moves.push((loc, input.reg));
+        locations.push(loc);
+    }
+
+    let pre = parallel_copy(moves, compiler.machine(), context.mode);
+    // The outputs are read from their constrained registers after the template, which may also be where other outputs were allocated
+    let post = parallel_copy(post, compiler.machine(), context.mode);
+    let mut stmts = Vec::with_capacity(pre.len() + asm.template.len() + post.len());
+
+    for expr in pre {
+        let mut stmt = XvaStatement::Expr(expr);
+        compiler.lower_mce(&mut stmt, mode, context, frame);
+        stmts.push(stmt);
+    }
+
+    for instr in &asm.template {
+        let mut instr = instr.clone();
+        for opr in instr.operands_mut() {
+            if let Operand::Placeholder(n) = *opr {
+                let reg = *locations
+                    .get(n as usize)
+                    .expect("Placeholder refers to a nonexistant operand");
+                *opr = Operand::Register(reg);
+            }
+        }
+        stmts.push(XvaStatement::RawInstr(instr));
+    }
+
+    for expr in post {
+        let mut stmt = XvaStatement::Expr(expr);
+        compiler.lower_mce(&mut stmt, mode, context, frame);
+        stmts.push(stmt);
+    }
+
+    stmts
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode, X86Opcode, X86Register},
+        instr::Instruction,
+        mach::{Opcode, Regset},
+        traits::IntoId,
+        xva::XvaAsmOperand,
+    };
+
+    fn context(mode: X86Mode) -> CompilerContext {
+        let properties = TargetProperties { global_properties: HashMap::new() };
+        CompilerContext {
+            mode: mode.into_id(),
+            properties: TargetInfo { properties: properties.clone(), ptr_width: 64 },
+            property_overrides: properties,
+            target_features: HashSet::new(),
+            global_address_kind: AddressKind::Default,
+            global_call_address_kind: AddressKind::Default,
+            local_address_kind: AddressKind::Default,
+            global_tls_kind: AddressKind::Default,
+            local_tls_kind: AddressKind::Default,
+            relocation_model: RelocationModel::Static,
+            stack_protector: StackProtectorLevel::None,
+            stack_guard: StackGuard::Global(Symbol::intern("__stack_chk_guard")),
+            label_counter: Cell::new(0),
+        }
+    }
+
+    fn reg(reg: X86Register) -> XvaRegister {
+        XvaRegister::Physical(Register::new(reg))
+    }
+
+    fn operand(constraint: XvaAsmConstraint, reg: XvaRegister) -> XvaAsmOperand {
+        XvaAsmOperand { constraint, reg }
+    }
+
+    fn instr(op: X86Opcode, oprs: Vec<Operand>) -> Instruction {
+        Instruction::new(Opcode::new(op), oprs)
+    }
+
+    fn mov(dest: X86Register, src: X86Register) -> XvaStatement {
+        XvaStatement::RawInstr(instr(X86Opcode::Mov, vec![Operand::Register(Register::new(dest)), Operand::Register(Register::new(src))]))
+    }
+
+    const RAX: X86Register = crate::x86_register!(rax);
+    const RCX: X86Register = crate::x86_register!(rcx);
+    const RDX: X86Register = crate::x86_register!(rdx);
+    const RBX: X86Register = crate::x86_register!(rbx);
+    const RSI: X86Register = crate::x86_register!(rsi);
+    const RDI: X86Register = crate::x86_register!(rdi);
+
+    #[test]
+    fn inline_asm_moves_operands_into_their_registers() {
+        // add %0, %1; add %0, %2, with %0 in rax, %1 in rcx, %2 in any register, and %3 tied to %0
+        let asm = XvaInlineAsm {
+            template: vec![
+                instr(X86Opcode::Add, vec![Operand::Placeholder(0), Operand::Placeholder(1)]),
+                instr(X86Opcode::Add, vec![Operand::Placeholder(0), Operand::Placeholder(2)]),
+            ],
+            outputs: vec![operand(XvaAsmConstraint::Register(Register::new(RAX)), reg(RBX))],
+            inputs: vec![
+                operand(XvaAsmConstraint::Register(Register::new(RCX)), reg(RDX)),
+                operand(XvaAsmConstraint::Class(XvaCategory::Int), reg(RSI)),
+                operand(XvaAsmConstraint::Tied(0), reg(RDI)),
+            ],
+            clobbers: Regset::new(),
+        };
+
+        let stmts = lower_inline_asm(&X86, &asm, X86Mode::Long, &context(X86Mode::Long), &XvaFrameProperties::new());
+        let add = |src| XvaStatement::RawInstr(instr(X86Opcode::Add, vec![Operand::Register(Register::new(RAX)), Operand::Register(Register::new(src))]));
+        assert_eq!(stmts, [mov(RCX, RDX), mov(RAX, RDI), add(RCX), add(RSI), mov(RBX, RAX)]);
+    }
+
+    #[test]
+    fn inline_asm_keeps_operands_already_in_place() {
+        let asm = XvaInlineAsm {
+            template: vec![instr(X86Opcode::Mov, vec![Operand::Placeholder(0), Operand::Placeholder(1)])],
+            outputs: vec![operand(XvaAsmConstraint::Register(Register::new(RAX)), reg(RAX))],
+            inputs: vec![operand(XvaAsmConstraint::Register(Register::new(RCX)), reg(RCX))],
+            clobbers: Regset::new(),
+        };
+
+        let stmts = lower_inline_asm(&X86, &asm, X86Mode::Long, &context(X86Mode::Long), &XvaFrameProperties::new());
+        assert_eq!(stmts, [mov(RAX, RCX)]);
+    }
+
+    #[test]
+    fn inline_asm_orders_input_moves_as_a_parallel_copy() {
+        // The inputs swap rax and rcx, and rdx takes the old value of rax
+        let asm = XvaInlineAsm {
+            template: Vec::new(),
+            outputs: Vec::new(),
+            inputs: vec![
+                operand(XvaAsmConstraint::Register(Register::new(RAX)), reg(RCX)),
+                operand(XvaAsmConstraint::Register(Register::new(RDX)), reg(RAX)),
+                operand(XvaAsmConstraint::Register(Register::new(RCX)), reg(RAX)),
+            ],
+            clobbers: Regset::new(),
+        };
+
+        let stmts = lower_inline_asm(&X86, &asm, X86Mode::Long, &context(X86Mode::Long), &XvaFrameProperties::new());
+        let xor = |dest, src| XvaStatement::RawInstr(instr(X86Opcode::Xor, vec![Operand::Register(Register::new(dest)), Operand::Register(Register::new(src))]));
+        assert_eq!(stmts, [mov(RDX, RAX), xor(RAX, RCX), xor(RCX, RAX), xor(RAX, RCX)]);
+    }
+
+    #[test]
+    fn inline_asm_orders_output_moves_as_a_parallel_copy() {
+        // %0 is in rax and allocated to rcx, and %1 is in rcx and allocated to rdx
+        let asm = XvaInlineAsm {
+            template: Vec::new(),
+            outputs: vec![
+                operand(XvaAsmConstraint::Register(Register::new(RAX)), reg(RCX)),
+                operand(XvaAsmConstraint::Register(Register::new(RCX)), reg(RDX)),
+            ],
+            inputs: Vec::new(),
+            clobbers: Regset::new(),
+        };
+
+        let stmts = lower_inline_asm(&X86, &asm, X86Mode::Long, &context(X86Mode::Long), &XvaFrameProperties::new());
+        assert_eq!(stmts, [mov(RDX, RCX), mov(RCX, RAX)]);
+    }
+
+    #[test]
+    #[should_panic = "Cannot swap registers that are not general purpose in a parallel copy"]
+    fn parallel_copy_does_not_swap_vector_registers() {
+        let [xmm0, xmm1] = [crate::x86_register!(xmm0), crate::x86_register!(xmm1)];
+        parallel_copy(vec![(Register::new(xmm0), reg(xmm1)), (Register::new(xmm1), reg(xmm0))], &X86, X86Mode::Long.into_id());
+    }
+
+    #[test]
+    fn epilogue_remembers_cfi_state() {
+        let frame = XvaFrameProperties { has_prologue: true, use_frame_pointer: true, ..XvaFrameProperties::new() };
+        let rsp = Register::new(crate::x86_register!(rsp));
+        let rbp = Register::new(crate::x86_register!(rbp));
+
+        let mut stmt = XvaStatement::Return;
+        Compiler::mce_lower(&X86, &mut stmt, &frame, &context(X86Mode::Long));
+        assert_eq!(stmt, XvaStatement::Elaborated(vec![
+            XvaStatement::Cfi(XvaCfi::RememberState),
+            XvaStatement::RawInstr(instr(X86Opcode::Mov, vec![Operand::Register(rsp), Operand::Register(rbp)])),
+            XvaStatement::RawInstr(instr(X86Opcode::Pop, vec![Operand::Register(rbp)])),
+            XvaStatement::Cfi(XvaCfi::DefCfa(rsp, 8)),
+            XvaStatement::Cfi(XvaCfi::Restore(rbp)),
+            XvaStatement::RawInstr(Instruction::new_nullary(X86Opcode::Ret)),
+            XvaStatement::Cfi(XvaCfi::RestoreState),
+        ]));
+
+        // Without a prologue there is nothing to unwind
+        let mut stmt = XvaStatement::Return;
+        Compiler::mce_lower(&X86, &mut stmt, &XvaFrameProperties::new(), &context(X86Mode::Long));
+        assert_eq!(stmt, XvaStatement::Elaborated(vec![XvaStatement::RawInstr(Instruction::new_nullary(X86Opcode::Ret))]));
+    }
+
+    fn with_property(key: &str, val: PropertyValue) -> CompilerContext {
+        let mut context = context(X86Mode::Long);
+        context.property_overrides.global_properties.insert(Symbol::intern(key), val);
+        context
+    }
+
+    #[test]
+    fn stack_probe_properties() {
+        assert_eq!(context(X86Mode::Long).stack_probes(), StackProbeKind::None);
+        assert_eq!(with_property("stack-probes", PropertyValue::Bool(true)).stack_probes(), StackProbeKind::Inline);
+        assert_eq!(with_property("stack-probes", PropertyValue::Bool(false)).stack_probes(), StackProbeKind::None);
+        assert_eq!(with_property("stack-probes", PropertyValue::String(Symbol::intern("inline"))).stack_probes(), StackProbeKind::Inline);
+        assert_eq!(with_property("stack-probes", PropertyValue::String(Symbol::intern("call:__probestack"))).stack_probes(), StackProbeKind::Call(Symbol::intern("__probestack")));
+
+        assert_eq!(context(X86Mode::Long).stack_probe_size(), 4096);
+        assert_eq!(with_property("stack-probe-size", PropertyValue::Int(65536)).stack_probe_size(), 65536);
+    }
+
+    #[test]
+    #[should_panic = "Invalid value for stack-probe-size: Int(0)"]
+    fn rejects_zero_stack_probe_size() {
+        with_property("stack-probe-size", PropertyValue::Int(0)).stack_probe_size();
+    }
+
+    #[test]
+    #[should_panic = "Invalid value for stack-probe-size: Int(3000)"]
+    fn rejects_stack_probe_size_not_a_power_of_two() {
+        with_property("stack-probe-size", PropertyValue::Int(3000)).stack_probe_size();
+    }
+
+    #[test]
+    #[should_panic = "Invalid value for stack-probes: probe"]
+    fn rejects_unknown_stack_probe_kind() {
+        with_property("stack-probes", PropertyValue::String(Symbol::intern("probe"))).stack_probes();
+    }
+
+    #[test]
+    fn stack_protector_levels() {
+        let plain = XvaFrameProperties::new();
+        let buffers = XvaFrameProperties { has_local_buffers: true, ..XvaFrameProperties::new() };
+        assert!(!StackProtectorLevel::None.protects(&buffers));
+        assert!(!StackProtectorLevel::Strong.protects(&plain));
+        assert!(StackProtectorLevel::Strong.protects(&buffers));
+        assert!(StackProtectorLevel::All.protects(&plain));
+    }
+
+    #[test]
+    fn relocation_model_sets_address_kinds() {
+        let mut context = context(X86Mode::Long);
+        context.set_relocation_model(RelocationModel::Pie);
+        assert!(context.relocation_model.is_pic());
+        assert_eq!((context.local_address_kind, context.global_address_kind, context.global_call_address_kind), (AddressKind::Default, AddressKind::GotRel, AddressKind::Plt));
+        assert_eq!((context.tls_model(true), context.tls_model(false)), (TlsModel::LocalExec, TlsModel::InitialExec));
+
+        context.set_relocation_model(RelocationModel::Pic);
+        assert_eq!((context.tls_model(true), context.tls_model(false)), (TlsModel::LocalDynamic, TlsModel::GeneralDynamic));
+
+        context.set_relocation_model(RelocationModel::Static);
+        assert!(!context.relocation_model.is_pic());
+        assert_eq!((context.global_address_kind, context.global_call_address_kind), (AddressKind::Default, AddressKind::Default));
+        assert_eq!((context.tls_model(true), context.tls_model(false)), (TlsModel::LocalExec, TlsModel::LocalExec));
     }
 }
@@ -0,0 +1,282 @@
+//! Calling conventions, which assign the locations of arguments and return values and compute the register sets of [`XvaFunction`] and [`XvaStatement::Call`]
+use crate::{
+    mach::{FeatureSet, Machine, Register, Regset}, xva::{XvaCategory, XvaFrameProperties, XvaFunction, XvaOperand, XvaStatement, XvaType}
+};
+
+/// A scalar field of an aggregate, used to classify aggregates for calling conventions
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct AggregateField {
+    /// The offset of the field from the start of the aggregate
+    pub offset: u64,
+    pub ty: XvaType,
+}
+
+/// A parameter or return value of a [`CallSignature`]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct CallArg {
+    pub ty: XvaType,
+    /// For [`XvaCategory::Aggregate`], the scalar fields of the aggregate in order, with nested aggregates and arrays flattened.
+    /// This is empty for other categories
+    pub fields: Vec<AggregateField>,
+}
+
+impl CallArg {
+    /// An aggregate with the given size, alignment, and fields
+    pub fn aggregate(size: u64, align: u64, fields: Vec<AggregateField>) -> Self {
+        Self { ty: XvaType { size, align, category: XvaCategory::Aggregate }, fields }
+    }
+
+    /// Whether at least one field of the aggregate overlaps `range`, and every such field is a floating-point or vector field.
+    /// Padding does not count as a non-floating-point field
+    pub fn is_fp_range(&self, range: core::ops::Range<u64>) -> bool {
+        let mut overlapping = self.fields.iter().filter(|field| field.offset < range.end && field.offset + field.ty.size > range.start).peekable();
+        overlapping.peek().is_some() && overlapping.all(|field| is_fp_category(field.ty.category))
+    }
+
+    /// Whether any field of the aggregate is not naturally aligned, such as in a packed struct
+    pub fn is_packed(&self) -> bool {
+        self.fields.iter().any(|field| field.ty.align != 0 && field.offset % field.ty.align != 0)
+    }
+}
+
+impl From<XvaType> for CallArg {
+    fn from(ty: XvaType) -> Self {
+        Self { ty, fields: Vec::new() }
+    }
+}
+
+/// The signature of a function, used to compute a [`CallLayout`]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct CallSignature {
+    /// The parameters. For variadic functions, this includes the variadic arguments at a particular call site
+    pub params: Vec<CallArg>,
+    /// The return value, or [`None`] if the function does not return a value
+    pub ret: Option<CallArg>,
+    /// The number of fixed parameters if the function is variadic
+    pub fixed_params: Option<usize>,
+}
+
+impl CallSignature {
+    pub fn new(params: Vec<CallArg>, ret: Option<CallArg>) -> Self {
+        Self { params, ret, fixed_params: None }
+    }
+
+    /// Whether the parameter numbered `n` is a variadic argument
+    pub fn is_variadic_param(&self, n: usize) -> bool {
+        self.fixed_params.is_some_and(|fixed| n >= fixed)
+    }
+}
+
+/// Where a single argument or return value is passed
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ArgLocation {
+    /// In registers, starting from the lowest bytes of the value
+    Registers(Vec<Register>),
+    /// On the stack, at the offset from the stack pointer at the call instruction
+    Stack(u64),
+    /// A pointer to a copy of the value made by the caller, which is itself passed at the inner location.
+    /// For return values, this is the hidden pointer to the memory the callee stores the value to
+    Indirect(Box<ArgLocation>),
+}
+
+impl ArgLocation {
+    /// The registers used by this location, which are empty for [`ArgLocation::Stack`]
+    pub fn registers(&self) -> &[Register] {
+        match self {
+            ArgLocation::Registers(regs) => regs,
+            ArgLocation::Stack(_) => &[],
+            ArgLocation::Indirect(loc) => loc.registers(),
+        }
+    }
+}
+
+/// The locations of the arguments and return value of a [`CallSignature`] under a particular [`CallingConvention`]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CallLayout {
+    pub params: Vec<ArgLocation>,
+    pub ret: Option<ArgLocation>,
+    /// The size of the stack argument area, including any space reserved for the callee
+    pub stack_size: u64,
+    /// The number of bytes of the stack argument area that the callee pops on return
+    pub callee_pop: u64,
+    pub param_regs: Regset,
+    pub return_regs: Regset,
+    pub preserve_regs: Regset,
+    pub clobber_regs: Regset,
+}
+
+impl CallLayout {
+    /// Creates an empty layout with the register sets of the convention. Used by [`CallingConvention`] implementations
+    pub fn new(preserve_regs: Regset, clobber_regs: Regset) -> Self {
+        Self { params: Vec::new(), ret: None, stack_size: 0, callee_pop: 0, param_regs: Regset::new(), return_regs: Regset::new(), preserve_regs, clobber_regs }
+    }
+
+    /// Adds a parameter, adding any registers to [`Self::param_regs`]
+    pub fn push_param(&mut self, loc: ArgLocation, mach: &dyn Machine) {
+        self.param_regs.insert_regids(loc.registers().iter().copied(), mach);
+        self.params.push(loc);
+    }
+
+    /// Sets the return value location, adding any registers to [`Self::return_regs`].
+    /// The registers of the hidden pointer of an [`ArgLocation::Indirect`] return value are added to [`Self::param_regs`] instead
+    pub fn set_ret(&mut self, loc: ArgLocation, mach: &dyn Machine) {
+        match &loc {
+            ArgLocation::Indirect(ptr) => self.param_regs.insert_regids(ptr.registers().iter().copied(), mach),
+            loc => self.return_regs.insert_regids(loc.registers().iter().copied(), mach),
+        }
+        self.ret = Some(loc);
+    }
+
+    /// Whether the return value is passed in memory, through a hidden pointer argument
+    pub fn is_indirect_ret(&self) -> bool {
+        matches!(self.ret, Some(ArgLocation::Indirect(_)))
+    }
+
+    /// Sets the register sets, incoming stack argument size, number of bytes popped on return, and return kind of `func` to the ones of this layout
+    pub fn apply_to(&self, func: &mut XvaFunction) {
+        func.params = self.param_regs;
+        func.return_regs = self.return_regs;
+        func.preserve_regs = self.preserve_regs;
+        func.clobber_regs = self.clobber_regs;
+        func.frame_properties.incoming_args_size = self.stack_size as usize;
+        func.frame_properties.callee_pop = self.callee_pop as usize;
+        func.frame_properties.indirect_ret = self.is_indirect_ret();
+    }
+
+    /// A [`XvaStatement::Call`] to `dest` using this layout, reserving the stack arguments of the call in the outgoing argument area of `frame`.
+    /// Stack arguments are stored before the call with [`XvaStatement::Write`] to [`XvaOperand::OutgoingArg`]
+    pub fn call(&self, dest: XvaOperand, frame: &mut XvaFrameProperties) -> XvaStatement {
+        frame.reserve_outgoing_args(self.stack_size as usize);
+        XvaStatement::Call { dest, params: self.param_regs, ret_val: self.return_regs, call_clobber_regs: self.clobber_regs, callee_pop: self.callee_pop as usize, indirect_ret: self.is_indirect_ret() }
+    }
+
+    /// A [`XvaStatement::Tailcall`] to `dest` using this layout.
+    /// Any stack arguments are written to the caller's incoming argument area with [`XvaOperand::IncomingArg`], so it must be at least [`Self::stack_size`] bytes
+    pub fn tailcall(&self, dest: XvaOperand) -> XvaStatement {
+        XvaStatement::Tailcall { dest, params: self.param_regs }
+    }
+}
+
+/// A machine specific calling convention
+pub trait CallingConvention {
+    /// The name of the convention, as accepted by [`CompilerSpec::calling_convention`][super::CompilerSpec::calling_convention]
+    fn name(&self) -> &'static str;
+
+    /// Assigns the locations of the parameters and return value of `sig`
+    fn layout(&self, sig: &CallSignature, features: &FeatureSet) -> CallLayout;
+}
+
+/// Helper for [`CallingConvention`] implementations that assigns stack argument slots in order
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct StackArgs {
+    offset: u64,
+    slot_size: u64,
+}
+
+impl StackArgs {
+    /// Starts assigning slots at `offset`, with each argument taking up a multiple of `slot_size` bytes
+    pub const fn new(offset: u64, slot_size: u64) -> Self {
+        Self { offset, slot_size }
+    }
+
+    /// Assigns the next slot for a value of type `ty`
+    pub fn alloc(&mut self, ty: &XvaType) -> ArgLocation {
+        let align = ty.align.max(self.slot_size);
+        let size = ty.size.next_multiple_of(self.slot_size);
+        self.offset = self.offset.next_multiple_of(align);
+        let loc = ArgLocation::Stack(self.offset);
+        self.offset += size;
+        loc
+    }
+
+    /// The size of the stack argument area, rounded up to `align`
+    pub fn size(&self, align: u64) -> u64 {
+        self.offset.next_multiple_of(align)
+    }
+}
+
+/// Whether values of category `cat` are passed in vector or floating-point registers by most conventions
+pub fn is_fp_category(cat: XvaCategory) -> bool {
+    matches!(cat, XvaCategory::Float | XvaCategory::VectorAny | XvaCategory::VectorInt | XvaCategory::VectorFloat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stack_args_are_aligned() {
+        let byte = XvaType { size: 1, align: 1, category: XvaCategory::Int };
+        let quad = XvaType { size: 16, align: 16, category: XvaCategory::Int };
+
+        let mut stack = StackArgs::new(4, 4);
+        assert_eq!(stack.alloc(&byte), ArgLocation::Stack(4));
+        // Each slot is rounded up to the slot size
+        assert_eq!(stack.alloc(&byte), ArgLocation::Stack(8));
+        assert_eq!(stack.alloc(&quad), ArgLocation::Stack(16));
+        assert_eq!(stack.size(4), 32);
+        assert_eq!(stack.alloc(&byte), ArgLocation::Stack(32));
+        assert_eq!(stack.size(16), 48);
+    }
+
+    #[test]
+    #[cfg(feature = "x86")]
+    fn indirect_locations_use_their_pointer_registers() {
+        use crate::traits::IdType;
+
+        assert!(ArgLocation::Stack(8).registers().is_empty());
+        assert!(ArgLocation::Indirect(Box::new(ArgLocation::Stack(8))).registers().is_empty());
+
+        let regs = vec![Register::new(crate::x86_register!(rcx))];
+        assert_eq!(ArgLocation::Indirect(Box::new(ArgLocation::Registers(regs.clone()))).registers(), regs);
+    }
+
+    #[test]
+    fn variadic_params() {
+        let int = XvaType { size: 4, align: 4, category: XvaCategory::Int };
+        let fixed = CallSignature::new(vec![int.into(), int.into()], None);
+        assert!(!fixed.is_variadic_param(1));
+
+        let variadic = CallSignature { fixed_params: Some(1), ..fixed };
+        assert!(!variadic.is_variadic_param(0));
+        assert!(variadic.is_variadic_param(1));
+    }
+
+    #[test]
+    fn calls_reserve_outgoing_args() {
+        let mut layout = CallLayout::new(Regset::new(), Regset::new());
+        layout.stack_size = 24;
+
+        let mut frame = XvaFrameProperties::new();
+        frame.reserve_outgoing_args(32);
+        layout.call(XvaOperand::Const(crate::xva::XvaConst::Bits(0)), &mut frame);
+        assert_eq!(frame.outgoing_args_size, 32);
+
+        let mut frame = XvaFrameProperties::new();
+        layout.call(XvaOperand::Const(crate::xva::XvaConst::Bits(0)), &mut frame);
+        assert_eq!(frame.outgoing_args_size, 24);
+    }
+
+    #[test]
+    fn aggregate_ranges() {
+        let float = XvaType { size: 4, align: 4, category: XvaCategory::Float };
+        let int = XvaType { size: 4, align: 4, category: XvaCategory::Int };
+        // struct { float a; int b; float c; }, followed by 4 bytes of padding
+        let arg = CallArg::aggregate(16, 4, vec![
+            AggregateField { offset: 0, ty: float },
+            AggregateField { offset: 4, ty: int },
+            AggregateField { offset: 8, ty: float },
+        ]);
+
+        assert!(arg.is_fp_range(0..4));
+        assert!(!arg.is_fp_range(0..8));
+        assert!(arg.is_fp_range(8..16));
+        // A range of padding has no floating-point fields either
+        assert!(!arg.is_fp_range(12..16));
+        assert!(!arg.is_packed());
+
+        let packed = CallArg::aggregate(5, 1, vec![AggregateField { offset: 1, ty: int }]);
+        assert!(packed.is_packed());
+    }
+}
@@ -38,6 +38,12 @@ impl Instruction {
     pub const fn new_nullary<O: const IntoId<Opcode>>(op: O) -> Self {
         Self::new(op, vec![])
     }
+    /// Adds `prefixes` to the instruction, which are encoded before it in order
+    pub fn with_prefixes(mut self, prefixes: impl IntoIterator<Item = Opcode>) -> Self {
+        self.prefixes.extend(prefixes);
+        self
+    }
+
     pub fn mode_override(&self) -> Option<MachineMode> {
         self.mode_override
     }
@@ -53,6 +59,10 @@ impl Instruction {
     pub fn operands(&self) -> &[Operand] {
         &self.operands
     }
+
+    pub fn operands_mut(&mut self) -> &mut [Operand] {
+        &mut self.operands
+    }
 }
 
 impl<'a> core::fmt::Display for PrettyPrinter<'a, Instruction> {
@@ -86,6 +96,8 @@ pub enum Operand {
     AbsSymbol(RelocSym, Option<NonZeroI64>),
     RelSymbol(RelocSym, Option<NonZeroI64>),
     Memory(MemoryOperand),
+    /// Refers to an operand of an inline assembly statement by number. Replaced by the operand's register when the statement is lowered
+    Placeholder(u32),
 }
 
 impl<'a> core::fmt::Display for PrettyPrinter<'a, Operand> {
@@ -122,6 +134,7 @@ impl<'a> core::fmt::Display for PrettyPrinter<'a, Operand> {
                 Ok(())
             }
             Operand::Memory(memory_operand) => PrettyPrinter(memory_operand, self.1, self.2).fmt(f),
+            Operand::Placeholder(n) => f.write_fmt(format_args!("%{n}")),
         }
     }
 }
@@ -218,9 +231,17 @@ impl core::fmt::Display for RelocSym {
             AddressKind::Default => Ok(()),
             AddressKind::GotRel => f.write_str("@gotpcrel"),
             AddressKind::GotAbs => f.write_str("@gotabs"),
+            AddressKind::GotOff => f.write_str("@gotoff"),
             AddressKind::Plt => f.write_str("@plt"),
             AddressKind::Tpoff => f.write_str("@tpoff"),
-            AddressKind::DTpoff => f.write_str("@gottpoff"),
+            AddressKind::NTpoff => f.write_str("@ntpoff"),
+            AddressKind::DTpoff => f.write_str("@dtpoff"),
+            AddressKind::GotTpoff => f.write_str("@gottpoff"),
+            AddressKind::GotNTpoff => f.write_str("@gotntpoff"),
+            AddressKind::IndNTpoff => f.write_str("@indntpoff"),
+            AddressKind::TlsGd => f.write_str("@tlsgd"),
+            AddressKind::TlsLd => f.write_str("@tlsld"),
+            AddressKind::TlsLdm => f.write_str("@tlsldm"),
             AddressKind::TlsDesc => f.write_str("@tlsdesc"),
             AddressKind::LTlsDesc => f.write_str("@ltlsdesc"),
         }
@@ -232,9 +253,25 @@ pub enum AddressKind {
     Default,
     GotRel,
     GotAbs,
+    /// The offset of a symbol from the GOT, used to address local symbols relative to the GOT base on targets without pc-relative addressing
+    GotOff,
     Plt,
     Tpoff,
+    /// The negated offset of a thread-local variable from the thread pointer, which i386 adds to the thread pointer for the local-exec model
+    NTpoff,
     DTpoff,
     TlsDesc,
     LTlsDesc,
+    /// The GOT entry holding the offset of a thread-local variable from the thread pointer, used by the initial-exec model
+    GotTpoff,
+    /// Like [`AddressKind::GotTpoff`], but the GOT entry holds the negated offset, and is addressed relative to the GOT base. Used by i386
+    GotNTpoff,
+    /// Like [`AddressKind::GotNTpoff`], but the GOT entry is addressed absolutely. Used by i386 position-dependent code
+    IndNTpoff,
+    /// The `tls_index` of a thread-local variable, used by the general-dynamic model
+    TlsGd,
+    /// The `tls_index` of the current module, used by the local-dynamic model
+    TlsLd,
+    /// The i386 spelling of [`AddressKind::TlsLd`]
+    TlsLdm,
 }
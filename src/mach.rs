@@ -63,6 +63,11 @@ pub trait RegisterSpec: AsId<Register> + Name + Sized {
     fn regmap_bit(self) -> Option<u32>;
 
     fn supported_registers(features: &FeatureSet, mode: Self::MachineMode) -> Regset;
+
+    /// The DWARF register number of the register in the current `mode`, if it has one
+    fn dwarf_regno(&self, mode: Self::MachineMode) -> Option<u16> {
+        None
+    }
 }
 
 pub trait TargetFeatureSpec: Name + Sized {
@@ -237,6 +242,17 @@ impl<M: MachineSpec> Machine for M {
                 }
             }
 
+            fn dwarf_regno(&self, reg: Register, mode: MachineMode) -> Option<u16> {
+                match (
+                    reg.downcast::<This::Register>(),
+                    mode.downcast::<This::MachineMode>(),
+                ) {
+                    (Some(reg), Some(mode)) => reg.dwarf_regno(mode),
+                    (None, _) => panic!("Unknown Register"),
+                    (_, None) => panic!("Unknown MachineMode"),
+                }
+            }
+
             fn __sealed(&self,) -> () {}
         }
     );
@@ -343,6 +359,8 @@ pub trait Registers: DynList<Register> {
 
     fn supported_registers(&self, features: &FeatureSet, mode: MachineMode) -> Regset;
 
+    fn dwarf_regno(&self, reg: Register, mode: MachineMode) -> Option<u16>;
+
     #[doc(hidden)]
     fn __sealed(&self);
 }
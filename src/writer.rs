@@ -1,7 +1,7 @@
 
 use std::io::{Write, Result};
 
-use crate::{instr::Instruction, reloc::RelocValue};
+use crate::{instr::Instruction, intern::Symbol, reloc::RelocValue};
 
 pub trait RelocatableWriter : Write {
     fn write_with_reloc(&mut self, data: &[u8], reloc: RelocValue) -> Result<()>;
@@ -10,3 +10,23 @@ pub trait RelocatableWriter : Write {
 pub trait Encoder {
     fn encode_instr(&self, writer: &mut dyn RelocatableWriter, instr: Instruction) -> Result<()>;
 }
+
+/// A writer for an object file format, such as ELF or COFF, that holds the contents of each section
+pub trait ObjectWriter {
+    /// The size of an address in the object file, in bytes
+    fn address_size(&self) -> u8;
+
+    fn big_endian(&self) -> bool;
+
+    /// Whether unwind tables are written to `.eh_frame`. Otherwise they are only written to `.debug_frame` along with the other debug info
+    fn uses_eh_frame(&self) -> bool;
+
+    /// The writer for the section called `name`, which is created the first time it is used
+    fn section(&mut self, name: &str) -> &mut dyn RelocatableWriter;
+
+    /// The number of bytes written to the section called `name` so far
+    fn section_size(&self, name: &str) -> u64;
+
+    /// Defines `sym` at `offset` in the section called `section`
+    fn define_symbol(&mut self, section: &str, sym: Symbol, offset: u64, global: bool);
+}
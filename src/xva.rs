@@ -1,7 +1,7 @@
-use std::num::NonZero;
+use std::{collections::HashSet, num::NonZero};
 
 use crate::{
-    compiler::{Compiler, CompilerContext}, fmt::pretty_print_list, instr::{Address, AddressKind, Instruction, MemoryOperand, Operand, RegisterKind, RelocSym}, intern::Symbol, mach::{FeatureSet, Machine, MachineMode, Register, Regset}, traits::{AsId, IdType as _, IntoId}, xva
+    compiler::{Compiler, CompilerContext}, fmt::pretty_print_list, instr::{Address, AddressKind, Instruction, MemoryOperand, Operand, RegisterKind, RelocSym}, intern::Symbol, mach::{FeatureSet, Machine, MachineMode, Register, Regset}, reloc::RelocationKind, traits::{AsId, IdType as _, IntoId}, writer::{Encoder, ObjectWriter}, xva
 };
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
@@ -93,6 +93,20 @@ pub struct XvaBasicBlock {
     pub body: XvaBlockBody,
 }
 
+impl XvaBasicBlock {
+    pub fn stmts(&self) -> &Vec<XvaStatement> {
+        match &self.body {
+            XvaBlockBody::Statement(stmts) => stmts,
+        }
+    }
+
+    pub fn stmts_mut(&mut self) -> &mut Vec<XvaStatement> {
+        match &mut self.body {
+            XvaBlockBody::Statement(stmts) => stmts,
+        }
+    }
+}
+
 impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaBasicBlock> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&self.label)?;
@@ -125,6 +139,8 @@ pub enum XvaStatement {
     Expr(XvaExpr),
     Write(XvaOperand, XvaType, XvaRegister),
     Jump(Symbol),
+    /// Jumps to the label if the register is nonzero, and otherwise continues with the next statement
+    JumpIf(XvaRegister, Symbol),
     Tailcall {
         dest: XvaOperand,
         params: Regset,
@@ -134,6 +150,10 @@ pub enum XvaStatement {
         params: Regset,
         ret_val: Regset,
         call_clobber_regs: Regset,
+        /// The number of bytes of stack arguments the callee pops on return, which the caller reserves again after the call
+        callee_pop: usize,
+        /// Set when the callee returns its value in memory, through a hidden pointer in `params`
+        indirect_ret: bool,
     },
     Return,
     Trap(XvaTrap),
@@ -144,6 +164,101 @@ pub enum XvaStatement {
     Elaborated(Vec<XvaStatement>),
     Use(Vec<XvaRegister>, UseKind),
     Fallthrough(Symbol),
+    InlineAsm(XvaInlineAsm),
+    /// Marks the source location of the statements that follow it, until the next [`XvaStatement::Loc`]
+    Loc(XvaSourceLoc),
+    /// Records a change to the call frame that takes effect after the preceeding instruction.
+    /// Produced by the prologue and epilogue
+    Cfi(XvaCfi),
+    /// Defines a local label in the middle of a block.
+    /// Only produced by machine code lowering, [`XvaFile::lower_mc`] splits the block at each label
+    Label(Symbol),
+}
+
+impl XvaStatement {
+    /// Calls `f` with each register this statement writes, not looking into [`XvaStatement::Elaborated`]
+    pub fn for_each_def(&self, mut f: impl FnMut(XvaRegister)) {
+        match self {
+            XvaStatement::Expr(expr) => core::iter::once(expr.dest).chain(expr.dest2).for_each(f),
+            XvaStatement::Use(regs, UseKind::Write | UseKind::ReadWrite) => regs.iter().copied().for_each(f),
+            XvaStatement::InlineAsm(asm) => asm.outputs.iter().for_each(|output| f(output.reg)),
+            _ => {}
+        }
+    }
+}
+
+/// Calls `f` with each statement of `stmts` and the index of the top-level statement that contains it, looking into [`XvaStatement::Elaborated`]
+pub fn for_each_stmt<'a>(stmts: &'a [XvaStatement], f: &mut impl FnMut(usize, &'a XvaStatement)) {
+    fn visit<'a>(idx: usize, stmt: &'a XvaStatement, f: &mut impl FnMut(usize, &'a XvaStatement)) {
+        match stmt {
+            XvaStatement::Elaborated(stmts) => stmts.iter().for_each(|stmt| visit(idx, stmt, f)),
+            stmt => f(idx, stmt),
+        }
+    }
+
+    for (idx, stmt) in stmts.iter().enumerate() {
+        visit(idx, stmt, f);
+    }
+}
+
+/// The symbols that `func` uses other than as the target of a jump, such as globals and labels whose address is taken.
+///
+/// A symbol operand of a raw or inline assembly instruction that names a label of `func` is the target of a jump, and is not included
+pub(crate) fn used_symbols(func: &XvaFunction) -> HashSet<Symbol> {
+    let mut labels = HashSet::new();
+    for block in &func.body {
+        labels.insert(block.label);
+        for_each_stmt(block.stmts(), &mut |_, stmt| {
+            if let XvaStatement::Label(label) = stmt {
+                labels.insert(*label);
+            }
+        });
+    }
+
+    let mut syms = HashSet::new();
+    let operand_symbols = |opr: &XvaOperand, syms: &mut HashSet<Symbol>| {
+        if let XvaOperand::Const(XvaConst::Label(sym) | XvaConst::Global(sym, _)) = opr {
+            syms.insert(*sym);
+        }
+    };
+    let instr_symbols = |instr: &Instruction, syms: &mut HashSet<Symbol>| {
+        for opr in instr.operands() {
+            match opr {
+                Operand::AbsSymbol(sym, _) => {
+                    syms.insert(sym.sym);
+                }
+                Operand::RelSymbol(sym, _) if !labels.contains(&sym.sym) => {
+                    syms.insert(sym.sym);
+                }
+                Operand::Memory(mem) => syms.extend(mem.addr.sym.map(|sym| sym.sym)),
+                _ => {}
+            }
+        }
+    };
+
+    for block in &func.body {
+        for_each_stmt(block.stmts(), &mut |_, stmt| match stmt {
+            XvaStatement::Expr(expr) => match &expr.op {
+                XvaOpcode::Const(c) => operand_symbols(&XvaOperand::Const(*c), &mut syms),
+                XvaOpcode::ComputeAddr { base, index, .. } => {
+                    operand_symbols(base, &mut syms);
+                    operand_symbols(index, &mut syms);
+                }
+                XvaOpcode::BinaryOp { right, .. } | XvaOpcode::CheckedBinaryOp { right, .. } => operand_symbols(right, &mut syms),
+                XvaOpcode::Read(opr) => operand_symbols(opr, &mut syms),
+                _ => {}
+            },
+            XvaStatement::Write(opr, _, _) | XvaStatement::Call { dest: opr, .. } | XvaStatement::Tailcall { dest: opr, .. } => operand_symbols(opr, &mut syms),
+            XvaStatement::RawInstr(raw) => instr_symbols(raw, &mut syms),
+            XvaStatement::InlineAsm(asm) => {
+                for raw in &asm.template {
+                    instr_symbols(raw, &mut syms);
+                }
+            }
+            _ => {}
+        });
+    }
+    syms
 }
 
 impl Default for XvaStatement {
@@ -162,6 +277,10 @@ impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaStatement> {
                 PrettyPrinter(reg, self.1, self.2)
             )),
             XvaStatement::Jump(symbol) => f.write_fmt(format_args!("jump {symbol}")),
+            XvaStatement::JumpIf(cond, symbol) => f.write_fmt(format_args!(
+                "jump {symbol} if {}",
+                PrettyPrinter(cond, self.1, self.2)
+            )),
             XvaStatement::Tailcall { dest, params } => f.write_fmt(format_args!(
                 "tailcall {} ({})",
                 PrettyPrinter(dest, self.1, self.2),
@@ -172,13 +291,24 @@ impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaStatement> {
                 params,
                 ret_val,
                 call_clobber_regs,
-            } => f.write_fmt(format_args!(
-                "call {} ({}) -> {} clobbers [{}]",
-                PrettyPrinter(dest, self.1, self.2),
-                PrettyPrinter(params, self.1, self.2),
-                PrettyPrinter(ret_val, self.1, self.2),
-                PrettyPrinter(call_clobber_regs, self.1, self.2),
-            )),
+                callee_pop,
+                indirect_ret,
+            } => {
+                f.write_fmt(format_args!(
+                    "call {} ({}) -> {} clobbers [{}]",
+                    PrettyPrinter(dest, self.1, self.2),
+                    PrettyPrinter(params, self.1, self.2),
+                    PrettyPrinter(ret_val, self.1, self.2),
+                    PrettyPrinter(call_clobber_regs, self.1, self.2),
+                ))?;
+                if *callee_pop != 0 {
+                    f.write_fmt(format_args!(" pops {callee_pop}"))?;
+                }
+                if *indirect_ret {
+                    f.write_str(" indirect ret")?;
+                }
+                Ok(())
+            }
             XvaStatement::Return => f.write_str("return"),
             XvaStatement::Trap(trap) => f.write_fmt(format_args!("trap {trap}")),
             XvaStatement::RawInstr(instruction) => {
@@ -201,6 +331,13 @@ impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaStatement> {
                 pretty_print_list(reg, ", ", self.1, self.2)
             )),
             XvaStatement::Fallthrough(name) => f.write_fmt(format_args!("fallthrough {name}")),
+            XvaStatement::InlineAsm(asm) => PrettyPrinter(asm, self.1, self.2).fmt(f),
+            XvaStatement::Loc(loc) => f.write_fmt(format_args!("loc {loc}")),
+            XvaStatement::Cfi(cfi) => {
+                f.write_str("cfi ")?;
+                PrettyPrinter(cfi, self.1, self.2).fmt(f)
+            }
+            XvaStatement::Label(name) => f.write_fmt(format_args!("{name}:")),
         }
     }
 }
@@ -227,6 +364,149 @@ impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaExpr> {
     }
 }
 
+/// A location in the source file that some XVA was generated from
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct XvaSourceLoc {
+    pub file: Symbol,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl core::fmt::Display for XvaSourceLoc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("\"{}\" {}:{}", self.file, self.line, self.column))
+    }
+}
+
+/// A Call Frame Information instruction, describing how to unwind the current frame
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum XvaCfi {
+    /// The CFA is now the value of the register plus the offset
+    DefCfa(Register, i64),
+    /// The CFA is now computed using the register, with the same offset
+    DefCfaRegister(Register),
+    /// The CFA is now computed using the offset, with the same register
+    DefCfaOffset(i64),
+    /// The previous value of the register is saved at the CFA plus the offset
+    Offset(Register, i64),
+    /// The previous value of the first register is saved in the second register
+    Register(Register, Register),
+    /// The register has the same rule as it did on entry to the function
+    Restore(Register),
+    /// Saves the current rules, so that they can be restored after an epilogue
+    RememberState,
+    RestoreState,
+}
+
+impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaCfi> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let regs = self.1.registers();
+        match *self.0 {
+            XvaCfi::DefCfa(reg, off) => f.write_fmt(format_args!("def_cfa {}, {off}", regs.name_of(reg))),
+            XvaCfi::DefCfaRegister(reg) => f.write_fmt(format_args!("def_cfa_register {}", regs.name_of(reg))),
+            XvaCfi::DefCfaOffset(off) => f.write_fmt(format_args!("def_cfa_offset {off}")),
+            XvaCfi::Offset(reg, off) => f.write_fmt(format_args!("offset {}, {off}", regs.name_of(reg))),
+            XvaCfi::Register(reg, reg2) => f.write_fmt(format_args!("register {}, {}", regs.name_of(reg), regs.name_of(reg2))),
+            XvaCfi::Restore(reg) => f.write_fmt(format_args!("restore {}", regs.name_of(reg))),
+            XvaCfi::RememberState => f.write_str("remember_state"),
+            XvaCfi::RestoreState => f.write_str("restore_state"),
+        }
+    }
+}
+
+/// The constraint placed on an operand of an [`XvaInlineAsm`] statement
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum XvaAsmConstraint {
+    /// Any register of the given category
+    Class(XvaCategory),
+    /// The specified physical register
+    Register(Register),
+    /// The same register as the output with the given index. Only valid for inputs
+    Tied(u32),
+}
+
+impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaAsmConstraint> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            XvaAsmConstraint::Class(cat) => f.write_fmt(format_args!("{cat}")),
+            XvaAsmConstraint::Register(reg) => f.write_str(self.1.registers().name_of(*reg)),
+            XvaAsmConstraint::Tied(n) => f.write_fmt(format_args!("%{n}")),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct XvaAsmOperand {
+    pub constraint: XvaAsmConstraint,
+    pub reg: XvaRegister,
+}
+
+impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaAsmOperand> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        PrettyPrinter(&self.0.reg, self.1, self.2).fmt(f)?;
+        f.write_str(" in ")?;
+        PrettyPrinter(&self.0.constraint, self.1, self.2).fmt(f)
+    }
+}
+
+/// An inline assembly statement.
+///
+/// Operands are numbered with the outputs first, followed by the inputs, and are referred to in the template by [`Operand::Placeholder`].
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct XvaInlineAsm {
+    pub template: Vec<Instruction>,
+    pub outputs: Vec<XvaAsmOperand>,
+    pub inputs: Vec<XvaAsmOperand>,
+    pub clobbers: Regset,
+}
+
+impl XvaInlineAsm {
+    /// Obtains the operand with the given placeholder number
+    pub fn operand(&self, n: u32) -> Option<&XvaAsmOperand> {
+        let n = n as usize;
+        if n < self.outputs.len() {
+            self.outputs.get(n)
+        } else {
+            self.inputs.get(n - self.outputs.len())
+        }
+    }
+
+    /// The registers that hold a different value after the statement: the declared clobbers, the physical registers of the outputs,
+    /// and the registers that inputs are moved into
+    pub fn clobber_set(&self, mach: &dyn Machine) -> Regset {
+        let mut regs = self.clobbers;
+        for output in &self.outputs {
+            if let XvaRegister::Physical(reg) = output.reg {
+                regs.insert_regid(reg, mach);
+            }
+        }
+        for input in &self.inputs {
+            if let XvaAsmConstraint::Register(reg) = input.constraint {
+                regs.insert_regid(reg, mach);
+            }
+        }
+        regs
+    }
+}
+
+impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaInlineAsm> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "asm ({}) <- ({}) clobbers [{}] {{",
+            pretty_print_list(&self.0.outputs, ", ", self.1, self.2),
+            pretty_print_list(&self.0.inputs, ", ", self.1, self.2),
+            PrettyPrinter(&self.0.clobbers, self.1, self.2),
+        ))?;
+
+        for instr in &self.0.template {
+            f.write_str("\n\t\t")?;
+            PrettyPrinter(instr, self.1, self.2).fmt(f)?;
+        }
+
+        f.write_str("\n\t}")
+    }
+}
+
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum XvaConst {
     Bits(u64),
@@ -239,7 +519,7 @@ impl XvaConst {
         match self {
             XvaConst::Bits(n) => Operand::Immediate(*n as u128),
             XvaConst::Label(symbol) => Operand::RelSymbol(RelocSym{sym: *symbol, kind: local_address_kind}, None),
-            XvaConst::Global(symbol, disp) => Operand::RelSymbol(RelocSym{sym: *symbol, kind: local_address_kind}, NonZero::new(*disp)),
+            XvaConst::Global(symbol, disp) => Operand::RelSymbol(RelocSym{sym: *symbol, kind: global_address_kind}, NonZero::new(*disp)),
         }
     }
 
@@ -289,6 +569,10 @@ pub enum XvaOperand {
     Register(XvaRegister),
     Const(XvaConst),
     FrameAddr(i32),
+    /// The incoming stack argument at the given offset, as assigned by [`ArgLocation::Stack`][crate::compiler::callconv::ArgLocation::Stack]
+    IncomingArg(u32),
+    /// The slot in the outgoing argument area for the stack argument at the given offset of the next call
+    OutgoingArg(u32),
 }
 
 impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaOperand> {
@@ -297,6 +581,8 @@ impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaOperand> {
             XvaOperand::Register(reg) => PrettyPrinter(reg, self.1, self.2).fmt(f),
             XvaOperand::Const(cn) => cn.fmt(f),
             XvaOperand::FrameAddr(op) => f.write_fmt(format_args!("frame_addr {op}")),
+            XvaOperand::IncomingArg(off) => f.write_fmt(format_args!("incoming_arg {off}")),
+            XvaOperand::OutgoingArg(off) => f.write_fmt(format_args!("outgoing_arg {off}")),
         }
     }
 }
@@ -329,14 +615,50 @@ pub enum XvaOpcode {
         left: XvaRegister,
     },
     Read(XvaOperand),
+    /// Multiplies `left` by `right` as unsigned values. `dest` receives the low half of the product, and `dest2`, if present, the high half
     UMul {
         left: XvaRegister,
         right: XvaRegister,
     },
+    /// Multiplies `left` by `right` as signed values. `dest` receives the low half of the product, and `dest2`, if present, the high half
     SMul {
         left: XvaRegister,
         right: XvaRegister,
     },
+    /// Divides `left` by `right` as unsigned values. `dest` receives the quotient, and `dest2`, if present, the remainder.
+    /// Dividing by zero is undefined
+    UDiv {
+        left: XvaRegister,
+        right: XvaRegister,
+    },
+    /// Divides `left` by `right` as signed values, rounding towards zero. `dest` receives the quotient, and `dest2`, if present, the remainder, which has the sign of `left`.
+    /// Dividing by zero, or the most negative value by -1, is undefined
+    SDiv {
+        left: XvaRegister,
+        right: XvaRegister,
+    },
+    /// Selects `left` if `cond` is nonzero, and `right` otherwise, without branching where the machine supports it
+    Select {
+        cond: XvaRegister,
+        left: XvaRegister,
+        right: XvaRegister,
+    },
+    /// The address of the thread-local variable `sym` plus `disp`, using the [`TlsModel`][crate::compiler::TlsModel] from [`CompilerContext::tls_model`].
+    /// `local` is set if `sym` is defined in the current module.
+    ///
+    /// The dynamic models call into the TLS runtime, so they are expanded with [`XvaFile::expand_runtime_calls`], which should be done before register allocation.
+    /// For the local-dynamic model, this sets `module_base` to the register holding the address of the module's TLS block
+    TlsAddr {
+        sym: Symbol,
+        disp: i64,
+        local: bool,
+        module_base: Option<XvaRegister>,
+    },
+    /// The address of the `tls_index` passed to the TLS runtime for `sym`, or for the module that defines `sym` if `module` is set
+    TlsIndex {
+        sym: Symbol,
+        module: bool,
+    },
 }
 
 impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaOpcode> {
@@ -385,6 +707,34 @@ impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaOpcode> {
                 PrettyPrinter(left, self.1, self.2),
                 PrettyPrinter(right, self.1, self.2)
             )),
+            XvaOpcode::UDiv { left, right } => f.write_fmt(format_args!(
+                "udiv {}, {}",
+                PrettyPrinter(left, self.1, self.2),
+                PrettyPrinter(right, self.1, self.2)
+            )),
+            XvaOpcode::SDiv { left, right } => f.write_fmt(format_args!(
+                "sdiv {}, {}",
+                PrettyPrinter(left, self.1, self.2),
+                PrettyPrinter(right, self.1, self.2)
+            )),
+            XvaOpcode::Select { cond, left, right } => f.write_fmt(format_args!(
+                "select {}, {}, {}",
+                PrettyPrinter(cond, self.1, self.2),
+                PrettyPrinter(left, self.1, self.2),
+                PrettyPrinter(right, self.1, self.2)
+            )),
+            XvaOpcode::TlsAddr { sym, disp, local, module_base } => {
+                f.write_fmt(format_args!("tls_addr {sym}+{disp}"))?;
+                if *local {
+                    f.write_str(" local")?;
+                }
+                if let Some(base) = module_base {
+                    f.write_fmt(format_args!(" from {}", PrettyPrinter(base, self.1, self.2)))?;
+                }
+                Ok(())
+            }
+            XvaOpcode::TlsIndex { sym, module: true } => f.write_fmt(format_args!("tls_index module {sym}")),
+            XvaOpcode::TlsIndex { sym, module: false } => f.write_fmt(format_args!("tls_index {sym}")),
         }
     }
 }
@@ -553,6 +903,14 @@ impl XvaRegister {
         XvaRegister::Physical(reg.into_id())
     }
 
+    /// Whether writing to `def` changes the value of this register
+    pub fn aliases(self, def: XvaRegister, mach: &dyn Machine) -> bool {
+        match (self, def) {
+            (XvaRegister::Physical(a), XvaRegister::Physical(b)) => a == b || mach.registers().register_overlaps(a, b),
+            (a, b) => a == b,
+        }
+    }
+
     pub fn size(&self, mach: &dyn Machine, mode: MachineMode) -> u64 {
         match self {
             Self::Physical(r) => mach.registers().register_size(*r, mode) as u64,
@@ -612,6 +970,25 @@ pub struct XvaFrameProperties {
     pub has_prologue: bool,
     pub use_frame_pointer: bool,
     pub is_leaf: bool,
+    /// Set when the function returns its value in memory, through a hidden pointer passed by its caller
+    pub indirect_ret: bool,
+    /// Set when the function has no prologue or epilogue at all, such as a support function that is called by the prologue of other functions
+    pub naked: bool,
+    /// Set when the function refers to a symbol other than as the target of a jump, including through a thread-local access.
+    /// [`XvaFile::lower_mc`] sets this from the body of the function before the prologue is emitted
+    pub references_symbols: bool,
+    /// Set when the function has arrays or other locals whose address is taken, which are protected by [`StackProtectorLevel::Strong`][crate::compiler::StackProtectorLevel::Strong]
+    pub has_local_buffers: bool,
+    /// The offset of the stack protector canary from the frame pointer if [`Self::use_frame_pointer`] is set, or from the stack pointer after the prologue otherwise.
+    /// Set by the prologue if the function is protected
+    pub stack_protector_slot: Option<i32>,
+    /// The size of the stack arguments passed to the function, which are read with [`XvaOperand::IncomingArg`]
+    pub incoming_args_size: usize,
+    /// The number of bytes of the incoming stack arguments that the function pops on return
+    pub callee_pop: usize,
+    /// The size of the outgoing argument area at the bottom of the frame, which is written with [`XvaOperand::OutgoingArg`].
+    /// This is the largest stack argument area of any call in the function
+    pub outgoing_args_size: usize,
     pub features: FeatureSet,
 
     #[doc(hidden)]
@@ -621,9 +998,14 @@ pub struct XvaFrameProperties {
 impl XvaFrameProperties {
     pub const fn new() -> Self {
         Self {
-            frame_size: 0, frame_align: 1, call_align: 1, call_align_offset: 0, has_prologue: false, use_frame_pointer: false, is_leaf: false, features: FeatureSet::new(), __non_exhaustive: ()
+            frame_size: 0, frame_align: 1, call_align: 1, call_align_offset: 0, has_prologue: false, use_frame_pointer: false, is_leaf: false, indirect_ret: false, naked: false, references_symbols: false, has_local_buffers: false, stack_protector_slot: None, incoming_args_size: 0, callee_pop: 0, outgoing_args_size: 0, features: FeatureSet::new(), __non_exhaustive: ()
         }
     }
+
+    /// Grows the outgoing argument area to fit a call with `size` bytes of stack arguments
+    pub fn reserve_outgoing_args(&mut self, size: usize) {
+        self.outgoing_args_size = self.outgoing_args_size.max(size);
+    }
 }
 
 impl core::fmt::Display for PrettyPrinter<'_, XvaFrameProperties> {
@@ -642,6 +1024,17 @@ impl core::fmt::Display for PrettyPrinter<'_, XvaFrameProperties> {
         self.call_align_offset.fmt(f)?;
         f.write_str("\n")?;
 
+        f.write_str("STACK ARGS: incoming ")?;
+        self.incoming_args_size.fmt(f)?;
+        if self.callee_pop != 0 {
+            f.write_str(" (callee pops ")?;
+            self.callee_pop.fmt(f)?;
+            f.write_str(")")?;
+        }
+        f.write_str(" outgoing ")?;
+        self.outgoing_args_size.fmt(f)?;
+        f.write_str("\n")?;
+
         f.write_str("TARGET FEATURES: ")?;
 
         PrettyPrinter(&self.features, self.1, self.2).fmt(f)?;
@@ -662,6 +1055,26 @@ impl core::fmt::Display for PrettyPrinter<'_, XvaFrameProperties> {
             f.write_str("LEAF ")?;
         }
 
+        if self.indirect_ret {
+            f.write_str("INDIRECT RET ")?;
+        }
+
+        if self.naked {
+            f.write_str("NAKED ")?;
+        }
+
+        if self.references_symbols {
+            f.write_str("SYMBOLS ")?;
+        }
+
+        if self.has_local_buffers {
+            f.write_str("LOCAL BUFFERS ")?;
+        }
+
+        if let Some(slot) = self.stack_protector_slot {
+            f.write_fmt(format_args!("STACK PROTECTOR {slot} "))?;
+        }
+
         f.write_str("\n")
     }
 }
@@ -672,7 +1085,7 @@ pub struct XvaFunction {
     pub preserve_regs: Regset,
     pub clobber_regs: Regset,
     pub return_regs: Regset,
-    pub prologue: Vec<Instruction>,
+    pub prologue: Vec<XvaStatement>,
     pub body: Vec<XvaBasicBlock>,
     pub frame_properties: XvaFrameProperties
 }
@@ -704,9 +1117,9 @@ impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaFunction> {
 
         if self.prologue.len() > 0 {
             f.write_str("PROLOGUE:\n")?;
-            for instr in &self.prologue {
+            for stmt in &self.prologue {
                 f.write_str("\t")?;
-                PrettyPrinter(instr, self.1, self.2).fmt(f)?;
+                PrettyPrinter(stmt, self.1, self.2).fmt(f)?;
                 f.write_str("\n")?;
             }
         }
@@ -736,12 +1149,77 @@ impl XvaFile {
         PrettyPrinter(self, mach, mode)
     }
 
+    /// Expands the expressions that call into the runtime with [`Compiler::expand_runtime_calls`], such as accesses to thread-local variables that use a dynamic [`TlsModel`][crate::compiler::TlsModel].
+    /// This should be done before register allocation, so that the registers clobbered by the calls are known, and must be done before [`XvaFile::lower_mc`]
+    pub fn expand_runtime_calls(&mut self, compiler: &dyn Compiler, context: &CompilerContext) {
+        for func in &mut self.functions {
+            for block in &mut func.body.body {
+                match &mut block.body {
+                    XvaBlockBody::Statement(stmts) => {
+                        let mut expanded = false;
+                        for stmt in &mut *stmts {
+                            if let XvaStatement::Expr(expr) = stmt
+                                && let Some(stmts) = compiler.expand_runtime_calls(expr, context, &func.body.frame_properties)
+                            {
+                                *stmt = XvaStatement::Elaborated(stmts);
+                                expanded = true;
+                            }
+                        }
+
+                        if expanded {
+                            // The runtime call needs the stack to be aligned for a call
+                            func.body.frame_properties.is_leaf = false;
+                            let _stmts = core::mem::take(stmts);
+                            opt::flatten_statements(stmts, _stmts);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Recomputes [`XvaBasicBlock::live_at_start`] for every function from register liveness
+    pub fn compute_live_at_start(&mut self, mach: &dyn Machine, mode: MachineMode) {
+        for func in &mut self.functions {
+            let cfg = cfg::XvaCfg::new(&func.body);
+            dataflow::compute_live_at_start(&mut func.body, &cfg, mach, mode);
+        }
+    }
+
+    /// Adds the [`Compiler::support_functions`] of the target that are not already defined in this file
+    pub fn add_support_functions(&mut self, compiler: &dyn Compiler, context: &CompilerContext) {
+        for func in compiler.support_functions(context) {
+            if !self.functions.iter().any(|f| f.label == func.label) {
+                self.functions.push(func);
+            }
+        }
+    }
+
+    /// Lowers each function into machine code with [`Compiler::mce_lower`], emitting its prologue first if it needs one.
+    /// The expressions that call into the runtime must already have been expanded with [`XvaFile::expand_runtime_calls`]
     pub fn lower_mc(&mut self, compiler: &dyn Compiler, context: &CompilerContext) {
         for func in &mut self.functions {
-            if func.body.frame_properties.has_prologue {
-                func.body.prologue = compiler.emit_prologue(&mut func.body.frame_properties, context.mode);
+            let references_tls = func.body.body.iter().any(|block| match &block.body {
+                XvaBlockBody::Statement(stmts) => stmts.iter().any(|stmt| matches!(stmt, XvaStatement::Expr(XvaExpr { op: XvaOpcode::TlsAddr { .. } | XvaOpcode::TlsIndex { .. }, .. }))),
+            });
+            func.body.frame_properties.references_symbols |= references_tls || !used_symbols(&func.body).is_empty();
+
+            let blocks = core::mem::take(&mut func.body.body);
+            if !func.body.frame_properties.naked && compiler.needs_prologue(&func.body.frame_properties, context) {
+                let mut prologue = compiler.emit_prologue(&mut func.body.frame_properties, context);
+
+                // Labels in the prologue (such as the loop of a stack probe) start blocks before the body, the same as labels in the body
+                if let Some(pos) = prologue.iter().position(|stmt| matches!(stmt, XvaStatement::Label(_))) {
+                    let mut rest = prologue.split_off(pos).into_iter();
+                    let Some(XvaStatement::Label(label)) = rest.next() else {
+                        unreachable!()
+                    };
+                    let live_at_start = blocks.first().map(|block| block.live_at_start.clone()).unwrap_or_default();
+                    split_local_labels(&mut func.body.body, XvaBasicBlock { label, live_at_start, body: XvaBlockBody::Statement(rest.collect()) });
+                }
+                func.body.prologue = prologue;
             }
-            for block in &mut func.body.body {
+            for mut block in blocks {
                 match &mut block.body {
                     XvaBlockBody::Statement(stmts) => {
                         for stmt in &mut *stmts {
@@ -753,7 +1231,150 @@ impl XvaFile {
                         opt::flatten_statements(stmts, _stmts);
                     },
                 }
+
+                split_local_labels(&mut func.body.body, block);
+            }
+        }
+    }
+
+    /// Compiles the file into machine code.
+    ///
+    /// This expands the expressions that call into the runtime, runs the pipelines of `passes` for each phase before [`XvaOptPhase::Mce`][opt::XvaOptPhase::Mce], adds the support functions of the target,
+    /// lowers the file with [`XvaFile::lower_mc`], and then runs the pipeline of [`XvaOptPhase::Mce`][opt::XvaOptPhase::Mce] on the machine code.
+    /// Register allocation is not part of the pipeline yet, so the register allocation phases run back to back
+    pub fn compile(&mut self, compiler: &dyn Compiler, context: &CompilerContext, passes: &mut opt::manager::PassManager) {
+        let mach = compiler.machine();
+        self.expand_runtime_calls(compiler, context);
+
+        for &phase in opt::XvaOptPhase::ALL {
+            if phase != opt::XvaOptPhase::Mce {
+                passes.run(phase, self, mach, context.mode);
+            }
+        }
+
+        self.add_support_functions(compiler, context);
+        self.lower_mc(compiler, context);
+        passes.run(opt::XvaOptPhase::Mce, self, mach, context.mode);
+    }
+}
+
+/// The options for the debug info written by [`XvaFile::write_functions`]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct XvaDebugOptions {
+    pub producer: Symbol,
+    /// The name of the source file of the compilation unit
+    pub name: Symbol,
+    pub comp_dir: Symbol,
+}
+
+impl XvaSection {
+    /// The name of the section in the object file, using the ELF names
+    pub fn object_name(&self) -> &str {
+        match self {
+            XvaSection::Text | XvaSection::PrivateText => ".text",
+            XvaSection::RoData => ".rodata",
+            XvaSection::Data => ".data",
+            XvaSection::Explicit(name) => name.as_str(),
+            XvaSection::Common | XvaSection::Bss => ".bss",
+            XvaSection::TlsData => ".tdata",
+        }
+    }
+}
+
+impl XvaFile {
+    /// Writes the machine code of each function to its section of `writer`, once the file has been lowered with [`XvaFile::lower_mc`].
+    ///
+    /// Unwind tables are written for the functions if the machine has a [`Compiler::cfi_entry_state`].
+    /// If `debug` is set, `.debug_abbrev`, `.debug_info`, and `.debug_line` are written as well
+    pub fn write_functions(
+        &self,
+        encoder: &dyn Encoder,
+        compiler: &dyn Compiler,
+        context: &CompilerContext,
+        writer: &mut dyn ObjectWriter,
+        debug: Option<XvaDebugOptions>,
+    ) -> std::io::Result<()> {
+        let mut encoded = Vec::with_capacity(self.functions.len());
+        for def in &self.functions {
+            let section = def.section.object_name();
+            let start = writer.section_size(section);
+            let func = dwarf::encode_function(encoder, writer.section(section), &def.body)?;
+            writer.define_symbol(section, def.label, start, def.linkage != Linkage::Internal);
+            for &(label, offset) in &func.labels {
+                writer.define_symbol(section, label, start + offset, false);
+            }
+            encoded.push(func);
+        }
+
+        let address_size = writer.address_size();
+        let big_endian = writer.big_endian();
+
+        let frame_format = if writer.uses_eh_frame() {
+            Some(dwarf::DwarfFrameFormat::EhFrame)
+        } else {
+            debug.map(|_| dwarf::DwarfFrameFormat::DebugFrame)
+        };
+        if let Some(format) = frame_format
+            && let Some(cie) = compiler.cfi_entry_state(context.mode)
+        {
+            let table = dwarf::DwarfFrameTable {
+                format,
+                cie,
+                address_size,
+                big_endian,
+                fdes: self.functions.iter().zip(&encoded).map(|(def, encoded)| dwarf::DwarfFde::new(def, encoded)).collect(),
+            };
+            let name = match format {
+                dwarf::DwarfFrameFormat::EhFrame => ".eh_frame",
+                dwarf::DwarfFrameFormat::DebugFrame => ".debug_frame",
+            };
+            let section = Symbol::intern(name);
+            writer.define_symbol(name, section, writer.section_size(name), false);
+            table.write(writer.section(name), section, compiler.machine(), context.mode)?;
+        }
+
+        if let Some(debug) = debug {
+            let unit = dwarf::DwarfCompileUnit {
+                producer: debug.producer,
+                name: debug.name,
+                comp_dir: debug.comp_dir,
+                address_size,
+                big_endian,
+                subprograms: self.functions.iter().zip(&encoded).map(|(def, encoded)| dwarf::DwarfSubprogram::new(def, encoded)).collect(),
+            };
+
+            let debug_abbrev = Symbol::intern(".debug_abbrev");
+            let debug_line = Symbol::intern(".debug_line");
+            writer.define_symbol(".debug_abbrev", debug_abbrev, writer.section_size(".debug_abbrev"), false);
+            unit.write_debug_abbrev(writer.section(".debug_abbrev"))?;
+            writer.define_symbol(".debug_line", debug_line, writer.section_size(".debug_line"), false);
+            unit.write_debug_line(writer.section(".debug_line"))?;
+            unit.write_debug_info(writer.section(".debug_info"), debug_abbrev, debug_line)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits `block` at each [`XvaStatement::Label`], pushing the resulting blocks to `dest`.
+/// Control falls through from each piece into the next
+fn split_local_labels(dest: &mut Vec<XvaBasicBlock>, block: XvaBasicBlock) {
+    let XvaBasicBlock { mut label, mut live_at_start, body } = block;
+
+    match body {
+        XvaBlockBody::Statement(stmts) => {
+            let mut cur = Vec::with_capacity(stmts.len());
+            for stmt in stmts {
+                match stmt {
+                    XvaStatement::Label(next) => {
+                        dest.push(XvaBasicBlock { label, live_at_start: core::mem::take(&mut live_at_start), body: XvaBlockBody::Statement(core::mem::take(&mut cur)) });
+                        label = next;
+                    }
+                    stmt => cur.push(stmt),
+                }
             }
+
+            dest.push(XvaBasicBlock { label, live_at_start, body: XvaBlockBody::Statement(cur) });
         }
     }
 }
@@ -788,6 +1409,16 @@ pub struct XvaFunctionDef {
     pub linkage: Linkage,
     pub label: Symbol,
     pub section: XvaSection,
+    pub debug: Option<XvaDebugInfo>,
+}
+
+/// Debug information for a function, used to emit the function's subprogram entry
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct XvaDebugInfo {
+    /// The source-level name of the function
+    pub name: Symbol,
+    /// The location of the function's declaration
+    pub decl: XvaSourceLoc,
 }
 
 impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaFunctionDef> {
@@ -797,11 +1428,16 @@ impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaFunctionDef> {
             linkage,
             label,
             section,
+            debug,
         } = self.0;
         f.write_fmt(format_args!(
             "{linkage} function {label} (section {section}):\n"
         ))?;
 
+        if let Some(debug) = debug {
+            f.write_fmt(format_args!("\tdebug {} at {}\n", debug.name, debug.decl))?;
+        }
+
         PrettyPrinter(body, self.1, self.2).fmt(f)?;
 
         f.write_fmt(format_args!("end function {label}\n"))
@@ -817,6 +1453,8 @@ pub enum XvaSection {
     PrivateText,
     Common,
     TlsData,
+    /// Zero-initialized data, which has no body in the object file
+    Bss,
 }
 
 impl core::fmt::Display for Linkage {
@@ -839,6 +1477,7 @@ impl core::fmt::Display for XvaSection {
             XvaSection::PrivateText => f.write_str("private"),
             XvaSection::Common => f.write_str("common"),
             XvaSection::TlsData => f.write_str("tls data"),
+            XvaSection::Bss => f.write_str("bss"),
         }
     }
 }
@@ -883,9 +1522,17 @@ impl<'a> core::fmt::Display for PrettyPrinter<'a, XvaObjectDef> {
 pub struct XvaRelocation {
     pub offset: usize,
     pub addr: Address,
+    pub kind: RelocationKind,
 }
 
 use crate::fmt::PrettyPrinter;
 
+pub mod cfg;
+pub mod data;
+pub mod dataflow;
+pub mod dwarf;
 pub mod opt;
 pub mod regalloc;
+pub mod verify;
+
+pub use verify::verify;
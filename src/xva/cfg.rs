@@ -0,0 +1,549 @@
+//! The control-flow graph of an [`XvaFunction`], with dominator trees and natural loops.
+//!
+//! Blocks are referred to by their index in [`XvaFunction::body`]. The first block is the entry of the function.
+//! Analyses are cached by [`CfgCache`], which must be invalidated when a pass changes the blocks or terminators of a function.
+use std::collections::HashMap;
+
+use crate::{
+    intern::Symbol, xva::{XvaFunction, XvaStatement, for_each_stmt}
+};
+
+/// The successors and predecessors of each block of a function
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XvaCfg {
+    succs: Vec<Vec<usize>>,
+    preds: Vec<Vec<usize>>,
+    labels: HashMap<Symbol, usize>,
+    rpo: Vec<usize>,
+}
+
+/// Whether control never continues from `stmt` to the statement after it
+pub fn is_terminator(stmt: &XvaStatement) -> bool {
+    matches!(stmt, XvaStatement::Jump(_) | XvaStatement::Fallthrough(_) | XvaStatement::Return | XvaStatement::Tailcall { .. } | XvaStatement::Trap(_))
+}
+
+impl XvaCfg {
+    /// Computes the graph of `func`.
+    ///
+    /// The edges of a block come from its [`XvaStatement::Jump`], [`XvaStatement::JumpIf`] and [`XvaStatement::Fallthrough`] statements.
+    /// A block whose last statement is not a terminator (see [`is_terminator`]) falls through into the next block
+    pub fn new(func: &XvaFunction) -> Self {
+        let len = func.body.len();
+        let mut labels = HashMap::new();
+
+        for (n, block) in func.body.iter().enumerate() {
+            labels.insert(block.label, n);
+            for_each_stmt(block.stmts(), &mut |_, stmt| {
+                if let XvaStatement::Label(label) = stmt {
+                    labels.insert(*label, n);
+                }
+            });
+        }
+
+        let mut succs = vec![Vec::new(); len];
+        let mut preds = vec![Vec::new(); len];
+
+        for (n, block) in func.body.iter().enumerate() {
+            let mut last = None;
+            for_each_stmt(block.stmts(), &mut |_, stmt| {
+                if let XvaStatement::Jump(target) | XvaStatement::JumpIf(_, target) | XvaStatement::Fallthrough(target) = stmt
+                    && let Some(&target) = labels.get(target)
+                    && !succs[n].contains(&target)
+                {
+                    succs[n].push(target);
+                }
+                last = Some(stmt);
+            });
+
+            if !last.is_some_and(is_terminator) && n + 1 < len && !succs[n].contains(&(n + 1)) {
+                succs[n].push(n + 1);
+            }
+        }
+
+        for (n, block_succs) in succs.iter().enumerate() {
+            for &succ in block_succs {
+                preds[succ].push(n);
+            }
+        }
+
+        let rpo = if len == 0 { Vec::new() } else { reverse_post_order(&[0], &succs) };
+
+        Self { succs, preds, labels, rpo }
+    }
+
+    /// The number of blocks in the function
+    pub fn len(&self) -> usize {
+        self.succs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.succs.is_empty()
+    }
+
+    pub fn successors(&self, block: usize) -> &[usize] {
+        &self.succs[block]
+    }
+
+    pub fn predecessors(&self, block: usize) -> &[usize] {
+        &self.preds[block]
+    }
+
+    /// The block that defines `label`, either as the label of the block or with an [`XvaStatement::Label`] in it
+    pub fn block_of(&self, label: Symbol) -> Option<usize> {
+        self.labels.get(&label).copied()
+    }
+
+    /// The blocks reachable from the entry, in reverse post-order
+    pub fn reverse_post_order(&self) -> &[usize] {
+        &self.rpo
+    }
+
+    pub fn is_reachable(&self, block: usize) -> bool {
+        self.rpo.contains(&block)
+    }
+
+    /// The blocks that leave the function, which are the blocks without successors
+    pub fn exits(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len()).filter(|&n| self.succs[n].is_empty())
+    }
+}
+
+/// The reverse post-order of the nodes reachable from `roots` in the graph given by `succs`
+fn reverse_post_order(roots: &[usize], succs: &[Vec<usize>]) -> Vec<usize> {
+    let mut visited = vec![false; succs.len()];
+    let mut order = Vec::with_capacity(succs.len());
+
+    for &root in roots {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        let mut stack = vec![(root, 0)];
+
+        while let Some((node, next)) = stack.last_mut() {
+            if let Some(&succ) = succs[*node].get(*next) {
+                *next += 1;
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                order.push(*node);
+                stack.pop();
+            }
+        }
+    }
+
+    order.reverse();
+    order
+}
+
+/// A dominator (or post-dominator) tree
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DomTree {
+    idom: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+}
+
+impl DomTree {
+    /// The dominator tree of `cfg`, rooted at the entry block
+    pub fn dominators(cfg: &XvaCfg) -> Self {
+        if cfg.is_empty() {
+            return Self { idom: Vec::new(), children: Vec::new() };
+        }
+        Self::build(&[0], &cfg.succs, &cfg.preds)
+    }
+
+    /// The post-dominator tree of `cfg`, whose roots are the exits of the function.
+    /// Blocks that cannot reach an exit, such as those in an infinite loop, have no post-dominator
+    pub fn post_dominators(cfg: &XvaCfg) -> Self {
+        let exits: Vec<_> = cfg.exits().collect();
+        Self::build(&exits, &cfg.preds, &cfg.succs)
+    }
+
+    /// Computes the tree using the algorithm of Cooper, Harvey, and Kennedy.
+    /// The roots are treated as the successors of a virtual root node, which is removed from the result
+    fn build(roots: &[usize], succs: &[Vec<usize>], preds: &[Vec<usize>]) -> Self {
+        let len = succs.len();
+        let virt = len;
+
+        let mut graph = succs.to_vec();
+        graph.push(roots.to_vec());
+        let rpo = reverse_post_order(&[virt], &graph);
+
+        let mut rpo_index = vec![usize::MAX; len + 1];
+        for (i, &n) in rpo.iter().enumerate() {
+            rpo_index[n] = i;
+        }
+
+        let mut idom = vec![None; len + 1];
+        idom[virt] = Some(virt);
+
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while rpo_index[a] > rpo_index[b] {
+                    a = idom[a].unwrap();
+                }
+                while rpo_index[b] > rpo_index[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &n in &rpo[1..] {
+                let virt_pred = roots.contains(&n).then_some(virt);
+                let mut new_idom = None;
+                for pred in preds[n].iter().copied().chain(virt_pred) {
+                    if idom[pred].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(cur) => intersect(&idom, cur, pred),
+                    });
+                }
+
+                if new_idom.is_some() && idom[n] != new_idom {
+                    idom[n] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        idom.truncate(len);
+        for dom in &mut idom {
+            if *dom == Some(virt) {
+                *dom = None;
+            }
+        }
+
+        let mut children = vec![Vec::new(); len];
+        for (n, dom) in idom.iter().enumerate() {
+            if let Some(dom) = *dom {
+                children[dom].push(n);
+            }
+        }
+
+        Self { idom, children }
+    }
+
+    /// The immediate dominator of `block`, or [`None`] for a root of the tree or an unreachable block
+    pub fn idom(&self, block: usize) -> Option<usize> {
+        self.idom[block]
+    }
+
+    /// The blocks immediately dominated by `block`
+    pub fn children(&self, block: usize) -> &[usize] {
+        &self.children[block]
+    }
+
+    /// Whether `a` dominates `b`. Every block dominates itself
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        let mut cur = Some(b);
+        while let Some(n) = cur {
+            if n == a {
+                return true;
+            }
+            cur = self.idom[n];
+        }
+        false
+    }
+}
+
+/// A natural loop, made up of the blocks that can reach a back edge to the header without passing through the header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NaturalLoop {
+    pub header: usize,
+    /// The blocks with a back edge to the header
+    pub latches: Vec<usize>,
+    /// The blocks of the loop, including the header, in ascending order
+    pub blocks: Vec<usize>,
+    /// The index of the innermost loop that contains this one, in [`LoopInfo::loops`]
+    pub parent: Option<usize>,
+}
+
+impl NaturalLoop {
+    pub fn contains(&self, block: usize) -> bool {
+        self.blocks.binary_search(&block).is_ok()
+    }
+
+    /// The blocks outside of the loop that are successors of a block in the loop
+    pub fn exits(&self, cfg: &XvaCfg) -> Vec<usize> {
+        let mut exits = Vec::new();
+        for &n in &self.blocks {
+            for &succ in cfg.successors(n) {
+                if !self.contains(succ) && !exits.contains(&succ) {
+                    exits.push(succ);
+                }
+            }
+        }
+        exits
+    }
+}
+
+/// The natural loops of a function
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoopInfo {
+    /// The loops, with each loop after all of the loops it contains
+    pub loops: Vec<NaturalLoop>,
+    innermost: Vec<Option<usize>>,
+}
+
+impl LoopInfo {
+    /// Finds the loops of `cfg`. Back edges to the same header are merged into a single loop
+    pub fn new(cfg: &XvaCfg, doms: &DomTree) -> Self {
+        let mut headers: Vec<(usize, Vec<usize>)> = Vec::new();
+        for &n in cfg.reverse_post_order() {
+            for &succ in cfg.successors(n) {
+                if doms.dominates(succ, n) {
+                    match headers.iter_mut().find(|(header, _)| *header == succ) {
+                        Some((_, latches)) => latches.push(n),
+                        None => headers.push((succ, vec![n])),
+                    }
+                }
+            }
+        }
+
+        let mut loops: Vec<NaturalLoop> = headers
+            .into_iter()
+            .map(|(header, latches)| {
+                let mut in_loop = vec![false; cfg.len()];
+                in_loop[header] = true;
+                let mut stack = latches.clone();
+                while let Some(n) = stack.pop() {
+                    if !in_loop[n] {
+                        in_loop[n] = true;
+                        stack.extend(cfg.predecessors(n).iter().copied());
+                    }
+                }
+
+                let blocks = (0..cfg.len()).filter(|&n| in_loop[n]).collect();
+                NaturalLoop { header, latches, blocks, parent: None }
+            })
+            .collect();
+
+        // Inner loops are smaller than the loops containing them
+        loops.sort_by_key(|l| l.blocks.len());
+
+        for i in 0..loops.len() {
+            let header = loops[i].header;
+            loops[i].parent = (i + 1..loops.len()).find(|&j| loops[j].contains(header));
+        }
+
+        let mut innermost = vec![None; cfg.len()];
+        for (i, l) in loops.iter().enumerate() {
+            for &n in &l.blocks {
+                innermost[n].get_or_insert(i);
+            }
+        }
+
+        Self { loops, innermost }
+    }
+
+    /// The index of the innermost loop containing `block`
+    pub fn innermost(&self, block: usize) -> Option<usize> {
+        self.innermost[block]
+    }
+
+    /// The number of loops containing `block`
+    pub fn depth(&self, block: usize) -> usize {
+        let mut depth = 0;
+        let mut cur = self.innermost[block];
+        while let Some(l) = cur {
+            depth += 1;
+            cur = self.loops[l].parent;
+        }
+        depth
+    }
+}
+
+/// The analyses of a function computed by [`CfgCache`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CfgInfo {
+    pub cfg: XvaCfg,
+    pub dominators: DomTree,
+    pub post_dominators: DomTree,
+    pub loops: LoopInfo,
+}
+
+impl CfgInfo {
+    pub fn new(func: &XvaFunction) -> Self {
+        let cfg = XvaCfg::new(func);
+        let dominators = DomTree::dominators(&cfg);
+        let post_dominators = DomTree::post_dominators(&cfg);
+        let loops = LoopInfo::new(&cfg, &dominators);
+        Self { cfg, dominators, post_dominators, loops }
+    }
+}
+
+/// Caches the [`CfgInfo`] of a function between passes.
+///
+/// A pass that adds, removes, or reorders blocks, or changes their terminators, must call [`CfgCache::invalidate`] before the info is used again
+#[derive(Clone, Debug, Default)]
+pub struct CfgCache {
+    info: Option<CfgInfo>,
+}
+
+impl CfgCache {
+    pub const fn new() -> Self {
+        Self { info: None }
+    }
+
+    /// The info of `func`, which is computed if it is not cached
+    pub fn get(&mut self, func: &XvaFunction) -> &CfgInfo {
+        self.info.get_or_insert_with(|| CfgInfo::new(func))
+    }
+
+    pub fn invalidate(&mut self) {
+        self.info = None;
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.info.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mach::Regset,
+        xva::{XvaBasicBlock, XvaCategory, XvaDest, XvaFrameProperties, XvaRegister, XvaType},
+    };
+
+    fn block(label: &str, stmts: Vec<XvaStatement>) -> XvaBasicBlock {
+        XvaBasicBlock { label: Symbol::intern(label), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }
+    }
+
+    fn function(body: Vec<XvaBasicBlock>) -> XvaFunction {
+        XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body,
+            frame_properties: XvaFrameProperties::new(),
+        }
+    }
+
+    fn jump(label: &str) -> XvaStatement {
+        XvaStatement::Jump(Symbol::intern(label))
+    }
+
+    /// entry -> body -> exit, with an unreachable block that jumps into the middle of body
+    fn chain() -> XvaFunction {
+        function(vec![
+            block("entry", vec![XvaStatement::Fallthrough(Symbol::intern("body"))]),
+            block("body", vec![XvaStatement::Label(Symbol::intern("mid")), XvaStatement::Elaborated(vec![jump("exit")])]),
+            block("dead", vec![jump("mid")]),
+            block("exit", vec![XvaStatement::Return]),
+        ])
+    }
+
+    /// entry falls into a loop of head and latch, which never exits
+    fn infinite_loop() -> XvaFunction {
+        function(vec![
+            block("entry", vec![]),
+            block("head", vec![jump("latch")]),
+            block("exit", vec![XvaStatement::Return]),
+            block("latch", vec![jump("head")]),
+        ])
+    }
+
+    #[test]
+    fn edges_follow_jumps_and_fallthrough() {
+        let cfg = XvaCfg::new(&chain());
+        assert_eq!(cfg.len(), 4);
+        assert_eq!(cfg.successors(0), [1]);
+        assert_eq!(cfg.successors(1), [3]);
+        assert_eq!(cfg.successors(2), [1]);
+        assert_eq!(cfg.predecessors(1), [0, 2]);
+        assert_eq!(cfg.block_of(Symbol::intern("mid")), Some(1));
+        assert_eq!(cfg.block_of(Symbol::intern("missing")), None);
+
+        assert_eq!(cfg.reverse_post_order(), [0, 1, 3]);
+        assert!(!cfg.is_reachable(2));
+        assert_eq!(cfg.exits().collect::<Vec<_>>(), [3]);
+
+        // A block without a terminator falls into the next one
+        let cfg = XvaCfg::new(&infinite_loop());
+        assert_eq!(cfg.successors(0), [1]);
+        assert_eq!(cfg.successors(3), [1]);
+    }
+
+    #[test]
+    fn dominator_trees() {
+        let cfg = XvaCfg::new(&chain());
+        let doms = DomTree::dominators(&cfg);
+        assert_eq!((doms.idom(0), doms.idom(1), doms.idom(3)), (None, Some(0), Some(1)));
+        assert_eq!(doms.idom(2), None);
+        assert_eq!(doms.children(0), [1]);
+        assert!(doms.dominates(0, 3));
+        assert!(doms.dominates(1, 1));
+        assert!(!doms.dominates(3, 1));
+
+        let pdoms = DomTree::post_dominators(&cfg);
+        assert_eq!((pdoms.idom(0), pdoms.idom(1), pdoms.idom(2), pdoms.idom(3)), (Some(1), Some(3), Some(1), None));
+        assert!(pdoms.dominates(3, 0));
+    }
+
+    #[test]
+    fn blocks_that_never_exit_have_no_post_dominator() {
+        let cfg = XvaCfg::new(&infinite_loop());
+        let pdoms = DomTree::post_dominators(&cfg);
+        assert_eq!([0, 1, 3].map(|n| pdoms.idom(n)), [None; 3]);
+        assert!(!pdoms.dominates(2, 1));
+    }
+
+    #[test]
+    fn natural_loops() {
+        let cfg = XvaCfg::new(&infinite_loop());
+        let loops = LoopInfo::new(&cfg, &DomTree::dominators(&cfg));
+        assert_eq!(loops.loops, [NaturalLoop { header: 1, latches: vec![3], blocks: vec![1, 3], parent: None }]);
+        assert_eq!(loops.innermost(3), Some(0));
+        assert_eq!((loops.depth(0), loops.depth(1), loops.depth(3)), (0, 1, 1));
+        assert!(loops.loops[0].exits(&cfg).is_empty());
+
+        let cfg = XvaCfg::new(&chain());
+        assert!(LoopInfo::new(&cfg, &DomTree::dominators(&cfg)).loops.is_empty());
+    }
+
+    #[test]
+    fn cache_is_computed_once_until_invalidated() {
+        let func = chain();
+        let mut cache = CfgCache::new();
+        assert!(!cache.is_valid());
+        assert_eq!(cache.get(&func).cfg.successors(0), [1]);
+        assert!(cache.is_valid());
+
+        // The cached info is returned even though the function changed
+        let looping = infinite_loop();
+        assert_eq!(cache.get(&looping).cfg.successors(2), [1]);
+        cache.invalidate();
+        assert!(cache.get(&looping).cfg.successors(2).is_empty());
+    }
+
+    #[test]
+    fn conditional_jumps_also_fall_through() {
+        let cond = XvaRegister::Virtual(XvaDest { id: 0, ty: XvaType { size: 1, align: 1, category: XvaCategory::Condition } });
+        let func = function(vec![
+            block("entry", vec![XvaStatement::JumpIf(cond, Symbol::intern("other"))]),
+            block("then", vec![jump("join")]),
+            block("other", vec![]),
+            block("join", vec![XvaStatement::Return]),
+        ]);
+        let cfg = XvaCfg::new(&func);
+        assert_eq!(cfg.successors(0), [2, 1]);
+        assert_eq!(cfg.predecessors(3), [1, 2]);
+
+        let doms = DomTree::dominators(&cfg);
+        assert_eq!(doms.idom(3), Some(0));
+        let pdoms = DomTree::post_dominators(&cfg);
+        assert_eq!(pdoms.idom(0), Some(3));
+    }
+}
@@ -0,0 +1,232 @@
+//! Builder for the bodies of static data objects
+use core::num::NonZeroI64;
+
+use crate::{
+    compiler::RelocationModel, instr::{Address, AddressKind, RelocSym}, intern::Symbol, reloc::{RelocSpan, RelocationKind}, xva::{Linkage, XvaCategory, XvaObjectDef, XvaRelocation, XvaSection, XvaType}
+};
+
+/// Builds the body of an [`XvaObjectDef`] in the byte order and pointer width of the target
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct XvaDataBuilder {
+    body: Vec<u8>,
+    relocs: Vec<XvaRelocation>,
+    big_endian: bool,
+    ptr_size: u8,
+    align: u64,
+    relocation_model: RelocationModel,
+}
+
+impl XvaDataBuilder {
+    /// Creates an empty builder. `ptr_size` is the size of a pointer on the target, in bytes
+    pub const fn new(big_endian: bool, ptr_size: u8) -> Self {
+        Self { body: Vec::new(), relocs: Vec::new(), big_endian, ptr_size, align: 1, relocation_model: RelocationModel::Static }
+    }
+
+    /// Sets the relocation model of the code the object is built for, which is [`RelocationModel::Static`] by default
+    pub fn relocation_model(&mut self, model: RelocationModel) -> &mut Self {
+        self.relocation_model = model;
+        self
+    }
+
+    /// The number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.body.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.body.is_empty()
+    }
+
+    /// Appends the low `width` bytes of `val`
+    pub fn uint(&mut self, val: u128, width: usize) -> &mut Self {
+        assert!(width <= 16, "Integer of {width} bytes is too wide");
+        if self.big_endian {
+            self.body.extend_from_slice(&val.to_be_bytes()[16 - width..]);
+        } else {
+            self.body.extend_from_slice(&val.to_le_bytes()[..width]);
+        }
+        self
+    }
+
+    /// Appends `val` as a two's complement integer of `width` bytes
+    pub fn int(&mut self, val: i128, width: usize) -> &mut Self {
+        self.uint(val as u128, width)
+    }
+
+    pub fn bytes(&mut self, val: &[u8]) -> &mut Self {
+        self.body.extend_from_slice(val);
+        self
+    }
+
+    /// Appends `val` followed by a nul terminator
+    pub fn string(&mut self, val: &str) -> &mut Self {
+        self.body.extend_from_slice(val.as_bytes());
+        self.body.push(0);
+        self
+    }
+
+    /// Appends `len` zero bytes
+    pub fn zeroes(&mut self, len: usize) -> &mut Self {
+        self.body.resize(self.body.len() + len, 0);
+        self
+    }
+
+    /// Pads with zeroes to a multiple of `align`, and raises the alignment of the object to at least `align`
+    pub fn align_to(&mut self, align: u64) -> &mut Self {
+        assert!(align.is_power_of_two(), "Alignment must be a power of two");
+        self.align = self.align.max(align);
+        let len = (self.body.len() as u64).next_multiple_of(align) as usize;
+        self.body.resize(len, 0);
+        self
+    }
+
+    /// Appends a pointer to `sym` plus `addend`, which is an absolute relocation as wide as a pointer.
+    ///
+    /// The value of the pointer is the address of the symbol, so the relocation stays absolute for position-independent code, and is applied by the dynamic linker.
+    /// Use [`Self::relative`] or [`Self::got_relative`] for data that must not need dynamic relocations
+    pub fn pointer(&mut self, sym: Symbol, addend: i64) -> &mut Self {
+        let width = self.ptr_size;
+        self.reloc(sym, addend, RelocationKind::Absolute(RelocSpan { byte_width: width, bit_width: width * 8, ..RelocSpan::new() }))
+    }
+
+    /// Appends the offset of `sym` plus `addend` from the start of the value itself, as a pc-relative relocation of `width` bytes
+    pub fn relative(&mut self, sym: Symbol, addend: i64, width: u8) -> &mut Self {
+        self.reloc(sym, addend, RelocationKind::Pcrel(RelocSpan { byte_width: width, bit_width: width * 8, ..RelocSpan::new() }))
+    }
+
+    /// Appends the offset of the GOT entry of `sym` from the start of the value itself, as a relocation of `width` bytes.
+    /// This refers to a symbol that may be defined in another module without a dynamic relocation in the object
+    pub fn got_relative(&mut self, sym: Symbol, width: u8) -> &mut Self {
+        self.reloc(sym, 0, RelocationKind::GotPcrel(RelocSpan { byte_width: width, bit_width: width * 8, ..RelocSpan::new() }))
+    }
+
+    /// Appends a relocation of the given `kind` to `sym` plus `addend`.
+    /// The space for the relocation, which is the byte width of the `kind`'s [`RelocSpan`], is filled with zeroes
+    pub fn reloc(&mut self, sym: Symbol, addend: i64, kind: RelocationKind) -> &mut Self {
+        let width = match kind {
+            RelocationKind::Absolute(span)
+            | RelocationKind::Pcrel(span)
+            | RelocationKind::GotPcrel(span)
+            | RelocationKind::Plt(span)
+            | RelocationKind::GotDisp(span)
+            | RelocationKind::Tpoff(span)
+            | RelocationKind::GottpOff(span)
+            | RelocationKind::TlsGd(span)
+            | RelocationKind::TlsLd(span)
+            | RelocationKind::ImageRelative(span) => span.byte_width as usize,
+            kind => panic!("Relocation {kind:?} cannot be used in data"),
+        };
+
+        let offset = self.body.len();
+        self.zeroes(width);
+        self.relocs.push(XvaRelocation {
+            offset,
+            addr: Address { segment: None, base: None, index: None, scale: nzlit!(1), sym: Some(RelocSym { sym, kind: AddressKind::Default }), disp: NonZeroI64::new(addend), rel: false },
+            kind,
+        });
+        self
+    }
+
+    /// Finishes the object.
+    ///
+    /// An object in [`XvaSection::Data`] whose body is entirely zero is placed in [`XvaSection::Bss`] instead, and has no body.
+    /// For position-independent code, an object in [`XvaSection::RoData`] with absolute relocations is placed in [`XvaSection::Data`] instead, since the dynamic linker writes to it
+    pub fn build(self, label: Symbol, linkage: Linkage, section: XvaSection) -> XvaObjectDef {
+        let ty = XvaType { size: self.body.len() as u64, align: self.align, category: XvaCategory::Aggregate };
+        let zeroed = self.relocs.is_empty() && self.body.iter().all(|&b| b == 0);
+        let dynamic_relocs = self.relocation_model.is_pic() && self.relocs.iter().any(|reloc| matches!(reloc.kind, RelocationKind::Absolute(_)));
+
+        let (section, body) = match section {
+            XvaSection::Data if zeroed => (XvaSection::Bss, Vec::new()),
+            XvaSection::RoData if dynamic_relocs => (XvaSection::Data, self.body),
+            section => (section, self.body),
+        };
+
+        XvaObjectDef { ty, body, relocs: self.relocs, linkage, label, section }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(width: u8) -> RelocSpan {
+        RelocSpan { byte_width: width, bit_width: width * 8, ..RelocSpan::new() }
+    }
+
+    fn reloc(offset: usize, sym: &str, addend: i64, kind: RelocationKind) -> XvaRelocation {
+        let addr = Address { segment: None, base: None, index: None, scale: nzlit!(1), sym: Some(RelocSym { sym: Symbol::intern(sym), kind: AddressKind::Default }), disp: NonZeroI64::new(addend), rel: false };
+        XvaRelocation { offset, addr, kind }
+    }
+
+    #[test]
+    fn writes_scalars_in_target_byte_order() {
+        let mut data = XvaDataBuilder::new(false, 8);
+        data.uint(0x1234, 2).int(-2, 4).string("hi");
+        assert_eq!(data.len(), 9);
+        let obj = data.build(Symbol::intern("x"), Linkage::Internal, XvaSection::RoData);
+        assert_eq!(obj.body, [0x34, 0x12, 0xfe, 0xff, 0xff, 0xff, b'h', b'i', 0]);
+        assert_eq!(obj.ty, XvaType { size: 9, align: 1, category: XvaCategory::Aggregate });
+
+        let mut data = XvaDataBuilder::new(true, 4);
+        data.uint(0x1234, 2).int(-2, 4);
+        let obj = data.build(Symbol::intern("x"), Linkage::Internal, XvaSection::RoData);
+        assert_eq!(obj.body, [0x12, 0x34, 0xff, 0xff, 0xff, 0xfe]);
+    }
+
+    #[test]
+    fn pointers_are_relocated() {
+        let mut data = XvaDataBuilder::new(false, 8);
+        data.uint(1, 1).align_to(8).pointer(Symbol::intern("target"), 4).reloc(Symbol::intern("other"), -4, RelocationKind::Pcrel(span(4)));
+        let obj = data.build(Symbol::intern("x"), Linkage::External, XvaSection::Data);
+
+        // The relocations keep the object in .data even though its body is zero apart from the first byte
+        assert_eq!(obj.section, XvaSection::Data);
+        let mut body = vec![0; 20];
+        body[0] = 1;
+        assert_eq!(obj.body, body);
+        assert_eq!(obj.ty, XvaType { size: 20, align: 8, category: XvaCategory::Aggregate });
+        assert_eq!(obj.relocs, [
+            reloc(8, "target", 4, RelocationKind::Absolute(span(8))),
+            reloc(16, "other", -4, RelocationKind::Pcrel(span(4))),
+        ]);
+    }
+
+    #[test]
+    fn pic_keeps_dynamic_relocations_out_of_rodata() {
+        let mut data = XvaDataBuilder::new(false, 8);
+        data.relocation_model(RelocationModel::Pic).relative(Symbol::intern("local"), 0, 4).got_relative(Symbol::intern("extern"), 4);
+        let obj = data.clone().build(Symbol::intern("x"), Linkage::Internal, XvaSection::RoData);
+        // Relative relocations are resolved by the static linker
+        assert_eq!(obj.section, XvaSection::RoData);
+        assert_eq!(obj.relocs, [
+            reloc(0, "local", 0, RelocationKind::Pcrel(span(4))),
+            reloc(4, "extern", 0, RelocationKind::GotPcrel(span(4))),
+        ]);
+
+        data.pointer(Symbol::intern("local"), 0);
+        let obj = data.build(Symbol::intern("x"), Linkage::Internal, XvaSection::RoData);
+        assert_eq!(obj.section, XvaSection::Data);
+    }
+
+    #[test]
+    fn zeroed_data_goes_in_bss() {
+        let mut data = XvaDataBuilder::new(false, 8);
+        data.zeroes(12).align_to(16);
+        let obj = data.clone().build(Symbol::intern("x"), Linkage::Internal, XvaSection::Data);
+        assert_eq!(obj.section, XvaSection::Bss);
+        assert!(obj.body.is_empty());
+        assert_eq!(obj.ty, XvaType { size: 16, align: 16, category: XvaCategory::Aggregate });
+
+        // Only writable data is moved
+        let obj = data.build(Symbol::intern("x"), Linkage::Internal, XvaSection::RoData);
+        assert_eq!(obj.section, XvaSection::RoData);
+        assert_eq!(obj.body, [0; 16]);
+    }
+
+    #[test]
+    #[should_panic = "Alignment must be a power of two"]
+    fn rejects_unaligned_alignment() {
+        XvaDataBuilder::new(false, 8).align_to(12);
+    }
+}
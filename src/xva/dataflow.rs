@@ -0,0 +1,569 @@
+//! A generic solver for dataflow problems over the [`XvaCfg`], and the register liveness analysis built on it
+use std::collections::VecDeque;
+
+use crate::{
+    instr::Operand, mach::{Machine, MachineMode, Regset}, xva::{self, cfg::XvaCfg, UseKind, XvaBasicBlock, XvaFunction, XvaOpcode, XvaOperand, XvaRegister, XvaStatement}
+};
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Direction {
+    /// Facts flow from the entry of the function along control flow
+    Forward,
+    /// Facts flow from the exits of the function against control flow
+    Backward,
+}
+
+/// A dataflow problem over a lattice of facts, solved by [`solve`]
+pub trait Dataflow {
+    type Fact: Clone + PartialEq;
+
+    fn direction(&self) -> Direction;
+
+    /// The fact at the start of the entry block for a forward problem, or at the end of blocks without successors for a backward problem
+    fn boundary(&self) -> Self::Fact;
+
+    /// The fact every other block starts with, which must be the identity of [`Self::join`]
+    fn init(&self) -> Self::Fact;
+
+    /// Combines `other`, the fact from another edge, into `fact`
+    fn join(&self, fact: &mut Self::Fact, other: &Self::Fact);
+
+    /// Applies the effect of `stmt` to `fact`. For a backward problem, statements are visited in reverse order
+    fn transfer(&self, stmt: &XvaStatement, fact: &mut Self::Fact);
+}
+
+/// The solution of a [`Dataflow`] problem
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataflowResult<F> {
+    /// The fact at the start of each block, before its first statement
+    pub block_start: Vec<F>,
+    /// The fact at the end of each block, after its last statement
+    pub block_end: Vec<F>,
+}
+
+/// The statements of `block` in order, looking into [`XvaStatement::Elaborated`]
+fn block_stmts(block: &XvaBasicBlock) -> Vec<&XvaStatement> {
+    let mut stmts = Vec::new();
+    xva::for_each_stmt(block.stmts(), &mut |_, stmt| stmts.push(stmt));
+    stmts
+}
+
+/// Solves `analysis` over `func` with a worklist, iterating until the facts of every block stop changing
+pub fn solve<D: Dataflow>(analysis: &D, func: &XvaFunction, cfg: &XvaCfg) -> DataflowResult<D::Fact> {
+    let len = cfg.len();
+    let mut block_start = vec![analysis.init(); len];
+    let mut block_end = vec![analysis.init(); len];
+
+    let stmts: Vec<_> = func.body.iter().map(block_stmts).collect();
+
+    // Visit blocks in an order where most inputs are computed before they are needed
+    let mut order: Vec<usize> = cfg.reverse_post_order().to_vec();
+    order.extend((0..len).filter(|n| !cfg.is_reachable(*n)));
+    let direction = analysis.direction();
+    if direction == Direction::Backward {
+        order.reverse();
+    }
+
+    let mut queued = vec![true; len];
+    let mut worklist: VecDeque<usize> = order.into();
+
+    while let Some(n) = worklist.pop_front() {
+        queued[n] = false;
+
+        match direction {
+            Direction::Forward => {
+                let mut fact = if n == 0 { analysis.boundary() } else { analysis.init() };
+                for &pred in cfg.predecessors(n) {
+                    analysis.join(&mut fact, &block_end[pred]);
+                }
+                block_start[n] = fact.clone();
+
+                for stmt in &stmts[n] {
+                    analysis.transfer(stmt, &mut fact);
+                }
+
+                if fact != block_end[n] {
+                    block_end[n] = fact;
+                    for &succ in cfg.successors(n) {
+                        if !queued[succ] {
+                            queued[succ] = true;
+                            worklist.push_back(succ);
+                        }
+                    }
+                }
+            }
+            Direction::Backward => {
+                let mut fact = if cfg.successors(n).is_empty() { analysis.boundary() } else { analysis.init() };
+                for &succ in cfg.successors(n) {
+                    analysis.join(&mut fact, &block_start[succ]);
+                }
+                block_end[n] = fact.clone();
+
+                for stmt in stmts[n].iter().rev() {
+                    analysis.transfer(stmt, &mut fact);
+                }
+
+                if fact != block_start[n] {
+                    block_start[n] = fact;
+                    for &pred in cfg.predecessors(n) {
+                        if !queued[pred] {
+                            queued[pred] = true;
+                            worklist.push_back(pred);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    DataflowResult { block_start, block_end }
+}
+
+/// A set of live registers, which keeps the order registers were added in
+#[derive(Clone, Debug, Default, Hash)]
+pub struct LiveSet(Vec<XvaRegister>);
+
+impl LiveSet {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Whether `reg` itself is in the set
+    pub fn contains(&self, reg: XvaRegister) -> bool {
+        self.0.contains(&reg)
+    }
+
+    /// Whether `reg`, or a physical register that overlaps it, is live
+    pub fn is_live(&self, reg: XvaRegister, mach: &dyn Machine) -> bool {
+        match reg {
+            XvaRegister::Virtual(_) => self.contains(reg),
+            XvaRegister::Physical(r) => self.0.iter().any(|live| match *live {
+                XvaRegister::Physical(l) => l == r || mach.registers().register_overlaps(l, r),
+                XvaRegister::Virtual(_) => false,
+            }),
+        }
+    }
+
+    pub fn insert(&mut self, reg: XvaRegister) {
+        if !self.contains(reg) {
+            self.0.push(reg);
+        }
+    }
+
+    /// Removes the registers whose value is replaced by a write to `reg`.
+    /// For a physical register, this is every overlapping register that is no larger than `reg`, since a larger register keeps the rest of its value
+    pub fn kill(&mut self, reg: XvaRegister, mach: &dyn Machine, mode: MachineMode) {
+        match reg {
+            XvaRegister::Virtual(_) => self.0.retain(|&live| live != reg),
+            XvaRegister::Physical(r) => {
+                let regs = mach.registers();
+                let size = regs.register_size(r, mode);
+                self.0.retain(|live| match *live {
+                    XvaRegister::Physical(l) => !(l == r || (regs.register_overlaps(l, r) && regs.register_size(l, mode) <= size)),
+                    XvaRegister::Virtual(_) => true,
+                });
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = XvaRegister> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+impl PartialEq for LiveSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|reg| other.contains(reg))
+    }
+}
+
+impl Eq for LiveSet {}
+
+/// The registers that are live at each point of a function, which are those that may be read before they are next written
+pub struct Liveness<'a> {
+    mach: &'a dyn Machine,
+    mode: MachineMode,
+    return_regs: Regset,
+}
+
+impl<'a> Liveness<'a> {
+    pub fn new(func: &XvaFunction, mach: &'a dyn Machine, mode: MachineMode) -> Self {
+        Self { mach, mode, return_regs: func.return_regs }
+    }
+
+    fn physical(&self, regs: Regset) -> impl Iterator<Item = XvaRegister> + '_ {
+        regs.into_regids(self.mach, self.mode).map(XvaRegister::Physical)
+    }
+
+    fn operand(opr: &XvaOperand, uses: &mut Vec<XvaRegister>) {
+        if let XvaOperand::Register(reg) = opr {
+            uses.push(*reg);
+        }
+    }
+
+    /// Collects the registers written by `stmt` into `kills`, and the ones it reads into `uses`.
+    /// Returns true if nothing is live before `stmt` other than its uses
+    fn effects(&self, stmt: &XvaStatement, kills: &mut Vec<XvaRegister>, uses: &mut Vec<XvaRegister>) -> bool {
+        match stmt {
+            XvaStatement::Expr(expr) => {
+                match &expr.op {
+                    XvaOpcode::ZeroInit | XvaOpcode::Const(_) | XvaOpcode::Uninit | XvaOpcode::GetFrameAddr(_) | XvaOpcode::TlsIndex { .. } => {}
+                    XvaOpcode::Move(reg) => uses.push(*reg),
+                    XvaOpcode::ComputeAddr { base, index, .. } => {
+                        Self::operand(base, uses);
+                        Self::operand(index, uses);
+                    }
+                    XvaOpcode::BinaryOp { left, right, .. } | XvaOpcode::CheckedBinaryOp { left, right, .. } => {
+                        uses.push(*left);
+                        Self::operand(right, uses);
+                    }
+                    XvaOpcode::UnaryOp { left, .. } => uses.push(*left),
+                    XvaOpcode::Read(opr) => Self::operand(opr, uses),
+                    XvaOpcode::UMul { left, right } | XvaOpcode::SMul { left, right } | XvaOpcode::UDiv { left, right } | XvaOpcode::SDiv { left, right } => uses.extend([*left, *right]),
+                    XvaOpcode::Select { cond, left, right } => uses.extend([*cond, *left, *right]),
+                    XvaOpcode::TlsAddr { module_base, .. } => uses.extend(*module_base),
+                }
+                kills.push(expr.dest);
+                kills.extend(expr.dest2);
+            }
+            XvaStatement::Write(opr, _, reg) => {
+                Self::operand(opr, uses);
+                uses.push(*reg);
+            }
+            XvaStatement::JumpIf(cond, _) => uses.push(*cond),
+            XvaStatement::Call { dest, params, ret_val, call_clobber_regs, .. } => {
+                kills.extend(self.physical(*ret_val));
+                kills.extend(self.physical(*call_clobber_regs));
+                Self::operand(dest, uses);
+                uses.extend(self.physical(*params));
+            }
+            XvaStatement::Tailcall { dest, params } => {
+                Self::operand(dest, uses);
+                uses.extend(self.physical(*params));
+                return true;
+            }
+            XvaStatement::Return => {
+                uses.extend(self.physical(self.return_regs));
+                return true;
+            }
+            XvaStatement::Use(regs, UseKind::Read | UseKind::ReadWrite) => uses.extend(regs.iter().copied()),
+            XvaStatement::Use(regs, UseKind::Write) => kills.extend(regs.iter().copied()),
+            XvaStatement::InlineAsm(asm) => {
+                kills.extend(asm.outputs.iter().map(|opr| opr.reg));
+                kills.extend(self.physical(asm.clobbers));
+                uses.extend(asm.inputs.iter().map(|opr| opr.reg));
+            }
+            XvaStatement::RawInstr(instr) => {
+                for opr in instr.operands() {
+                    match opr {
+                        Operand::Register(reg) => uses.push(XvaRegister::Physical(*reg)),
+                        Operand::Memory(mem) => {
+                            let addr = &mem.addr;
+                            uses.extend([addr.segment, addr.base, addr.index].into_iter().flatten().map(XvaRegister::Physical));
+                        }
+                        Operand::AbsSymbol(_, _) | Operand::RelSymbol(_, _) | Operand::Immediate(_) | Operand::Placeholder(_) => {}
+                    }
+                }
+            }
+            XvaStatement::Jump(_)
+            | XvaStatement::Elaborated(_)
+            | XvaStatement::Trap(_)
+            | XvaStatement::OptGate(_, _)
+            | XvaStatement::EndOptGate(_)
+            | XvaStatement::Noop(_)
+            | XvaStatement::Fallthrough(_)
+            | XvaStatement::Loc(_)
+            | XvaStatement::Cfi(_)
+            | XvaStatement::Label(_) => {}
+        }
+        false
+    }
+
+    /// Solves liveness for `func`. The fact at the start of each block is the set of registers live into it
+    pub fn solve(&self, func: &XvaFunction, cfg: &XvaCfg) -> DataflowResult<LiveSet> {
+        solve(self, func, cfg)
+    }
+
+    /// The registers live after each top-level statement of `block`, given the registers live at the end of the block
+    pub fn live_out_per_statement(&self, block: &XvaBasicBlock, live_out: &LiveSet) -> Vec<LiveSet> {
+        let stmts = block.stmts();
+
+        let mut live = live_out.clone();
+        let mut result = vec![LiveSet::new(); stmts.len()];
+        for (n, stmt) in stmts.iter().enumerate().rev() {
+            result[n] = live.clone();
+            self.transfer(stmt, &mut live);
+        }
+
+        result
+    }
+}
+
+impl Dataflow for Liveness<'_> {
+    type Fact = LiveSet;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn boundary(&self) -> LiveSet {
+        LiveSet::new()
+    }
+
+    fn init(&self) -> LiveSet {
+        LiveSet::new()
+    }
+
+    fn join(&self, fact: &mut LiveSet, other: &LiveSet) {
+        for reg in other.iter() {
+            fact.insert(reg);
+        }
+    }
+
+    fn transfer(&self, stmt: &XvaStatement, fact: &mut LiveSet) {
+        if let XvaStatement::Elaborated(stmts) = stmt {
+            for stmt in stmts.iter().rev() {
+                self.transfer(stmt, fact);
+            }
+            return;
+        }
+
+        let mut kills = Vec::new();
+        let mut uses = Vec::new();
+        if self.effects(stmt, &mut kills, &mut uses) {
+            fact.clear();
+        }
+
+        for reg in kills {
+            fact.kill(reg, self.mach, self.mode);
+        }
+        for reg in uses {
+            fact.insert(reg);
+        }
+    }
+}
+
+/// Sets [`XvaBasicBlock::live_at_start`] of every block of `func` to the registers that are live into it
+pub fn compute_live_at_start(func: &mut XvaFunction, cfg: &XvaCfg, mach: &dyn Machine, mode: MachineMode) {
+    let result = Liveness::new(func, mach, mode).solve(func, cfg);
+
+    for (block, live) in func.body.iter_mut().zip(result.block_start) {
+        block.live_at_start = live.0;
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode},
+        intern::Symbol,
+        mach::Register,
+        traits::{IdType, IntoId},
+        xva::{BinaryOp, XvaCategory, XvaConst, XvaDest, XvaExpr, XvaFrameProperties, XvaType},
+    };
+
+    const I64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+
+    fn vreg(id: u32) -> XvaRegister {
+        XvaRegister::Virtual(XvaDest { id, ty: I64 })
+    }
+
+    fn preg(reg: crate::archs::x86::X86Register) -> XvaRegister {
+        XvaRegister::Physical(Register::new(reg))
+    }
+
+    fn expr(dest: XvaRegister, op: XvaOpcode) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest, dest2: None, op })
+    }
+
+    fn konst(dest: XvaRegister) -> XvaStatement {
+        expr(dest, XvaOpcode::Const(XvaConst::Bits(1)))
+    }
+
+    fn add(dest: XvaRegister, left: XvaRegister, right: XvaRegister) -> XvaStatement {
+        expr(dest, XvaOpcode::BinaryOp { op: BinaryOp::Add, left, right: XvaOperand::Register(right) })
+    }
+
+    fn block(label: &str, stmts: Vec<XvaStatement>) -> XvaBasicBlock {
+        XvaBasicBlock { label: Symbol::intern(label), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }
+    }
+
+    fn function(body: Vec<XvaBasicBlock>, return_regs: Regset) -> XvaFunction {
+        XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs,
+            prologue: Vec::new(),
+            body,
+            frame_properties: XvaFrameProperties::new(),
+        }
+    }
+
+    fn live(regs: &[XvaRegister]) -> LiveSet {
+        let mut set = LiveSet::new();
+        regs.iter().for_each(|&reg| set.insert(reg));
+        set
+    }
+
+    fn mode() -> MachineMode {
+        X86Mode::Long.into_id()
+    }
+
+    fn liveness(func: &XvaFunction) -> DataflowResult<LiveSet> {
+        Liveness::new(func, &X86, mode()).solve(func, &XvaCfg::new(func))
+    }
+
+    const RAX: crate::archs::x86::X86Register = crate::x86_register!(rax);
+
+    #[test]
+    fn registers_are_live_from_their_uses_back_to_their_definitions() {
+        let func = function(
+            vec![
+                block("entry", vec![konst(vreg(0)), konst(vreg(1)), XvaStatement::Fallthrough(Symbol::intern("body"))]),
+                block("body", vec![add(vreg(2), vreg(0), vreg(1)), XvaStatement::Jump(Symbol::intern("exit"))]),
+                block("exit", vec![expr(preg(RAX), XvaOpcode::Move(vreg(2))), XvaStatement::Return]),
+            ],
+            Regset::from_registers([RAX]),
+        );
+        let result = liveness(&func);
+        assert_eq!(result.block_start, [live(&[]), live(&[vreg(0), vreg(1)]), live(&[vreg(2)])]);
+        assert_eq!(result.block_end, [live(&[vreg(0), vreg(1)]), live(&[vreg(2)]), live(&[])]);
+
+        // Each statement sees the registers read after it
+        let per_stmt = Liveness::new(&func, &X86, mode()).live_out_per_statement(&func.body[2], &LiveSet::new());
+        assert_eq!(per_stmt, [live(&[preg(RAX)]), live(&[])]);
+    }
+
+    #[test]
+    fn loops_keep_registers_live_around_the_back_edge() {
+        // v0 is read in every iteration, so it is live at the end of the loop body
+        let func = function(
+            vec![
+                block("entry", vec![konst(vreg(0)), konst(vreg(1))]),
+                block("loop", vec![add(vreg(1), vreg(1), vreg(0)), XvaStatement::Jump(Symbol::intern("loop"))]),
+            ],
+            Regset::new(),
+        );
+        let result = liveness(&func);
+        assert_eq!(result.block_start[1], live(&[vreg(0), vreg(1)]));
+        assert_eq!(result.block_end[1], live(&[vreg(0), vreg(1)]));
+        assert_eq!(result.block_start[0], live(&[]));
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        let rdi = preg(crate::x86_register!(rdi));
+        let rcx = preg(crate::x86_register!(rcx));
+        let call = XvaStatement::Call {
+            dest: XvaOperand::Const(XvaConst::Global(Symbol::intern("g"), 0)),
+            params: Regset::from_registers([crate::x86_register!(rdi)]),
+            ret_val: Regset::from_registers([RAX]),
+            call_clobber_regs: Regset::from_registers([crate::x86_register!(rcx)]),
+            callee_pop: 0,
+            indirect_ret: false,
+        };
+        let func = function(vec![block("entry", vec![call.clone(), XvaStatement::Return])], Regset::from_registers([RAX]));
+        let analysis = Liveness::new(&func, &X86, mode());
+
+        // The call reads its parameters and replaces the return value and the clobbered registers
+        let mut fact = live(&[preg(RAX), rcx, vreg(0)]);
+        analysis.transfer(&call, &mut fact);
+        assert_eq!(fact, live(&[vreg(0), rdi]));
+
+        // Nothing is live before a return other than the return registers
+        let mut fact = live(&[vreg(0)]);
+        analysis.transfer(&XvaStatement::Return, &mut fact);
+        assert_eq!(fact, live(&[preg(RAX)]));
+    }
+
+    #[test]
+    fn writes_to_sub_registers_keep_the_full_register_live() {
+        let eax = preg(crate::x86_register!(eax));
+        let mut set = live(&[preg(RAX), eax]);
+        set.kill(eax, &X86, mode());
+        assert_eq!(set, live(&[preg(RAX)]));
+        assert!(set.is_live(eax, &X86));
+
+        set.insert(eax);
+        set.kill(preg(RAX), &X86, mode());
+        assert!(set.is_empty());
+
+        // The order registers were added in does not matter for equality
+        assert_eq!(live(&[vreg(0), vreg(1)]), live(&[vreg(1), vreg(0)]));
+    }
+
+    /// The virtual registers defined on some path to each point
+    struct MayDefine;
+
+    impl Dataflow for MayDefine {
+        type Fact = BTreeSet<u32>;
+
+        fn direction(&self) -> Direction {
+            Direction::Forward
+        }
+
+        fn boundary(&self) -> Self::Fact {
+            BTreeSet::new()
+        }
+
+        fn init(&self) -> Self::Fact {
+            BTreeSet::new()
+        }
+
+        fn join(&self, fact: &mut Self::Fact, other: &Self::Fact) {
+            fact.extend(other);
+        }
+
+        fn transfer(&self, stmt: &XvaStatement, fact: &mut Self::Fact) {
+            if let XvaStatement::Expr(XvaExpr { dest: XvaRegister::Virtual(dest), .. }) = stmt {
+                fact.insert(dest.id);
+            }
+        }
+    }
+
+    #[test]
+    fn forward_problems_reach_a_fixed_point() {
+        // The definition of v1 at the end of the loop flows back into its header
+        let func = function(
+            vec![
+                block("entry", vec![konst(vreg(0))]),
+                block("loop", vec![XvaStatement::Elaborated(vec![konst(vreg(2))]), konst(vreg(1)), XvaStatement::Jump(Symbol::intern("loop"))]),
+                block("dead", vec![konst(vreg(3))]),
+            ],
+            Regset::new(),
+        );
+        let result = solve(&MayDefine, &func, &XvaCfg::new(&func));
+        assert_eq!(result.block_start[1], BTreeSet::from([0, 1, 2]));
+        assert_eq!(result.block_end[1], BTreeSet::from([0, 1, 2]));
+        assert_eq!(result.block_end[2], BTreeSet::from([3]));
+    }
+
+    #[test]
+    fn live_at_start_is_recomputed() {
+        let mut func = function(
+            vec![block("entry", vec![XvaStatement::Fallthrough(Symbol::intern("exit"))]), block("exit", vec![XvaStatement::Use(vec![vreg(0)], UseKind::Read), XvaStatement::Return])],
+            Regset::new(),
+        );
+        func.body[0].live_at_start = vec![vreg(5)];
+        let cfg = XvaCfg::new(&func);
+        compute_live_at_start(&mut func, &cfg, &X86, mode());
+        assert_eq!(func.body[0].live_at_start, [vreg(0)]);
+        assert_eq!(func.body[1].live_at_start, [vreg(0)]);
+    }
+}
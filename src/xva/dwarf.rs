@@ -0,0 +1,1101 @@
+//! Generation of DWARF debug information (`.debug_line`, `.debug_info`, and `.debug_abbrev`) and unwind information (`.eh_frame` or `.debug_frame`) for XVA functions
+//!
+//! Source locations are attached to functions with [`XvaStatement::Loc`], and changes to the call frame with [`XvaStatement::Cfi`].
+//! Code offsets for both are recorded while the function is encoded with [`encode_function`].
+use std::io::{Result, Write};
+
+use crate::{
+    intern::Symbol, mach::{Machine, MachineMode, Register}, reloc::{RelocSpan, RelocValue, RelocationKind}, writer::{Encoder, RelocatableWriter}, xva::{Linkage, XvaBlockBody, XvaCfi, XvaFunction, XvaFunctionDef, XvaSourceLoc, XvaStatement}
+};
+
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_TAG_SUBPROGRAM: u64 = 0x2e;
+
+const DW_CHILDREN_NO: u8 = 0;
+const DW_CHILDREN_YES: u8 = 1;
+
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_AT_COMP_DIR: u64 = 0x1b;
+const DW_AT_PRODUCER: u64 = 0x25;
+const DW_AT_DECL_FILE: u64 = 0x3a;
+const DW_AT_DECL_LINE: u64 = 0x3b;
+const DW_AT_EXTERNAL: u64 = 0x3f;
+const DW_AT_FRAME_BASE: u64 = 0x40;
+const DW_AT_LINKAGE_NAME: u64 = 0x6e;
+
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_FLAG: u64 = 0x0c;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+const DW_FORM_EXPRLOC: u64 = 0x18;
+
+const DW_OP_REG0: u8 = 0x50;
+const DW_OP_REGX: u8 = 0x90;
+const DW_OP_CALL_FRAME_CFA: u8 = 0x9c;
+
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_PC: u8 = 0x02;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNS_SET_FILE: u8 = 0x04;
+const DW_LNS_SET_COLUMN: u8 = 0x05;
+
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_RESTORE: u8 = 0xc0;
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_OFFSET_EXTENDED: u8 = 0x05;
+const DW_CFA_RESTORE_EXTENDED: u8 = 0x06;
+const DW_CFA_REGISTER: u8 = 0x09;
+const DW_CFA_REMEMBER_STATE: u8 = 0x0a;
+const DW_CFA_RESTORE_STATE: u8 = 0x0b;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0d;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+const DW_CFA_OFFSET_EXTENDED_SF: u8 = 0x11;
+const DW_CFA_DEF_CFA_SF: u8 = 0x12;
+
+const DW_EH_PE_PCREL_SDATA4: u8 = 0x1b;
+
+const ABBREV_COMPILE_UNIT: u64 = 1;
+const ABBREV_SUBPROGRAM: u64 = 2;
+
+const DWARF_VERSION: u16 = 4;
+
+/// A [`RelocatableWriter`] that tracks the number of bytes written through it
+pub struct OffsetWriter<'a> {
+    inner: &'a mut dyn RelocatableWriter,
+    offset: u64,
+}
+
+impl<'a> OffsetWriter<'a> {
+    pub fn new(inner: &'a mut dyn RelocatableWriter) -> Self {
+        Self { inner, offset: 0 }
+    }
+
+    /// The number of bytes written so far
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<'a> Write for OffsetWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<'a> RelocatableWriter for OffsetWriter<'a> {
+    fn write_with_reloc(&mut self, data: &[u8], reloc: RelocValue) -> Result<()> {
+        self.inner.write_with_reloc(data, reloc)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// A row of the line table, relative to the start of a function
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct DwarfLineRow {
+    pub offset: u64,
+    pub loc: XvaSourceLoc,
+}
+
+/// The result of encoding a function with [`encode_function`]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct EncodedFunction {
+    pub size: u64,
+    pub lines: Vec<DwarfLineRow>,
+    /// The call frame instructions of the function, and the offset each takes effect at
+    pub cfi: Vec<(u64, XvaCfi)>,
+    /// The offset of the label of each block after the first
+    pub labels: Vec<(Symbol, u64)>,
+}
+
+fn encode_statements(
+    encoder: &dyn Encoder,
+    writer: &mut OffsetWriter,
+    stmts: &[XvaStatement],
+    encoded: &mut EncodedFunction,
+) -> Result<()> {
+    for stmt in stmts {
+        match stmt {
+            XvaStatement::RawInstr(instr) => encoder.encode_instr(writer, instr.clone())?,
+            XvaStatement::Elaborated(stmts) => encode_statements(encoder, writer, stmts, encoded)?,
+            XvaStatement::Loc(loc) => {
+                let offset = writer.offset();
+                match encoded.lines.last_mut() {
+                    Some(row) if row.offset == offset => row.loc = *loc,
+                    _ => encoded.lines.push(DwarfLineRow { offset, loc: *loc }),
+                }
+            }
+            XvaStatement::Cfi(cfi) => encoded.cfi.push((writer.offset(), *cfi)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Encodes the prologue and body of a function that has been lowered to machine code,
+/// recording the offset of each [`XvaStatement::Loc`] and [`XvaStatement::Cfi`].
+pub fn encode_function(
+    encoder: &dyn Encoder,
+    writer: &mut dyn RelocatableWriter,
+    func: &XvaFunction,
+) -> Result<EncodedFunction> {
+    let mut writer = OffsetWriter::new(writer);
+    let mut encoded = EncodedFunction {
+        size: 0,
+        lines: Vec::new(),
+        cfi: Vec::new(),
+        labels: Vec::new(),
+    };
+
+    encode_statements(encoder, &mut writer, &func.prologue, &mut encoded)?;
+
+    for (n, block) in func.body.iter().enumerate() {
+        if n != 0 {
+            encoded.labels.push((block.label, writer.offset()));
+        }
+        match &block.body {
+            XvaBlockBody::Statement(stmts) => {
+                encode_statements(encoder, &mut writer, stmts, &mut encoded)?
+            }
+        }
+    }
+
+    encoded.size = writer.offset();
+
+    Ok(encoded)
+}
+
+/// The debug information for a single function
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct DwarfSubprogram {
+    /// The source-level name of the function
+    pub name: Symbol,
+    /// The symbol the function is defined at
+    pub label: Symbol,
+    pub external: bool,
+    pub decl: Option<XvaSourceLoc>,
+    /// The DWARF register number of the frame base register, if any. Otherwise the canonical frame address is used
+    pub frame_base: Option<u16>,
+    pub size: u64,
+    pub rows: Vec<DwarfLineRow>,
+}
+
+impl DwarfSubprogram {
+    /// Creates the debug information for `def`, given the result of [`encode_function`]
+    pub fn new(def: &XvaFunctionDef, encoded: &EncodedFunction) -> Self {
+        Self {
+            name: def.debug.map_or(def.label, |debug| debug.name),
+            label: def.label,
+            external: def.linkage != Linkage::Internal,
+            decl: def.debug.map(|debug| debug.decl),
+            frame_base: None,
+            size: encoded.size,
+            rows: encoded.lines.clone(),
+        }
+    }
+}
+
+/// The contents of a debug section, along with the relocations applied to it
+struct SectionBuf {
+    data: Vec<u8>,
+    relocs: Vec<(usize, usize, RelocValue)>,
+    big_endian: bool,
+}
+
+impl SectionBuf {
+    fn new(big_endian: bool) -> Self {
+        Self {
+            data: Vec::new(),
+            relocs: Vec::new(),
+            big_endian,
+        }
+    }
+
+    fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    fn uint(&mut self, val: u64, width: usize) {
+        let bytes = if self.big_endian {
+            val.to_be_bytes()
+        } else {
+            val.to_le_bytes()
+        };
+
+        if self.big_endian {
+            self.data.extend_from_slice(&bytes[8 - width..]);
+        } else {
+            self.data.extend_from_slice(&bytes[..width]);
+        }
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.uint(val as u64, 2);
+    }
+
+    fn u32(&mut self, val: u32) {
+        self.uint(val as u64, 4);
+    }
+
+    fn uleb(&mut self, mut val: u64) {
+        loop {
+            let byte = (val & 0x7f) as u8;
+            val >>= 7;
+            if val == 0 {
+                self.data.push(byte);
+                break;
+            }
+            self.data.push(byte | 0x80);
+        }
+    }
+
+    fn sleb(&mut self, mut val: i64) {
+        loop {
+            let byte = (val & 0x7f) as u8;
+            val >>= 7;
+            if (val == 0 && (byte & 0x40) == 0) || (val == -1 && (byte & 0x40) != 0) {
+                self.data.push(byte);
+                break;
+            }
+            self.data.push(byte | 0x80);
+        }
+    }
+
+    fn string(&mut self, val: &str) {
+        self.data.extend_from_slice(val.as_bytes());
+        self.data.push(0);
+    }
+
+    /// Writes an absolute relocation to `sym` that is `width` bytes wide
+    fn reloc(&mut self, sym: Symbol, addend: i64, width: u8) {
+        let off = self.data.len();
+        self.uint(0, width as usize);
+        self.relocs.push((
+            off,
+            width as usize,
+            RelocValue {
+                sym: Some(sym),
+                addend,
+                kind: RelocationKind::Absolute(RelocSpan {
+                    byte_width: width,
+                    bit_width: width * 8,
+                    ..RelocSpan::new()
+                }),
+            },
+        ));
+    }
+
+    /// Writes a pc-relative relocation to `sym` that is `width` bytes wide
+    fn pcrel_reloc(&mut self, sym: Symbol, addend: i64, width: u8) {
+        let off = self.data.len();
+        self.uint(0, width as usize);
+        self.relocs.push((
+            off,
+            width as usize,
+            RelocValue {
+                sym: Some(sym),
+                addend,
+                kind: RelocationKind::Pcrel(RelocSpan {
+                    byte_width: width,
+                    bit_width: width * 8,
+                    ..RelocSpan::new()
+                }),
+            },
+        ));
+    }
+
+    /// Begins a unit with a 32-bit `unit_length` field. Returns the offset to pass to [`SectionBuf::end_unit`]
+    fn begin_unit(&mut self) -> usize {
+        let off = self.data.len();
+        self.u32(0);
+        off
+    }
+
+    fn end_unit(&mut self, start: usize) {
+        self.patch_u32(start, (self.data.len() - start - 4) as u32);
+    }
+
+    fn patch_u32(&mut self, off: usize, val: u32) {
+        let bytes = if self.big_endian {
+            val.to_be_bytes()
+        } else {
+            val.to_le_bytes()
+        };
+        self.data[off..off + 4].copy_from_slice(&bytes);
+    }
+
+    fn write_to(&self, writer: &mut dyn RelocatableWriter) -> Result<()> {
+        let mut pos = 0;
+        for (off, len, reloc) in &self.relocs {
+            writer.write_all(&self.data[pos..*off])?;
+            writer.write_with_reloc(&self.data[*off..(*off + *len)], *reloc)?;
+            pos = *off + *len;
+        }
+        writer.write_all(&self.data[pos..])
+    }
+}
+
+/// A DWARF Compilation unit containing each function in an XVA file
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct DwarfCompileUnit {
+    pub producer: Symbol,
+    pub name: Symbol,
+    pub comp_dir: Symbol,
+    /// The size of an address on the target, in bytes
+    pub address_size: u8,
+    pub big_endian: bool,
+    pub subprograms: Vec<DwarfSubprogram>,
+}
+
+impl DwarfCompileUnit {
+    /// The file table of the unit. File numbers used in the line table and `DW_AT_decl_file` are one more than the index into this list
+    pub fn files(&self) -> Vec<Symbol> {
+        let mut files = Vec::new();
+
+        for subprogram in &self.subprograms {
+            for loc in subprogram
+                .decl
+                .iter()
+                .chain(subprogram.rows.iter().map(|row| &row.loc))
+            {
+                if !files.contains(&loc.file) {
+                    files.push(loc.file);
+                }
+            }
+        }
+
+        files
+    }
+
+    fn file_number(files: &[Symbol], file: Symbol) -> u64 {
+        files.iter().position(|&f| f == file).unwrap() as u64 + 1
+    }
+
+    /// Writes the `.debug_abbrev` section for the unit
+    pub fn write_debug_abbrev(&self, writer: &mut dyn RelocatableWriter) -> Result<()> {
+        let mut buf = SectionBuf::new(self.big_endian);
+
+        buf.uleb(ABBREV_COMPILE_UNIT);
+        buf.uleb(DW_TAG_COMPILE_UNIT);
+        buf.u8(DW_CHILDREN_YES);
+        for (at, form) in [
+            (DW_AT_PRODUCER, DW_FORM_STRING),
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_COMP_DIR, DW_FORM_STRING),
+            (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        ] {
+            buf.uleb(at);
+            buf.uleb(form);
+        }
+        buf.uleb(0);
+        buf.uleb(0);
+
+        buf.uleb(ABBREV_SUBPROGRAM);
+        buf.uleb(DW_TAG_SUBPROGRAM);
+        buf.u8(DW_CHILDREN_NO);
+        for (at, form) in [
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_LINKAGE_NAME, DW_FORM_STRING),
+            (DW_AT_EXTERNAL, DW_FORM_FLAG),
+            (DW_AT_DECL_FILE, DW_FORM_UDATA),
+            (DW_AT_DECL_LINE, DW_FORM_UDATA),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_DATA4),
+            (DW_AT_FRAME_BASE, DW_FORM_EXPRLOC),
+        ] {
+            buf.uleb(at);
+            buf.uleb(form);
+        }
+        buf.uleb(0);
+        buf.uleb(0);
+
+        buf.uleb(0);
+
+        buf.write_to(writer)
+    }
+
+    /// Writes the `.debug_info` section for the unit.
+    ///
+    /// `debug_abbrev` and `debug_line` are symbols that refer to the start of the `.debug_abbrev` and `.debug_line` sections respectively
+    pub fn write_debug_info(
+        &self,
+        writer: &mut dyn RelocatableWriter,
+        debug_abbrev: Symbol,
+        debug_line: Symbol,
+    ) -> Result<()> {
+        let files = self.files();
+        let mut buf = SectionBuf::new(self.big_endian);
+
+        let start = buf.begin_unit();
+        buf.u16(DWARF_VERSION);
+        buf.reloc(debug_abbrev, 0, 4);
+        buf.u8(self.address_size);
+
+        buf.uleb(ABBREV_COMPILE_UNIT);
+        buf.string(&self.producer);
+        buf.string(&self.name);
+        buf.string(&self.comp_dir);
+        buf.reloc(debug_line, 0, 4);
+
+        for subprogram in &self.subprograms {
+            buf.uleb(ABBREV_SUBPROGRAM);
+            buf.string(&subprogram.name);
+            buf.string(&subprogram.label);
+            buf.u8(subprogram.external as u8);
+            match subprogram.decl {
+                Some(decl) => {
+                    buf.uleb(Self::file_number(&files, decl.file));
+                    buf.uleb(decl.line as u64);
+                }
+                None => {
+                    buf.uleb(0);
+                    buf.uleb(0);
+                }
+            }
+            buf.reloc(subprogram.label, 0, self.address_size);
+            buf.u32(subprogram.size as u32);
+            match subprogram.frame_base {
+                Some(regno @ 0..32) => {
+                    buf.uleb(1);
+                    buf.u8(DW_OP_REG0 + regno as u8);
+                }
+                Some(regno) => {
+                    let mut expr = SectionBuf::new(self.big_endian);
+                    expr.u8(DW_OP_REGX);
+                    expr.uleb(regno as u64);
+                    buf.uleb(expr.data.len() as u64);
+                    buf.data.extend_from_slice(&expr.data);
+                }
+                None => {
+                    buf.uleb(1);
+                    buf.u8(DW_OP_CALL_FRAME_CFA);
+                }
+            }
+        }
+
+        buf.u8(0);
+        buf.end_unit(start);
+
+        buf.write_to(writer)
+    }
+
+    /// Writes the `.debug_line` section for the unit
+    pub fn write_debug_line(&self, writer: &mut dyn RelocatableWriter) -> Result<()> {
+        let files = self.files();
+        let mut buf = SectionBuf::new(self.big_endian);
+
+        let start = buf.begin_unit();
+        buf.u16(DWARF_VERSION);
+        let header_length = buf.data.len();
+        buf.u32(0);
+        let header_start = buf.data.len();
+
+        buf.u8(1); // minimum_instruction_length
+        buf.u8(1); // maximum_operations_per_instruction
+        buf.u8(1); // default_is_stmt
+        buf.u8((-5i8) as u8); // line_base
+        buf.u8(14); // line_range
+        buf.u8(13); // opcode_base
+        for len in [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1] {
+            buf.u8(len);
+        }
+
+        buf.u8(0); // include_directories
+
+        for file in &files {
+            buf.string(file);
+            buf.uleb(0);
+            buf.uleb(0);
+            buf.uleb(0);
+        }
+        buf.u8(0);
+
+        buf.patch_u32(header_length, (buf.data.len() - header_start) as u32);
+
+        for subprogram in &self.subprograms {
+            buf.u8(0);
+            buf.uleb(1 + self.address_size as u64);
+            buf.u8(DW_LNE_SET_ADDRESS);
+            buf.reloc(subprogram.label, 0, self.address_size);
+
+            let mut file = 1;
+            let mut line = 1i64;
+            let mut column = 0;
+            let mut offset = 0;
+
+            for row in &subprogram.rows {
+                let row_file = Self::file_number(&files, row.loc.file);
+                if row_file != file {
+                    buf.u8(DW_LNS_SET_FILE);
+                    buf.uleb(row_file);
+                    file = row_file;
+                }
+
+                if row.loc.column != column {
+                    buf.u8(DW_LNS_SET_COLUMN);
+                    buf.uleb(row.loc.column as u64);
+                    column = row.loc.column;
+                }
+
+                if row.loc.line as i64 != line {
+                    buf.u8(DW_LNS_ADVANCE_LINE);
+                    buf.sleb(row.loc.line as i64 - line);
+                    line = row.loc.line as i64;
+                }
+
+                if row.offset != offset {
+                    buf.u8(DW_LNS_ADVANCE_PC);
+                    buf.uleb(row.offset - offset);
+                    offset = row.offset;
+                }
+
+                buf.u8(DW_LNS_COPY);
+            }
+
+            if subprogram.size != offset {
+                buf.u8(DW_LNS_ADVANCE_PC);
+                buf.uleb(subprogram.size - offset);
+            }
+
+            buf.u8(0);
+            buf.uleb(1);
+            buf.u8(DW_LNE_END_SEQUENCE);
+        }
+
+        buf.end_unit(start);
+
+        buf.write_to(writer)
+    }
+}
+
+/// Describes the call frame on entry to a function. Used to build the CIE shared by each FDE
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct DwarfCie {
+    /// The factor applied to each code offset. Typically the minimum size of an instruction
+    pub code_align: u64,
+    /// The factor applied to offsets of saved registers
+    pub data_align: i64,
+    /// The DWARF register number of the column that holds the return address
+    pub return_address: u16,
+    pub cfa_register: Register,
+    pub cfa_offset: i64,
+    /// The offset from the CFA the return address is saved at, or [`None`] if it is kept in the return address register
+    pub return_address_offset: Option<i64>,
+}
+
+/// The unwind information for a single function
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct DwarfFde {
+    pub label: Symbol,
+    pub size: u64,
+    pub cfi: Vec<(u64, XvaCfi)>,
+}
+
+impl DwarfFde {
+    /// Creates the unwind information for `def`, given the result of [`encode_function`]
+    pub fn new(def: &XvaFunctionDef, encoded: &EncodedFunction) -> Self {
+        Self {
+            label: def.label,
+            size: encoded.size,
+            cfi: encoded.cfi.clone(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum DwarfFrameFormat {
+    /// The `.eh_frame` section used for unwinding at runtime
+    EhFrame,
+    /// The `.debug_frame` section
+    DebugFrame,
+}
+
+/// A table of call frame information, with one FDE per function
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct DwarfFrameTable {
+    pub format: DwarfFrameFormat,
+    pub cie: DwarfCie,
+    /// The size of an address on the target, in bytes
+    pub address_size: u8,
+    pub big_endian: bool,
+    pub fdes: Vec<DwarfFde>,
+}
+
+impl DwarfFrameTable {
+    fn regno(mach: &dyn Machine, mode: MachineMode, reg: Register) -> u64 {
+        match mach.registers().dwarf_regno(reg, mode) {
+            Some(regno) => regno as u64,
+            None => panic!(
+                "Register {} has no DWARF register number",
+                mach.registers().name_of(reg)
+            ),
+        }
+    }
+
+    fn write_cfa(&self, buf: &mut SectionBuf, cfi: XvaCfi, mach: &dyn Machine, mode: MachineMode) {
+        let data_align = self.cie.data_align;
+        match cfi {
+            XvaCfi::DefCfa(reg, off) => {
+                let regno = Self::regno(mach, mode, reg);
+                if off >= 0 {
+                    buf.u8(DW_CFA_DEF_CFA);
+                    buf.uleb(regno);
+                    buf.uleb(off as u64);
+                } else {
+                    buf.u8(DW_CFA_DEF_CFA_SF);
+                    buf.uleb(regno);
+                    buf.sleb(off / data_align);
+                }
+            }
+            XvaCfi::DefCfaRegister(reg) => {
+                buf.u8(DW_CFA_DEF_CFA_REGISTER);
+                buf.uleb(Self::regno(mach, mode, reg));
+            }
+            XvaCfi::DefCfaOffset(off) => {
+                buf.u8(DW_CFA_DEF_CFA_OFFSET);
+                buf.uleb(off as u64);
+            }
+            XvaCfi::Offset(reg, off) => {
+                let regno = Self::regno(mach, mode, reg);
+                Self::write_offset(buf, regno, off / data_align);
+            }
+            XvaCfi::Register(reg, reg2) => {
+                buf.u8(DW_CFA_REGISTER);
+                buf.uleb(Self::regno(mach, mode, reg));
+                buf.uleb(Self::regno(mach, mode, reg2));
+            }
+            XvaCfi::Restore(reg) => match Self::regno(mach, mode, reg) {
+                regno @ 0..64 => buf.u8(DW_CFA_RESTORE | regno as u8),
+                regno => {
+                    buf.u8(DW_CFA_RESTORE_EXTENDED);
+                    buf.uleb(regno);
+                }
+            },
+            XvaCfi::RememberState => buf.u8(DW_CFA_REMEMBER_STATE),
+            XvaCfi::RestoreState => buf.u8(DW_CFA_RESTORE_STATE),
+        }
+    }
+
+    fn write_offset(buf: &mut SectionBuf, regno: u64, factored: i64) {
+        match (regno, factored) {
+            (0..64, 0..) => {
+                buf.u8(DW_CFA_OFFSET | regno as u8);
+                buf.uleb(factored as u64);
+            }
+            (_, 0..) => {
+                buf.u8(DW_CFA_OFFSET_EXTENDED);
+                buf.uleb(regno);
+                buf.uleb(factored as u64);
+            }
+            _ => {
+                buf.u8(DW_CFA_OFFSET_EXTENDED_SF);
+                buf.uleb(regno);
+                buf.sleb(factored);
+            }
+        }
+    }
+
+    fn write_advance(&self, buf: &mut SectionBuf, delta: u64) {
+        let delta = delta / self.cie.code_align;
+        match delta {
+            0 => {}
+            1..64 => buf.u8(DW_CFA_ADVANCE_LOC | delta as u8),
+            64..0x100 => {
+                buf.u8(DW_CFA_ADVANCE_LOC1);
+                buf.u8(delta as u8);
+            }
+            0x100..0x10000 => {
+                buf.u8(DW_CFA_ADVANCE_LOC2);
+                buf.u16(delta as u16);
+            }
+            _ => {
+                buf.u8(DW_CFA_ADVANCE_LOC4);
+                buf.u32(delta as u32);
+            }
+        }
+    }
+
+    /// Pads the current entry to a multiple of the address size
+    fn pad(&self, buf: &mut SectionBuf, start: usize) {
+        let align = self.address_size as usize;
+        while (buf.data.len() - start) % align != 0 {
+            buf.u8(DW_CFA_NOP);
+        }
+    }
+
+    /// Writes the table to the `.eh_frame` or `.debug_frame` section, according to [`DwarfFrameTable::format`].
+    ///
+    /// `section` is a symbol that refers to the start of the section, which is used by `.debug_frame` to refer to the CIE.
+    pub fn write(
+        &self,
+        writer: &mut dyn RelocatableWriter,
+        section: Symbol,
+        mach: &dyn Machine,
+        mode: MachineMode,
+    ) -> Result<()> {
+        let mut buf = SectionBuf::new(self.big_endian);
+
+        let cie_start = buf.begin_unit();
+        match self.format {
+            DwarfFrameFormat::EhFrame => {
+                buf.u32(0);
+                buf.u8(1);
+                buf.string("zR");
+            }
+            DwarfFrameFormat::DebugFrame => {
+                buf.u32(0xffffffff);
+                buf.u8(DWARF_VERSION as u8);
+                buf.string("");
+                buf.u8(self.address_size);
+                buf.u8(0); // segment_selector_size
+            }
+        }
+        buf.uleb(self.cie.code_align);
+        buf.sleb(self.cie.data_align);
+        buf.uleb(self.cie.return_address as u64);
+        if self.format == DwarfFrameFormat::EhFrame {
+            buf.uleb(1);
+            buf.u8(DW_EH_PE_PCREL_SDATA4);
+        }
+
+        self.write_cfa(&mut buf, XvaCfi::DefCfa(self.cie.cfa_register, self.cie.cfa_offset), mach, mode);
+        if let Some(off) = self.cie.return_address_offset {
+            Self::write_offset(&mut buf, self.cie.return_address as u64, off / self.cie.data_align);
+        }
+        self.pad(&mut buf, cie_start);
+        buf.end_unit(cie_start);
+
+        for fde in &self.fdes {
+            let fde_start = buf.begin_unit();
+            match self.format {
+                DwarfFrameFormat::EhFrame => {
+                    let cie_pointer = buf.data.len() - cie_start;
+                    buf.u32(cie_pointer as u32);
+                    buf.pcrel_reloc(fde.label, 0, 4);
+                    buf.u32(fde.size as u32);
+                    buf.uleb(0);
+                }
+                DwarfFrameFormat::DebugFrame => {
+                    buf.reloc(section, cie_start as i64, 4);
+                    buf.reloc(fde.label, 0, self.address_size);
+                    buf.uint(fde.size, self.address_size as usize);
+                }
+            }
+
+            let mut offset = 0;
+            for &(cfi_offset, cfi) in &fde.cfi {
+                self.write_advance(&mut buf, cfi_offset - offset);
+                offset = cfi_offset;
+                self.write_cfa(&mut buf, cfi, mach, mode);
+            }
+
+            self.pad(&mut buf, fde_start);
+            buf.end_unit(fde_start);
+        }
+
+        buf.write_to(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A section written by the emitters, with the offset of each relocation in it
+    #[derive(Default)]
+    struct Section {
+        data: Vec<u8>,
+        relocs: Vec<(usize, RelocValue)>,
+    }
+
+    impl Write for Section {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl RelocatableWriter for Section {
+        fn write_with_reloc(&mut self, data: &[u8], reloc: RelocValue) -> Result<()> {
+            self.relocs.push((self.data.len(), reloc));
+            self.data.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn abs(sym: &str, width: u8) -> RelocValue {
+        RelocValue {
+            sym: Some(Symbol::intern(sym)),
+            addend: 0,
+            kind: RelocationKind::Absolute(RelocSpan { byte_width: width, bit_width: width * 8, ..RelocSpan::new() }),
+        }
+    }
+
+    fn loc(line: u32, column: u32) -> XvaSourceLoc {
+        XvaSourceLoc { file: Symbol::intern("a.c"), line, column }
+    }
+
+    /// A unit with a function `g`, defined at the symbol `f`, that is 10 bytes long and declared on line 1
+    fn unit(frame_base: Option<u16>) -> DwarfCompileUnit {
+        DwarfCompileUnit {
+            producer: Symbol::intern("p"),
+            name: Symbol::intern("a.c"),
+            comp_dir: Symbol::intern("/"),
+            address_size: 8,
+            big_endian: false,
+            subprograms: vec![DwarfSubprogram {
+                name: Symbol::intern("g"),
+                label: Symbol::intern("f"),
+                external: true,
+                decl: Some(loc(1, 0)),
+                frame_base,
+                size: 10,
+                rows: vec![DwarfLineRow { offset: 0, loc: loc(2, 5) }, DwarfLineRow { offset: 4, loc: loc(1, 5) }],
+            }],
+        }
+    }
+
+    fn debug_info(unit: &DwarfCompileUnit) -> Section {
+        let mut section = Section::default();
+        unit.write_debug_info(&mut section, Symbol::intern(".debug_abbrev"), Symbol::intern(".debug_line")).unwrap();
+        section
+    }
+
+    #[test]
+    fn writes_debug_abbrev() {
+        let mut section = Section::default();
+        unit(None).write_debug_abbrev(&mut section).unwrap();
+        assert_eq!(section.data, [
+            0x01, 0x11, 0x01, 0x25, 0x08, 0x03, 0x08, 0x1b, 0x08, 0x10, 0x17, 0x00, 0x00,
+            0x02, 0x2e, 0x00, 0x03, 0x08, 0x6e, 0x08, 0x3f, 0x0c, 0x3a, 0x0f, 0x3b, 0x0f, 0x11, 0x01, 0x12, 0x06, 0x40, 0x18, 0x00, 0x00,
+            0x00,
+        ]);
+        assert!(section.relocs.is_empty());
+    }
+
+    #[test]
+    fn writes_debug_info() {
+        let section = debug_info(&unit(None));
+        assert_eq!(section.data, [
+            0x2b, 0x00, 0x00, 0x00, // unit_length
+            0x04, 0x00, // version
+            0x00, 0x00, 0x00, 0x00, // debug_abbrev_offset
+            0x08, // address_size
+            0x01, b'p', 0x00, b'a', b'.', b'c', 0x00, b'/', 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x02, b'g', 0x00, b'f', 0x00, 0x01, 0x01, 0x01,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // low_pc
+            0x0a, 0x00, 0x00, 0x00, // high_pc
+            0x01, 0x9c, // DW_OP_call_frame_cfa
+            0x00,
+        ]);
+        assert_eq!(section.relocs, [(6, abs(".debug_abbrev", 4)), (20, abs(".debug_line", 4)), (32, abs("f", 8))]);
+    }
+
+    #[test]
+    fn writes_frame_base_registers() {
+        let section = debug_info(&unit(Some(6)));
+        assert_eq!(section.data[44..], [0x01, 0x56, 0x00]);
+
+        let section = debug_info(&unit(Some(40)));
+        assert_eq!(section.data[44..], [0x02, 0x90, 0x28, 0x00]);
+        assert_eq!(section.data[0], 0x2c);
+    }
+
+    #[test]
+    fn writes_big_endian_units() {
+        let mut unit = unit(None);
+        unit.big_endian = true;
+        let section = debug_info(&unit);
+        assert_eq!(section.data[..6], [0x00, 0x00, 0x00, 0x2b, 0x00, 0x04]);
+        assert_eq!(section.data[40..44], [0x00, 0x00, 0x00, 0x0a]);
+    }
+
+    #[test]
+    fn writes_debug_line() {
+        let mut section = Section::default();
+        unit(None).write_debug_line(&mut section).unwrap();
+        assert_eq!(section.data, [
+            0x3b, 0x00, 0x00, 0x00, // unit_length
+            0x04, 0x00, // version
+            0x1b, 0x00, 0x00, 0x00, // header_length
+            0x01, 0x01, 0x01, 0xfb, 0x0e, 0x0d,
+            0x00, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x01, // standard_opcode_lengths
+            0x00, // include_directories
+            b'a', b'.', b'c', 0x00, 0x00, 0x00, 0x00, 0x00, // file_names
+            0x00, 0x09, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // DW_LNE_set_address
+            0x05, 0x05, 0x03, 0x01, 0x01, // column 5, line 2
+            0x03, 0x7f, 0x02, 0x04, 0x01, // line 1 at offset 4
+            0x02, 0x06, 0x00, 0x01, 0x01, // DW_LNE_end_sequence at offset 10
+        ]);
+        assert_eq!(section.relocs, [(40, abs("f", 8))]);
+    }
+
+    /// Encodes every instruction as 3 bytes
+    struct ThreeBytes;
+
+    impl Encoder for ThreeBytes {
+        fn encode_instr(&self, writer: &mut dyn RelocatableWriter, _: crate::instr::Instruction) -> Result<()> {
+            writer.write_all(&[0; 3])
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "x86")]
+    fn records_lines_and_cfi_while_encoding() {
+        use crate::{archs::x86::X86Opcode, instr::Instruction, mach::Regset, xva::XvaFrameProperties};
+
+        let instr = || XvaStatement::RawInstr(Instruction::new_nullary(X86Opcode::Ud2));
+        let func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            // The prologue is encoded before the body
+            prologue: vec![instr(), XvaStatement::Cfi(XvaCfi::DefCfaOffset(16))],
+            body: vec![
+                XvaBasicBlock { label: Symbol::intern("f"), live_at_start: Vec::new(), body: XvaBlockBody::Statement(vec![XvaStatement::Loc(loc(1, 1)), instr(), instr()]) },
+                XvaBasicBlock {
+                    label: Symbol::intern("next"),
+                    live_at_start: Vec::new(),
+                    // Only the last of several locations at the same offset is kept
+                    body: XvaBlockBody::Statement(vec![
+                        XvaStatement::Loc(loc(2, 1)),
+                        XvaStatement::Elaborated(vec![XvaStatement::Loc(loc(3, 1)), XvaStatement::Cfi(XvaCfi::RememberState), instr()]),
+                    ]),
+                },
+            ],
+            frame_properties: XvaFrameProperties::new(),
+        };
+
+        let mut section = Section::default();
+        let encoded = encode_function(&ThreeBytes, &mut section, &func).unwrap();
+        assert_eq!(encoded.size, 12);
+        assert_eq!(section.data.len(), 12);
+        assert_eq!(encoded.lines, [DwarfLineRow { offset: 3, loc: loc(1, 1) }, DwarfLineRow { offset: 9, loc: loc(3, 1) }]);
+        assert_eq!(encoded.cfi, [(3, XvaCfi::DefCfaOffset(16)), (9, XvaCfi::RememberState)]);
+        // The first block is at the symbol of the function
+        assert_eq!(encoded.labels, [(Symbol::intern("next"), 9)]);
+    }
+
+    #[cfg(feature = "x86")]
+    mod frame {
+        use super::*;
+        use crate::{
+            archs::x86::{X86, X86Mode, X86Register},
+            traits::{IdType, IntoId},
+        };
+
+        fn reg(reg: X86Register) -> Register {
+            Register::new(reg)
+        }
+
+        /// A frame table for `f`, which pushes and sets up a frame pointer in its first 4 bytes and restores the CFA at offset 10
+        fn table(format: DwarfFrameFormat, address_size: u8, sp: X86Register, bp: X86Register) -> DwarfFrameTable {
+            let ptr = address_size as i64;
+            DwarfFrameTable {
+                format,
+                cie: DwarfCie {
+                    code_align: 1,
+                    data_align: -ptr,
+                    return_address: if address_size == 8 { 16 } else { 8 },
+                    cfa_register: reg(sp),
+                    cfa_offset: ptr,
+                    return_address_offset: Some(-ptr),
+                },
+                address_size,
+                big_endian: false,
+                fdes: vec![DwarfFde {
+                    label: Symbol::intern("f"),
+                    size: 12,
+                    cfi: vec![
+                        (1, XvaCfi::DefCfaOffset(2 * ptr)),
+                        (1, XvaCfi::Offset(reg(bp), -2 * ptr)),
+                        (4, XvaCfi::DefCfaRegister(reg(bp))),
+                        (10, XvaCfi::DefCfa(reg(sp), ptr)),
+                    ],
+                }],
+            }
+        }
+
+        #[test]
+        fn writes_eh_frame() {
+            let mut section = Section::default();
+            table(DwarfFrameFormat::EhFrame, 8, crate::x86_register!(rsp), crate::x86_register!(rbp))
+                .write(&mut section, Symbol::intern(".eh_frame"), &X86, X86Mode::Long.into_id())
+                .unwrap();
+            assert_eq!(section.data, [
+                // CIE, as emitted by GCC for x86-64
+                0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, b'z', b'R', 0x00, 0x01, 0x78, 0x10, 0x01, 0x1b,
+                0x0c, 0x07, 0x08, 0x90, 0x01, 0x00, 0x00,
+                // FDE
+                0x1c, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x00,
+                0x41, 0x0e, 0x10, 0x86, 0x02, 0x43, 0x0d, 0x06, 0x46, 0x0c, 0x07, 0x08, 0x00, 0x00, 0x00,
+            ]);
+            let pcrel = RelocValue {
+                sym: Some(Symbol::intern("f")),
+                addend: 0,
+                kind: RelocationKind::Pcrel(RelocSpan { byte_width: 4, bit_width: 32, ..RelocSpan::new() }),
+            };
+            assert_eq!(section.relocs, [(32, pcrel)]);
+        }
+
+        #[test]
+        fn writes_debug_frame() {
+            let mut section = Section::default();
+            table(DwarfFrameFormat::DebugFrame, 4, crate::x86_register!(esp), crate::x86_register!(ebp))
+                .write(&mut section, Symbol::intern(".debug_frame"), &X86, X86Mode::Protected.into_id())
+                .unwrap();
+            assert_eq!(section.data, [
+                // CIE
+                0x10, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x04, 0x00, 0x04, 0x00, 0x01, 0x7c, 0x08, 0x0c,
+                0x04, 0x04, 0x88, 0x01,
+                // FDE
+                0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00,
+                0x41, 0x0e, 0x08, 0x85, 0x02, 0x43, 0x0d, 0x05, 0x46, 0x0c, 0x04, 0x04,
+            ]);
+            assert_eq!(section.relocs, [(24, abs(".debug_frame", 4)), (28, abs("f", 4))]);
+        }
+
+        #[test]
+        fn writes_long_advances_and_extended_offsets() {
+            let mut table = table(DwarfFrameFormat::EhFrame, 8, crate::x86_register!(rsp), crate::x86_register!(rbp));
+            table.fdes[0].size = 0x200;
+            table.fdes[0].cfi = vec![
+                (100, XvaCfi::Offset(reg(crate::x86_register!(rbx)), 8)),
+                (0x180, XvaCfi::Restore(reg(crate::x86_register!(rbx)))),
+            ];
+            let mut section = Section::default();
+            table.write(&mut section, Symbol::intern(".eh_frame"), &X86, X86Mode::Long.into_id()).unwrap();
+            assert_eq!(&section.data[37..], [
+                0x02, 0x64, // DW_CFA_advance_loc1
+                0x11, 0x03, 0x7f, // DW_CFA_offset_extended_sf rbx, -1
+                0x03, 0x1c, 0x01, // DW_CFA_advance_loc2
+                0xc3, // DW_CFA_restore rbx
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ]);
+        }
+    }
+}
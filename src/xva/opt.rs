@@ -1,6 +1,6 @@
-use std::{any::Any, collections::HashSet};
+use std::any::Any;
 
-use crate::{mach::{Machine, MachineMode}, xva::{BarrierKind, XvaBasicBlock, XvaFile, XvaFunction, XvaRegister, XvaStatement}};
+use crate::{instr::Instruction, mach::{Machine, MachineMode}, xva::{cfg::CfgCache, opt::remark::RemarkKind, BarrierKind, XvaBasicBlock, XvaFunction, XvaRegister, XvaStatement}};
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum XvaOptPhase {
@@ -13,22 +13,67 @@ pub enum XvaOptPhase {
     Mce,
 }
 
+impl XvaOptPhase {
+    /// Every phase, in the order they run
+    pub const ALL: &[XvaOptPhase] = &[
+        XvaOptPhase::AfterLower,
+        XvaOptPhase::BeforeRegalloc,
+        XvaOptPhase::AfterRegalloc,
+        XvaOptPhase::BeforeLegalize,
+        XvaOptPhase::AfterLegalize,
+        XvaOptPhase::BeforeMce,
+        XvaOptPhase::Mce,
+    ];
+
+    /// The name of the phase, such as `after-lower`
+    pub fn name(self) -> &'static str {
+        match self {
+            XvaOptPhase::AfterLower => "after-lower",
+            XvaOptPhase::BeforeRegalloc => "before-regalloc",
+            XvaOptPhase::AfterRegalloc => "after-regalloc",
+            XvaOptPhase::BeforeLegalize => "before-legalize",
+            XvaOptPhase::AfterLegalize => "after-legalize",
+            XvaOptPhase::BeforeMce => "before-mce",
+            XvaOptPhase::Mce => "mce",
+        }
+    }
+
+    /// Looks up the phase named `name`, as returned by [`Self::name`]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|phase| phase.name() == name)
+    }
+}
+
 pub trait State: Any {
     fn reset_registers(&mut self);
     fn mark_has_value(&mut self, reg: XvaRegister);
     fn push_gate(&mut self, ty: BarrierKind, num: u32);
     fn pop_gate(&mut self, num: u32);
+
+    /// Takes the remarks emitted by the pass since the last call
+    fn take_remarks(&mut self) -> Vec<RemarkKind> {
+        Vec::new()
+    }
 }
 
 pub trait XvaOpt {
+    /// The name of the pass, used to select it in a [`PassManager`][manager::PassManager]
+    fn name(&self) -> &'static str;
+
     fn phases(&self) -> &[XvaOptPhase];
     fn cost(&self) -> usize;
 
+    /// Whether the pass leaves the blocks and terminators of functions unchanged, so that the [`CfgCache`] remains valid after it runs
+    fn preserves_cfg(&self) -> bool {
+        false
+    }
+
     fn make_state(&self, mode: MachineMode) -> Box<dyn State>;
 }
 
 pub trait XvaFunctionOpt: XvaOpt {
-    fn optimize_function(&self, state: &mut dyn State, func: &mut XvaFunction, phase: XvaOptPhase, mach: &dyn Machine);
+    /// Optimizes `func`. `cfg` caches the control-flow analyses of `func`, and must be invalidated if the pass changes the control flow of `func`
+    fn optimize_function(&self, state: &mut dyn State, func: &mut XvaFunction, cfg: &mut CfgCache, phase: XvaOptPhase, mach: &dyn Machine);
 }
 
 pub trait XvaBasicBlockOpt: XvaOpt {
@@ -52,7 +97,7 @@ pub trait XvaStatementOpt: XvaOpt {
 }
 
 impl<X: XvaBasicBlockOpt> XvaFunctionOpt for X {
-    fn optimize_function(&self, state: &mut dyn State, func: &mut XvaFunction, phase: XvaOptPhase, mach: &dyn Machine) {
+    fn optimize_function(&self, state: &mut dyn State, func: &mut XvaFunction, _: &mut CfgCache, phase: XvaOptPhase, mach: &dyn Machine) {
         for block in &mut func.body {
             state.reset_registers();
             for &live in &block.live_at_start {
@@ -81,12 +126,28 @@ impl<X: XvaStatementOpt> XvaBasicBlockOpt for X {
     }
 }
 
+pub mod constprop;
+pub mod licm;
+pub mod manager;
 pub mod pass;
+pub mod peephole;
+pub mod remark;
+pub mod sched;
+pub mod strength;
+pub mod tailcall;
 
 pub const ALL_PASSES: &[&dyn XvaFunctionOpt] = &[
     &pass::OptimizeFallthrough,
     &pass::FoldRegisterPass,
+    &constprop::PropagateConstants,
+    &strength::StrengthReduce,
+    &pass::GlobalValueNumbering,
+    &licm::LoopInvariantCodeMotion,
+    &tailcall::ConvertTailcalls,
+    &pass::SimplifyCfg,
     &pass::RemoveUnused,
+    &peephole::Peephole,
+    &sched::Schedule,
 ];
 
 pub(crate) fn flatten_statements(dest: &mut Vec<XvaStatement>, stmts: Vec<XvaStatement>) {
@@ -98,42 +159,53 @@ pub(crate) fn flatten_statements(dest: &mut Vec<XvaStatement>, stmts: Vec<XvaSta
     }
 }
 
-pub fn run_passes<'a>(
-    passes: impl Iterator<Item = &'a (dyn XvaFunctionOpt + 'a)>,
-    phase: XvaOptPhase,
-    prg: &mut XvaFile,
-    mut fuel: usize,
-    mach: &dyn Machine,
-    mode: MachineMode,
-) {
-    let mut modified_funcs = HashSet::new();
-    for pass in passes {
-        if !pass.phases().contains(&phase) {}
-        let cost = pass.cost();
-
-        if cost > fuel {
-            continue;
-        }
-        fuel -= cost;
-        for func in &mut prg.functions {
-            modified_funcs.insert(func as *mut _);
-            let mut state = pass.make_state(mode);
-            pass.optimize_function(&mut *state, &mut func.body, phase, mach);
+/// Flattens the [`XvaStatement::Elaborated`] statements in each block of `func`
+pub(crate) fn flatten_function(func: &mut XvaFunction) {
+    for block in &mut func.body {
+        match &mut block.body {
+            super::XvaBlockBody::Statement(xva_statements) => {
+                let stmts = core::mem::take(xva_statements);
+                flatten_statements(xva_statements, stmts);
+            }
         }
     }
+}
 
-    if !modified_funcs.is_empty() {
-        for func in &mut prg.functions {
-            if modified_funcs.contains(&(func as *mut _)) {
-                for block in &mut func.body.body {
-                    match &mut block.body {
-                        super::XvaBlockBody::Statement(xva_statements) => {
-                            let stmts = core::mem::take(xva_statements);
-                            flatten_statements(xva_statements, stmts);
-                        }
-                    }
+/// Flattens `func`, then calls `f` on each maximal run of [`XvaStatement::RawInstr`] in its blocks that is not behind an
+/// opt gate forbidding `forbids`. The run is replaced by the instructions `f` leaves in the vector
+pub(crate) fn raw_runs(func: &mut XvaFunction, state: &mut pass::PassState, forbids: BarrierKind, mut f: impl FnMut(&mut pass::PassState, &mut Vec<Instruction>)) {
+    flatten_function(func);
+    let gates = state.block_gate_states(func);
+
+    for (block, gates) in func.body.iter_mut().zip(gates) {
+        state.opt_gate_state = gates;
+        let super::XvaBlockBody::Statement(stmts) = &mut block.body;
+        let mut i = 0;
+        while i < stmts.len() {
+            match &stmts[i] {
+                XvaStatement::OptGate(kind, num) => state.push_gate(*kind, *num),
+                XvaStatement::EndOptGate(num) => state.pop_gate(*num),
+                XvaStatement::RawInstr(_) if state.test_barrier(forbids) => {
+                    let end = stmts[i..]
+                        .iter()
+                        .position(|stmt| !matches!(stmt, XvaStatement::RawInstr(_)))
+                        .map_or(stmts.len(), |n| i + n);
+                    let mut instrs: Vec<Instruction> = stmts
+                        .drain(i..end)
+                        .map(|stmt| match stmt {
+                            XvaStatement::RawInstr(instr) => instr,
+                            _ => unreachable!(),
+                        })
+                        .collect();
+                    f(state, &mut instrs);
+                    let len = instrs.len();
+                    stmts.splice(i..i, instrs.into_iter().map(XvaStatement::RawInstr));
+                    i += len;
+                    continue;
                 }
+                _ => {}
             }
+            i += 1;
         }
     }
 }
@@ -0,0 +1,587 @@
+//! Conditional constant and copy propagation across the blocks of a function
+use std::{
+    any::Any,
+    collections::{HashMap, VecDeque},
+};
+
+use crate::{
+    mach::{Machine, MachineMode},
+    xva::{
+        BarrierKind, BinaryOp, RightShiftMode, ShiftBehaviour, UnaryOp, UseKind, XvaBlockBody, XvaConst, XvaExpr, XvaFunction, XvaOpcode, XvaOperand, XvaRegister, XvaStatement,
+        cfg::{CfgCache, is_terminator},
+        opt::{
+            State, XvaFunctionOpt, XvaOpt, XvaOptPhase, flatten_function,
+            pass::{LiveValue, PassState},
+            remark::RemarkKind,
+        },
+    },
+};
+
+/// The known values of registers at a point. A register that is not in the map has an unknown value
+type Values = HashMap<XvaRegister, LiveValue>;
+
+/// The mask of the low `size` bytes, or [`None`] if values of that size are not folded
+fn size_mask(size: u64) -> Option<u64> {
+    match size {
+        1..8 => Some((1 << (size * 8)) - 1),
+        8 => Some(u64::MAX),
+        _ => None,
+    }
+}
+
+/// The shift quantity used by a shift of `bits` bits by `quantity` with `behaviour`, or [`None`] if the result is not folded
+fn shift_quantity(behaviour: ShiftBehaviour, quantity: u64, bits: u64) -> Option<u32> {
+    if quantity < bits {
+        Some(quantity as u32)
+    } else if behaviour == ShiftBehaviour::WrapQuantity {
+        Some((quantity % bits) as u32)
+    } else {
+        None
+    }
+}
+
+/// Computes `left op right` on values of `size` bytes, or returns [`None`] if the result cannot be known at compile time
+pub fn fold_binary_op(op: BinaryOp, left: u64, right: u64, size: u64) -> Option<u64> {
+    let mask = size_mask(size)?;
+    let bits = size * 8;
+    let (left, right) = (left & mask, right & mask);
+
+    let val = match op {
+        BinaryOp::Add => left.wrapping_add(right),
+        BinaryOp::Sub => left.wrapping_sub(right),
+        BinaryOp::And => left & right,
+        BinaryOp::Or => left | right,
+        BinaryOp::Xor => left ^ right,
+        BinaryOp::ShiftLeft(behaviour) => left << shift_quantity(behaviour, right, bits)?,
+        BinaryOp::ShiftRight(behaviour, RightShiftMode::Unsigned) => left >> shift_quantity(behaviour, right, bits)?,
+        BinaryOp::ShiftRight(behaviour, RightShiftMode::Signed) => {
+            let quantity = shift_quantity(behaviour, right, bits)?;
+            // Sign-extend from the top bit of the value
+            let unused = 64 - bits as u32;
+            (((left << unused) as i64) >> unused >> quantity) as u64
+        }
+    };
+
+    Some(val & mask)
+}
+
+/// Computes `op left` on a value of `size` bytes, or returns [`None`] if the result cannot be known at compile time
+pub fn fold_unary_op(op: UnaryOp, left: u64, size: u64) -> Option<u64> {
+    let mask = size_mask(size)?;
+    let val = match op {
+        UnaryOp::Neg => left.wrapping_neg(),
+        UnaryOp::Not => !left,
+    };
+
+    Some(val & mask)
+}
+
+/// The bits of `val`, if it is an integer constant
+fn known_bits(val: LiveValue) -> Option<u64> {
+    match val {
+        LiveValue::ZeroInit => Some(0),
+        LiveValue::Const(XvaConst::Bits(bits)) => Some(bits),
+        _ => None,
+    }
+}
+
+/// Propagates the values of registers through the statements of a function
+struct Propagator<'a> {
+    state: &'a mut PassState,
+    mach: &'a dyn Machine,
+}
+
+impl<'a> Propagator<'a> {
+    fn value(values: &Values, reg: XvaRegister) -> Option<LiveValue> {
+        values.get(&reg).copied()
+    }
+
+    fn operand_value(values: &Values, opr: XvaOperand) -> Option<LiveValue> {
+        match opr {
+            XvaOperand::Register(reg) => Self::value(values, reg),
+            XvaOperand::Const(XvaConst::Bits(0)) => Some(LiveValue::ZeroInit),
+            XvaOperand::Const(c) => Some(LiveValue::Const(c)),
+            XvaOperand::FrameAddr(_) | XvaOperand::IncomingArg(_) | XvaOperand::OutgoingArg(_) => None,
+        }
+    }
+
+    /// Forgets the value of `reg`, and of every register that holds a copy of it
+    fn kill(&self, values: &mut Values, reg: XvaRegister) {
+        values.retain(|&r, val| !(r.aliases(reg, self.mach) || matches!(*val, LiveValue::CopyReg(src) if src.aliases(reg, self.mach))));
+    }
+
+    fn define(&self, values: &mut Values, reg: XvaRegister, val: Option<LiveValue>) {
+        self.kill(values, reg);
+        if let Some(val) = val {
+            if let LiveValue::CopyReg(src) = val
+                && src.aliases(reg, self.mach)
+            {
+                return;
+            }
+            values.insert(reg, val);
+        }
+    }
+
+    /// The value `expr` writes to its `dest`, if it is known
+    fn eval(&self, values: &Values, expr: &XvaExpr) -> Option<LiveValue> {
+        let size = expr.dest.size(self.mach, self.state.mode);
+        match &expr.op {
+            XvaOpcode::ZeroInit => Some(LiveValue::ZeroInit),
+            XvaOpcode::Const(c) => Some(LiveValue::Const(*c)),
+            XvaOpcode::Uninit => Some(LiveValue::Uninit),
+            XvaOpcode::Move(src) => Some(Self::value(values, *src).unwrap_or(LiveValue::CopyReg(*src))),
+            XvaOpcode::BinaryOp { op, left, right } => {
+                let left = Self::value(values, *left)?;
+                let right = Self::operand_value(values, *right)?;
+                if left == LiveValue::Uninit || right == LiveValue::Uninit {
+                    return Some(LiveValue::Uninit);
+                }
+                fold_binary_op(*op, known_bits(left)?, known_bits(right)?, size).map(|bits| LiveValue::Const(XvaConst::Bits(bits)))
+            }
+            XvaOpcode::UnaryOp { op, left } => match Self::value(values, *left)? {
+                LiveValue::Uninit => Some(LiveValue::Uninit),
+                left => fold_unary_op(*op, known_bits(left)?, size).map(|bits| LiveValue::Const(XvaConst::Bits(bits))),
+            },
+            XvaOpcode::Select { cond, left, right } => {
+                let src = if self.branch_taken(values, *cond)? { *left } else { *right };
+                Some(Self::value(values, src).unwrap_or(LiveValue::CopyReg(src)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `cond` is known to be nonzero or zero
+    fn branch_taken(&self, values: &Values, cond: XvaRegister) -> Option<bool> {
+        let mask = size_mask(cond.size(self.mach, self.state.mode))?;
+        known_bits(Self::value(values, cond)?).map(|bits| bits & mask != 0)
+    }
+
+    /// Whether the branch of a [`XvaStatement::JumpIf`] on `cond` is known and can be folded at the current point
+    fn fold_branch(&self, values: &Values, cond: XvaRegister) -> Option<bool> {
+        if self.state.test_barrier(BarrierKind::PROPAGATE_THROUGH | BarrierKind::ELIDE_INSTRS) {
+            self.branch_taken(values, cond)
+        } else {
+            None
+        }
+    }
+
+    /// Applies the effect of `stmt` to `values`
+    fn transfer(&mut self, values: &mut Values, stmt: &XvaStatement) {
+        match stmt {
+            XvaStatement::Expr(expr) => {
+                let val = if self.state.test_barrier(BarrierKind::PROPAGATE_THROUGH) { self.eval(values, expr) } else { None };
+                if let Some(dest2) = expr.dest2 {
+                    let val2 = val.filter(|v| matches!(v, LiveValue::ZeroInit | LiveValue::Uninit));
+                    self.define(values, dest2, val2);
+                }
+                self.define(values, expr.dest, val);
+            }
+            XvaStatement::Call { ret_val, call_clobber_regs, .. } => {
+                for reg in call_clobber_regs.into_regids(self.mach, self.state.mode).chain(ret_val.into_regids(self.mach, self.state.mode)) {
+                    self.kill(values, XvaRegister::Physical(reg));
+                }
+            }
+            XvaStatement::RawInstr(instr) => {
+                for opr in instr.operands() {
+                    if let crate::instr::Operand::Register(reg) = opr {
+                        self.kill(values, XvaRegister::Physical(*reg));
+                    }
+                }
+            }
+            XvaStatement::OptGate(kind, num) => self.state.push_gate(*kind, *num),
+            XvaStatement::EndOptGate(num) => self.state.pop_gate(*num),
+            XvaStatement::Elaborated(stmts) => {
+                for stmt in stmts {
+                    self.transfer(values, stmt);
+                }
+            }
+            XvaStatement::Use(regs, UseKind::Write | UseKind::ReadWrite) => {
+                for reg in regs {
+                    self.kill(values, *reg);
+                }
+            }
+            XvaStatement::InlineAsm(asm) => {
+                for reg in asm.clobbers.into_regids(self.mach, self.state.mode) {
+                    self.kill(values, XvaRegister::Physical(reg));
+                }
+                for output in &asm.outputs {
+                    self.kill(values, output.reg);
+                }
+            }
+            // The label may be the target of a jump in a raw instruction
+            XvaStatement::Label(_) => values.clear(),
+            XvaStatement::Write(_, _, _)
+            | XvaStatement::Jump(_)
+            | XvaStatement::JumpIf(_, _)
+            | XvaStatement::Tailcall { .. }
+            | XvaStatement::Return
+            | XvaStatement::Trap(_)
+            | XvaStatement::Noop(_)
+            | XvaStatement::Use(_, UseKind::Read)
+            | XvaStatement::Fallthrough(_)
+            | XvaStatement::Loc(_)
+            | XvaStatement::Cfi(_) => {}
+        }
+    }
+
+    fn replace_reg(values: &Values, reg: &mut XvaRegister) {
+        if let Some(LiveValue::CopyReg(src)) = Self::value(values, *reg) {
+            *reg = src;
+        }
+    }
+
+    fn replace_operand(values: &Values, opr: &mut XvaOperand) {
+        if let XvaOperand::Register(reg) = *opr {
+            match Self::value(values, reg) {
+                Some(val @ (LiveValue::Uninit | LiveValue::ZeroInit | LiveValue::Const(XvaConst::Bits(_)) | LiveValue::CopyReg(_))) => *opr = val.into_operand(reg),
+                _ => {}
+            }
+        }
+    }
+
+    /// Rewrites `stmt` to use the known values of its operands. Returns true if a branch was folded
+    fn rewrite(&mut self, values: &Values, stmt: &mut XvaStatement) -> bool {
+        if let XvaStatement::JumpIf(cond, target) = stmt {
+            let target = *target;
+            match self.fold_branch(values, *cond) {
+                Some(true) => {
+                    self.state.remark(RemarkKind::FoldedBranch { target, taken: true });
+                    *stmt = XvaStatement::Jump(target);
+                }
+                Some(false) => {
+                    self.state.remark(RemarkKind::FoldedBranch { target, taken: false });
+                    *stmt = XvaStatement::Elaborated(vec![]);
+                }
+                None => {
+                    if self.state.test_barrier(BarrierKind::PROPAGATE_THROUGH | BarrierKind::ELIDE_REGISTERS) {
+                        Self::replace_reg(values, cond);
+                    }
+                    return false;
+                }
+            }
+            return true;
+        }
+
+        if !self.state.test_barrier(BarrierKind::PROPAGATE_THROUGH | BarrierKind::ELIDE_REGISTERS) {
+            return false;
+        }
+
+        match stmt {
+            XvaStatement::Expr(expr) => {
+                let foldable = expr.dest2.is_none() && matches!(expr.op, XvaOpcode::Move(_) | XvaOpcode::BinaryOp { .. } | XvaOpcode::UnaryOp { .. } | XvaOpcode::Select { .. });
+                if foldable && let Some(val) = self.eval(values, expr) {
+                    if let Some(op) = val.replace_move_opcode() {
+                        if op != expr.op {
+                            self.state.remark(RemarkKind::Folded { reg: expr.dest, op: op.clone() });
+                        }
+                        expr.op = op;
+                    }
+                    return false;
+                }
+
+                match &mut expr.op {
+                    XvaOpcode::BinaryOp { left, right, .. } | XvaOpcode::CheckedBinaryOp { left, right, .. } => {
+                        Self::replace_reg(values, left);
+                        Self::replace_operand(values, right);
+                    }
+                    XvaOpcode::UnaryOp { left, .. } => Self::replace_reg(values, left),
+                    XvaOpcode::UMul { left, right } | XvaOpcode::SMul { left, right } | XvaOpcode::UDiv { left, right } | XvaOpcode::SDiv { left, right } => {
+                        Self::replace_reg(values, left);
+                        Self::replace_reg(values, right);
+                    }
+                    XvaOpcode::Select { cond, left, right } => {
+                        Self::replace_reg(values, cond);
+                        Self::replace_reg(values, left);
+                        Self::replace_reg(values, right);
+                    }
+                    XvaOpcode::ComputeAddr { base, index, .. } => {
+                        for opr in [base, index] {
+                            if let XvaOperand::Register(reg) = opr {
+                                Self::replace_reg(values, reg);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            XvaStatement::Write(_, _, reg) => Self::replace_reg(values, reg),
+            _ => {}
+        }
+
+        false
+    }
+}
+
+/// Propagates constants and copies between blocks, following only the edges that can be taken.
+///
+/// Unlike [`FoldRegisterPass`][super::pass::FoldRegisterPass], values flow along jumps and fallthroughs, and meet at the start of each block.
+/// A [`XvaStatement::JumpIf`] whose condition is known is replaced with a [`XvaStatement::Jump`] or removed.
+pub struct PropagateConstants;
+
+impl XvaOpt for PropagateConstants {
+    fn name(&self) -> &'static str {
+        "constprop"
+    }
+
+    fn phases(&self) -> &[XvaOptPhase] {
+        &[XvaOptPhase::AfterLower, XvaOptPhase::BeforeRegalloc]
+    }
+
+    fn cost(&self) -> usize {
+        20
+    }
+
+    /// Folding a branch invalidates the [`CfgCache`] directly
+    fn preserves_cfg(&self) -> bool {
+        true
+    }
+
+    fn make_state(&self, mode: MachineMode) -> Box<dyn State> {
+        Box::new(PassState::new(mode))
+    }
+}
+
+/// Joins `values` into the values at the start of `block`. Returns true if they changed
+fn join(block_in: &mut [Option<Values>], block: usize, values: &Values) -> bool {
+    match &mut block_in[block] {
+        Some(existing) => {
+            let len = existing.len();
+            existing.retain(|reg, val| values.get(reg) == Some(val));
+            existing.len() != len
+        }
+        slot @ None => {
+            *slot = Some(values.clone());
+            true
+        }
+    }
+}
+
+impl XvaFunctionOpt for PropagateConstants {
+    fn optimize_function(&self, state: &mut dyn State, func: &mut XvaFunction, cfg: &mut CfgCache, _: XvaOptPhase, mach: &dyn Machine) {
+        let state = (state as &mut dyn Any).downcast_mut::<PassState>().unwrap();
+
+        flatten_function(func);
+        let gates = state.block_gate_states(func);
+
+        let len = func.body.len();
+        let mut block_in: Vec<Option<Values>> = vec![None; len];
+        if len == 0 {
+            return;
+        }
+        block_in[0] = Some(Values::new());
+
+        let mut prop = Propagator { state, mach };
+
+        {
+            let graph = &cfg.get(func).cfg;
+            let mut queued = vec![false; len];
+            queued[0] = true;
+            let mut worklist = VecDeque::from([0]);
+
+            while let Some(n) = worklist.pop_front() {
+                queued[n] = false;
+                let mut values = block_in[n].clone().unwrap();
+                prop.state.opt_gate_state = gates[n].clone();
+
+                let mut targets = Vec::new();
+                let mut falls_through = true;
+                let XvaBlockBody::Statement(stmts) = &func.body[n].body;
+                for stmt in stmts {
+                    match stmt {
+                        XvaStatement::Jump(target) | XvaStatement::Fallthrough(target) => {
+                            targets.push((*target, values.clone()));
+                            falls_through = false;
+                            break;
+                        }
+                        XvaStatement::JumpIf(cond, target) => match prop.fold_branch(&values, *cond) {
+                            Some(true) => {
+                                targets.push((*target, values.clone()));
+                                falls_through = false;
+                                break;
+                            }
+                            Some(false) => {}
+                            None => targets.push((*target, values.clone())),
+                        },
+                        XvaStatement::Return | XvaStatement::Tailcall { .. } | XvaStatement::Trap(_) => {
+                            falls_through = false;
+                            break;
+                        }
+                        stmt => prop.transfer(&mut values, stmt),
+                    }
+                }
+
+                let mut succs: Vec<(usize, Values)> = targets.into_iter().filter_map(|(target, values)| graph.block_of(target).map(|m| (m, values))).collect();
+                if falls_through && n + 1 < len {
+                    succs.push((n + 1, values));
+                }
+
+                for (m, values) in succs {
+                    if join(&mut block_in, m, &values) && !queued[m] {
+                        queued[m] = true;
+                        worklist.push_back(m);
+                    }
+                }
+            }
+        }
+
+        let mut folded = false;
+        for (n, block) in func.body.iter_mut().enumerate() {
+            let Some(mut values) = block_in[n].take() else {
+                continue;
+            };
+            prop.state.opt_gate_state = gates[n].clone();
+
+            let XvaBlockBody::Statement(stmts) = &mut block.body;
+            for stmt in stmts {
+                folded |= prop.rewrite(&values, stmt);
+                if is_terminator(stmt) {
+                    break;
+                }
+                prop.transfer(&mut values, stmt);
+            }
+        }
+
+        if folded {
+            cfg.invalidate();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode},
+        intern::Symbol,
+        mach::Regset,
+        traits::IntoId,
+        xva::{XvaBasicBlock, XvaCategory, XvaDest, XvaFrameProperties, XvaType},
+    };
+
+    const I64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+
+    fn vreg(id: u32) -> XvaRegister {
+        XvaRegister::Virtual(XvaDest { id, ty: I64 })
+    }
+
+    fn expr(dest: XvaRegister, op: XvaOpcode) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest, dest2: None, op })
+    }
+
+    fn konst(dest: XvaRegister, bits: u64) -> XvaStatement {
+        expr(dest, XvaOpcode::Const(XvaConst::Bits(bits)))
+    }
+
+    /// A value that is not known at compile time
+    fn arg(dest: XvaRegister) -> XvaStatement {
+        expr(dest, XvaOpcode::Read(XvaOperand::IncomingArg(0)))
+    }
+
+    fn add(dest: XvaRegister, left: XvaRegister, right: u64) -> XvaStatement {
+        expr(dest, XvaOpcode::BinaryOp { op: BinaryOp::Add, left, right: XvaOperand::Const(XvaConst::Bits(right)) })
+    }
+
+    fn block(label: &str, stmts: Vec<XvaStatement>) -> XvaBasicBlock {
+        XvaBasicBlock { label: Symbol::intern(label), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }
+    }
+
+    fn stmts(func: &XvaFunction, n: usize) -> &[XvaStatement] {
+        let XvaBlockBody::Statement(stmts) = &func.body[n].body;
+        stmts
+    }
+
+    /// Runs the pass over a function made of `blocks`
+    fn propagate(blocks: Vec<XvaBasicBlock>) -> XvaFunction {
+        let mut func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: blocks,
+            frame_properties: XvaFrameProperties::new(),
+        };
+        let mut state = PropagateConstants.make_state(X86Mode::Long.into_id());
+        PropagateConstants.optimize_function(&mut *state, &mut func, &mut CfgCache::new(), XvaOptPhase::BeforeRegalloc, &X86);
+        func
+    }
+
+    /// A diamond that sets v1 to `then` or `other` depending on the condition computed by `cond`, and adds 1 to it in the join block
+    fn diamond(cond: XvaStatement, then: u64, other: u64) -> XvaFunction {
+        propagate(vec![
+            block("entry", vec![cond, XvaStatement::JumpIf(vreg(0), Symbol::intern("other"))]),
+            block("then", vec![konst(vreg(1), then), XvaStatement::Jump(Symbol::intern("join"))]),
+            block("other", vec![konst(vreg(1), other), XvaStatement::Fallthrough(Symbol::intern("join"))]),
+            block("join", vec![add(vreg(2), vreg(1), 1), XvaStatement::Return]),
+        ])
+    }
+
+    #[test]
+    fn folds_operations_on_constants() {
+        assert_eq!(fold_binary_op(BinaryOp::Add, 0xff, 1, 1), Some(0));
+        assert_eq!(fold_binary_op(BinaryOp::Sub, 0, 1, 4), Some(0xffff_ffff));
+        assert_eq!(fold_binary_op(BinaryOp::ShiftRight(ShiftBehaviour::AssumeQuantity, RightShiftMode::Signed), 0x80, 4, 1), Some(0xf8));
+        assert_eq!(fold_binary_op(BinaryOp::ShiftRight(ShiftBehaviour::AssumeQuantity, RightShiftMode::Unsigned), 0x80, 4, 1), Some(0x08));
+        assert_eq!(fold_binary_op(BinaryOp::ShiftLeft(ShiftBehaviour::WrapQuantity), 1, 33, 4), Some(2));
+        assert_eq!(fold_binary_op(BinaryOp::ShiftLeft(ShiftBehaviour::UnboundQuantity), 1, 32, 4), None);
+        assert_eq!(fold_binary_op(BinaryOp::Add, 1, 1, 16), None);
+        assert_eq!(fold_unary_op(UnaryOp::Neg, 1, 2), Some(0xffff));
+        assert_eq!(fold_unary_op(UnaryOp::Not, 0, 8), Some(u64::MAX));
+    }
+
+    #[test]
+    fn propagates_constants_into_later_blocks() {
+        let func = propagate(vec![
+            block("entry", vec![konst(vreg(0), 5), XvaStatement::Fallthrough(Symbol::intern("next"))]),
+            block("next", vec![add(vreg(1), vreg(0), 3), XvaStatement::Label(Symbol::intern("l")), add(vreg(2), vreg(0), 1), XvaStatement::Return]),
+        ]);
+        assert_eq!(stmts(&func, 1)[0], konst(vreg(1), 8));
+        // A label inside a block may be jumped to from anywhere, so nothing is known after it
+        assert_eq!(stmts(&func, 1)[2], add(vreg(2), vreg(0), 1));
+    }
+
+    #[test]
+    fn values_meet_at_joins() {
+        let same = diamond(arg(vreg(0)), 4, 4);
+        assert_eq!(stmts(&same, 3)[0], konst(vreg(2), 5));
+
+        let different = diamond(arg(vreg(0)), 4, 6);
+        assert_eq!(stmts(&different, 3)[0], add(vreg(2), vreg(1), 1));
+    }
+
+    #[test]
+    fn folds_known_branches_and_ignores_edges_not_taken() {
+        let taken = diamond(konst(vreg(0), 1), 4, 6);
+        assert_eq!(stmts(&taken, 0)[1], XvaStatement::Jump(Symbol::intern("other")));
+        assert_eq!(stmts(&taken, 3)[0], konst(vreg(2), 7));
+
+        let not_taken = diamond(konst(vreg(0), 0), 4, 6);
+        assert_eq!(stmts(&not_taken, 0)[1], XvaStatement::Elaborated(vec![]));
+        assert_eq!(stmts(&not_taken, 3)[0], konst(vreg(2), 5));
+    }
+
+    #[test]
+    fn propagates_copies() {
+        let func = propagate(vec![block("entry", vec![
+            arg(vreg(0)),
+            expr(vreg(1), XvaOpcode::Move(vreg(0))),
+            expr(vreg(2), XvaOpcode::BinaryOp { op: BinaryOp::Xor, left: vreg(1), right: XvaOperand::Register(vreg(1)) }),
+            XvaStatement::Return,
+        ])]);
+        assert_eq!(stmts(&func, 0)[2], expr(vreg(2), XvaOpcode::BinaryOp { op: BinaryOp::Xor, left: vreg(0), right: XvaOperand::Register(vreg(0)) }));
+    }
+
+    #[test]
+    fn respects_opt_gates() {
+        let body = vec![
+            konst(vreg(0), 1),
+            XvaStatement::OptGate(BarrierKind::DO_NOT_OPTIMIZE, 0),
+            add(vreg(1), vreg(0), 3),
+            XvaStatement::JumpIf(vreg(0), Symbol::intern("entry")),
+            XvaStatement::EndOptGate(0),
+            XvaStatement::Return,
+        ];
+        let func = propagate(vec![block("entry", body.clone())]);
+        assert_eq!(stmts(&func, 0), body);
+    }
+}
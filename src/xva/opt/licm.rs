@@ -0,0 +1,447 @@
+//! Loop-invariant code motion
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+};
+
+use crate::{
+    intern::Symbol,
+    mach::{Machine, MachineMode},
+    xva::{
+        self, BarrierKind, used_symbols, XvaBasicBlock, XvaBlockBody, XvaExpr, XvaFunction, XvaOpcode, XvaOperand, XvaRegister, XvaStatement,
+        cfg::{CfgCache, CfgInfo, NaturalLoop, XvaCfg, is_terminator},
+        opt::{
+            State, XvaFunctionOpt, XvaOpt, XvaOptPhase, flatten_function,
+            pass::{PassState, count_defs, may_alias},
+            remark::RemarkKind,
+        },
+    },
+};
+
+/// The memory written in a loop
+enum LoopWrites {
+    /// The addresses and sizes of each write
+    Known(Vec<(XvaOperand, u64)>),
+    /// The loop calls a function or contains instructions that may write anywhere
+    Unknown,
+}
+
+impl LoopWrites {
+    fn may_alias(&self, addr: XvaOperand, size: u64) -> bool {
+        match self {
+            LoopWrites::Known(writes) => writes.iter().any(|&(write, write_size)| may_alias(addr, size, write, write_size)),
+            LoopWrites::Unknown => true,
+        }
+    }
+}
+
+/// Whether `opr` has the same value on every iteration of a loop, given the registers written in the loop
+fn operand_invariant(opr: &XvaOperand, defs: &HashMap<XvaRegister, usize>) -> bool {
+    match opr {
+        XvaOperand::Register(reg) => reg_invariant(*reg, defs),
+        _ => true,
+    }
+}
+
+/// Physical registers may be changed by calls and raw instructions in the loop, so only virtual registers are invariant
+fn reg_invariant(reg: XvaRegister, defs: &HashMap<XvaRegister, usize>) -> bool {
+    matches!(reg, XvaRegister::Virtual(_)) && !defs.contains_key(&reg)
+}
+
+/// Whether `stmt` leaves the function, which also leaves any loop it is in
+fn leaves_function(stmt: &XvaStatement) -> bool {
+    matches!(stmt, XvaStatement::Return | XvaStatement::Tailcall { .. } | XvaStatement::Trap(_))
+}
+
+struct LoopHoister<'a> {
+    info: &'a CfgInfo,
+    l: &'a NaturalLoop,
+    mach: &'a dyn Machine,
+    mode: MachineMode,
+    /// The registers with a single definition in the function
+    single_def: HashSet<XvaRegister>,
+    /// The definitions of each register in the loop, not counting the hoisted ones
+    defs: HashMap<XvaRegister, usize>,
+    writes: LoopWrites,
+    /// The blocks of the loop with a successor outside of it, or that leave the function
+    exiting: Vec<usize>,
+}
+
+impl<'a> LoopHoister<'a> {
+    fn new(func: &XvaFunction, info: &'a CfgInfo, l: &'a NaturalLoop, mach: &'a dyn Machine, mode: MachineMode) -> Self {
+        let single_def = count_defs(func).into_iter().filter(|&(_, n)| n == 1).map(|(reg, _)| reg).collect();
+
+        let mut defs: HashMap<XvaRegister, usize> = HashMap::new();
+        let mut writes = LoopWrites::Known(Vec::new());
+        for &n in &l.blocks {
+            for stmt in func.body[n].stmts() {
+                stmt.for_each_def(|reg| *defs.entry(reg).or_default() += 1);
+                match stmt {
+                    XvaStatement::InlineAsm(_) | XvaStatement::Call { .. } | XvaStatement::RawInstr(_) => writes = LoopWrites::Unknown,
+                    XvaStatement::Write(addr, ty, _) => {
+                        if let LoopWrites::Known(writes) = &mut writes {
+                            writes.push((*addr, ty.size));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // A block that returns, tail calls or traps leaves the loop without a successor outside of it
+        let exiting = l
+            .blocks
+            .iter()
+            .copied()
+            .filter(|&n| info.cfg.successors(n).iter().any(|&succ| !l.contains(succ)) || func.body[n].stmts().iter().any(leaves_function))
+            .collect();
+
+        Self { info, l, mach, mode, single_def, defs, writes, exiting }
+    }
+
+    /// Whether the statement at `pos` in `block` runs on every iteration of the loop that completes or leaves it
+    fn always_runs(&self, block: usize, stmts: &[XvaStatement], pos: usize) -> bool {
+        let doms = &self.info.dominators;
+        if !self.l.latches.iter().all(|&latch| doms.dominates(block, latch)) {
+            return false;
+        }
+        let first_branch = stmts.iter().position(|stmt| matches!(stmt, XvaStatement::JumpIf(..)) || leaves_function(stmt)).unwrap_or(stmts.len());
+        self.exiting.iter().all(|&exit| if exit == block { pos < first_branch } else { doms.dominates(block, exit) })
+    }
+
+    /// Whether `expr` at `pos` in `block` can be computed once before the loop
+    fn is_invariant(&self, expr: &XvaExpr, block: usize, stmts: &[XvaStatement], pos: usize) -> bool {
+        if expr.dest2.is_some() || !matches!(expr.dest, XvaRegister::Virtual(_)) || !self.single_def.contains(&expr.dest) {
+            return false;
+        }
+        match &expr.op {
+            XvaOpcode::ZeroInit | XvaOpcode::Const(_) => true,
+            XvaOpcode::ComputeAddr { base, index, .. } => operand_invariant(base, &self.defs) && operand_invariant(index, &self.defs),
+            XvaOpcode::BinaryOp { left, right, .. } => reg_invariant(*left, &self.defs) && operand_invariant(right, &self.defs),
+            // A read may fault, so it is only hoisted if it would have run anyway
+            XvaOpcode::Read(addr) => {
+                let size = expr.dest.ty(self.mach, self.mode).size;
+                operand_invariant(addr, &self.defs) && !self.writes.may_alias(*addr, size) && self.always_runs(block, stmts, pos)
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes the invariant expressions from the loop, in an order where each one comes after the expressions it uses
+    fn hoist(&mut self, func: &mut XvaFunction) -> Vec<XvaExpr> {
+        let mut hoisted = Vec::new();
+        loop {
+            let mut progress = false;
+            for &n in &self.l.blocks {
+                let XvaBlockBody::Statement(stmts) = &mut func.body[n].body;
+                let mut pos = 0;
+                while pos < stmts.len() {
+                    if let XvaStatement::Expr(expr) = &stmts[pos]
+                        && self.is_invariant(expr, n, stmts, pos)
+                    {
+                        let XvaStatement::Expr(expr) = stmts.remove(pos) else { unreachable!() };
+                        self.defs.remove(&expr.dest);
+                        hoisted.push(expr);
+                        progress = true;
+                    } else {
+                        pos += 1;
+                    }
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+        hoisted
+    }
+}
+
+/// Finds a label based on `base` that is not used in `func`
+fn fresh_label(func: &XvaFunction, cfg: &XvaCfg, base: Symbol) -> Symbol {
+    let used = used_symbols(func);
+    (0..)
+        .map(|n| if n == 0 { Symbol::intern(&format!("{base}.preheader")) } else { Symbol::intern(&format!("{base}.preheader.{n}")) })
+        .find(|&label| cfg.block_of(label).is_none() && !used.contains(&label))
+        .unwrap()
+}
+
+/// The block that only jumps to the header of `l`, and that is the only way into the loop from outside of it, if there is one.
+/// The block must end in the same opt gates that are open at the header, given by `gates`
+fn find_preheader(func: &XvaFunction, cfg: &XvaCfg, l: &NaturalLoop, gates: &[Vec<(u32, BarrierKind)>]) -> Option<usize> {
+    let mut outside = cfg.predecessors(l.header).iter().copied().filter(|&pred| !l.contains(pred));
+    let (Some(pred), None) = (outside.next(), outside.next()) else {
+        return None;
+    };
+    let XvaBlockBody::Statement(stmts) = &func.body[pred].body;
+    let simple = !stmts.iter().any(|stmt| matches!(stmt, XvaStatement::JumpIf(..) | XvaStatement::OptGate(..) | XvaStatement::EndOptGate(_)));
+    (cfg.successors(pred) == [l.header] && simple && gates[pred] == gates[l.header]).then_some(pred)
+}
+
+/// Inserts a block before the header of `l` that every edge into the loop from outside of it goes through, and returns its index
+fn insert_preheader(func: &mut XvaFunction, cfg: &XvaCfg, l: &NaturalLoop) -> usize {
+    let header = l.header;
+    let header_label = func.body[header].label;
+    let label = fresh_label(func, cfg, header_label);
+
+    for &pred in cfg.predecessors(header) {
+        let XvaBlockBody::Statement(stmts) = &mut func.body[pred].body;
+        if l.contains(pred) {
+            // The preheader is placed between the header and the block before it, so a back edge from that block must now jump
+            if pred + 1 == header {
+                match stmts.last_mut() {
+                    Some(stmt @ XvaStatement::Fallthrough(_)) => *stmt = XvaStatement::Jump(header_label),
+                    Some(stmt) if is_terminator(stmt) => {}
+                    _ => stmts.push(XvaStatement::Jump(header_label)),
+                }
+            }
+        } else {
+            for stmt in stmts.iter_mut() {
+                match stmt {
+                    XvaStatement::Jump(target) | XvaStatement::JumpIf(_, target) | XvaStatement::Fallthrough(target) if *target == header_label => *target = label,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    func.body.insert(header, XvaBasicBlock { label, live_at_start: Vec::new(), body: XvaBlockBody::Statement(vec![XvaStatement::Fallthrough(header_label)]) });
+    header
+}
+
+/// Moves the invariant expressions of each loop into the preheader of the loop, which is created if it is missing.
+///
+/// [`XvaOpcode::Const`], [`XvaOpcode::ZeroInit`], [`XvaOpcode::ComputeAddr`] and [`XvaOpcode::BinaryOp`] are moved if their operands are not written in the loop,
+/// and their destination is a virtual register written exactly once.
+/// [`XvaOpcode::Read`]s are also moved if no write in the loop may overlap them, and they run on every iteration.
+/// Inner loops are processed first, so an expression can move out of several loops.
+///
+/// Loops that contain opt gates, or that are in an opt gate that forbids [`BarrierKind::MISC_OPTIMIZATION`], are not changed
+pub struct LoopInvariantCodeMotion;
+
+impl XvaOpt for LoopInvariantCodeMotion {
+    fn name(&self) -> &'static str {
+        "licm"
+    }
+
+    fn phases(&self) -> &[XvaOptPhase] {
+        &[XvaOptPhase::AfterLower, XvaOptPhase::BeforeRegalloc]
+    }
+
+    fn cost(&self) -> usize {
+        30
+    }
+
+    fn preserves_cfg(&self) -> bool {
+        false
+    }
+
+    fn make_state(&self, mode: MachineMode) -> Box<dyn State> {
+        Box::new(PassState::new(mode))
+    }
+}
+
+impl XvaFunctionOpt for LoopInvariantCodeMotion {
+    fn optimize_function(&self, state: &mut dyn State, func: &mut XvaFunction, cfg: &mut CfgCache, _: XvaOptPhase, mach: &dyn Machine) {
+        let state = (state as &mut dyn Any).downcast_mut::<PassState>().unwrap();
+
+        flatten_function(func);
+        let used = used_symbols(func);
+        let mut visited = HashSet::new();
+        let mut changed = false;
+
+        // Blocks are inserted as loops are processed, so the loops are found again after each change
+        loop {
+            let info = cfg.get(func).clone();
+            let Some(l) = info.loops.loops.iter().find(|l| !visited.contains(&func.body[l.header].label)) else {
+                break;
+            };
+            visited.insert(func.body[l.header].label);
+
+            // The entry block cannot have a preheader, and a header whose address is taken may be entered without one
+            if l.header == 0 || used.contains(&func.body[l.header].label) {
+                continue;
+            }
+
+            state.opt_gate_state.clear();
+            let gates = state.block_gate_states(func);
+            let gated = l.blocks.iter().any(|&n| {
+                let XvaBlockBody::Statement(stmts) = &func.body[n].body;
+                state.opt_gate_state = gates[n].clone();
+                !state.test_barrier(BarrierKind::MISC_OPTIMIZATION) || stmts.iter().any(|stmt| matches!(stmt, XvaStatement::OptGate(..) | XvaStatement::EndOptGate(_)))
+            });
+            if gated {
+                continue;
+            }
+
+            let hoisted = LoopHoister::new(func, &info, l, mach, state.mode).hoist(func);
+            if hoisted.is_empty() {
+                continue;
+            }
+
+            let preheader = match find_preheader(func, &info.cfg, l, &gates) {
+                Some(pred) => pred,
+                None => insert_preheader(func, &info.cfg, l),
+            };
+            let XvaBlockBody::Statement(stmts) = &mut func.body[preheader].body;
+            let pos = if stmts.last().is_some_and(is_terminator) { stmts.len() - 1 } else { stmts.len() };
+            for expr in &hoisted {
+                state.remark(RemarkKind::Hoisted(expr.dest));
+            }
+            stmts.splice(pos..pos, hoisted.into_iter().map(XvaStatement::Expr));
+
+            cfg.invalidate();
+            changed = true;
+        }
+
+        if changed {
+            let info = cfg.get(func).clone();
+            xva::dataflow::compute_live_at_start(func, &info.cfg, mach, state.mode);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode},
+        mach::Regset,
+        traits::IntoId,
+        xva::{BinaryOp, XvaCategory, XvaConst, XvaDest, XvaFrameProperties, XvaType},
+    };
+
+    const I64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+
+    fn vreg(id: u32) -> XvaRegister {
+        XvaRegister::Virtual(XvaDest { id, ty: I64 })
+    }
+
+    fn sym(name: &str) -> Symbol {
+        Symbol::intern(name)
+    }
+
+    fn expr(dest: XvaRegister, op: XvaOpcode) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest, dest2: None, op })
+    }
+
+    fn add(dest: XvaRegister, left: XvaRegister, right: u64) -> XvaStatement {
+        expr(dest, XvaOpcode::BinaryOp { op: BinaryOp::Add, left, right: XvaOperand::Const(XvaConst::Bits(right)) })
+    }
+
+    fn read(dest: XvaRegister, offset: i32) -> XvaStatement {
+        expr(dest, XvaOpcode::Read(XvaOperand::FrameAddr(offset)))
+    }
+
+    /// A value that changes on every iteration
+    fn clobber(reg: XvaRegister) -> XvaStatement {
+        XvaStatement::Use(vec![reg], UseKind::Write)
+    }
+
+    fn block(label: &str, stmts: Vec<XvaStatement>) -> XvaBasicBlock {
+        XvaBasicBlock { label: sym(label), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }
+    }
+
+    fn stmts(func: &XvaFunction, n: usize) -> &[XvaStatement] {
+        let XvaBlockBody::Statement(stmts) = &func.body[n].body;
+        stmts
+    }
+
+    /// Runs the pass over a function made of `blocks`. Returns the function and the remarks
+    fn hoist(blocks: Vec<XvaBasicBlock>) -> (XvaFunction, Vec<RemarkKind>) {
+        let mut func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: blocks,
+            frame_properties: XvaFrameProperties::new(),
+        };
+        let mut state = LoopInvariantCodeMotion.make_state(X86Mode::Long.into_id());
+        LoopInvariantCodeMotion.optimize_function(&mut *state, &mut func, &mut CfgCache::new(), XvaOptPhase::AfterLower, &X86);
+        (func, state.take_remarks())
+    }
+
+    /// A function that reads v0 and then runs `body` in a loop until v9 is zero
+    fn simple_loop(body: Vec<XvaStatement>) -> (XvaFunction, Vec<RemarkKind>) {
+        let mut looped = body;
+        looped.extend([clobber(vreg(9)), XvaStatement::JumpIf(vreg(9), sym("loop"))]);
+        hoist(vec![
+            block("entry", vec![read(vreg(0), 0), XvaStatement::Fallthrough(sym("loop"))]),
+            block("loop", looped),
+            block("exit", vec![XvaStatement::Return]),
+        ])
+    }
+
+    #[test]
+    fn hoists_invariant_expressions_into_the_preheader() {
+        let use_all = XvaStatement::Use(vec![vreg(1), vreg(2), vreg(3)], UseKind::Read);
+        let (func, remarks) = simple_loop(vec![add(vreg(2), vreg(1), 2), add(vreg(1), vreg(0), 1), expr(vreg(3), XvaOpcode::Const(XvaConst::Bits(7))), use_all.clone()]);
+        // v2 uses v1, so it is hoisted after it
+        assert_eq!(stmts(&func, 0), [
+            read(vreg(0), 0),
+            add(vreg(1), vreg(0), 1),
+            expr(vreg(3), XvaOpcode::Const(XvaConst::Bits(7))),
+            add(vreg(2), vreg(1), 2),
+            XvaStatement::Fallthrough(sym("loop")),
+        ]);
+        assert_eq!(stmts(&func, 1), [use_all, clobber(vreg(9)), XvaStatement::JumpIf(vreg(9), sym("loop"))]);
+        assert_eq!(remarks, [RemarkKind::Hoisted(vreg(1)), RemarkKind::Hoisted(vreg(3)), RemarkKind::Hoisted(vreg(2))]);
+    }
+
+    #[test]
+    fn keeps_expressions_that_change_between_iterations() {
+        // v1 depends on a register written in the loop, and v2 is written twice
+        let body = vec![clobber(vreg(5)), add(vreg(1), vreg(5), 1), add(vreg(2), vreg(0), 1), add(vreg(2), vreg(0), 2)];
+        let (func, remarks) = simple_loop(body.clone());
+        assert_eq!(stmts(&func, 0), [read(vreg(0), 0), XvaStatement::Fallthrough(sym("loop"))]);
+        assert_eq!(&stmts(&func, 1)[..4], body);
+        assert!(remarks.is_empty());
+    }
+
+    #[test]
+    fn hoists_reads_that_no_write_in_the_loop_overlaps() {
+        let (func, _) = simple_loop(vec![read(vreg(1), 16), read(vreg(2), 4), XvaStatement::Write(XvaOperand::FrameAddr(8), I64, vreg(0))]);
+        assert_eq!(stmts(&func, 0), [read(vreg(0), 0), read(vreg(1), 16), XvaStatement::Fallthrough(sym("loop"))]);
+        assert_eq!(stmts(&func, 1)[0], read(vreg(2), 4));
+
+        // A read after the loop may be left is not hoisted, since it may fault when the loop would not run it
+        let (func, _) = hoist(vec![
+            block("entry", vec![read(vreg(0), 0), XvaStatement::Fallthrough(sym("loop"))]),
+            block("loop", vec![clobber(vreg(9)), XvaStatement::JumpIf(vreg(9), sym("exit")), read(vreg(1), 16), XvaStatement::Jump(sym("loop"))]),
+            block("exit", vec![XvaStatement::Return]),
+        ]);
+        assert_eq!(stmts(&func, 1)[2], read(vreg(1), 16));
+
+        // Neither is a read after a return in the loop, which has no successor outside of it
+        let (func, _) = hoist(vec![
+            block("entry", vec![read(vreg(0), 0), XvaStatement::Fallthrough(sym("loop"))]),
+            block("loop", vec![clobber(vreg(9)), XvaStatement::JumpIf(vreg(9), sym("body")), XvaStatement::Return]),
+            block("body", vec![read(vreg(1), 16), XvaStatement::Jump(sym("loop"))]),
+        ]);
+        assert_eq!(stmts(&func, 0), [read(vreg(0), 0), XvaStatement::Fallthrough(sym("loop"))]);
+        assert_eq!(stmts(&func, 2)[0], read(vreg(1), 16));
+    }
+
+    #[test]
+    fn inserts_a_preheader_when_there_is_none() {
+        let (func, _) = hoist(vec![
+            block("entry", vec![read(vreg(0), 0), clobber(vreg(8)), XvaStatement::JumpIf(vreg(8), sym("exit"))]),
+            block("loop", vec![add(vreg(1), vreg(0), 1), clobber(vreg(9)), XvaStatement::JumpIf(vreg(9), sym("loop"))]),
+            block("exit", vec![XvaStatement::Return]),
+        ]);
+        let labels: Vec<Symbol> = func.body.iter().map(|block| block.label).collect();
+        assert_eq!(labels, [sym("entry"), sym("loop.preheader"), sym("loop"), sym("exit")]);
+        assert_eq!(stmts(&func, 1), [add(vreg(1), vreg(0), 1), XvaStatement::Fallthrough(sym("loop"))]);
+        // The entry block still jumps past the loop
+        assert_eq!(stmts(&func, 0)[2], XvaStatement::JumpIf(vreg(8), sym("exit")));
+    }
+
+    #[test]
+    fn leaves_loops_with_opt_gates_alone() {
+        let body = vec![XvaStatement::OptGate(BarrierKind::MISC_OPTIMIZATION, 0), add(vreg(1), vreg(0), 1), XvaStatement::EndOptGate(0)];
+        let (func, remarks) = simple_loop(body.clone());
+        assert_eq!(&stmts(&func, 1)[..3], body);
+        assert!(remarks.is_empty());
+    }
+}
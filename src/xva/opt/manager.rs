@@ -0,0 +1,628 @@
+//! Pipelines of optimization passes, selected by optimization level and by name
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    fmt::PrettyPrinter,
+    intern::Symbol,
+    mach::{Machine, MachineMode},
+    xva::{
+        XvaFile, XvaFunction, for_each_stmt,
+        cfg::CfgCache,
+        opt::{ALL_PASSES, XvaFunctionOpt, XvaOptPhase, flatten_function, remark::Remark},
+        verify::verify,
+    },
+};
+
+/// The optimization level, which selects the passes of the default pipelines
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    /// No optimization
+    #[default]
+    O0,
+    /// Cheap local optimizations
+    O1,
+    /// Every optimization
+    O2,
+    /// Every optimization that does not increase code size
+    Os,
+}
+
+impl OptLevel {
+    /// Parses the argument of `-O`, which is `0`, `1`, `2`, or `s`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "0" => Some(OptLevel::O0),
+            "1" => Some(OptLevel::O1),
+            "2" => Some(OptLevel::O2),
+            "s" => Some(OptLevel::Os),
+            _ => None,
+        }
+    }
+
+    /// The names of the passes enabled at this level, in the order they run
+    pub fn passes(self) -> &'static [&'static str] {
+        match self {
+            OptLevel::O0 => &[],
+            OptLevel::O1 => &["optimize-fallthrough", "fold-register", "tailcall", "simplify-cfg", "remove-unused", "peephole"],
+            OptLevel::O2 => &[
+                "optimize-fallthrough",
+                "fold-register",
+                "constprop",
+                "strength-reduce",
+                "gvn",
+                "licm",
+                "tailcall",
+                "simplify-cfg",
+                "remove-unused",
+                "peephole",
+                "schedule",
+            ],
+            OptLevel::Os => &["optimize-fallthrough", "fold-register", "constprop", "gvn", "tailcall", "simplify-cfg", "remove-unused", "peephole", "schedule"],
+        }
+    }
+}
+
+/// A pass name that does not name any pass in [`ALL_PASSES`]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct UnknownPass(pub String);
+
+impl core::fmt::Display for UnknownPass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("unknown optimization pass {}", self.0))
+    }
+}
+
+impl std::error::Error for UnknownPass {}
+
+/// Looks up the pass in [`ALL_PASSES`] named `name`
+pub fn find_pass(name: &str) -> Option<&'static dyn XvaFunctionOpt> {
+    ALL_PASSES.iter().copied().find(|pass| pass.name() == name)
+}
+
+/// The statistics of one pass, summed over every function it ran on
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PassStats {
+    /// The number of functions the pass ran on
+    pub runs: usize,
+    /// The number of statements by which the pass shrank the functions it ran on, summed over the functions it shrank.
+    /// A run that removes some statements and adds as many others counts as neither removing nor adding any
+    pub stmts_removed: usize,
+    /// The number of statements by which the pass grew the functions it ran on, summed over the functions it grew
+    pub stmts_added: usize,
+    pub time: Duration,
+}
+
+/// One run of a pass on a function
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct PassRun {
+    /// The position of the run among every run of the [`PassManager`], which is compared against the bisect limit
+    pub index: usize,
+    pub pass: &'static str,
+    pub phase: XvaOptPhase,
+    /// The label of the function
+    pub function: Symbol,
+}
+
+/// Counts the statements of `func`, not counting [`XvaStatement::Elaborated`][crate::xva::XvaStatement::Elaborated] itself
+fn count_statements(func: &XvaFunction) -> usize {
+    let mut count = 0;
+    for block in &func.body {
+        for_each_stmt(block.stmts(), &mut |_, _| count += 1);
+    }
+    count
+}
+
+/// Observes the [`XvaFile`] around the phases and passes run by a [`PassManager`].
+/// Each method is called with the whole file
+pub trait PassHook {
+    fn before_phase(&mut self, _phase: XvaOptPhase, _prg: &XvaFile, _mach: &dyn Machine, _mode: MachineMode) {}
+
+    fn after_phase(&mut self, _phase: XvaOptPhase, _prg: &XvaFile, _mach: &dyn Machine, _mode: MachineMode) {}
+
+    /// Called before the pass named `pass` runs on the functions of `prg` in `phase`
+    fn before_pass(&mut self, _pass: &'static str, _phase: XvaOptPhase, _prg: &XvaFile, _mach: &dyn Machine, _mode: MachineMode) {}
+
+    fn after_pass(&mut self, _pass: &'static str, _phase: XvaOptPhase, _prg: &XvaFile, _mach: &dyn Machine, _mode: MachineMode) {}
+}
+
+/// Whether an [`IrDump`] is taken before or after a pass or phase
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum DumpWhen {
+    Before,
+    After,
+}
+
+/// A [`PassHook`] that writes the [`XvaFile`] with [`XvaFile::pretty_print`] before or after the selected passes and phases
+pub struct IrDump<W> {
+    out: W,
+    passes: HashSet<(String, DumpWhen)>,
+    phases: HashSet<(XvaOptPhase, DumpWhen)>,
+}
+
+impl<W: Write> IrDump<W> {
+    /// Creates a dump that writes to `out`, and has no passes or phases selected
+    pub fn new(out: W) -> Self {
+        Self { out, passes: HashSet::new(), phases: HashSet::new() }
+    }
+
+    /// Dumps the file `when` the pass named `name` runs
+    pub fn dump_pass(&mut self, name: &str, when: DumpWhen) {
+        self.passes.insert((name.to_string(), when));
+    }
+
+    /// Dumps the file `when` the passes of `phase` run
+    pub fn dump_phase(&mut self, phase: XvaOptPhase, when: DumpWhen) {
+        self.phases.insert((phase, when));
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn dump(&mut self, title: core::fmt::Arguments, prg: &XvaFile, mach: &dyn Machine, mode: MachineMode) {
+        // A failed dump should not stop compilation
+        let _ = writeln!(self.out, "*** IR Dump {title} ***\n{}", prg.pretty_print(mach, mode));
+    }
+}
+
+impl<W: Write> PassHook for IrDump<W> {
+    fn before_phase(&mut self, phase: XvaOptPhase, prg: &XvaFile, mach: &dyn Machine, mode: MachineMode) {
+        if self.phases.contains(&(phase, DumpWhen::Before)) {
+            self.dump(format_args!("Before {}", phase.name()), prg, mach, mode);
+        }
+    }
+
+    fn after_phase(&mut self, phase: XvaOptPhase, prg: &XvaFile, mach: &dyn Machine, mode: MachineMode) {
+        if self.phases.contains(&(phase, DumpWhen::After)) {
+            self.dump(format_args!("After {}", phase.name()), prg, mach, mode);
+        }
+    }
+
+    fn before_pass(&mut self, pass: &'static str, phase: XvaOptPhase, prg: &XvaFile, mach: &dyn Machine, mode: MachineMode) {
+        if self.passes.contains(&(pass.to_string(), DumpWhen::Before)) {
+            self.dump(format_args!("Before {pass} ({})", phase.name()), prg, mach, mode);
+        }
+    }
+
+    fn after_pass(&mut self, pass: &'static str, phase: XvaOptPhase, prg: &XvaFile, mach: &dyn Machine, mode: MachineMode) {
+        if self.passes.contains(&(pass.to_string(), DumpWhen::After)) {
+            self.dump(format_args!("After {pass} ({})", phase.name()), prg, mach, mode);
+        }
+    }
+}
+
+/// Runs a pipeline of passes for each [`XvaOptPhase`].
+///
+/// Each function gets its own fuel in each phase, and a pass is skipped for the rest of the phase once its cost exceeds the fuel left.
+/// The bisect limit stops running passes after that many runs, so that a miscompile can be narrowed down to the run that caused it with [`PassManager::last_run`]
+pub struct PassManager {
+    pipelines: HashMap<XvaOptPhase, Vec<&'static dyn XvaFunctionOpt>>,
+    disabled: HashSet<&'static str>,
+    fuel: usize,
+    bisect_limit: Option<usize>,
+    runs: usize,
+    last_run: Option<PassRun>,
+    stats: HashMap<&'static str, PassStats>,
+    hooks: Vec<Box<dyn PassHook>>,
+    collect_remarks: bool,
+    remarks: Vec<Remark>,
+    verify: bool,
+}
+
+impl PassManager {
+    /// Creates the default pipelines for `level`
+    pub fn new(level: OptLevel) -> Self {
+        let passes: Vec<_> = level.passes().iter().map(|&name| find_pass(name).expect("Unknown pass in preset")).collect();
+        let pipelines = XvaOptPhase::ALL
+            .iter()
+            .map(|&phase| (phase, passes.iter().copied().filter(|pass| pass.phases().contains(&phase)).collect()))
+            .collect();
+        Self {
+            pipelines,
+            disabled: HashSet::new(),
+            fuel: usize::MAX,
+            bisect_limit: None,
+            runs: 0,
+            last_run: None,
+            stats: HashMap::new(),
+            hooks: Vec::new(),
+            collect_remarks: false,
+            remarks: Vec::new(),
+            verify: cfg!(debug_assertions),
+        }
+    }
+
+    /// The passes run in `phase`, in order. This includes disabled passes
+    pub fn pipeline(&self, phase: XvaOptPhase) -> &[&'static dyn XvaFunctionOpt] {
+        self.pipelines.get(&phase).map_or(&[], |passes| &passes[..])
+    }
+
+    /// Replaces the pipeline of `phase` with the passes named in `names`.
+    /// Passes that do not run in `phase` are skipped
+    pub fn set_pipeline<S: AsRef<str>>(&mut self, phase: XvaOptPhase, names: &[S]) -> Result<(), UnknownPass> {
+        let passes = names
+            .iter()
+            .map(|name| find_pass(name.as_ref()).ok_or_else(|| UnknownPass(name.as_ref().to_string())))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.pipelines.insert(phase, passes);
+        Ok(())
+    }
+
+    /// Enables the pass named `name`. If it is not in the pipeline of a phase it runs in, it is inserted in the order of [`ALL_PASSES`]
+    pub fn enable(&mut self, name: &str) -> Result<(), UnknownPass> {
+        let pass = find_pass(name).ok_or_else(|| UnknownPass(name.to_string()))?;
+        self.disabled.remove(pass.name());
+
+        let order = |pass: &dyn XvaFunctionOpt| ALL_PASSES.iter().position(|other| other.name() == pass.name());
+        for &phase in pass.phases() {
+            let pipeline = self.pipelines.entry(phase).or_default();
+            if pipeline.iter().any(|other| other.name() == pass.name()) {
+                continue;
+            }
+            let pos = pipeline.iter().position(|&other| order(other) > order(pass)).unwrap_or(pipeline.len());
+            pipeline.insert(pos, pass);
+        }
+        Ok(())
+    }
+
+    /// Disables the pass named `name` in every pipeline
+    pub fn disable(&mut self, name: &str) -> Result<(), UnknownPass> {
+        let pass = find_pass(name).ok_or_else(|| UnknownPass(name.to_string()))?;
+        self.disabled.insert(pass.name());
+        Ok(())
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        !self.disabled.contains(name)
+    }
+
+    /// Sets the fuel given to each function in each phase. Defaults to unlimited
+    pub fn set_fuel(&mut self, fuel: usize) {
+        self.fuel = fuel;
+    }
+
+    /// Sets the number of pass runs after which no more passes run, or [`None`] to run every pass
+    pub fn set_bisect_limit(&mut self, limit: Option<usize>) {
+        self.bisect_limit = limit;
+    }
+
+    /// The number of pass runs so far, including those skipped by the bisect limit
+    pub fn runs(&self) -> usize {
+        self.runs
+    }
+
+    /// The last pass run that was not skipped by the bisect limit
+    pub fn last_run(&self) -> Option<PassRun> {
+        self.last_run
+    }
+
+    /// The statistics of each pass that ran
+    pub fn stats(&self) -> impl Iterator<Item = (&'static str, &PassStats)> + '_ {
+        self.stats.iter().map(|(&name, stats)| (name, stats))
+    }
+
+    /// Adds a hook that is called around each phase and pass that runs
+    pub fn add_hook(&mut self, hook: Box<dyn PassHook>) {
+        self.hooks.push(hook);
+    }
+
+    /// Sets whether the remarks emitted by passes are kept. Defaults to false
+    pub fn set_collect_remarks(&mut self, collect: bool) {
+        self.collect_remarks = collect;
+    }
+
+    /// The remarks collected so far, in the order they were emitted
+    pub fn remarks(&self) -> &[Remark] {
+        &self.remarks
+    }
+
+    /// Takes the remarks collected so far
+    pub fn take_remarks(&mut self) -> Vec<Remark> {
+        core::mem::take(&mut self.remarks)
+    }
+
+    /// Sets whether the file is checked with [`verify`] after each phase that runs passes, panicking if a pass left it malformed.
+    /// Defaults to true when debug assertions are enabled
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Runs the pipeline of `phase` on each function of `prg`
+    pub fn run(&mut self, phase: XvaOptPhase, prg: &mut XvaFile, mach: &dyn Machine, mode: MachineMode) {
+        let Some(pipeline) = self.pipelines.get(&phase) else {
+            return;
+        };
+
+        for hook in &mut self.hooks {
+            hook.before_phase(phase, prg, mach, mode);
+        }
+
+        let mut cfgs: Vec<CfgCache> = prg.functions.iter().map(|_| CfgCache::new()).collect();
+        let mut fuel = vec![self.fuel; prg.functions.len()];
+        let mut modified = vec![false; prg.functions.len()];
+        for &pass in pipeline {
+            if !pass.phases().contains(&phase) || self.disabled.contains(pass.name()) {
+                continue;
+            }
+            for hook in &mut self.hooks {
+                hook.before_pass(pass.name(), phase, prg, mach, mode);
+            }
+
+            let cost = pass.cost();
+            for (n, func) in prg.functions.iter_mut().enumerate() {
+                if cost > fuel[n] {
+                    continue;
+                }
+                fuel[n] -= cost;
+
+                let run = PassRun { index: self.runs, pass: pass.name(), phase, function: func.label };
+                self.runs += 1;
+                if self.bisect_limit.is_some_and(|limit| run.index >= limit) {
+                    continue;
+                }
+                self.last_run = Some(run);
+
+                let before = count_statements(&func.body);
+                let start = Instant::now();
+                let mut state = pass.make_state(mode);
+                pass.optimize_function(&mut *state, &mut func.body, &mut cfgs[n], phase, mach);
+                let time = start.elapsed();
+                if !pass.preserves_cfg() {
+                    cfgs[n].invalidate();
+                }
+                let after = count_statements(&func.body);
+                modified[n] = true;
+
+                let stats = self.stats.entry(pass.name()).or_default();
+                stats.runs += 1;
+                stats.stmts_removed += before.saturating_sub(after);
+                stats.stmts_added += after.saturating_sub(before);
+                stats.time += time;
+
+                let remarks = state.take_remarks();
+                if self.collect_remarks {
+                    self.remarks.extend(remarks.into_iter().map(|kind| Remark { pass: pass.name(), function: func.label, kind }));
+                }
+            }
+
+            for hook in &mut self.hooks {
+                hook.after_pass(pass.name(), phase, prg, mach, mode);
+            }
+        }
+
+        for (func, modified) in prg.functions.iter_mut().zip(modified) {
+            if modified {
+                flatten_function(&mut func.body);
+            }
+        }
+
+        for hook in &mut self.hooks {
+            hook.after_phase(phase, prg, mach, mode);
+        }
+
+        if self.verify
+            && let Err(errors) = verify(prg, mach, mode)
+        {
+            let errors: Vec<String> = errors.iter().map(|error| PrettyPrinter(error, mach, mode).to_string()).collect();
+            panic!("Malformed XVA after {}:\n{}", phase.name(), errors.join("\n"));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode},
+        mach::Regset,
+        traits::IntoId,
+        xva::{opt::remark::RemarkKind, Linkage, UseKind, XvaBasicBlock, XvaCategory, XvaDest, XvaFrameProperties, XvaFunctionDef, XvaOpcode, XvaOperand, XvaRegister, XvaSection, XvaType},
+    };
+
+    fn names(passes: &[&'static dyn XvaFunctionOpt]) -> Vec<&'static str> {
+        passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    fn block(label: &str, stmts: Vec<XvaStatement>) -> XvaBasicBlock {
+        XvaBasicBlock { label: Symbol::intern(label), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }
+    }
+
+    /// A file with one function `f`, with a block that simplify-cfg merges into its predecessor, removing the jump between them
+    fn mergeable() -> XvaFile {
+        let v0 = XvaRegister::Virtual(XvaDest { id: 0, ty: XvaType { size: 8, align: 8, category: XvaCategory::Int } });
+        let body = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: vec![
+                block("entry", vec![XvaStatement::Expr(crate::xva::XvaExpr { dest: v0, dest2: None, op: XvaOpcode::Read(XvaOperand::FrameAddr(0)) }), XvaStatement::Jump(Symbol::intern("next"))]),
+                block("next", vec![XvaStatement::Use(vec![v0], UseKind::Read), XvaStatement::Return]),
+            ],
+            frame_properties: XvaFrameProperties::new(),
+        };
+        let func = XvaFunctionDef { body, linkage: Linkage::External, label: Symbol::intern("f"), section: XvaSection::Text, debug: None };
+        XvaFile { functions: vec![func], ..XvaFile::default() }
+    }
+
+    fn mode() -> MachineMode {
+        X86Mode::Long.into_id()
+    }
+
+    fn run(manager: &mut PassManager, prg: &mut XvaFile) {
+        manager.run(XvaOptPhase::AfterLower, prg, &X86, mode());
+    }
+
+    fn simplify_cfg_only() -> PassManager {
+        let mut manager = PassManager::new(OptLevel::O0);
+        manager.set_pipeline(XvaOptPhase::AfterLower, &["simplify-cfg"]).unwrap();
+        manager
+    }
+
+    #[test]
+    fn pipelines_follow_the_level() {
+        let o0 = PassManager::new(OptLevel::O0);
+        assert!(XvaOptPhase::ALL.iter().all(|&phase| o0.pipeline(phase).is_empty()));
+
+        let o1 = PassManager::new(OptLevel::O1);
+        assert_eq!(names(o1.pipeline(XvaOptPhase::AfterLower)), ["optimize-fallthrough", "fold-register", "tailcall", "simplify-cfg", "remove-unused"]);
+        assert_eq!(names(o1.pipeline(XvaOptPhase::Mce)), ["peephole"]);
+
+        // Strength reduction can grow the code
+        let os = PassManager::new(OptLevel::Os);
+        assert!(!names(os.pipeline(XvaOptPhase::AfterLower)).contains(&"strength-reduce"));
+        assert!(names(PassManager::new(OptLevel::O2).pipeline(XvaOptPhase::AfterLower)).contains(&"strength-reduce"));
+
+        assert_eq!(OptLevel::from_name("s"), Some(OptLevel::Os));
+        assert_eq!(OptLevel::from_name("3"), None);
+        assert_eq!(XvaOptPhase::from_name("before-regalloc"), Some(XvaOptPhase::BeforeRegalloc));
+    }
+
+    #[test]
+    fn enable_inserts_in_the_order_of_all_passes() {
+        let mut manager = PassManager::new(OptLevel::O1);
+        manager.enable("constprop").unwrap();
+        assert_eq!(names(manager.pipeline(XvaOptPhase::AfterLower)), ["optimize-fallthrough", "fold-register", "constprop", "tailcall", "simplify-cfg", "remove-unused"]);
+
+        manager.disable("constprop").unwrap();
+        assert!(!manager.is_enabled("constprop"));
+        manager.enable("constprop").unwrap();
+        assert!(manager.is_enabled("constprop"));
+        assert_eq!(names(manager.pipeline(XvaOptPhase::AfterLower)).iter().filter(|&&name| name == "constprop").count(), 1);
+
+        assert_eq!(manager.enable("nonexistent"), Err(UnknownPass("nonexistent".to_string())));
+        assert_eq!(manager.disable("nonexistent"), Err(UnknownPass("nonexistent".to_string())));
+        assert!(manager.set_pipeline(XvaOptPhase::Mce, &["peephole", "nonexistent"]).is_err());
+        assert_eq!(names(manager.pipeline(XvaOptPhase::Mce)), ["peephole"]);
+    }
+
+    #[test]
+    fn disabled_passes_do_not_run() {
+        let mut manager = simplify_cfg_only();
+        manager.disable("simplify-cfg").unwrap();
+        let mut prg = mergeable();
+        run(&mut manager, &mut prg);
+        assert_eq!(prg, mergeable());
+        assert_eq!(manager.runs(), 0);
+    }
+
+    #[test]
+    fn records_statistics_and_runs() {
+        let mut manager = simplify_cfg_only();
+        let mut prg = mergeable();
+        run(&mut manager, &mut prg);
+
+        assert_eq!(prg.functions[0].body.body.len(), 1);
+        let stats: Vec<_> = manager.stats().map(|(name, stats)| (name, stats.runs, stats.stmts_removed, stats.stmts_added)).collect();
+        assert_eq!(stats, [("simplify-cfg", 1, 1, 0)]);
+        assert_eq!(manager.runs(), 1);
+        assert_eq!(manager.last_run(), Some(PassRun { index: 0, pass: "simplify-cfg", phase: XvaOptPhase::AfterLower, function: Symbol::intern("f") }));
+    }
+
+    #[test]
+    fn bisect_limit_and_fuel_skip_runs() {
+        let mut manager = simplify_cfg_only();
+        manager.set_bisect_limit(Some(0));
+        let mut prg = mergeable();
+        run(&mut manager, &mut prg);
+        assert_eq!(prg, mergeable());
+        // Runs skipped by the bisect limit are still counted, so that the limit can be moved past them
+        assert_eq!(manager.runs(), 1);
+        assert_eq!(manager.last_run(), None);
+
+        let mut manager = simplify_cfg_only();
+        manager.set_fuel(find_pass("simplify-cfg").unwrap().cost() - 1);
+        run(&mut manager, &mut prg);
+        assert_eq!(prg, mergeable());
+        assert_eq!(manager.runs(), 0);
+    }
+
+    #[test]
+    fn collects_remarks_when_asked() {
+        let mut manager = simplify_cfg_only();
+        run(&mut manager, &mut mergeable());
+        assert!(manager.remarks().is_empty());
+
+        manager.set_collect_remarks(true);
+        run(&mut manager, &mut mergeable());
+        let merged = Remark { pass: "simplify-cfg", function: Symbol::intern("f"), kind: RemarkKind::MergedBlock(Symbol::intern("next")) };
+        assert_eq!(manager.remarks(), [merged.clone()]);
+        assert_eq!(manager.take_remarks(), [merged.clone()]);
+        assert!(manager.remarks().is_empty());
+
+        assert_eq!(PrettyPrinter(&merged, &X86, mode()).to_string(), "simplify-cfg: f: merged block next into its predecessor");
+    }
+
+    /// Records the calls to each hook, with the number of blocks of the first function
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl PassHook for Recorder {
+        fn before_phase(&mut self, phase: XvaOptPhase, prg: &XvaFile, _: &dyn Machine, _: MachineMode) {
+            self.0.borrow_mut().push(format!("before {} with {} blocks", phase.name(), prg.functions[0].body.body.len()));
+        }
+
+        fn after_phase(&mut self, phase: XvaOptPhase, prg: &XvaFile, _: &dyn Machine, _: MachineMode) {
+            self.0.borrow_mut().push(format!("after {} with {} blocks", phase.name(), prg.functions[0].body.body.len()));
+        }
+
+        fn before_pass(&mut self, pass: &'static str, _: XvaOptPhase, prg: &XvaFile, _: &dyn Machine, _: MachineMode) {
+            self.0.borrow_mut().push(format!("before {pass} with {} blocks", prg.functions[0].body.body.len()));
+        }
+
+        fn after_pass(&mut self, pass: &'static str, _: XvaOptPhase, prg: &XvaFile, _: &dyn Machine, _: MachineMode) {
+            self.0.borrow_mut().push(format!("after {pass} with {} blocks", prg.functions[0].body.body.len()));
+        }
+    }
+
+    #[test]
+    fn calls_hooks_around_phases_and_passes() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut manager = simplify_cfg_only();
+        manager.add_hook(Box::new(Recorder(log.clone())));
+        run(&mut manager, &mut mergeable());
+        assert_eq!(*log.borrow(), [
+            "before after-lower with 2 blocks",
+            "before simplify-cfg with 2 blocks",
+            "after simplify-cfg with 1 blocks",
+            "after after-lower with 1 blocks",
+        ]);
+    }
+
+    #[test]
+    fn dumps_the_selected_passes_and_phases() {
+        let prg = mergeable();
+        let mut dump = IrDump::new(Vec::new());
+        dump.dump_pass("gvn", DumpWhen::After);
+        dump.dump_phase(XvaOptPhase::Mce, DumpWhen::Before);
+        dump.before_pass("gvn", XvaOptPhase::AfterLower, &prg, &X86, mode());
+        dump.after_pass("peephole", XvaOptPhase::Mce, &prg, &X86, mode());
+        dump.after_phase(XvaOptPhase::Mce, &prg, &X86, mode());
+        assert!(dump.out.is_empty());
+
+        dump.after_pass("gvn", XvaOptPhase::AfterLower, &prg, &X86, mode());
+        dump.before_phase(XvaOptPhase::Mce, &prg, &X86, mode());
+        let out = String::from_utf8(dump.into_inner()).unwrap();
+        assert!(out.starts_with("*** IR Dump After gvn (after-lower) ***\n"));
+        assert!(out.contains("*** IR Dump Before mce ***\n"));
+        assert_eq!(out.matches("function f").count(), 2);
+    }
+
+    #[test]
+    #[should_panic = "Malformed XVA after after-lower"]
+    fn verifies_the_file_after_each_phase() {
+        let mut manager = simplify_cfg_only();
+        manager.set_verify(true);
+        // v0 is used without its definition
+        let mut prg = mergeable();
+        let XvaBlockBody::Statement(stmts) = &mut prg.functions[0].body.body[0].body;
+        stmts.remove(0);
+        run(&mut manager, &mut prg);
+    }
+}
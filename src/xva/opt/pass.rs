@@ -4,11 +4,14 @@ use std::{
 };
 
 use crate::{
+    intern::Symbol,
     mach::{Machine, MachineMode, Register, RegisterSpec, Regset},
     xva::{
         self, BarrierKind, UseKind, XvaConst, XvaExpr, XvaOpcode, XvaOperand, XvaRegister,
         XvaStatement,
-        opt::{State, XvaFunctionOpt, XvaOpt, XvaOptPhase, XvaStatementOpt},
+        cfg::{CfgCache, XvaCfg, is_terminator},
+        dataflow::{LiveSet, Liveness},
+        opt::{State, XvaFunctionOpt, XvaOpt, XvaOptPhase, XvaStatementOpt, remark::RemarkKind},
     },
 };
 
@@ -29,11 +32,16 @@ pub struct PassState {
     pub live_register_values: HashMap<XvaRegister, LiveValue>,
     pub opt_gate_state: Vec<(u32, BarrierKind)>,
     pub mode: MachineMode,
+    pub remarks: Vec<RemarkKind>,
 }
 
 impl PassState {
     pub fn new(mode: MachineMode) -> Self {
-        Self{live_register_values: HashMap::new(), opt_gate_state: Vec::new(), mode}
+        Self{live_register_values: HashMap::new(), opt_gate_state: Vec::new(), mode, remarks: Vec::new()}
+    }
+
+    pub fn remark(&mut self, kind: RemarkKind) {
+        self.remarks.push(kind);
     }
 }
 
@@ -49,6 +57,27 @@ impl PassState {
         return !state.intersects(forbids);
     }
 
+    /// The opt gates that are open at the start of each block of `func`.
+    /// Opt gates are scoped by the layout of the function, not by its control flow
+    pub fn block_gate_states(&mut self, func: &xva::XvaFunction) -> Vec<Vec<(u32, BarrierKind)>> {
+        let mut gates = Vec::with_capacity(func.body.len());
+        for block in &func.body {
+            gates.push(self.opt_gate_state.clone());
+            match &block.body {
+                xva::XvaBlockBody::Statement(stmts) => {
+                    for stmt in stmts {
+                        match stmt {
+                            XvaStatement::OptGate(kind, num) => self.push_gate(*kind, *num),
+                            XvaStatement::EndOptGate(num) => self.pop_gate(*num),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        gates
+    }
+
     pub fn test_uninit(&self, reg: XvaRegister) -> bool {
         self.live_register_values
             .get(&reg)
@@ -76,6 +105,10 @@ impl State for PassState {
 
         assert_eq!(num_stored, num);
     }
+
+    fn take_remarks(&mut self) -> Vec<RemarkKind> {
+        core::mem::take(&mut self.remarks)
+    }
 }
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
@@ -111,6 +144,10 @@ impl LiveValue {
 pub struct FoldRegisterPass;
 
 impl XvaOpt for FoldRegisterPass {
+    fn name(&self) -> &'static str {
+        "fold-register"
+    }
+
     fn cost(&self) -> usize {
         10
     }
@@ -118,6 +155,10 @@ impl XvaOpt for FoldRegisterPass {
         &[super::XvaOptPhase::AfterLower, XvaOptPhase::BeforeRegalloc]
     }
 
+    fn preserves_cfg(&self) -> bool {
+        true
+    }
+
     fn make_state(&self, mode: MachineMode) -> Box<dyn State> {
         Box::new(PassState::new(mode))
     }
@@ -184,6 +225,7 @@ impl XvaStatementOpt for FoldRegisterPass {
                                 if state.test_barrier(BarrierKind::ELIDE_REGISTERS)
                                     && let Some(op) = val.replace_move_opcode()
                                 {
+                                    state.remark(RemarkKind::Folded { reg: xva_expr.dest, op: op.clone() });
                                     xva_expr.op = op;
                                 }
                             }
@@ -250,12 +292,14 @@ impl XvaStatementOpt for FoldRegisterPass {
                 }
             }
             crate::xva::XvaStatement::Jump(_) => {}
+            crate::xva::XvaStatement::JumpIf(_, _) => {}
             crate::xva::XvaStatement::Tailcall { .. } => {}
             crate::xva::XvaStatement::Call {
                 dest,
                 params,
                 ret_val,
                 call_clobber_regs,
+                ..
             } => {
                 for reg in call_clobber_regs.into_regids(mach, state.mode) {
                     if state.test_barrier(BarrierKind::ELIDE_STORE) {
@@ -306,20 +350,28 @@ impl XvaStatementOpt for FoldRegisterPass {
                 }
                 _ => {}
             },
-            XvaStatement::Fallthrough(_) => {}
+            XvaStatement::Fallthrough(_) | XvaStatement::Loc(_) | XvaStatement::Cfi(_) => {}
+            XvaStatement::InlineAsm(asm) => {
+                for reg in asm.clobbers.into_regids(mach, state.mode) {
+                    state.mark_has_value(XvaRegister::Physical(reg));
+                }
+                for output in &asm.outputs {
+                    state.mark_has_value(output.reg);
+                }
+            }
+            XvaStatement::Label(_) => {
+                state.reset_registers();
+            }
         }
     }
 }
 pub struct RemoveUnusedState {
     pass: PassState,
-    used_regs: HashSet<XvaRegister>,
-    return_regs: Regset,
-    used_pregs: Regset,
 }
 
 impl RemoveUnusedState {
     pub fn new(mode: MachineMode) -> Self {
-        Self{pass: PassState::new(mode), used_regs: HashSet::new(), return_regs: Regset::new(), used_pregs: Regset::new()}
+        Self{pass: PassState::new(mode)}
     }
 }
 
@@ -339,11 +391,23 @@ impl State for RemoveUnusedState {
     fn pop_gate(&mut self, num: u32) {
         self.pass.pop_gate(num);
     }
+
+    fn take_remarks(&mut self) -> Vec<RemarkKind> {
+        self.pass.take_remarks()
+    }
 }
 
+/// Removes the expressions whose results are not live after them, and moves of a register into itself.
+///
+/// Removing an expression can leave the definitions of its operands dead, so this repeats until nothing else is removed.
+/// The parameters of the function and [`XvaBasicBlock::live_at_start`][xva::XvaBasicBlock::live_at_start] are then narrowed to the registers that are still live
 pub struct RemoveUnused;
 
 impl XvaOpt for RemoveUnused {
+    fn name(&self) -> &'static str {
+        "remove-unused"
+    }
+
     fn cost(&self) -> usize {
         15
     }
@@ -355,146 +419,36 @@ impl XvaOpt for RemoveUnused {
     fn phases(&self) -> &[XvaOptPhase] {
         &[super::XvaOptPhase::AfterLower, XvaOptPhase::BeforeRegalloc]
     }
-}
-
-impl RemoveUnused {
-    fn collect_operand(&self, state: &mut RemoveUnusedState, opr: XvaOperand) {
-        match opr {
-            XvaOperand::Register(reg) => {
-                state.used_regs.insert(reg);
-            }
-            XvaOperand::Const(_) | XvaOperand::FrameAddr(_) => {}
-        }
-    }
-
-    fn collect_expr(&self, state: &mut RemoveUnusedState, expr: &XvaExpr) {
-        match &expr.op {
-            XvaOpcode::ZeroInit
-            | XvaOpcode::Const(_)
-            | XvaOpcode::Uninit
-            | XvaOpcode::GetFrameAddr(_) => {}
-            XvaOpcode::Move(xva_register) => {
-                state.used_regs.insert(*xva_register);
-            }
-            XvaOpcode::Read(opr) => {
-                self.collect_operand(state, *opr);
-            }
-            XvaOpcode::ComputeAddr { base, index, .. } => {
-                self.collect_operand(state, *base);
-                self.collect_operand(state, *index);
-            }
-            XvaOpcode::CheckedBinaryOp { left, right, .. }
-            | XvaOpcode::BinaryOp { left, right, .. } => {
-                state.used_regs.insert(*left);
-                self.collect_operand(state, *right);
-            }
-
-            XvaOpcode::UnaryOp { left, .. } => {
-                state.used_regs.insert(*left);
-            }
-
-            XvaOpcode::UMul { left, right } | XvaOpcode::SMul { left, right } => {
-                state.used_regs.insert(*left);
-                state.used_regs.insert(*right);
-            }
-        }
-    }
-    pub fn collect_phase(&self, state: &mut RemoveUnusedState, stmt: &XvaStatement, mach: &dyn Machine) {
-        match stmt {
-            xva::XvaStatement::Expr(xva_expr) => self.collect_expr(state, xva_expr),
-            xva::XvaStatement::Write(opr, _,  reg) => {
-                self.collect_operand(state, *opr);
-                state.used_regs.insert(*reg);
-            }
-            xva::XvaStatement::Jump(symbol) => {}
-            xva::XvaStatement::Call { dest, params, .. }
-            | xva::XvaStatement::Tailcall { dest, params } => {
-                self.collect_operand(state, *dest);
-                state.used_pregs.insert_bits(**params);
-                for reg in params.into_regids(mach, state.pass.mode) {
-                    state.used_regs.insert(XvaRegister::Physical(reg));
-                }
-            }
-            xva::XvaStatement::Return => {
-                state.used_pregs.insert_bits(*state.return_regs);
-                for reg in state.return_regs.into_regids(mach, state.pass.mode) {
-                    state.used_regs.insert(XvaRegister::Physical(reg));
-                }
-            }
-            xva::XvaStatement::Trap(_) => {}
-            xva::XvaStatement::RawInstr(instruction) => {
-                for opr in instruction.operands() {
-                    match opr {
-                        crate::instr::Operand::Register(register) => {
-                            state.used_regs.insert(XvaRegister::Physical(*register));
-                        }
-                        crate::instr::Operand::AbsSymbol(_, _)
-                        | crate::instr::Operand::RelSymbol(_, _)
-                        | crate::instr::Operand::Immediate(_) => {}
-
-                        crate::instr::Operand::Memory(memory_operand) => {
-                            let addr = &memory_operand.addr;
-
-                            for reg in [addr.segment, addr.base, addr.index].into_iter().flatten() {
-                                state.used_regs.insert(XvaRegister::Physical(reg));
-                            }
-                        }
-                    }
-                }
-            }
-            xva::XvaStatement::OptGate(kind, num) => state.push_gate(*kind, *num),
-            xva::XvaStatement::EndOptGate(num) => state.pop_gate(*num),
-            xva::XvaStatement::Noop(_) => {}
-            xva::XvaStatement::Elaborated(xva_statements) => {
-                for stmt in xva_statements {
-                    self.collect_phase(state, stmt, mach);
-                }
-            }
-            xva::XvaStatement::Use(regs, use_kind) => match use_kind {
-                UseKind::Read | UseKind::ReadWrite
-                    if state.pass.test_barrier(BarrierKind::ELIDE_REGISTERS) =>
-                {
-                    for reg in regs {
-                        state.used_regs.insert(*reg);
-                    }
-                }
-                _ => {}
-            },
-            XvaStatement::Fallthrough(_) => {}
-        }
-    }
-
-    fn reg_used(&self, state: &RemoveUnusedState, reg: XvaRegister, mach: &dyn Machine) -> bool {
-        if state.used_regs.contains(&reg) {
-            return true;
-        }
 
-        match reg {
-            XvaRegister::Physical(preg) => {
-                state.used_pregs.contains_regid(preg, mach)
-            }
-            _ => false
-        }
+    fn preserves_cfg(&self) -> bool {
+        true
     }
+}
 
-    pub fn remove_phase(&self, state: &mut RemoveUnusedState, stmt: &mut XvaStatement, mach: &dyn Machine) {
-        match stmt {
-            xva::XvaStatement::OptGate(kind, num) => state.push_gate(*kind, *num),
-            xva::XvaStatement::EndOptGate(num) => state.pop_gate(*num),
-            XvaStatement::Expr(xva_expr) => {
-                if state.pass.test_barrier(BarrierKind::ELIDE_REGISTERS | BarrierKind::ELIDE_INSTRS) {
-                    let dest = xva_expr.dest;
-                    let dest2 = xva_expr.dest2;
-
-                    if !self.reg_used(state,dest, mach) && !dest2.filter(|r| self.reg_used(state, *r, mach)).is_some() {
+impl RemoveUnused {
+    /// Removes the dead expressions of `block`, given the registers live after each of its statements.
+    /// Returns true if anything was removed
+    fn remove_dead(&self, state: &mut RemoveUnusedState, stmts: &mut [XvaStatement], live_out: &[LiveSet], mach: &dyn Machine) -> bool {
+        let mut changed = false;
+        for (stmt, live) in stmts.iter_mut().zip(live_out) {
+            match stmt {
+                XvaStatement::OptGate(kind, num) => state.push_gate(*kind, *num),
+                XvaStatement::EndOptGate(num) => state.pop_gate(*num),
+                XvaStatement::Expr(expr) if state.pass.test_barrier(BarrierKind::ELIDE_REGISTERS | BarrierKind::ELIDE_INSTRS) => {
+                    let dest = expr.dest;
+                    if !live.is_live(dest, mach) && !expr.dest2.is_some_and(|dest2| live.is_live(dest2, mach)) {
+                        state.pass.remark(RemarkKind::RemovedDeadDef(dest));
                         *stmt = XvaStatement::Elaborated(vec![]);
-                    } else if let XvaOpcode::Move(reg) = xva_expr.op && reg == dest {
+                        changed = true;
+                    } else if let XvaOpcode::Move(reg) = expr.op && reg == dest {
                         *stmt = XvaStatement::Elaborated(vec![]);
+                        changed = true;
                     }
                 }
+                _ => {}
             }
-            _ => {}
         }
+        changed
     }
 }
 
@@ -503,6 +457,7 @@ impl XvaFunctionOpt for RemoveUnused {
         &self,
         state: &mut dyn State,
         func: &mut xva::XvaFunction,
+        cfgs: &mut CfgCache,
         _: XvaOptPhase,
         mach: &dyn Machine
     ) {
@@ -510,34 +465,34 @@ impl XvaFunctionOpt for RemoveUnused {
             .downcast_mut::<RemoveUnusedState>()
             .unwrap();
 
-        state.return_regs = func.return_regs;
+        let cfg = cfgs.get(func).cfg.clone();
+        let gates = state.pass.block_gate_states(func);
+        let liveness = Liveness::new(func, mach, state.pass.mode);
 
-        for stmt in &mut func.body {
-            match &mut stmt.body {
-                xva::XvaBlockBody::Statement(xva_statements) => {
-                    for stmt in xva_statements {
-                        self.collect_phase(state, stmt, mach);
-                    }
+        let live = loop {
+            let live = liveness.solve(func, &cfg);
+            let mut changed = false;
+            for (n, block) in func.body.iter_mut().enumerate() {
+                let live_out = liveness.live_out_per_statement(block, &live.block_end[n]);
+                state.pass.opt_gate_state = gates[n].clone();
+                match &mut block.body {
+                    xva::XvaBlockBody::Statement(stmts) => changed |= self.remove_dead(state, stmts, &live_out, mach),
                 }
             }
-        }
-
-        func.params.retain_all_regids(state.used_regs.iter().filter_map(|r| {
-            match r {
-                XvaRegister::Physical(r) => Some(*r),
-                _ => None
+            if !changed {
+                break live;
             }
-        }), mach);
+        };
 
-        for stmt in &mut func.body {
-            stmt.live_at_start.retain(|v| state.used_regs.contains(v));
-            match &mut stmt.body {
-                xva::XvaBlockBody::Statement(xva_statements) => {
-                    for stmt in xva_statements {
-                        self.remove_phase(state, stmt, mach);
-                    }
-                }
-            }
+        if let Some(entry) = live.block_start.first() {
+            func.params.retain_all_regids(entry.iter().filter_map(|reg| match reg {
+                XvaRegister::Physical(reg) => Some(reg),
+                XvaRegister::Virtual(_) => None,
+            }), mach);
+        }
+
+        for (block, start) in func.body.iter_mut().zip(&live.block_start) {
+            block.live_at_start.retain(|&reg| start.is_live(reg, mach));
         }
     }
 }
@@ -545,6 +500,10 @@ impl XvaFunctionOpt for RemoveUnused {
 pub struct OptimizeFallthrough;
 
 impl XvaOpt for OptimizeFallthrough {
+    fn name(&self) -> &'static str {
+        "optimize-fallthrough"
+    }
+
     fn phases(&self) -> &[XvaOptPhase] {
         &[XvaOptPhase::AfterLower]
     }
@@ -556,10 +515,15 @@ impl XvaOpt for OptimizeFallthrough {
     fn make_state(&self,_ :MachineMode) -> Box<dyn State> {
         Box::new(NoState)
     }
+
+    /// Replacing a jump to the next block with a fallthrough does not change the edges of the graph
+    fn preserves_cfg(&self) -> bool {
+        true
+    }
 }
 
 impl XvaFunctionOpt for OptimizeFallthrough {
-    fn optimize_function(&self, _: &mut dyn State, func: &mut xva::XvaFunction, _: XvaOptPhase, _: &dyn Machine) {
+    fn optimize_function(&self, _: &mut dyn State, func: &mut xva::XvaFunction, _: &mut CfgCache, _: XvaOptPhase, _: &dyn Machine) {
         let mut labels = Vec::new();
         for bb in &func.body {
             labels.push(bb.label);
@@ -582,3 +546,992 @@ impl XvaFunctionOpt for OptimizeFallthrough {
         }
     }
 }
+
+/// Simplifies the control flow of functions.
+///
+/// Deletes blocks that cannot be reached from the entry block, threads jumps through blocks that only jump elsewhere,
+/// removes empty blocks that fall through, and merges blocks into their only predecessor.
+/// Blocks whose labels are used as values are always kept. [`xva::XvaBasicBlock::live_at_start`] is recomputed if anything changes
+pub struct SimplifyCfg;
+
+impl XvaOpt for SimplifyCfg {
+    fn name(&self) -> &'static str {
+        "simplify-cfg"
+    }
+
+    fn phases(&self) -> &[XvaOptPhase] {
+        &[XvaOptPhase::AfterLower, XvaOptPhase::BeforeRegalloc]
+    }
+
+    fn cost(&self) -> usize {
+        15
+    }
+
+    fn make_state(&self, mode: MachineMode) -> Box<dyn State> {
+        Box::new(PassState::new(mode))
+    }
+}
+
+fn jump_target(stmt: &mut XvaStatement) -> Option<&mut Symbol> {
+    match stmt {
+        XvaStatement::Jump(target) | XvaStatement::JumpIf(_, target) | XvaStatement::Fallthrough(target) => Some(target),
+        _ => None,
+    }
+}
+
+impl SimplifyCfg {
+    /// Whether `block` defines a label in `syms`
+    fn defines_any(block: &xva::XvaBasicBlock, syms: &HashSet<Symbol>) -> bool {
+        syms.contains(&block.label) || block.stmts().iter().any(|stmt| matches!(stmt, XvaStatement::Label(l) if syms.contains(l)))
+    }
+
+    fn has_gates(block: &xva::XvaBasicBlock) -> bool {
+        block.stmts().iter().any(|stmt| matches!(stmt, XvaStatement::OptGate(..) | XvaStatement::EndOptGate(_)))
+    }
+
+    /// The label that block `n` immediately passes control to, if the block does nothing else
+    fn forward_target(func: &xva::XvaFunction, n: usize) -> Option<Symbol> {
+        match func.body[n].stmts().as_slice() {
+            [] => func.body.get(n + 1).map(|next| next.label),
+            [XvaStatement::Jump(target) | XvaStatement::Fallthrough(target)] => Some(*target),
+            _ => None,
+        }
+    }
+
+    /// Retargets jumps to blocks that only pass control to another block
+    fn thread_jumps(func: &mut xva::XvaFunction) -> bool {
+        let blocks: HashMap<Symbol, usize> = func.body.iter().enumerate().map(|(n, block)| (block.label, n)).collect();
+        let mut threaded = HashMap::new();
+
+        for n in 0..func.body.len() {
+            let label = func.body[n].label;
+            let mut target = label;
+            let mut resolved = false;
+            // Bounded by the number of blocks, so that a cycle of empty blocks is left alone
+            for _ in 0..func.body.len() {
+                match blocks.get(&target).and_then(|&m| Self::forward_target(func, m)) {
+                    Some(next) if next == label => break,
+                    Some(next) => target = next,
+                    None => {
+                        resolved = true;
+                        break;
+                    }
+                }
+            }
+            if resolved && target != label {
+                threaded.insert(label, target);
+            }
+        }
+
+        let mut changed = false;
+        for block in &mut func.body {
+            let label = block.label;
+            for stmt in block.stmts_mut() {
+                if let Some(target) = jump_target(stmt)
+                    && let Some(&new) = threaded.get(target)
+                    && new != label
+                {
+                    *target = new;
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    /// Deletes blocks that cannot be reached from the entry block, or from a block whose label is used as a value.
+    /// Blocks with opt gates are kept, so that gates stay balanced
+    fn remove_unreachable(func: &mut xva::XvaFunction, state: &mut PassState, used: &HashSet<Symbol>) -> bool {
+        let cfg = XvaCfg::new(func);
+        let mut reachable = vec![false; func.body.len()];
+        let mut stack: Vec<usize> = (0..func.body.len()).filter(|&n| n == 0 || Self::defines_any(&func.body[n], used)).collect();
+        while let Some(n) = stack.pop() {
+            if !core::mem::replace(&mut reachable[n], true) {
+                stack.extend(cfg.successors(n).iter().copied().filter(|&s| !reachable[s]));
+            }
+        }
+
+        let len = func.body.len();
+        let mut n = 0;
+        func.body.retain(|block| {
+            let keep = reachable[n] || Self::has_gates(block);
+            if !keep {
+                state.remark(RemarkKind::RemovedBlock(block.label));
+            }
+            n += 1;
+            keep
+        });
+        func.body.len() != len
+    }
+
+    /// Deletes empty blocks that fall through into the next block, and are no longer the target of any jump
+    fn remove_empty(func: &mut xva::XvaFunction, state: &mut PassState, used: &HashSet<Symbol>) -> bool {
+        let mut targets = HashSet::new();
+        for block in &mut func.body {
+            for stmt in block.stmts_mut() {
+                if let Some(target) = jump_target(stmt) {
+                    targets.insert(*target);
+                }
+            }
+        }
+
+        let len = func.body.len();
+        for n in (1..len.saturating_sub(1)).rev() {
+            let block = &func.body[n];
+            let next = func.body[n + 1].label;
+            let empty = match block.stmts().as_slice() {
+                [] => true,
+                [XvaStatement::Fallthrough(target)] => *target == next,
+                _ => false,
+            };
+            if empty && !targets.contains(&block.label) && !used.contains(&block.label) {
+                state.remark(RemarkKind::RemovedBlock(block.label));
+                func.body.remove(n);
+            }
+        }
+        func.body.len() != len
+    }
+
+    /// Merges a block into its only predecessor, if the predecessor has no other successor
+    fn merge_blocks(func: &mut xva::XvaFunction, state: &mut PassState, used: &HashSet<Symbol>) -> bool {
+        let cfg = XvaCfg::new(func);
+        let gates = state.block_gate_states(func);
+        let len = func.body.len();
+
+        for b in 1..len {
+            let &[a] = cfg.predecessors(b) else {
+                continue;
+            };
+            if a == b || cfg.successors(a) != [b] || Self::defines_any(&func.body[b], used) {
+                continue;
+            }
+
+            // The statements of `b` are moved to the end of `a`, so they must be in the scope of the same opt gates
+            let gates_after_a = gates.get(a + 1).cloned().unwrap_or_default();
+            if a + 1 != b && (Self::has_gates(&func.body[b]) || gates_after_a != gates[b]) {
+                continue;
+            }
+
+            let label = func.body[b].label;
+            let pred = func.body[a].stmts();
+            let ends_with_jump = matches!(pred.last(), Some(XvaStatement::Jump(target) | XvaStatement::Fallthrough(target)) if *target == label);
+            let other_refs = pred.iter().rev().skip(usize::from(ends_with_jump)).any(|stmt| matches!(stmt, XvaStatement::Jump(t) | XvaStatement::JumpIf(_, t) | XvaStatement::Fallthrough(t) if *t == label));
+            if other_refs || !(ends_with_jump || a + 1 == b) {
+                continue;
+            }
+
+            let falls_off = !func.body[b].stmts().last().is_some_and(is_terminator);
+            if falls_off && a + 1 != b && b + 1 >= len {
+                continue;
+            }
+
+            let next = func.body.get(b + 1).map(|block| block.label);
+            let block = func.body.remove(b);
+            let mut stmts = match block.body {
+                xva::XvaBlockBody::Statement(stmts) => stmts,
+            };
+            // `b` no longer follows its old predecessor in the layout, so its fallthrough becomes explicit
+            if falls_off && a + 1 != b
+                && let Some(next) = next
+            {
+                stmts.push(XvaStatement::Jump(next));
+            }
+            let a = if a > b { a - 1 } else { a };
+            state.remark(RemarkKind::MergedBlock(label));
+
+            let pred = func.body[a].stmts_mut();
+            if ends_with_jump {
+                pred.pop();
+            }
+            pred.append(&mut stmts);
+            return true;
+        }
+
+        false
+    }
+
+    /// Replaces each [`XvaStatement::Fallthrough`] to a block that no longer follows it with a [`XvaStatement::Jump`],
+    /// and each jump at the end of a block to the block that now follows it with a [`XvaStatement::Fallthrough`]
+    fn fix_fallthroughs(func: &mut xva::XvaFunction) {
+        let labels: Vec<Symbol> = func.body.iter().map(|block| block.label).collect();
+        for (n, block) in func.body.iter_mut().enumerate() {
+            let next = labels.get(n + 1).copied();
+            let stmts = block.stmts_mut();
+            let last = stmts.len().saturating_sub(1);
+            for (i, stmt) in stmts.iter_mut().enumerate() {
+                match *stmt {
+                    XvaStatement::Fallthrough(target) if next != Some(target) => *stmt = XvaStatement::Jump(target),
+                    XvaStatement::Jump(target) if i == last && next == Some(target) => *stmt = XvaStatement::Fallthrough(target),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+impl XvaFunctionOpt for SimplifyCfg {
+    fn optimize_function(&self, state: &mut dyn State, func: &mut xva::XvaFunction, _: &mut CfgCache, _: XvaOptPhase, mach: &dyn Machine) {
+        let state = (state as &mut dyn Any).downcast_mut::<PassState>().unwrap();
+
+        super::flatten_function(func);
+        let used = xva::used_symbols(func);
+
+        let mut changed = false;
+        loop {
+            let mut progress = Self::thread_jumps(func);
+            progress |= Self::remove_unreachable(func, state, &used);
+            progress |= Self::remove_empty(func, state, &used);
+            progress |= Self::merge_blocks(func, state, &used);
+            if !progress {
+                break;
+            }
+            changed = true;
+        }
+
+        if changed {
+            Self::fix_fallthroughs(func);
+            let cfg = XvaCfg::new(func);
+            xva::dataflow::compute_live_at_start(func, &cfg, mach, state.mode);
+        }
+    }
+}
+
+/// Counts the definitions of each register in `func`. Registers live at the start of the entry block count as defined once there
+pub(crate) fn count_defs(func: &xva::XvaFunction) -> HashMap<XvaRegister, usize> {
+    let mut defs: HashMap<XvaRegister, usize> = HashMap::new();
+    if let Some(entry) = func.body.first() {
+        for &reg in &entry.live_at_start {
+            *defs.entry(reg).or_default() += 1;
+        }
+    }
+    for block in &func.body {
+        for stmt in block.stmts() {
+            stmt.for_each_def(|reg| *defs.entry(reg).or_default() += 1);
+        }
+    }
+    defs
+}
+
+fn for_each_operand_reg(opr: &mut XvaOperand, f: &mut impl FnMut(&mut XvaRegister)) {
+    if let XvaOperand::Register(reg) = opr {
+        f(reg);
+    }
+}
+
+/// Calls `f` with each register read by `op`
+pub(crate) fn for_each_opcode_reg(op: &mut XvaOpcode, f: &mut impl FnMut(&mut XvaRegister)) {
+    match op {
+        XvaOpcode::ZeroInit | XvaOpcode::Const(_) | XvaOpcode::Uninit | XvaOpcode::GetFrameAddr(_) | XvaOpcode::TlsIndex { .. } => {}
+        XvaOpcode::Move(reg) | XvaOpcode::UnaryOp { left: reg, .. } => f(reg),
+        XvaOpcode::ComputeAddr { base, index, .. } => {
+            for_each_operand_reg(base, f);
+            for_each_operand_reg(index, f);
+        }
+        XvaOpcode::BinaryOp { left, right, .. } | XvaOpcode::CheckedBinaryOp { left, right, .. } => {
+            f(left);
+            for_each_operand_reg(right, f);
+        }
+        XvaOpcode::Read(opr) => for_each_operand_reg(opr, f),
+        XvaOpcode::UMul { left, right } | XvaOpcode::SMul { left, right } | XvaOpcode::UDiv { left, right } | XvaOpcode::SDiv { left, right } => {
+            f(left);
+            f(right);
+        }
+        XvaOpcode::Select { cond, left, right } => {
+            f(cond);
+            f(left);
+            f(right);
+        }
+        XvaOpcode::TlsAddr { module_base, .. } => {
+            if let Some(reg) = module_base {
+                f(reg);
+            }
+        }
+    }
+}
+
+fn opcode_regs(op: &XvaOpcode) -> Vec<XvaRegister> {
+    let mut regs = Vec::new();
+    for_each_opcode_reg(&mut op.clone(), &mut |reg| regs.push(*reg));
+    regs
+}
+
+/// Whether an access of `a_size` bytes at `a` may overlap an access of `b_size` bytes at `b`
+pub(crate) fn may_alias(a: XvaOperand, a_size: u64, b: XvaOperand, b_size: u64) -> bool {
+    let overlaps = |a: i64, b: i64| a < b + b_size as i64 && b < a + a_size as i64;
+    match (a, b) {
+        (XvaOperand::FrameAddr(a), XvaOperand::FrameAddr(b)) => overlaps(a as i64, b as i64),
+        (XvaOperand::Const(XvaConst::Global(a, a_off)), XvaOperand::Const(XvaConst::Global(b, b_off))) => a == b && overlaps(a_off, b_off),
+        (XvaOperand::Const(XvaConst::Label(a)), XvaOperand::Const(XvaConst::Label(b))) => a == b,
+        // The stack frame never overlaps a static object
+        (XvaOperand::FrameAddr(_), XvaOperand::Const(XvaConst::Global(..) | XvaConst::Label(_)))
+        | (XvaOperand::Const(XvaConst::Global(..) | XvaConst::Label(_)), XvaOperand::FrameAddr(_)) => false,
+        _ => true,
+    }
+}
+
+/// An expression computed by [`GlobalValueNumbering`], by its opcode and the type of its destination
+type ValueKey = (XvaOpcode, xva::XvaType);
+
+#[derive(Default)]
+struct ValueTable {
+    exprs: HashMap<ValueKey, XvaRegister>,
+    copies: HashMap<XvaRegister, XvaRegister>,
+}
+
+/// An entry of a [`ValueTable`], by its key
+enum ValueEntry {
+    Expr(ValueKey),
+    Copy(XvaRegister),
+}
+
+struct GvnState<'a> {
+    pass: &'a mut PassState,
+    mach: &'a dyn Machine,
+    /// Virtual registers that are written by exactly one statement
+    single_def: HashSet<XvaRegister>,
+    /// The values available in the current block from its dominators
+    global: ValueTable,
+    local: ValueTable,
+}
+
+impl<'a> GvnState<'a> {
+    fn leader(&self, reg: XvaRegister) -> XvaRegister {
+        self.local.copies.get(&reg).or_else(|| self.global.copies.get(&reg)).copied().unwrap_or(reg)
+    }
+
+    /// Forgets the values in the current block that are computed from `reg` or held in it
+    fn kill(&mut self, reg: XvaRegister) {
+        let mach = self.mach;
+        self.local.exprs.retain(|(op, _), val| !val.aliases(reg, mach) && !opcode_regs(op).into_iter().any(|r| r.aliases(reg, mach)));
+        self.local.copies.retain(|dest, src| !dest.aliases(reg, mach) && !src.aliases(reg, mach));
+    }
+
+    /// Forgets the values in the current block that are read from memory overlapping the `size` bytes at `addr`, or from all memory if `addr` is [`None`]
+    fn clobber_memory(&mut self, addr: Option<(XvaOperand, u64)>) {
+        self.local.exprs.retain(|(op, ty), _| match (op, addr) {
+            (XvaOpcode::Read(read), Some((addr, size))) => !may_alias(*read, ty.size, addr, size),
+            (XvaOpcode::Read(_), None) => false,
+            _ => true,
+        });
+    }
+
+    fn is_single_def(&self, reg: XvaRegister) -> bool {
+        self.single_def.contains(&reg)
+    }
+
+    fn process_expr(&mut self, expr: &mut XvaExpr, exports: Option<&mut Vec<ValueEntry>>) {
+        let numbered = expr.dest2.is_none()
+            && matches!(
+                expr.op,
+                XvaOpcode::ComputeAddr { .. }
+                    | XvaOpcode::BinaryOp { .. }
+                    | XvaOpcode::UnaryOp { .. }
+                    | XvaOpcode::UMul { .. }
+                    | XvaOpcode::SMul { .. }
+                    | XvaOpcode::UDiv { .. }
+                    | XvaOpcode::SDiv { .. }
+                    | XvaOpcode::Select { .. }
+                    | XvaOpcode::GetFrameAddr(_)
+                    | XvaOpcode::Read(_)
+            );
+
+        let mut key = None;
+        if numbered {
+            let mut op = expr.op.clone();
+            for_each_opcode_reg(&mut op, &mut |reg| *reg = self.leader(*reg));
+            let k = (op, expr.dest.ty(self.mach, self.pass.mode));
+
+            if self.pass.test_barrier(BarrierKind::PROPAGATE_THROUGH | BarrierKind::ELIDE_INSTRS)
+                && let Some(&val) = self.local.exprs.get(&k).or_else(|| self.global.exprs.get(&k))
+                && val != expr.dest
+            {
+                self.pass.remark(RemarkKind::ReusedValue { reg: expr.dest, from: val });
+                expr.op = XvaOpcode::Move(val);
+            } else {
+                key = Some(k);
+            }
+        }
+
+        let dest = expr.dest;
+        self.kill(dest);
+        if let Some(dest2) = expr.dest2 {
+            self.kill(dest2);
+        }
+
+        if !self.pass.test_barrier(BarrierKind::PROPAGATE_THROUGH) {
+            return;
+        }
+
+        if let XvaOpcode::Move(src) = expr.op {
+            let src = self.leader(src);
+            if src != dest {
+                self.local.copies.insert(dest, src);
+                if self.is_single_def(dest) && self.is_single_def(src)
+                    && let Some(exports) = exports
+                {
+                    exports.push(ValueEntry::Copy(dest));
+                }
+            }
+        } else if let Some(key) = key {
+            let regs = opcode_regs(&key.0);
+            if regs.iter().any(|&r| r.aliases(dest, self.mach)) {
+                return;
+            }
+
+            let global = !matches!(key.0, XvaOpcode::Read(_)) && self.is_single_def(dest) && regs.iter().all(|&r| self.is_single_def(r));
+            if global && let Some(exports) = exports {
+                exports.push(ValueEntry::Expr(key.clone()));
+            }
+            self.local.exprs.insert(key, dest);
+        }
+    }
+
+    /// Numbers the statements of `stmts`. Returns the values that are available at the end of the block, and can be used in the blocks it dominates
+    fn process_block(&mut self, stmts: &mut [XvaStatement]) -> Vec<ValueEntry> {
+        self.local = ValueTable::default();
+
+        // Values can only be used by the blocks this block dominates if they are computed before any branch out of the block,
+        // and after any label that can be jumped to from another block
+        let mut exports = Vec::new();
+        let mut branched = false;
+
+        for stmt in stmts {
+            match stmt {
+                XvaStatement::Expr(expr) => self.process_expr(expr, (!branched).then_some(&mut exports)),
+                XvaStatement::Write(addr, ty, _) => self.clobber_memory(Some((*addr, ty.size))),
+                XvaStatement::Call { ret_val, call_clobber_regs, .. } => {
+                    self.clobber_memory(None);
+                    for reg in call_clobber_regs.into_regids(self.mach, self.pass.mode).chain(ret_val.into_regids(self.mach, self.pass.mode)) {
+                        self.kill(XvaRegister::Physical(reg));
+                    }
+                }
+                XvaStatement::InlineAsm(asm) => {
+                    self.clobber_memory(None);
+                    for reg in asm.clobbers.into_regids(self.mach, self.pass.mode) {
+                        self.kill(XvaRegister::Physical(reg));
+                    }
+                    for output in &asm.outputs {
+                        self.kill(output.reg);
+                    }
+                }
+                XvaStatement::RawInstr(instr) => {
+                    // A raw instruction may write memory or branch anywhere
+                    self.clobber_memory(None);
+                    branched = true;
+                    for opr in instr.operands() {
+                        if let crate::instr::Operand::Register(reg) = opr {
+                            self.kill(XvaRegister::Physical(*reg));
+                        }
+                    }
+                }
+                XvaStatement::Use(regs, UseKind::Write | UseKind::ReadWrite) => {
+                    for &reg in regs.iter() {
+                        self.kill(reg);
+                    }
+                }
+                XvaStatement::OptGate(kind, num) => self.pass.push_gate(*kind, *num),
+                XvaStatement::EndOptGate(num) => self.pass.pop_gate(*num),
+                XvaStatement::JumpIf(_, _) => branched = true,
+                XvaStatement::Label(_) => {
+                    self.local = ValueTable::default();
+                    exports.clear();
+                }
+                XvaStatement::Elaborated(_) => unreachable!("statements are flattened before numbering"),
+                XvaStatement::Jump(_)
+                | XvaStatement::Tailcall { .. }
+                | XvaStatement::Return
+                | XvaStatement::Trap(_)
+                | XvaStatement::Noop(_)
+                | XvaStatement::Use(_, UseKind::Read)
+                | XvaStatement::Fallthrough(_)
+                | XvaStatement::Loc(_)
+                | XvaStatement::Cfi(_) => {}
+            }
+        }
+
+        exports
+    }
+
+    /// Makes the `exports` of a block available to the blocks it dominates. Returns the entries that must be removed after them
+    fn push_exports(&mut self, exports: Vec<ValueEntry>) -> Vec<ValueEntry> {
+        let mut undo = Vec::new();
+        for export in exports {
+            match export {
+                ValueEntry::Expr(key) => {
+                    if let Some(&val) = self.local.exprs.get(&key)
+                        && !self.global.exprs.contains_key(&key)
+                    {
+                        self.global.exprs.insert(key.clone(), val);
+                        undo.push(ValueEntry::Expr(key));
+                    }
+                }
+                ValueEntry::Copy(dest) => {
+                    if let Some(&src) = self.local.copies.get(&dest)
+                        && !self.global.copies.contains_key(&dest)
+                    {
+                        self.global.copies.insert(dest, src);
+                        undo.push(ValueEntry::Copy(dest));
+                    }
+                }
+            }
+        }
+        undo
+    }
+
+    fn pop_exports(&mut self, undo: Vec<ValueEntry>) {
+        for entry in undo {
+            match entry {
+                ValueEntry::Expr(key) => {
+                    self.global.exprs.remove(&key);
+                }
+                ValueEntry::Copy(dest) => {
+                    self.global.copies.remove(&dest);
+                }
+            }
+        }
+    }
+}
+
+/// Reuses the results of pure expressions that are computed again, keyed by [`XvaOpcode`] and the type of the destination.
+///
+/// Within a block, an expression is reused until one of its operands or its destination is written.
+/// An expression is reused in the blocks its block dominates if its destination and operands are virtual registers that are written exactly once.
+/// [`XvaOpcode::Read`]s are only reused within a block, and are forgotten at any write to memory that may overlap them.
+pub struct GlobalValueNumbering;
+
+impl XvaOpt for GlobalValueNumbering {
+    fn name(&self) -> &'static str {
+        "gvn"
+    }
+
+    fn phases(&self) -> &[XvaOptPhase] {
+        &[XvaOptPhase::AfterLower, XvaOptPhase::BeforeRegalloc]
+    }
+
+    fn cost(&self) -> usize {
+        25
+    }
+
+    fn preserves_cfg(&self) -> bool {
+        true
+    }
+
+    fn make_state(&self, mode: MachineMode) -> Box<dyn State> {
+        Box::new(PassState::new(mode))
+    }
+}
+
+impl XvaFunctionOpt for GlobalValueNumbering {
+    fn optimize_function(&self, state: &mut dyn State, func: &mut xva::XvaFunction, cfg: &mut CfgCache, _: XvaOptPhase, mach: &dyn Machine) {
+        let state = (state as &mut dyn Any).downcast_mut::<PassState>().unwrap();
+
+        super::flatten_function(func);
+        let gates = state.block_gate_states(func);
+
+        let single_def = count_defs(func).into_iter().filter(|&(reg, n)| n == 1 && matches!(reg, XvaRegister::Virtual(_))).map(|(reg, _)| reg).collect();
+
+        let dominators = cfg.get(func).dominators.clone();
+        let mut gvn = GvnState { pass: state, mach, single_def, global: ValueTable::default(), local: ValueTable::default() };
+
+        let len = func.body.len();
+        let mut visited = vec![false; len];
+
+        // Walk the dominator tree from the entry, so that the values of each block are available while its children are numbered
+        enum Walk {
+            Enter(usize),
+            Exit(Vec<ValueEntry>),
+        }
+        let mut stack = if len > 0 { vec![Walk::Enter(0)] } else { Vec::new() };
+        while let Some(walk) = stack.pop() {
+            match walk {
+                Walk::Enter(n) => {
+                    visited[n] = true;
+                    gvn.pass.opt_gate_state = gates[n].clone();
+                    let xva::XvaBlockBody::Statement(stmts) = &mut func.body[n].body;
+                    let exports = gvn.process_block(stmts);
+                    let undo = gvn.push_exports(exports);
+                    stack.push(Walk::Exit(undo));
+                    for &child in dominators.children(n).iter().rev() {
+                        stack.push(Walk::Enter(child));
+                    }
+                }
+                Walk::Exit(undo) => gvn.pop_exports(undo),
+            }
+        }
+
+        // Unreachable blocks are not in the tree, so only values from within the block are used
+        gvn.global = ValueTable::default();
+        for n in 0..len {
+            if !visited[n] {
+                gvn.pass.opt_gate_state = gates[n].clone();
+                let xva::XvaBlockBody::Statement(stmts) = &mut func.body[n].body;
+                gvn.process_block(stmts);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod gvn_tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode},
+        intern::Symbol,
+        traits::IntoId,
+        xva::{BinaryOp, XvaBasicBlock, XvaBlockBody, XvaCategory, XvaDest, XvaFrameProperties, XvaFunction, XvaType},
+    };
+
+    const I64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+
+    fn vreg(id: u32) -> XvaRegister {
+        XvaRegister::Virtual(XvaDest { id, ty: I64 })
+    }
+
+    fn expr(dest: XvaRegister, op: XvaOpcode) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest, dest2: None, op })
+    }
+
+    fn read(dest: XvaRegister, offset: i32) -> XvaStatement {
+        expr(dest, XvaOpcode::Read(XvaOperand::FrameAddr(offset)))
+    }
+
+    fn add(dest: XvaRegister, left: XvaRegister, right: XvaRegister) -> XvaStatement {
+        expr(dest, XvaOpcode::BinaryOp { op: BinaryOp::Add, left, right: XvaOperand::Register(right) })
+    }
+
+    fn moved(dest: XvaRegister, src: XvaRegister) -> XvaStatement {
+        expr(dest, XvaOpcode::Move(src))
+    }
+
+    fn block(label: &str, stmts: Vec<XvaStatement>) -> XvaBasicBlock {
+        XvaBasicBlock { label: Symbol::intern(label), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }
+    }
+
+    fn stmts(func: &XvaFunction, n: usize) -> &[XvaStatement] {
+        let XvaBlockBody::Statement(stmts) = &func.body[n].body;
+        stmts
+    }
+
+    /// Runs the pass over a function made of `blocks`
+    fn number(blocks: Vec<XvaBasicBlock>) -> XvaFunction {
+        let mut func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: blocks,
+            frame_properties: XvaFrameProperties::new(),
+        };
+        let mut state = GlobalValueNumbering.make_state(X86Mode::Long.into_id());
+        GlobalValueNumbering.optimize_function(&mut *state, &mut func, &mut CfgCache::new(), XvaOptPhase::AfterLower, &X86);
+        func
+    }
+
+    /// A diamond that branches on v0, with `head` computed in the entry block before the branch and v0 + v0 computed in each other block
+    fn diamond(head: Vec<XvaStatement>) -> XvaFunction {
+        let mut entry = vec![read(vreg(0), 0)];
+        entry.extend(head);
+        entry.push(XvaStatement::JumpIf(vreg(0), Symbol::intern("other")));
+        number(vec![
+            block("entry", entry),
+            block("then", vec![add(vreg(1), vreg(0), vreg(0)), XvaStatement::Jump(Symbol::intern("join"))]),
+            block("other", vec![add(vreg(2), vreg(0), vreg(0)), XvaStatement::Fallthrough(Symbol::intern("join"))]),
+            block("join", vec![add(vreg(3), vreg(0), vreg(0)), XvaStatement::Return]),
+        ])
+    }
+
+    #[test]
+    fn reuses_values_within_a_block() {
+        let func = number(vec![block("entry", vec![
+            read(vreg(0), 0),
+            read(vreg(1), 8),
+            add(vreg(2), vreg(0), vreg(1)),
+            add(vreg(3), vreg(0), vreg(1)),
+            moved(vreg(4), vreg(1)),
+            add(vreg(5), vreg(0), vreg(4)),
+            XvaStatement::Return,
+        ])]);
+        assert_eq!(stmts(&func, 0)[3], moved(vreg(3), vreg(2)));
+        // Copies are numbered as their source
+        assert_eq!(stmts(&func, 0)[5], moved(vreg(5), vreg(2)));
+    }
+
+    #[test]
+    fn reuses_values_only_in_dominated_blocks() {
+        // No block dominates another one that computes the same value
+        let func = diamond(Vec::new());
+        assert_eq!(stmts(&func, 1)[0], add(vreg(1), vreg(0), vreg(0)));
+        assert_eq!(stmts(&func, 2)[0], add(vreg(2), vreg(0), vreg(0)));
+        assert_eq!(stmts(&func, 3)[0], add(vreg(3), vreg(0), vreg(0)));
+
+        // The entry block dominates all the others
+        let func = diamond(vec![add(vreg(4), vreg(0), vreg(0))]);
+        assert_eq!(stmts(&func, 1)[0], moved(vreg(1), vreg(4)));
+        assert_eq!(stmts(&func, 2)[0], moved(vreg(2), vreg(4)));
+        assert_eq!(stmts(&func, 3)[0], moved(vreg(3), vreg(4)));
+    }
+
+    #[test]
+    fn only_reuses_single_definitions_across_blocks() {
+        // v4 is written again in another block, so it does not hold v0 + v0 everywhere the entry block dominates
+        let func = number(vec![
+            block("entry", vec![read(vreg(0), 0), add(vreg(4), vreg(0), vreg(0)), XvaStatement::JumpIf(vreg(0), Symbol::intern("other"))]),
+            block("then", vec![add(vreg(1), vreg(0), vreg(0)), XvaStatement::Return]),
+            block("other", vec![moved(vreg(4), vreg(0)), XvaStatement::Return]),
+        ]);
+        assert_eq!(stmts(&func, 1)[0], add(vreg(1), vreg(0), vreg(0)));
+    }
+
+    #[test]
+    fn forgets_reads_at_overlapping_writes() {
+        let func = number(vec![block("entry", vec![
+            read(vreg(0), 0),
+            XvaStatement::Write(XvaOperand::FrameAddr(8), I64, vreg(0)),
+            read(vreg(1), 0),
+            XvaStatement::Write(XvaOperand::FrameAddr(4), I64, vreg(0)),
+            read(vreg(2), 0),
+            XvaStatement::Return,
+        ])]);
+        assert_eq!(stmts(&func, 0)[2], moved(vreg(1), vreg(0)));
+        assert_eq!(stmts(&func, 0)[4], read(vreg(2), 0));
+    }
+
+    #[test]
+    fn forgets_values_whose_operands_are_written() {
+        let func = number(vec![block("entry", vec![
+            read(vreg(0), 0),
+            add(vreg(1), vreg(0), vreg(0)),
+            add(vreg(0), vreg(0), vreg(0)),
+            add(vreg(2), vreg(0), vreg(0)),
+            XvaStatement::Return,
+        ])]);
+        assert_eq!(stmts(&func, 0)[2], moved(vreg(0), vreg(1)));
+        assert_eq!(stmts(&func, 0)[3], add(vreg(2), vreg(0), vreg(0)));
+    }
+
+    #[test]
+    fn respects_opt_gates() {
+        let body = vec![
+            read(vreg(0), 0),
+            add(vreg(1), vreg(0), vreg(0)),
+            XvaStatement::OptGate(BarrierKind::DO_NOT_OPTIMIZE, 0),
+            add(vreg(2), vreg(0), vreg(0)),
+            XvaStatement::EndOptGate(0),
+            XvaStatement::Return,
+        ];
+        let func = number(vec![block("entry", body.clone())]);
+        assert_eq!(stmts(&func, 0), body);
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod simplify_cfg_tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode},
+        traits::IntoId,
+        xva::{XvaBasicBlock, XvaBlockBody, XvaCategory, XvaDest, XvaFrameProperties, XvaFunction, XvaType},
+    };
+
+    const I64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+
+    fn vreg(id: u32) -> XvaRegister {
+        XvaRegister::Virtual(XvaDest { id, ty: I64 })
+    }
+
+    fn arg(dest: XvaRegister) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest, dest2: None, op: XvaOpcode::Read(XvaOperand::IncomingArg(0)) })
+    }
+
+    fn sym(name: &str) -> Symbol {
+        Symbol::intern(name)
+    }
+
+    fn block(label: &str, stmts: Vec<XvaStatement>) -> XvaBasicBlock {
+        XvaBasicBlock { label: sym(label), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }
+    }
+
+    fn stmts(func: &XvaFunction, n: usize) -> &[XvaStatement] {
+        let XvaBlockBody::Statement(stmts) = &func.body[n].body;
+        stmts
+    }
+
+    fn labels(func: &XvaFunction) -> Vec<Symbol> {
+        func.body.iter().map(|block| block.label).collect()
+    }
+
+    /// Runs the pass over a function made of `blocks`
+    fn simplify(blocks: Vec<XvaBasicBlock>) -> XvaFunction {
+        let mut func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: blocks,
+            frame_properties: XvaFrameProperties::new(),
+        };
+        let mut state = SimplifyCfg.make_state(X86Mode::Long.into_id());
+        SimplifyCfg.optimize_function(&mut *state, &mut func, &mut CfgCache::new(), XvaOptPhase::AfterLower, &X86);
+        func
+    }
+
+    #[test]
+    fn threads_jumps_and_removes_unreachable_blocks() {
+        let func = simplify(vec![
+            block("entry", vec![arg(vreg(0)), XvaStatement::JumpIf(vreg(0), sym("hop"))]),
+            block("body", vec![XvaStatement::Return]),
+            block("hop", vec![XvaStatement::Jump(sym("exit"))]),
+            block("dead", vec![XvaStatement::Return]),
+            block("exit", vec![XvaStatement::Return]),
+        ]);
+        assert_eq!(labels(&func), [sym("entry"), sym("body"), sym("exit")]);
+        assert_eq!(stmts(&func, 0)[1], XvaStatement::JumpIf(vreg(0), sym("exit")));
+    }
+
+    #[test]
+    fn merges_blocks_into_their_only_predecessor() {
+        let func = simplify(vec![
+            block("entry", vec![arg(vreg(0)), XvaStatement::Jump(sym("next"))]),
+            block("skipped", vec![XvaStatement::Return]),
+            block("next", vec![XvaStatement::Use(vec![vreg(0)], UseKind::Read), XvaStatement::Return]),
+        ]);
+        assert_eq!(labels(&func), [sym("entry")]);
+        assert_eq!(stmts(&func, 0), [arg(vreg(0)), XvaStatement::Use(vec![vreg(0)], UseKind::Read), XvaStatement::Return]);
+    }
+
+    #[test]
+    fn removes_empty_blocks_but_not_cycles_of_them() {
+        let func = simplify(vec![
+            block("entry", vec![arg(vreg(0)), XvaStatement::JumpIf(vreg(0), sym("exit"))]),
+            block("empty", vec![]),
+            block("loop", vec![XvaStatement::Jump(sym("loop"))]),
+            block("exit", vec![XvaStatement::Return]),
+        ]);
+        assert_eq!(labels(&func), [sym("entry"), sym("loop"), sym("exit")]);
+        assert_eq!(stmts(&func, 1), [XvaStatement::Jump(sym("loop"))]);
+    }
+
+    #[test]
+    fn keeps_blocks_whose_address_is_taken() {
+        let take_addr = XvaStatement::Expr(XvaExpr { dest: vreg(0), dest2: None, op: XvaOpcode::Const(XvaConst::Label(sym("target"))) });
+        let func = simplify(vec![
+            block("entry", vec![take_addr.clone(), XvaStatement::Return]),
+            block("target", vec![XvaStatement::Return]),
+        ]);
+        assert_eq!(labels(&func), [sym("entry"), sym("target")]);
+        assert_eq!(stmts(&func, 0), [take_addr, XvaStatement::Return]);
+    }
+
+    #[test]
+    fn keeps_unreachable_blocks_with_opt_gates() {
+        let func = simplify(vec![
+            block("entry", vec![XvaStatement::Return]),
+            block("gated", vec![XvaStatement::OptGate(BarrierKind::DO_NOT_OPTIMIZE, 0), XvaStatement::Return]),
+            block("end", vec![XvaStatement::EndOptGate(0), XvaStatement::Return]),
+        ]);
+        assert_eq!(labels(&func), [sym("entry"), sym("gated"), sym("end")]);
+    }
+
+    #[test]
+    fn recomputes_live_at_start() {
+        let func = simplify(vec![
+            block("entry", vec![arg(vreg(0)), XvaStatement::JumpIf(vreg(0), sym("hop"))]),
+            block("body", vec![XvaStatement::Return]),
+            block("hop", vec![XvaStatement::Jump(sym("exit"))]),
+            block("exit", vec![XvaStatement::Use(vec![vreg(0)], UseKind::Read), XvaStatement::Return]),
+        ]);
+        assert_eq!(labels(&func), [sym("entry"), sym("body"), sym("exit")]);
+        assert_eq!(func.body[2].live_at_start, [vreg(0)]);
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod remove_unused_tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode},
+        traits::IntoId,
+        xva::{BinaryOp, XvaBasicBlock, XvaBlockBody, XvaCategory, XvaDest, XvaFrameProperties, XvaFunction, XvaType},
+    };
+
+    const I64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+
+    fn vreg(id: u32) -> XvaRegister {
+        XvaRegister::Virtual(XvaDest { id, ty: I64 })
+    }
+
+    fn expr(dest: XvaRegister, op: XvaOpcode) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest, dest2: None, op })
+    }
+
+    fn arg(dest: XvaRegister) -> XvaStatement {
+        expr(dest, XvaOpcode::Read(XvaOperand::IncomingArg(0)))
+    }
+
+    fn block(label: &str, live_at_start: Vec<XvaRegister>, stmts: Vec<XvaStatement>) -> XvaBasicBlock {
+        XvaBasicBlock { label: Symbol::intern(label), live_at_start, body: XvaBlockBody::Statement(stmts) }
+    }
+
+    fn stmts(func: &XvaFunction, n: usize) -> &[XvaStatement] {
+        let XvaBlockBody::Statement(stmts) = &func.body[n].body;
+        stmts
+    }
+
+    /// Runs the pass over a function made of `blocks`
+    fn remove(blocks: Vec<XvaBasicBlock>) -> XvaFunction {
+        let mut func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: blocks,
+            frame_properties: XvaFrameProperties::new(),
+        };
+        let mut state = RemoveUnused.make_state(X86Mode::Long.into_id());
+        RemoveUnused.optimize_function(&mut *state, &mut func, &mut CfgCache::new(), XvaOptPhase::AfterLower, &X86);
+        func
+    }
+
+    #[test]
+    fn removes_definitions_that_are_not_live() {
+        let add = expr(vreg(1), XvaOpcode::BinaryOp { op: BinaryOp::Add, left: vreg(0), right: XvaOperand::Register(vreg(0)) });
+        let func = remove(vec![
+            block("entry", vec![], vec![
+                arg(vreg(0)),
+                add,
+                arg(vreg(2)),
+                expr(vreg(3), XvaOpcode::Move(vreg(2))),
+                arg(vreg(4)),
+                expr(vreg(5), XvaOpcode::Move(vreg(4))),
+                expr(vreg(0), XvaOpcode::Move(vreg(0))),
+                XvaStatement::JumpIf(vreg(0), Symbol::intern("exit")),
+            ]),
+            block("next", vec![], vec![XvaStatement::Return]),
+            block("exit", vec![vreg(3), vreg(4)], vec![XvaStatement::Use(vec![vreg(3)], UseKind::Read), XvaStatement::Return]),
+        ]);
+
+        // v3 is only read after the branch to exit, and v4 only by the dead move into v5
+        let removed = XvaStatement::Elaborated(vec![]);
+        assert_eq!(stmts(&func, 0), [
+            arg(vreg(0)),
+            removed.clone(),
+            arg(vreg(2)),
+            expr(vreg(3), XvaOpcode::Move(vreg(2))),
+            removed.clone(),
+            removed.clone(),
+            removed,
+            XvaStatement::JumpIf(vreg(0), Symbol::intern("exit")),
+        ]);
+        assert_eq!(func.body[2].live_at_start, [vreg(3)]);
+    }
+
+    #[test]
+    fn keeps_definitions_in_opt_gates() {
+        let body = vec![XvaStatement::OptGate(BarrierKind::ELIDE_REGISTERS, 0), arg(vreg(0)), XvaStatement::EndOptGate(0), XvaStatement::Return];
+        let func = remove(vec![block("entry", vec![], body.clone())]);
+        assert_eq!(stmts(&func, 0), body);
+    }
+}
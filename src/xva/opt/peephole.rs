@@ -0,0 +1,160 @@
+//! Machine-specific peephole rewrites of the instructions emitted by `lower_mce`
+use std::any::Any;
+
+use crate::{
+    instr::Instruction,
+    mach::{Machine, MachineMode},
+    xva::{
+        self, BarrierKind,
+        cfg::CfgCache,
+        opt::{State, XvaFunctionOpt, XvaOpt, XvaOptPhase, pass::PassState, remark::RemarkKind},
+    },
+};
+
+/// A machine-specific rewrite over a window of consecutive machine instructions
+#[derive(Copy, Clone)]
+pub struct PeepholeRule {
+    /// The name of the rule, for diagnostics
+    pub name: &'static str,
+    /// The maximum number of instructions the rule inspects
+    pub window: usize,
+    /// Attempts to match the start of `instrs`, which holds at most `window` instructions.
+    /// On a match, returns the number of instructions consumed (at least one), and the instructions that replace them.
+    ///
+    /// The replacement must not match the same rule again, or the optimizer will not terminate
+    pub rewrite: fn(instrs: &[Instruction], mode: MachineMode) -> Option<(usize, Vec<Instruction>)>,
+}
+
+/// Applies `rules` to `instrs` until none of them match. Returns the names of the rules that matched, in the order they matched
+pub fn apply_rules(instrs: &mut Vec<Instruction>, rules: &[PeepholeRule], mode: MachineMode) -> Vec<&'static str> {
+    let backtrack = rules.iter().map(|rule| rule.window).max().unwrap_or(1).saturating_sub(1);
+    let mut matched = Vec::new();
+    let mut pos = 0;
+    while pos < instrs.len() {
+        let found = rules.iter().find_map(|rule| {
+            let end = instrs.len().min(pos + rule.window);
+            (rule.rewrite)(&instrs[pos..end], mode).map(|rewrite| (rule.name, rewrite))
+        });
+        match found {
+            Some((name, (consumed, replacement))) => {
+                assert!(consumed > 0, "Peephole rule consumed no instructions");
+                instrs.splice(pos..(pos + consumed), replacement);
+                pos = pos.saturating_sub(backtrack);
+                matched.push(name);
+            }
+            None => pos += 1,
+        }
+    }
+    matched
+}
+
+/// Applies the peephole rules of the machine to runs of [`XvaStatement::RawInstr`][xva::XvaStatement::RawInstr] after they are emitted by `lower_mce`.
+/// A run ends at any statement other than a raw instruction, including labels and opt gates
+pub struct Peephole;
+
+impl XvaOpt for Peephole {
+    fn name(&self) -> &'static str {
+        "peephole"
+    }
+
+    fn phases(&self) -> &[XvaOptPhase] {
+        &[XvaOptPhase::Mce]
+    }
+
+    fn cost(&self) -> usize {
+        10
+    }
+
+    fn preserves_cfg(&self) -> bool {
+        true
+    }
+
+    fn make_state(&self, mode: MachineMode) -> Box<dyn State> {
+        Box::new(PassState::new(mode))
+    }
+}
+
+impl XvaFunctionOpt for Peephole {
+    fn optimize_function(&self, state: &mut dyn State, func: &mut xva::XvaFunction, _: &mut CfgCache, _: XvaOptPhase, mach: &dyn Machine) {
+        let state = (state as &mut dyn Any).downcast_mut::<PassState>().unwrap();
+        let Some(compiler) = mach.as_compiler() else {
+            return;
+        };
+        let rules = compiler.peephole_rules(state.mode);
+        if rules.is_empty() {
+            return;
+        }
+
+        super::raw_runs(func, state, BarrierKind::ELIDE_INSTRS | BarrierKind::MISC_OPTIMIZATION, |state, instrs| {
+            for name in apply_rules(instrs, rules, state.mode) {
+                state.remark(RemarkKind::Peephole(name));
+            }
+        });
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode, X86Opcode, X86Register},
+        instr::Operand,
+        intern::Symbol,
+        mach::{Opcode, Register, Regset},
+        traits::{IdType, IntoId},
+        xva::{NoopKind, XvaBasicBlock, XvaBlockBody, XvaFrameProperties, XvaFunction, XvaStatement},
+    };
+
+    const RAX: X86Register = crate::x86_register!(rax);
+    const RBX: X86Register = crate::x86_register!(rbx);
+    const RCX: X86Register = crate::x86_register!(rcx);
+
+    fn raw(op: X86Opcode, regs: &[X86Register]) -> XvaStatement {
+        XvaStatement::RawInstr(Instruction::new(Opcode::new(op), regs.iter().map(|&reg| Operand::Register(Register::new(reg))).collect()))
+    }
+
+    /// Runs the pass over a function with a single block made of `stmts`
+    fn peephole(stmts: Vec<XvaStatement>) -> Vec<XvaStatement> {
+        peephole_with_remarks(stmts).0
+    }
+
+    fn peephole_with_remarks(stmts: Vec<XvaStatement>) -> (Vec<XvaStatement>, Vec<RemarkKind>) {
+        let mut func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: vec![XvaBasicBlock { label: Symbol::intern("entry"), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }],
+            frame_properties: XvaFrameProperties::new(),
+        };
+        let mut state = Peephole.make_state(X86Mode::Long.into_id());
+        Peephole.optimize_function(&mut *state, &mut func, &mut CfgCache::new(), XvaOptPhase::Mce, &X86);
+        let XvaBlockBody::Statement(stmts) = func.body.remove(0).body;
+        (stmts, state.take_remarks())
+    }
+
+    #[test]
+    fn rewrites_runs_of_raw_instructions() {
+        let stmts = vec![raw(X86Opcode::Mov, &[RAX, RAX]), raw(X86Opcode::Push, &[RBX]), raw(X86Opcode::Pop, &[RCX]), XvaStatement::Return];
+        let (stmts, remarks) = peephole_with_remarks(stmts);
+        assert_eq!(stmts, [raw(X86Opcode::Mov, &[RCX, RBX]), XvaStatement::Return]);
+        assert_eq!(remarks, [RemarkKind::Peephole("mov-self"), RemarkKind::Peephole("push-pop")]);
+    }
+
+    #[test]
+    fn does_not_match_across_other_statements() {
+        let stmts = vec![raw(X86Opcode::Push, &[RBX]), XvaStatement::Noop(NoopKind::Normal), raw(X86Opcode::Pop, &[RCX])];
+        assert_eq!(peephole(stmts.clone()), stmts);
+        let stmts = vec![raw(X86Opcode::Push, &[RBX]), XvaStatement::Label(Symbol::intern("l")), raw(X86Opcode::Pop, &[RCX])];
+        assert_eq!(peephole(stmts.clone()), stmts);
+    }
+
+    #[test]
+    fn respects_opt_gates() {
+        let stmts = vec![XvaStatement::OptGate(BarrierKind::ELIDE_INSTRS, 0), raw(X86Opcode::Mov, &[RAX, RAX]), XvaStatement::EndOptGate(0)];
+        assert_eq!(peephole(stmts.clone()), stmts);
+        let stmts = vec![XvaStatement::OptGate(BarrierKind::ELIDE_INSTRS, 0), XvaStatement::EndOptGate(0), raw(X86Opcode::Mov, &[RAX, RAX])];
+        assert_eq!(peephole(stmts), [XvaStatement::OptGate(BarrierKind::ELIDE_INSTRS, 0), XvaStatement::EndOptGate(0)]);
+    }
+}
@@ -0,0 +1,78 @@
+//! Structured remarks that optimization passes emit about the changes they make
+use crate::{
+    fmt::PrettyPrinter,
+    intern::Symbol,
+    xva::{XvaOpcode, XvaOperand, XvaRegister},
+};
+
+/// A change made by a pass
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum RemarkKind {
+    /// The value written to the register is known, and its definition was replaced with the opcode
+    Folded { reg: XvaRegister, op: XvaOpcode },
+    /// A conditional jump with a known condition was replaced with a jump if it is always taken, and removed otherwise
+    FoldedBranch { target: Symbol, taken: bool },
+    /// The definition of the register was removed, because the register is never read
+    RemovedDeadDef(XvaRegister),
+    /// The definition of the register was replaced with a copy of an equal value in `from`
+    ReusedValue { reg: XvaRegister, from: XvaRegister },
+    /// The definition of the register was replaced with `count` cheaper expressions
+    StrengthReduced { reg: XvaRegister, count: usize },
+    /// The block was removed, because it is unreachable or empty
+    RemovedBlock(Symbol),
+    /// The block was merged into its only predecessor
+    MergedBlock(Symbol),
+    /// The peephole rule with the name matched
+    Peephole(&'static str),
+    /// The definition of the register was moved out of a loop, into its preheader
+    Hoisted(XvaRegister),
+    /// A call to the operand was converted into a tail call
+    Tailcall(XvaOperand),
+    /// The instructions of a region were reordered, and the number of instructions that moved
+    Scheduled(usize),
+}
+
+/// A [`RemarkKind`] with the pass that emitted it and the function it applies to
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Remark {
+    pub pass: &'static str,
+    /// The label of the function
+    pub function: Symbol,
+    pub kind: RemarkKind,
+}
+
+impl<'a> core::fmt::Display for PrettyPrinter<'a, RemarkKind> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            RemarkKind::Folded { reg, op } => f.write_fmt(format_args!(
+                "folded {} into {}",
+                PrettyPrinter(reg, self.1, self.2),
+                PrettyPrinter(op, self.1, self.2)
+            )),
+            RemarkKind::FoldedBranch { target, taken: true } => f.write_fmt(format_args!("branch to {target} is always taken")),
+            RemarkKind::FoldedBranch { target, taken: false } => f.write_fmt(format_args!("branch to {target} is never taken")),
+            RemarkKind::RemovedDeadDef(reg) => f.write_fmt(format_args!("removed dead write to {}", PrettyPrinter(reg, self.1, self.2))),
+            RemarkKind::ReusedValue { reg, from } => f.write_fmt(format_args!(
+                "reused {} for {}",
+                PrettyPrinter(from, self.1, self.2),
+                PrettyPrinter(reg, self.1, self.2)
+            )),
+            RemarkKind::StrengthReduced { reg, count } => {
+                f.write_fmt(format_args!("reduced the definition of {} to {count} expressions", PrettyPrinter(reg, self.1, self.2)))
+            }
+            RemarkKind::RemovedBlock(label) => f.write_fmt(format_args!("removed block {label}")),
+            RemarkKind::MergedBlock(label) => f.write_fmt(format_args!("merged block {label} into its predecessor")),
+            RemarkKind::Peephole(rule) => f.write_fmt(format_args!("applied peephole rule {rule}")),
+            RemarkKind::Hoisted(reg) => f.write_fmt(format_args!("hoisted {} out of a loop", PrettyPrinter(reg, self.1, self.2))),
+            RemarkKind::Tailcall(dest) => f.write_fmt(format_args!("converted the call to {} into a tail call", PrettyPrinter(dest, self.1, self.2))),
+            RemarkKind::Scheduled(moved) => f.write_fmt(format_args!("scheduled a region, moving {moved} instructions")),
+        }
+    }
+}
+
+impl<'a> core::fmt::Display for PrettyPrinter<'a, Remark> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}: {}: ", self.0.pass, self.0.function))?;
+        PrettyPrinter(&self.0.kind, self.1, self.2).fmt(f)
+    }
+}
@@ -0,0 +1,299 @@
+//! List scheduling of machine instructions within basic blocks
+use std::any::Any;
+
+use crate::{
+    instr::Instruction,
+    mach::{Machine, MachineMode, Register},
+    xva::{
+        self, BarrierKind,
+        cfg::CfgCache,
+        opt::{State, XvaFunctionOpt, XvaOpt, XvaOptPhase, pass::PassState, remark::RemarkKind},
+    },
+};
+
+/// The machine model of one instruction, used to schedule it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SchedInfo {
+    /// The number of cycles before the results of the instruction can be used
+    pub latency: u32,
+    /// The set of ports that can issue the instruction, as a bitmask. Each port issues at most one instruction per cycle.
+    /// An instruction with no ports does not use any issue slot
+    pub ports: u32,
+    pub reads: Vec<Register>,
+    pub writes: Vec<Register>,
+    pub reads_flags: bool,
+    pub writes_flags: bool,
+    pub loads: bool,
+    pub stores: bool,
+}
+
+impl SchedInfo {
+    /// An instruction with the given latency and ports that does not access any register, the flags, or memory
+    pub const fn new(latency: u32, ports: u32) -> Self {
+        Self { latency, ports, reads: Vec::new(), writes: Vec::new(), reads_flags: false, writes_flags: false, loads: false, stores: false }
+    }
+}
+
+/// The minimum number of cycles between issuing `first` and issuing `second`, which comes after it in program order,
+/// or [`None`] if `second` does not depend on `first`
+fn dependency(first: &SchedInfo, second: &SchedInfo, overlaps: &impl Fn(Register, Register) -> bool) -> Option<u32> {
+    let any_overlap = |a: &[Register], b: &[Register]| a.iter().any(|&a| b.iter().any(|&b| overlaps(a, b)));
+
+    let true_dep = any_overlap(&first.writes, &second.reads) || (first.writes_flags && second.reads_flags) || (first.stores && (second.loads || second.stores));
+    let order_dep = any_overlap(&first.reads, &second.writes)
+        || any_overlap(&first.writes, &second.writes)
+        || (first.reads_flags && second.writes_flags)
+        || (first.writes_flags && second.writes_flags)
+        || (first.loads && second.stores);
+
+    if true_dep {
+        Some(first.latency)
+    } else if order_dep {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+/// Computes the order to issue the instructions described by `infos`, which are in program order, with a list scheduler.
+///
+/// Each cycle issues the ready instructions with the longest path to the end of the region first, while ports are free.
+/// Returns the indices of the instructions in the order they are issued
+pub fn schedule(infos: &[SchedInfo], overlaps: impl Fn(Register, Register) -> bool) -> Vec<usize> {
+    let n = infos.len();
+    let mut succs: Vec<Vec<(usize, u32)>> = vec![Vec::new(); n];
+    let mut preds = vec![0usize; n];
+    for second in 0..n {
+        for first in 0..second {
+            if let Some(latency) = dependency(&infos[first], &infos[second], &overlaps) {
+                succs[first].push((second, latency));
+                preds[second] += 1;
+            }
+        }
+    }
+
+    let mut height = vec![0u32; n];
+    for i in (0..n).rev() {
+        height[i] = succs[i].iter().map(|&(succ, latency)| latency + height[succ]).max().unwrap_or(0).max(infos[i].latency);
+    }
+
+    let mut earliest = vec![0u32; n];
+    let mut ready: Vec<usize> = (0..n).filter(|&i| preds[i] == 0).collect();
+    let mut order = Vec::with_capacity(n);
+    let mut cycle = 0;
+    while order.len() < n {
+        let mut used_ports = 0u32;
+        loop {
+            let candidate = ready
+                .iter()
+                .enumerate()
+                .filter(|&(_, &i)| earliest[i] <= cycle && (infos[i].ports == 0 || infos[i].ports & !used_ports != 0))
+                .max_by_key(|&(_, &i)| (height[i], core::cmp::Reverse(i)))
+                .map(|(pos, _)| pos);
+            let Some(pos) = candidate else {
+                break;
+            };
+            let i = ready.swap_remove(pos);
+            let free = infos[i].ports & !used_ports;
+            used_ports |= free & free.wrapping_neg();
+            order.push(i);
+
+            for &(succ, latency) in &succs[i] {
+                earliest[succ] = earliest[succ].max(cycle + latency);
+                preds[succ] -= 1;
+                if preds[succ] == 0 {
+                    ready.push(succ);
+                }
+            }
+        }
+        cycle += 1;
+    }
+
+    order
+}
+
+/// Reorders runs of [`XvaStatement::RawInstr`][xva::XvaStatement::RawInstr] emitted by `lower_mce` to hide latencies, using the model from
+/// [`CompilerSpec::sched_info`][crate::compiler::CompilerSpec::sched_info].
+///
+/// An instruction without a model is not moved, and no instruction is moved across it.
+/// A run ends at any statement other than a raw instruction, including labels and opt gates
+pub struct Schedule;
+
+impl XvaOpt for Schedule {
+    fn name(&self) -> &'static str {
+        "schedule"
+    }
+
+    fn phases(&self) -> &[XvaOptPhase] {
+        &[XvaOptPhase::Mce]
+    }
+
+    fn cost(&self) -> usize {
+        20
+    }
+
+    fn preserves_cfg(&self) -> bool {
+        true
+    }
+
+    fn make_state(&self, mode: MachineMode) -> Box<dyn State> {
+        Box::new(PassState::new(mode))
+    }
+}
+
+impl XvaFunctionOpt for Schedule {
+    fn optimize_function(&self, state: &mut dyn State, func: &mut xva::XvaFunction, _: &mut CfgCache, _: XvaOptPhase, mach: &dyn Machine) {
+        let state = (state as &mut dyn Any).downcast_mut::<PassState>().unwrap();
+        let Some(compiler) = mach.as_compiler() else {
+            return;
+        };
+        let overlaps = |a: Register, b: Register| a == b || mach.registers().register_overlaps(a, b);
+
+        super::raw_runs(func, state, BarrierKind::MISC_OPTIMIZATION, |state, instrs| {
+            // Schedule each region between instructions without a model separately
+            let mut start = 0;
+            while start < instrs.len() {
+                let infos: Vec<SchedInfo> = instrs[start..].iter().map_while(|instr| compiler.sched_info(instr, state.mode)).collect();
+
+                if infos.len() > 1 {
+                    let order = schedule(&infos, &overlaps);
+                    let moved = order.iter().enumerate().filter(|&(pos, &idx)| pos != idx).count();
+                    if moved != 0 {
+                        let mut region: Vec<Option<Instruction>> = instrs.drain(start..(start + infos.len())).map(Some).collect();
+                        let reordered: Vec<Instruction> = order.iter().map(|&idx| region[idx].take().unwrap()).collect();
+                        instrs.splice(start..start, reordered);
+                        state.remark(RemarkKind::Scheduled(moved));
+                    }
+                }
+                start += infos.len() + 1;
+            }
+        });
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode, X86Opcode, X86Register},
+        instr::{Address, Instruction, MemoryOperand, Operand},
+        intern::Symbol,
+        mach::{Opcode, Regset},
+        traits::{IdType, IntoId},
+        xva::{XvaBasicBlock, XvaBlockBody, XvaFrameProperties, XvaFunction, XvaStatement},
+    };
+
+    const RAX: X86Register = crate::x86_register!(rax);
+    const RBX: X86Register = crate::x86_register!(rbx);
+    const RCX: X86Register = crate::x86_register!(rcx);
+    const RDX: X86Register = crate::x86_register!(rdx);
+    const RSI: X86Register = crate::x86_register!(rsi);
+
+    fn info(latency: u32, ports: u32, reads: &[X86Register], writes: &[X86Register]) -> SchedInfo {
+        let mut info = SchedInfo::new(latency, ports);
+        info.reads = reads.iter().map(|&reg| Register::new(reg)).collect();
+        info.writes = writes.iter().map(|&reg| Register::new(reg)).collect();
+        info
+    }
+
+    fn order(infos: &[SchedInfo]) -> Vec<usize> {
+        schedule(infos, |a, b| a == b)
+    }
+
+    #[test]
+    fn issues_independent_instructions_under_latencies() {
+        // A load into rax, an add that uses it, and an unrelated move
+        let infos = [info(4, 0b10, &[RBX], &[RAX]), info(1, 0b01, &[RAX, RCX], &[RAX]), info(1, 0b01, &[RSI], &[RDX])];
+        assert_eq!(order(&infos), [0, 2, 1]);
+    }
+
+    #[test]
+    fn keeps_the_order_of_dependent_instructions() {
+        // Each second instruction has the longer latency, so it would issue first if it were independent
+        let infos = [info(1, 0b01, &[RAX], &[RBX]), info(4, 0b10, &[], &[RAX])];
+        assert_eq!(order(&infos), [0, 1]);
+        let infos = [info(1, 0b01, &[], &[RAX]), info(4, 0b10, &[], &[RAX])];
+        assert_eq!(order(&infos), [0, 1]);
+        assert_eq!(order(&[info(1, 0b01, &[], &[RAX]), info(4, 0b10, &[], &[RBX])]), [1, 0]);
+
+        let with = |latency, ports, set: fn(&mut SchedInfo)| {
+            let mut info = info(latency, ports, &[], &[]);
+            set(&mut info);
+            info
+        };
+        // Stores are ordered with loads and other stores
+        assert_eq!(order(&[with(1, 0b01, |info| info.stores = true), with(4, 0b10, |info| info.loads = true)]), [0, 1]);
+        assert_eq!(order(&[with(1, 0b01, |info| info.loads = true), with(4, 0b10, |info| info.stores = true)]), [0, 1]);
+        assert_eq!(order(&[with(1, 0b01, |info| info.stores = true), with(4, 0b10, |info| info.stores = true)]), [0, 1]);
+        // Loads can pass each other
+        assert_eq!(order(&[with(1, 0b01, |info| info.loads = true), with(4, 0b10, |info| info.loads = true)]), [1, 0]);
+        // The flags are ordered like a register
+        assert_eq!(order(&[with(1, 0b01, |info| info.writes_flags = true), with(4, 0b10, |info| info.reads_flags = true)]), [0, 1]);
+        assert_eq!(order(&[with(1, 0b01, |info| info.reads_flags = true), with(4, 0b10, |info| info.writes_flags = true)]), [0, 1]);
+    }
+
+    #[test]
+    fn issues_one_instruction_per_port_each_cycle() {
+        // Both moves only fit the first port, so the one with the longest path issues in the first cycle
+        let infos = [info(1, 0b01, &[], &[RAX]), info(1, 0b01, &[], &[RBX]), info(1, 0b10, &[RBX], &[RCX])];
+        assert_eq!(order(&infos), [1, 0, 2]);
+    }
+
+    fn raw(op: X86Opcode, oprs: Vec<Operand>) -> XvaStatement {
+        XvaStatement::RawInstr(Instruction::new(Opcode::new(op), oprs))
+    }
+
+    fn reg(reg: X86Register) -> Operand {
+        Operand::Register(Register::new(reg))
+    }
+
+    fn load(dest: X86Register, base: X86Register) -> XvaStatement {
+        let addr = Address { segment: None, base: Some(Register::new(base)), index: None, scale: crate::nzlit!(1), sym: None, disp: None, rel: false };
+        raw(X86Opcode::Mov, vec![reg(dest), Operand::Memory(MemoryOperand { value_size: Some(8), addr })])
+    }
+
+    /// Runs the pass over a function with a single block made of `stmts`. Returns the new statements and the remarks
+    fn run(stmts: Vec<XvaStatement>) -> (Vec<XvaStatement>, Vec<RemarkKind>) {
+        let mut func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: vec![XvaBasicBlock { label: Symbol::intern("entry"), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }],
+            frame_properties: XvaFrameProperties::new(),
+        };
+        let mut state = Schedule.make_state(X86Mode::Long.into_id());
+        Schedule.optimize_function(&mut *state, &mut func, &mut CfgCache::new(), XvaOptPhase::Mce, &X86);
+        let XvaBlockBody::Statement(stmts) = func.body.remove(0).body;
+        (stmts, state.take_remarks())
+    }
+
+    #[test]
+    fn reorders_runs_of_raw_instructions() {
+        let add = raw(X86Opcode::Add, vec![reg(RAX), reg(RCX)]);
+        let mov = raw(X86Opcode::Mov, vec![reg(RDX), reg(RSI)]);
+        let (stmts, remarks) = run(vec![load(RAX, RBX), add.clone(), mov.clone(), XvaStatement::Return]);
+        assert_eq!(stmts, [load(RAX, RBX), mov.clone(), add.clone(), XvaStatement::Return]);
+        assert_eq!(remarks, [RemarkKind::Scheduled(2)]);
+
+        // Instructions are not moved across other statements, or instructions without a model
+        let call = raw(X86Opcode::Call, vec![Operand::RelSymbol(crate::instr::RelocSym { sym: Symbol::intern("f"), kind: crate::instr::AddressKind::Default }, None)]);
+        for barrier in [XvaStatement::Label(Symbol::intern("l")), call] {
+            let stmts = vec![load(RAX, RBX), add.clone(), barrier, mov.clone()];
+            assert_eq!(run(stmts.clone()), (stmts, vec![]));
+        }
+    }
+
+    #[test]
+    fn respects_opt_gates() {
+        let stmts = vec![
+            XvaStatement::OptGate(BarrierKind::MISC_OPTIMIZATION, 0),
+            load(RAX, RBX),
+            raw(X86Opcode::Add, vec![reg(RAX), reg(RCX)]),
+            raw(X86Opcode::Mov, vec![reg(RDX), reg(RSI)]),
+            XvaStatement::EndOptGate(0),
+        ];
+        assert_eq!(run(stmts.clone()).0, stmts);
+    }
+}
@@ -0,0 +1,645 @@
+//! Strength reduction of multiplications, divisions and shifts by constants
+use std::{any::Any, collections::HashMap};
+
+use crate::{
+    compiler::Compiler,
+    mach::{Machine, MachineMode},
+    xva::{
+        BarrierKind, BinaryOp, RightShiftMode, ShiftBehaviour, UnaryOp, XvaBlockBody, XvaConst, XvaDest, XvaExpr, XvaFunction, XvaOpcode, XvaOperand, XvaRegister, XvaStatement,
+        cfg::CfgCache,
+        opt::{
+            State, XvaFunctionOpt, XvaOpt, XvaOptPhase, flatten_function,
+            pass::{PassState, count_defs},
+            remark::RemarkKind,
+        },
+    },
+};
+
+fn expr(dest: XvaRegister, op: XvaOpcode) -> XvaExpr {
+    XvaExpr { dest, dest2: None, op }
+}
+
+fn shl(dest: XvaRegister, left: XvaRegister, quantity: u32) -> XvaExpr {
+    expr(dest, XvaOpcode::BinaryOp { op: BinaryOp::ShiftLeft(ShiftBehaviour::AssumeQuantity), left, right: XvaOperand::Const(XvaConst::Bits(quantity as u64)) })
+}
+
+fn shr(dest: XvaRegister, left: XvaRegister, quantity: u32, mode: RightShiftMode) -> XvaExpr {
+    expr(dest, XvaOpcode::BinaryOp { op: BinaryOp::ShiftRight(ShiftBehaviour::AssumeQuantity, mode), left, right: XvaOperand::Const(XvaConst::Bits(quantity as u64)) })
+}
+
+fn binop(dest: XvaRegister, op: BinaryOp, left: XvaRegister, right: XvaRegister) -> XvaExpr {
+    expr(dest, XvaOpcode::BinaryOp { op, left, right: XvaOperand::Register(right) })
+}
+
+/// `x << quantity`, or `x` itself if `quantity` is 0
+fn shifted(seq: &mut Vec<XvaExpr>, x: XvaRegister, quantity: u32, temp: &mut impl FnMut() -> XvaRegister) -> XvaRegister {
+    if quantity == 0 {
+        x
+    } else {
+        let t = temp();
+        seq.push(shl(t, x, quantity));
+        t
+    }
+}
+
+/// The sequences of expressions that compute `dest = x * c` on values of `bits` bits without multiplying.
+/// `c` must already be truncated to `bits`
+fn mul_sequences(dest: XvaRegister, x: XvaRegister, c: u64, bits: u32, temp: &mut impl FnMut() -> XvaRegister) -> Vec<Vec<XvaExpr>> {
+    let mask = u64::MAX >> (64 - bits);
+    match c {
+        0 => return vec![vec![expr(dest, XvaOpcode::ZeroInit)]],
+        1 => return vec![vec![expr(dest, XvaOpcode::Move(x))]],
+        _ => {}
+    }
+
+    let mut seqs = Vec::new();
+    let k = c.trailing_zeros();
+    let odd = c >> k;
+
+    // 2^k
+    if odd == 1 {
+        seqs.push(vec![shl(dest, x, k)]);
+    }
+
+    // (2^j + 1) * 2^k, as a scaled add of `x` to itself
+    if odd > 1 && (odd - 1).is_power_of_two() && (odd - 1).trailing_zeros() < 32 {
+        let size = (odd - 1) as u32;
+        let mut seq = Vec::new();
+        let sum = if k == 0 { dest } else { temp() };
+        seq.push(expr(sum, XvaOpcode::ComputeAddr { base: XvaOperand::Register(x), size, index: XvaOperand::Register(x) }));
+        if k != 0 {
+            seq.push(shl(dest, sum, k));
+        }
+        seqs.push(seq);
+    }
+
+    // 2^j + 2^k
+    if c.count_ones() == 2 {
+        let j = 63 - c.leading_zeros();
+        let mut seq = Vec::new();
+        let high = shifted(&mut seq, x, j, temp);
+        let low = shifted(&mut seq, x, k, temp);
+        seq.push(expr(dest, XvaOpcode::BinaryOp { op: BinaryOp::Add, left: high, right: XvaOperand::Register(low) }));
+        seqs.push(seq);
+    }
+
+    // 2^j - 2^k
+    if let Some(sum) = c.checked_add(1 << k)
+        && sum.is_power_of_two()
+        && sum.trailing_zeros() < bits
+    {
+        let mut seq = Vec::new();
+        let high = shifted(&mut seq, x, sum.trailing_zeros(), temp);
+        let low = shifted(&mut seq, x, k, temp);
+        seq.push(expr(dest, XvaOpcode::BinaryOp { op: BinaryOp::Sub, left: high, right: XvaOperand::Register(low) }));
+        seqs.push(seq);
+    }
+
+    // -(2^k)
+    let neg = c.wrapping_neg() & mask;
+    if neg.is_power_of_two() {
+        let mut seq = Vec::new();
+        let left = shifted(&mut seq, x, neg.trailing_zeros(), temp);
+        seq.push(expr(dest, XvaOpcode::UnaryOp { op: UnaryOp::Neg, left }));
+        seqs.push(seq);
+    }
+
+    seqs
+}
+
+/// Computes the high half of `x * m` into `high`, multiplying as signed values if `signed` is set
+fn mul_high(seq: &mut Vec<XvaExpr>, high: XvaRegister, x: XvaRegister, m: u64, signed: bool, temp: &mut impl FnMut() -> XvaRegister) {
+    let c = temp();
+    seq.push(expr(c, XvaOpcode::Const(XvaConst::Bits(m))));
+    let op = if signed { XvaOpcode::SMul { left: x, right: c } } else { XvaOpcode::UMul { left: x, right: c } };
+    seq.push(XvaExpr { dest: temp(), dest2: Some(high), op });
+}
+
+/// The sequences of expressions that compute `dest = x / d` on unsigned values of `bits` bits without dividing, using the method of Granlund and Montgomery.
+/// `d` must already be truncated to `bits`, and must not be 0
+fn udiv_sequences(dest: XvaRegister, x: XvaRegister, d: u64, bits: u32, temp: &mut impl FnMut() -> XvaRegister) -> Vec<Vec<XvaExpr>> {
+    if d.is_power_of_two() {
+        let seq = if d == 1 { expr(dest, XvaOpcode::Move(x)) } else { shr(dest, x, d.trailing_zeros(), RightShiftMode::Unsigned) };
+        return vec![vec![seq]];
+    }
+
+    // The smallest `l` with `d <= 2^l`, at least 2 since `d` is not a power of two
+    let l = 64 - (d - 1).leading_zeros();
+    let d = d as u128;
+
+    // `m = ceil(2^(bits + s) / d)` gives the quotient as `(x * m) >> (bits + s)` for every `x` if `m * d - 2^(bits + s) <= 2^s`
+    for s in 0..l {
+        let p = 1u128 << (bits + s);
+        let m = p.div_ceil(d);
+        if m >> bits == 0 && m * d - p <= 1 << s {
+            let mut seq = Vec::new();
+            let high = if s == 0 { dest } else { temp() };
+            mul_high(&mut seq, high, x, m as u64, false, temp);
+            if s != 0 {
+                seq.push(shr(dest, high, s, RightShiftMode::Unsigned));
+            }
+            return vec![seq];
+        }
+    }
+
+    // Otherwise the multiplier needs `bits + 1` bits. The product with its low bits is computed, and `x` is added back as `((x - high) >> 1) + high` so that the sum does not overflow
+    let m = ((1u128 << bits) * ((1u128 << l) - d) / d + 1) as u64;
+    let mut seq = Vec::new();
+    let high = temp();
+    mul_high(&mut seq, high, x, m, false, temp);
+    let diff = temp();
+    seq.push(binop(diff, BinaryOp::Sub, x, high));
+    let half = temp();
+    seq.push(shr(half, diff, 1, RightShiftMode::Unsigned));
+    let sum = temp();
+    seq.push(binop(sum, BinaryOp::Add, half, high));
+    seq.push(shr(dest, sum, l - 1, RightShiftMode::Unsigned));
+    vec![seq]
+}
+
+/// The multiplier and shift that divide signed values of `bits` bits by `d`, from Hacker's Delight.
+/// `|d|` must be at least 2 and not a power of two
+fn signed_magic(d: i64, bits: u32) -> (u64, u32) {
+    let half = 1u128 << (bits - 1);
+    let ad = d.unsigned_abs() as u128;
+    let t = half + (d < 0) as u128;
+    let anc = t - 1 - t % ad;
+
+    let mut p = bits - 1;
+    let (mut q1, mut r1) = (half / anc, half % anc);
+    let (mut q2, mut r2) = (half / ad, half % ad);
+    loop {
+        p += 1;
+        q1 *= 2;
+        r1 *= 2;
+        if r1 >= anc {
+            q1 += 1;
+            r1 -= anc;
+        }
+        q2 *= 2;
+        r2 *= 2;
+        if r2 >= ad {
+            q2 += 1;
+            r2 -= ad;
+        }
+        let delta = ad - r2;
+        if !(q1 < delta || (q1 == delta && r1 == 0)) {
+            break;
+        }
+    }
+
+    let mask = u64::MAX >> (64 - bits);
+    let m = (q2 + 1) as u64 & mask;
+    let m = if d < 0 { m.wrapping_neg() & mask } else { m };
+    (m, p - bits)
+}
+
+/// The sequences of expressions that compute `dest = x / d` on signed values of `bits` bits without dividing, rounding towards zero.
+/// `d` must already be truncated to `bits`, and must not be 0
+fn sdiv_sequences(dest: XvaRegister, x: XvaRegister, d: u64, bits: u32, temp: &mut impl FnMut() -> XvaRegister) -> Vec<Vec<XvaExpr>> {
+    let d = ((d << (64 - bits)) as i64) >> (64 - bits);
+    match d {
+        1 => return vec![vec![expr(dest, XvaOpcode::Move(x))]],
+        -1 => return vec![vec![expr(dest, XvaOpcode::UnaryOp { op: UnaryOp::Neg, left: x })]],
+        _ => {}
+    }
+
+    let mut seq = Vec::new();
+    let ad = d.unsigned_abs();
+    if ad.is_power_of_two() {
+        // An arithmetic shift rounds down, so `2^k - 1` is added to negative dividends first
+        let k = ad.trailing_zeros();
+        let sign = temp();
+        seq.push(shr(sign, x, bits - 1, RightShiftMode::Signed));
+        let bias = temp();
+        seq.push(shr(bias, sign, bits - k, RightShiftMode::Unsigned));
+        let sum = temp();
+        seq.push(binop(sum, BinaryOp::Add, x, bias));
+        if d < 0 {
+            let q = temp();
+            seq.push(shr(q, sum, k, RightShiftMode::Signed));
+            seq.push(expr(dest, XvaOpcode::UnaryOp { op: UnaryOp::Neg, left: q }));
+        } else {
+            seq.push(shr(dest, sum, k, RightShiftMode::Signed));
+        }
+        return vec![seq];
+    }
+
+    let (m, s) = signed_magic(d, bits);
+    let mut q = temp();
+    mul_high(&mut seq, q, x, m, true, temp);
+
+    // The multiplier is a signed value, so it is corrected by `x` when its sign differs from the sign of `d`
+    let m_negative = (m >> (bits - 1)) & 1 != 0;
+    if d > 0 && m_negative {
+        let t = temp();
+        seq.push(binop(t, BinaryOp::Add, q, x));
+        q = t;
+    } else if d < 0 && !m_negative {
+        let t = temp();
+        seq.push(binop(t, BinaryOp::Sub, q, x));
+        q = t;
+    }
+    if s != 0 {
+        let t = temp();
+        seq.push(shr(t, q, s, RightShiftMode::Signed));
+        q = t;
+    }
+
+    // Adding the sign bit rounds negative quotients towards zero
+    let sign = temp();
+    seq.push(shr(sign, q, bits - 1, RightShiftMode::Unsigned));
+    seq.push(binop(dest, BinaryOp::Add, q, sign));
+    vec![seq]
+}
+
+struct Reducer<'a> {
+    compiler: &'a dyn Compiler,
+    mach: &'a dyn Machine,
+    mode: MachineMode,
+    consts: HashMap<XvaRegister, u64>,
+    next_id: u32,
+}
+
+impl<'a> Reducer<'a> {
+    /// The total cost of `seq`, or [`None`] if any of its expressions cannot be lowered
+    fn cost(&self, seq: &[XvaExpr]) -> Option<u32> {
+        seq.iter().map(|expr| self.compiler.expr_cost(&expr.op, expr.dest.ty(self.mach, self.mode), self.mode)).sum()
+    }
+
+    /// The cheapest replacement for `expr`, if one is cheaper than `expr` itself
+    fn reduce(&mut self, expr: &XvaExpr) -> Option<Vec<XvaExpr>> {
+        if expr.dest2.is_some() {
+            return None;
+        }
+        let ty = expr.dest.ty(self.mach, self.mode);
+        let current = self.cost(core::slice::from_ref(expr)).unwrap_or(u32::MAX);
+
+        let candidates = match expr.op {
+            XvaOpcode::BinaryOp { op: op @ (BinaryOp::ShiftLeft(_) | BinaryOp::ShiftRight(..)), left, right } => {
+                let quantity = match right {
+                    XvaOperand::Const(XvaConst::Bits(n)) => n,
+                    XvaOperand::Register(reg) => *self.consts.get(&reg)?,
+                    _ => return None,
+                };
+                if quantity == 0 {
+                    vec![vec![self::expr(expr.dest, XvaOpcode::Move(left))]]
+                } else if let XvaOperand::Register(_) = right {
+                    vec![vec![self::expr(expr.dest, XvaOpcode::BinaryOp { op, left, right: XvaOperand::Const(XvaConst::Bits(quantity)) })]]
+                } else {
+                    return None;
+                }
+            }
+            XvaOpcode::UMul { left, right } | XvaOpcode::SMul { left, right } => {
+                // The low half of the product is the same for signed and unsigned multiplication
+                let (x, c) = match (self.consts.get(&left), self.consts.get(&right)) {
+                    (_, Some(&c)) => (left, c),
+                    (Some(&c), None) => (right, c),
+                    (None, None) => return None,
+                };
+                if !(1..=8).contains(&ty.size) {
+                    return None;
+                }
+                let bits = (ty.size * 8) as u32;
+                let c = c & (u64::MAX >> (64 - bits));
+
+                let next_id = &mut self.next_id;
+                let mut temp = || {
+                    let id = *next_id;
+                    *next_id += 1;
+                    XvaRegister::Virtual(XvaDest { id, ty })
+                };
+                mul_sequences(expr.dest, x, c, bits, &mut temp)
+            }
+            XvaOpcode::UDiv { left, right } | XvaOpcode::SDiv { left, right } => {
+                let &d = self.consts.get(&right)?;
+                if !(1..=8).contains(&ty.size) {
+                    return None;
+                }
+                let bits = (ty.size * 8) as u32;
+                let d = d & (u64::MAX >> (64 - bits));
+                if d == 0 {
+                    return None;
+                }
+
+                let next_id = &mut self.next_id;
+                let mut temp = || {
+                    let id = *next_id;
+                    *next_id += 1;
+                    XvaRegister::Virtual(XvaDest { id, ty })
+                };
+                match expr.op {
+                    XvaOpcode::UDiv { .. } => udiv_sequences(expr.dest, left, d, bits, &mut temp),
+                    _ => sdiv_sequences(expr.dest, left, d, bits, &mut temp),
+                }
+            }
+            _ => return None,
+        };
+
+        candidates
+            .into_iter()
+            .filter_map(|seq| Some((self.cost(&seq)?, seq)))
+            .filter(|&(cost, _)| cost < current)
+            .min_by_key(|&(cost, _)| cost)
+            .map(|(_, seq)| seq)
+    }
+}
+
+/// Rewrites multiplications by constants into shifts, adds, and scaled adds, divisions by constants into multiplications by their reciprocal, and shifts by constants into immediate shifts or moves.
+///
+/// The replacements are chosen by [`CompilerSpec::expr_cost`][crate::compiler::CompilerSpec::expr_cost], so each machine gets the forms it can lower cheaply,
+/// such as `lea` on x86 and the shifted second source of `add` on Skyarch.
+/// A register is constant if it is a virtual register with a single definition, which is a constant.
+pub struct StrengthReduce;
+
+impl XvaOpt for StrengthReduce {
+    fn name(&self) -> &'static str {
+        "strength-reduce"
+    }
+
+    fn phases(&self) -> &[XvaOptPhase] {
+        &[XvaOptPhase::AfterLower, XvaOptPhase::BeforeRegalloc]
+    }
+
+    fn cost(&self) -> usize {
+        15
+    }
+
+    fn preserves_cfg(&self) -> bool {
+        true
+    }
+
+    fn make_state(&self, mode: MachineMode) -> Box<dyn State> {
+        Box::new(PassState::new(mode))
+    }
+}
+
+impl XvaFunctionOpt for StrengthReduce {
+    fn optimize_function(&self, state: &mut dyn State, func: &mut XvaFunction, _: &mut CfgCache, _: XvaOptPhase, mach: &dyn Machine) {
+        let state = (state as &mut dyn Any).downcast_mut::<PassState>().unwrap();
+        let Some(compiler) = mach.as_compiler() else {
+            return;
+        };
+
+        flatten_function(func);
+        let defs = count_defs(func);
+        let next_id = defs
+            .keys()
+            .filter_map(|reg| match reg {
+                XvaRegister::Virtual(dest) => Some(dest.id + 1),
+                XvaRegister::Physical(_) => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let gates = state.block_gate_states(func);
+
+        let mut consts = HashMap::new();
+        for (block, gates) in func.body.iter().zip(&gates) {
+            state.opt_gate_state = gates.clone();
+            let XvaBlockBody::Statement(stmts) = &block.body;
+            for stmt in stmts {
+                match stmt {
+                    XvaStatement::OptGate(kind, num) => state.push_gate(*kind, *num),
+                    XvaStatement::EndOptGate(num) => state.pop_gate(*num),
+                    XvaStatement::Expr(XvaExpr { dest: dest @ XvaRegister::Virtual(_), dest2: None, op }) if defs.get(dest) == Some(&1) && state.test_barrier(BarrierKind::PROPAGATE_THROUGH) => {
+                        match op {
+                            XvaOpcode::ZeroInit => {
+                                consts.insert(*dest, 0);
+                            }
+                            XvaOpcode::Const(XvaConst::Bits(n)) => {
+                                consts.insert(*dest, *n);
+                            }
+                            _ => {}
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut reducer = Reducer { compiler, mach, mode: state.mode, consts, next_id };
+        for (block, gates) in func.body.iter_mut().zip(gates) {
+            state.opt_gate_state = gates;
+            let XvaBlockBody::Statement(stmts) = &mut block.body;
+            let old = core::mem::take(stmts);
+            for stmt in old {
+                match &stmt {
+                    XvaStatement::OptGate(kind, num) => state.push_gate(*kind, *num),
+                    XvaStatement::EndOptGate(num) => state.pop_gate(*num),
+                    XvaStatement::Expr(expr) if state.test_barrier(BarrierKind::MISC_OPTIMIZATION) => {
+                        if let Some(seq) = reducer.reduce(expr) {
+                            state.remark(RemarkKind::StrengthReduced { reg: expr.dest, count: seq.len() });
+                            stmts.extend(seq.into_iter().map(XvaStatement::Expr));
+                            continue;
+                        }
+                    }
+                    _ => {}
+                }
+                stmts.push(stmt);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode},
+        intern::Symbol,
+        mach::Regset,
+        traits::IntoId,
+        xva::{RightShiftMode, XvaBasicBlock, XvaCategory, XvaFrameProperties, XvaType},
+    };
+
+    const I64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+
+    fn vreg(id: u32) -> XvaRegister {
+        XvaRegister::Virtual(XvaDest { id, ty: I64 })
+    }
+
+    /// Evaluates `seq` on values of `bits` bits, with register 0 holding `x`. Returns the value of register 1
+    fn eval(seq: &[XvaExpr], x: u64, bits: u32) -> u64 {
+        let mask = u64::MAX >> (64 - bits);
+        let mut regs = HashMap::from([(0, x & mask)]);
+        let id = |reg: XvaRegister| match reg {
+            XvaRegister::Virtual(dest) => dest.id,
+            XvaRegister::Physical(_) => panic!("physical register in sequence"),
+        };
+
+        let signed = |v: u64| ((v << (64 - bits)) as i64 >> (64 - bits)) as i128;
+
+        for expr in seq {
+            let get = |reg: XvaRegister| regs[&id(reg)];
+            let operand = |opr: XvaOperand| match opr {
+                XvaOperand::Register(reg) => get(reg),
+                XvaOperand::Const(XvaConst::Bits(n)) => n,
+                opr => panic!("unexpected operand {opr:?}"),
+            };
+            let val = match expr.op {
+                XvaOpcode::ZeroInit => 0,
+                XvaOpcode::Move(src) => get(src),
+                XvaOpcode::ComputeAddr { base, size, index } => operand(base).wrapping_add((size as u64).wrapping_mul(operand(index))),
+                XvaOpcode::UnaryOp { op: UnaryOp::Neg, left } => get(left).wrapping_neg(),
+                XvaOpcode::BinaryOp { op: BinaryOp::Add, left, right } => get(left).wrapping_add(operand(right)),
+                XvaOpcode::BinaryOp { op: BinaryOp::Sub, left, right } => get(left).wrapping_sub(operand(right)),
+                XvaOpcode::BinaryOp { op: BinaryOp::ShiftLeft(_), left, right } => get(left) << operand(right),
+                XvaOpcode::BinaryOp { op: BinaryOp::ShiftRight(_, RightShiftMode::Unsigned), left, right } => get(left) >> operand(right),
+                XvaOpcode::BinaryOp { op: BinaryOp::ShiftRight(_, RightShiftMode::Signed), left, right } => (signed(get(left)) >> operand(right)) as u64,
+                XvaOpcode::UMul { left, right } | XvaOpcode::SMul { left, right } => {
+                    let product = if matches!(expr.op, XvaOpcode::SMul { .. }) {
+                        (signed(get(left)) * signed(get(right))) as u128
+                    } else {
+                        get(left) as u128 * get(right) as u128
+                    };
+                    if let Some(high) = expr.dest2 {
+                        regs.insert(id(high), (product >> bits) as u64 & mask);
+                    }
+                    product as u64
+                },
+                ref op => panic!("unexpected opcode {op:?}"),
+            };
+            regs.insert(id(expr.dest), val & mask);
+        }
+
+        regs[&1]
+    }
+
+    /// A source of fresh registers, after the ones used by [`eval`]
+    fn temps() -> impl FnMut() -> XvaRegister {
+        let mut next = 2;
+        move || {
+            next += 1;
+            vreg(next - 1)
+        }
+    }
+
+    fn stmt(dest: XvaRegister, op: XvaOpcode) -> XvaStatement {
+        XvaStatement::Expr(expr(dest, op))
+    }
+
+    fn shift(dest: XvaRegister, op: BinaryOp, left: XvaRegister, quantity: u64) -> XvaStatement {
+        stmt(dest, XvaOpcode::BinaryOp { op, left, right: XvaOperand::Const(XvaConst::Bits(quantity)) })
+    }
+
+    /// Runs the pass over `v1 = op`, where v0 is unknown and v2 holds `c`. Returns the statements that replace `v1 = op`
+    fn reduce(op: XvaOpcode, c: u64) -> Vec<XvaStatement> {
+        reduce_in(vec![stmt(vreg(1), op)], c)
+    }
+
+    fn reduce_in(body: Vec<XvaStatement>, c: u64) -> Vec<XvaStatement> {
+        let mut stmts = vec![stmt(vreg(0), XvaOpcode::Read(XvaOperand::FrameAddr(0))), stmt(vreg(2), XvaOpcode::Const(XvaConst::Bits(c)))];
+        stmts.extend(body);
+        let mut func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: vec![XvaBasicBlock { label: Symbol::intern("entry"), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }],
+            frame_properties: XvaFrameProperties::new(),
+        };
+        let mut state = StrengthReduce.make_state(X86Mode::Long.into_id());
+        StrengthReduce.optimize_function(&mut *state, &mut func, &mut CfgCache::new(), XvaOptPhase::AfterLower, &X86);
+        let XvaBlockBody::Statement(stmts) = func.body.remove(0).body;
+        stmts[2..].to_vec()
+    }
+
+    #[test]
+    fn multiplies_by_every_8_bit_constant() {
+        for c in 0..256 {
+            for seq in mul_sequences(vreg(1), vreg(0), c, 8, &mut temps()) {
+                for x in 0..256 {
+                    assert_eq!(eval(&seq, x, 8), (x * c) & 0xff, "{x} * {c}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn multiplies_by_64_bit_constants() {
+        let consts = [3, 5, 6, 24, 36, 1 << 40, (1 << 63) + 1, u64::MAX, u64::MAX - 7, (1 << 63) - 1];
+        let xs = [0, 1, 2, 0x1234_5678_9abc_def0, u64::MAX, 1 << 63];
+        for c in consts {
+            let seqs = mul_sequences(vreg(1), vreg(0), c, 64, &mut temps());
+            assert!(!seqs.is_empty(), "no sequence for {c}");
+            for seq in seqs {
+                for x in xs {
+                    assert_eq!(eval(&seq, x, 64), x.wrapping_mul(c), "{x} * {c}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn divides_by_every_8_bit_constant() {
+        for d in 1..256 {
+            for seq in udiv_sequences(vreg(1), vreg(0), d, 8, &mut temps()) {
+                for x in 0..256 {
+                    assert_eq!(eval(&seq, x, 8), x / d, "{x} / {d}");
+                }
+            }
+            for seq in sdiv_sequences(vreg(1), vreg(0), d, 8, &mut temps()) {
+                for x in 0..256 {
+                    let quotient = (x as u8 as i8).wrapping_div(d as u8 as i8);
+                    assert_eq!(eval(&seq, x, 8), quotient as u8 as u64, "{} / {}", x as u8 as i8, d as u8 as i8);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn divides_by_64_bit_constants() {
+        let consts = [3, 5, 7, 10, 16, 641, 1 << 40, (1 << 63) + 1, u64::MAX, u64::MAX - 6, (1 << 63) - 1];
+        let xs = [0, 1, 2, 7, 0x1234_5678_9abc_def0, u64::MAX, 1 << 63, (1 << 63) - 1];
+        for d in consts {
+            for seq in udiv_sequences(vreg(1), vreg(0), d, 64, &mut temps()) {
+                for x in xs {
+                    assert_eq!(eval(&seq, x, 64), x / d, "{x} / {d}");
+                }
+            }
+            for seq in sdiv_sequences(vreg(1), vreg(0), d, 64, &mut temps()) {
+                for x in xs {
+                    assert_eq!(eval(&seq, x, 64), (x as i64).wrapping_div(d as i64) as u64, "{} / {}", x as i64, d as i64);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reduces_with_x86_costs() {
+        let shl = BinaryOp::ShiftLeft(ShiftBehaviour::AssumeQuantity);
+        assert_eq!(reduce(XvaOpcode::UMul { left: vreg(0), right: vreg(2) }, 8), [shift(vreg(1), shl, vreg(0), 3)]);
+        assert_eq!(reduce(XvaOpcode::SMul { left: vreg(2), right: vreg(0) }, 9), [stmt(vreg(1), XvaOpcode::ComputeAddr { base: XvaOperand::Register(vreg(0)), size: 8, index: XvaOperand::Register(vreg(0)) })]);
+        assert_eq!(reduce(XvaOpcode::UMul { left: vreg(0), right: vreg(2) }, 1), [stmt(vreg(1), XvaOpcode::Move(vreg(0)))]);
+        let shr = BinaryOp::ShiftRight(ShiftBehaviour::AssumeQuantity, RightShiftMode::Unsigned);
+        assert_eq!(reduce(XvaOpcode::UDiv { left: vreg(0), right: vreg(2) }, 16), [shift(vreg(1), shr, vreg(0), 4)]);
+        let by_ten = reduce(XvaOpcode::UDiv { left: vreg(0), right: vreg(2) }, 10);
+        assert!(by_ten.iter().any(|stmt| matches!(stmt, XvaStatement::Expr(XvaExpr { dest2: Some(_), op: XvaOpcode::UMul { .. }, .. }))));
+        assert!(!by_ten.iter().any(|stmt| matches!(stmt, XvaStatement::Expr(XvaExpr { op: XvaOpcode::UDiv { .. }, .. }))));
+
+        // Shifts by a constant register become immediate shifts, which x86 can lower
+        let sar = BinaryOp::ShiftRight(ShiftBehaviour::AssumeQuantity, RightShiftMode::Signed);
+        assert_eq!(reduce(XvaOpcode::BinaryOp { op: sar, left: vreg(0), right: XvaOperand::Register(vreg(2)) }, 5), [shift(vreg(1), sar, vreg(0), 5)]);
+        assert_eq!(reduce(XvaOpcode::BinaryOp { op: shl, left: vreg(0), right: XvaOperand::Register(vreg(2)) }, 0), [stmt(vreg(1), XvaOpcode::Move(vreg(0)))]);
+    }
+
+    #[test]
+    fn does_not_use_lea_for_8_bit_values() {
+        let byte = XvaType { size: 1, align: 1, category: XvaCategory::Int };
+        let reg = |id| XvaRegister::Virtual(XvaDest { id, ty: byte });
+        let stmts = reduce_in(vec![stmt(reg(1), XvaOpcode::UMul { left: vreg(0), right: vreg(2) })], 9);
+        assert!(!stmts.is_empty());
+        assert!(!stmts.iter().any(|stmt| matches!(stmt, XvaStatement::Expr(XvaExpr { op: XvaOpcode::ComputeAddr { .. } | XvaOpcode::UMul { .. }, .. }))));
+    }
+
+    #[test]
+    fn respects_opt_gates() {
+        let body = vec![
+            XvaStatement::OptGate(BarrierKind::MISC_OPTIMIZATION, 0),
+            stmt(vreg(1), XvaOpcode::UMul { left: vreg(0), right: vreg(2) }),
+            XvaStatement::EndOptGate(0),
+        ];
+        assert_eq!(reduce_in(body.clone(), 8), body);
+    }
+}
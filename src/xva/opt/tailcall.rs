@@ -0,0 +1,406 @@
+//! Conversion of calls in tail position into tail calls
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+};
+
+use crate::{
+    mach::{Machine, MachineMode, Register, Regset},
+    xva::{
+        self, BarrierKind, UseKind, XvaBlockBody, XvaFunction, XvaOpcode, XvaOperand, XvaRegister, XvaStatement,
+        cfg::{CfgCache, XvaCfg},
+        opt::{State, XvaFunctionOpt, XvaOpt, XvaOptPhase, flatten_function, pass::PassState, remark::RemarkKind},
+    },
+};
+
+/// Whether `func` may let a callee see the address of its stack frame or of its incoming arguments,
+/// which are gone (or overwritten) once it makes a tail call
+fn frame_escapes(func: &XvaFunction) -> bool {
+    let is_frame = |opr: &XvaOperand| matches!(opr, XvaOperand::FrameAddr(_) | XvaOperand::IncomingArg(_) | XvaOperand::OutgoingArg(_));
+
+    func.frame_properties.has_local_buffers
+        || func.body.iter().any(|block| {
+            let XvaBlockBody::Statement(stmts) = &block.body;
+            stmts.iter().any(|stmt| match stmt {
+                XvaStatement::Expr(expr) => match &expr.op {
+                    XvaOpcode::GetFrameAddr(_) => true,
+                    XvaOpcode::ComputeAddr { base, index, .. } => is_frame(base) || is_frame(index),
+                    XvaOpcode::BinaryOp { right, .. } | XvaOpcode::CheckedBinaryOp { right, .. } => is_frame(right),
+                    _ => false,
+                },
+                XvaStatement::Call { dest, .. } | XvaStatement::Tailcall { dest, .. } => is_frame(dest),
+                XvaStatement::InlineAsm(_) => true,
+                _ => false,
+            })
+        })
+}
+
+/// Whether `stmt` reads or writes the incoming argument area
+fn uses_incoming_args(stmt: &XvaStatement) -> bool {
+    matches!(
+        stmt,
+        XvaStatement::Expr(xva::XvaExpr { op: XvaOpcode::Read(XvaOperand::IncomingArg(_)), .. }) | XvaStatement::Write(XvaOperand::IncomingArg(_), _, _)
+    )
+}
+
+struct TailcallFinder<'a> {
+    func: &'a XvaFunction,
+    cfg: &'a XvaCfg,
+    mach: &'a dyn Machine,
+    mode: MachineMode,
+}
+
+impl<'a> TailcallFinder<'a> {
+    /// Whether the callee may change a register that the caller of `func` expects to be preserved,
+    /// or the epilogue of `func` restores a register that holds a parameter of the call
+    fn clobbers_preserved(&self, params: Regset, ret_val: Regset, clobbers: Regset) -> bool {
+        let func = self.func;
+        clobbers
+            .into_regids(self.mach, self.mode)
+            .chain(ret_val.into_regids(self.mach, self.mode))
+            .any(|reg| !func.clobber_regs.contains_regid(reg, self.mach) && !func.return_regs.contains_regid(reg, self.mach))
+            || params.into_regids(self.mach, self.mode).any(|reg| func.preserve_regs.contains_regid(reg, self.mach))
+    }
+
+    /// Whether control always reaches a [`XvaStatement::Return`] from the statement at `pos` in `block`, without any effect other than
+    /// moving the result of the call, which is in the registers of `ret_val`, into the return registers of the function
+    fn returns_result(&self, mut block: usize, mut pos: usize, ret_val: Regset) -> bool {
+        // The return register of the callee that each register holds
+        let mut holds: HashMap<XvaRegister, Register> =
+            ret_val.into_regids(self.mach, self.mode).map(|reg| (XvaRegister::Physical(reg), reg)).collect();
+        let mut visited = HashSet::new();
+
+        loop {
+            if !visited.insert(block) {
+                return false;
+            }
+            let XvaBlockBody::Statement(stmts) = &self.func.body[block].body;
+            let mut next = Some(block + 1).filter(|&n| n < self.func.body.len());
+            for stmt in &stmts[pos..] {
+                match stmt {
+                    XvaStatement::Expr(expr) if expr.dest2.is_none() && !matches!(expr.op, XvaOpcode::Read(_)) => {
+                        let src = match expr.op {
+                            XvaOpcode::Move(src) => holds.get(&src).copied(),
+                            _ => None,
+                        };
+                        holds.retain(|&reg, _| !reg.aliases(expr.dest, self.mach));
+                        if let Some(src) = src {
+                            holds.insert(expr.dest, src);
+                        }
+                    }
+                    XvaStatement::Use(_, UseKind::Read) | XvaStatement::Noop(_) | XvaStatement::Loc(_) => {}
+                    XvaStatement::Jump(target) | XvaStatement::Fallthrough(target) => {
+                        next = self.cfg.block_of(*target);
+                        break;
+                    }
+                    XvaStatement::Return => {
+                        return self.func.return_regs.into_regids(self.mach, self.mode).all(|reg| {
+                            ret_val.contains_regid(reg, self.mach) && holds.get(&XvaRegister::Physical(reg)) == Some(&reg)
+                        });
+                    }
+                    _ => return false,
+                }
+            }
+
+            let Some(n) = next else {
+                return false;
+            };
+            block = n;
+            pos = 0;
+        }
+    }
+
+    /// Whether a tail call from `func` to a callee that pops `callee_pop` bytes of arguments and returns in memory if `indirect_ret` is set
+    /// leaves the stack and return value the way the caller of `func` expects them
+    fn same_convention(&self, callee_pop: usize, indirect_ret: bool) -> bool {
+        let frame = &self.func.frame_properties;
+        callee_pop == frame.callee_pop && indirect_ret == frame.indirect_ret
+    }
+
+    /// Finds the stack arguments written for the call at `pos` in block `block`, which are written after any earlier call in the block.
+    /// Returns their positions if they can be written to the incoming argument area of `func` instead.
+    /// Any other write to the outgoing argument area in the function might be an argument of this call as well, so the call is not converted then
+    fn stack_args(&self, block: usize, pos: usize) -> Option<Vec<usize>> {
+        let stmts = self.func.body[block].stmts();
+        let start = stmts[..pos].iter().rposition(|stmt| matches!(stmt, XvaStatement::Call { .. })).map_or(0, |n| n + 1);
+        let is_arg = |stmt: &XvaStatement| matches!(stmt, XvaStatement::Write(XvaOperand::OutgoingArg(_), _, _));
+        let elsewhere = self.func.body.iter().enumerate().any(|(n, other)| {
+            other.stmts().iter().enumerate().any(|(i, stmt)| is_arg(stmt) && !(n == block && (start..pos).contains(&i)))
+        });
+        if elsewhere {
+            return None;
+        }
+
+        let writes: Vec<usize> = (start..pos).filter(|&n| is_arg(&stmts[n])).collect();
+        let Some(&first) = writes.first() else {
+            return Some(writes);
+        };
+
+        let fits = writes.iter().all(|&n| match stmts[n] {
+            XvaStatement::Write(XvaOperand::OutgoingArg(off), ty, _) => off as u64 + ty.size <= self.func.frame_properties.incoming_args_size as u64,
+            _ => unreachable!(),
+        });
+        // The incoming arguments must not be used once they start to be overwritten
+        (fits && !stmts[first..pos].iter().any(uses_incoming_args)).then_some(writes)
+    }
+}
+
+/// Converts [`XvaStatement::Call`]s whose results are returned unchanged into [`XvaStatement::Tailcall`]s.
+///
+/// A call is converted if it is followed only by moves that put its result in the return registers of the function (possibly through jumps to other blocks),
+/// and then by a [`XvaStatement::Return`]. The callee must be a constant, it must not clobber any register that the function preserves,
+/// it must pop as many bytes of arguments and return its value the same way as the function,
+/// and its stack arguments must fit in the incoming argument area of the function, where they are written instead.
+/// Functions that may expose the address of their stack frame are not changed
+pub struct ConvertTailcalls;
+
+impl XvaOpt for ConvertTailcalls {
+    fn name(&self) -> &'static str {
+        "tailcall"
+    }
+
+    fn phases(&self) -> &[XvaOptPhase] {
+        &[XvaOptPhase::AfterLower, XvaOptPhase::BeforeRegalloc]
+    }
+
+    fn cost(&self) -> usize {
+        10
+    }
+
+    fn preserves_cfg(&self) -> bool {
+        false
+    }
+
+    fn make_state(&self, mode: MachineMode) -> Box<dyn State> {
+        Box::new(PassState::new(mode))
+    }
+}
+
+impl XvaFunctionOpt for ConvertTailcalls {
+    fn optimize_function(&self, state: &mut dyn State, func: &mut XvaFunction, _: &mut CfgCache, _: XvaOptPhase, mach: &dyn Machine) {
+        let state = (state as &mut dyn Any).downcast_mut::<PassState>().unwrap();
+
+        flatten_function(func);
+        if frame_escapes(func) {
+            return;
+        }
+        let gates = state.block_gate_states(func);
+        let cfg = XvaCfg::new(func);
+
+        // The call to convert in each block, and the stack arguments to move to the incoming argument area
+        let mut found = Vec::new();
+        {
+            let finder = TailcallFinder { func, cfg: &cfg, mach, mode: state.mode };
+            for (n, (block, gates)) in func.body.iter().zip(gates).enumerate() {
+                state.opt_gate_state = gates;
+                let XvaBlockBody::Statement(stmts) = &block.body;
+                for (pos, stmt) in stmts.iter().enumerate() {
+                    match stmt {
+                        XvaStatement::OptGate(kind, num) => state.push_gate(*kind, *num),
+                        XvaStatement::EndOptGate(num) => state.pop_gate(*num),
+                        &XvaStatement::Call { dest: XvaOperand::Const(_), params, ret_val, call_clobber_regs, callee_pop, indirect_ret } if state.test_barrier(BarrierKind::MISC_OPTIMIZATION) => {
+                            if finder.same_convention(callee_pop, indirect_ret)
+                                && !finder.clobbers_preserved(params, ret_val, call_clobber_regs)
+                                && finder.returns_result(n, pos + 1, ret_val)
+                                && let Some(args) = finder.stack_args(n, pos)
+                            {
+                                found.push((n, pos, args));
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        if found.is_empty() {
+            return;
+        }
+
+        for (n, pos, args) in found {
+            let XvaBlockBody::Statement(stmts) = &mut func.body[n].body;
+            for arg in args {
+                if let XvaStatement::Write(addr, _, _) = &mut stmts[arg]
+                    && let XvaOperand::OutgoingArg(off) = *addr
+                {
+                    *addr = XvaOperand::IncomingArg(off);
+                }
+            }
+            stmts.truncate(pos + 1);
+            let XvaStatement::Call { dest, params, .. } = stmts[pos] else {
+                unreachable!()
+            };
+            stmts[pos] = XvaStatement::Tailcall { dest, params };
+            state.remark(RemarkKind::Tailcall(dest));
+        }
+
+        let cfg = XvaCfg::new(func);
+        xva::dataflow::compute_live_at_start(func, &cfg, mach, state.mode);
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode, X86Register},
+        intern::Symbol,
+        traits::IntoId,
+        xva::{BinaryOp, XvaBasicBlock, XvaCategory, XvaConst, XvaDest, XvaExpr, XvaFrameProperties, XvaTrap, XvaType},
+    };
+
+    const I64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+    const RAX: X86Register = crate::x86_register!(rax);
+    const RCX: X86Register = crate::x86_register!(rcx);
+    const RDI: X86Register = crate::x86_register!(rdi);
+    const RBX: X86Register = crate::x86_register!(rbx);
+
+    fn rax() -> XvaRegister {
+        XvaRegister::Physical(Register::new(RAX))
+    }
+
+    fn callee() -> XvaOperand {
+        XvaOperand::Const(XvaConst::Global(Symbol::intern("g"), 0))
+    }
+
+    fn call(clobbers: &[X86Register]) -> XvaStatement {
+        call_with(clobbers, 0, false)
+    }
+
+    fn call_with(clobbers: &[X86Register], callee_pop: usize, indirect_ret: bool) -> XvaStatement {
+        XvaStatement::Call {
+            dest: callee(),
+            params: Regset::from_regids([RDI], &X86),
+            ret_val: Regset::from_regids([RAX], &X86),
+            call_clobber_regs: Regset::from_regids(clobbers.iter().copied(), &X86),
+            callee_pop,
+            indirect_ret,
+        }
+    }
+
+    fn tailcall() -> XvaStatement {
+        XvaStatement::Tailcall { dest: callee(), params: Regset::from_regids([RDI], &X86) }
+    }
+
+    fn block(label: &str, stmts: Vec<XvaStatement>) -> XvaBasicBlock {
+        XvaBasicBlock { label: Symbol::intern(label), live_at_start: Vec::new(), body: XvaBlockBody::Statement(stmts) }
+    }
+
+    fn stmts(func: &XvaFunction, n: usize) -> &[XvaStatement] {
+        let XvaBlockBody::Statement(stmts) = &func.body[n].body;
+        stmts
+    }
+
+    /// Runs the pass over a function made of `blocks`, which returns in rax and may clobber rcx,
+    /// after `frame` sets up its frame properties. Returns the function and the remarks
+    fn convert(blocks: Vec<XvaBasicBlock>, frame: impl FnOnce(&mut XvaFrameProperties)) -> (XvaFunction, Vec<RemarkKind>) {
+        let mut func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::from_regids([RBX], &X86),
+            clobber_regs: Regset::from_regids([RCX], &X86),
+            return_regs: Regset::from_regids([RAX], &X86),
+            prologue: Vec::new(),
+            body: blocks,
+            frame_properties: XvaFrameProperties::new(),
+        };
+        frame(&mut func.frame_properties);
+        let mut state = ConvertTailcalls.make_state(X86Mode::Long.into_id());
+        ConvertTailcalls.optimize_function(&mut *state, &mut func, &mut CfgCache::new(), XvaOptPhase::AfterLower, &X86);
+        (func, state.take_remarks())
+    }
+
+    /// Runs the pass over a function with a single block of `stmts` and returns the statements of that block
+    fn convert_block(stmts: Vec<XvaStatement>, frame: impl FnOnce(&mut XvaFrameProperties)) -> Vec<XvaStatement> {
+        let (func, _) = convert(vec![block("entry", stmts)], frame);
+        self::stmts(&func, 0).to_vec()
+    }
+
+    #[test]
+    fn converts_calls_whose_result_is_returned() {
+        let (func, remarks) = convert(vec![block("entry", vec![call(&[RCX]), XvaStatement::Return])], |_| {});
+        assert_eq!(stmts(&func, 0), [tailcall()]);
+        assert_eq!(remarks, [RemarkKind::Tailcall(callee())]);
+    }
+
+    #[test]
+    fn follows_jumps_to_the_return() {
+        let (func, _) = convert(
+            vec![
+                block("entry", vec![call(&[RCX]), XvaStatement::Jump(Symbol::intern("exit"))]),
+                block("other", vec![XvaStatement::Trap(XvaTrap::Unreachable)]),
+                block("exit", vec![XvaStatement::Return]),
+            ],
+            |_| {},
+        );
+        assert_eq!(stmts(&func, 0), [tailcall()]);
+        assert_eq!(stmts(&func, 2), [XvaStatement::Return]);
+    }
+
+    #[test]
+    fn keeps_calls_whose_result_changes() {
+        let add = XvaStatement::Expr(XvaExpr {
+            dest: rax(),
+            dest2: None,
+            op: XvaOpcode::BinaryOp { op: BinaryOp::Add, left: rax(), right: XvaOperand::Const(XvaConst::Bits(1)) },
+        });
+        let body = vec![call(&[RCX]), add, XvaStatement::Return];
+        assert_eq!(convert_block(body.clone(), |_| {}), body);
+    }
+
+    #[test]
+    fn keeps_calls_that_clobber_preserved_registers() {
+        // The caller of the function expects rbx to be preserved, but the callee may change it
+        let body = vec![call(&[RCX, RBX]), XvaStatement::Return];
+        assert_eq!(convert_block(body.clone(), |_| {}), body);
+    }
+
+    #[test]
+    fn moves_stack_args_to_the_incoming_area() {
+        let arg = XvaRegister::Virtual(XvaDest { id: 0, ty: I64 });
+        let body = vec![XvaStatement::Write(XvaOperand::OutgoingArg(0), I64, arg), call(&[RCX]), XvaStatement::Return];
+        // The function has no incoming argument area to hold the argument
+        assert_eq!(convert_block(body.clone(), |_| {}), body);
+        assert_eq!(convert_block(body, |frame| frame.incoming_args_size = 8), [XvaStatement::Write(XvaOperand::IncomingArg(0), I64, arg), tailcall()]);
+    }
+
+    #[test]
+    fn keeps_calls_with_a_different_convention() {
+        // The callee would pop arguments that the caller of the function does not expect to be popped, and the other way around
+        let pops = call_with(&[RCX], 8, false);
+        assert_eq!(convert_block(vec![pops.clone(), XvaStatement::Return], |_| {}), [pops, XvaStatement::Return]);
+        let body = vec![call(&[RCX]), XvaStatement::Return];
+        assert_eq!(convert_block(body.clone(), |frame| frame.callee_pop = 8), body);
+
+        // A callee that returns in memory writes through the hidden pointer of its own caller
+        let indirect = call_with(&[RCX], 0, true);
+        assert_eq!(convert_block(vec![indirect.clone(), XvaStatement::Return], |_| {}), [indirect.clone(), XvaStatement::Return]);
+        assert_eq!(convert_block(vec![indirect, XvaStatement::Return], |frame| frame.indirect_ret = true), [tailcall()]);
+    }
+
+    #[test]
+    fn keeps_calls_with_stack_args_written_elsewhere() {
+        // The write in another block might be an argument of the call, which would not be moved to the incoming area
+        let arg = XvaRegister::Virtual(XvaDest { id: 0, ty: I64 });
+        let (func, _) = convert(
+            vec![
+                block("entry", vec![XvaStatement::Write(XvaOperand::OutgoingArg(0), I64, arg), XvaStatement::Fallthrough(Symbol::intern("call"))]),
+                block("call", vec![call(&[RCX]), XvaStatement::Return]),
+            ],
+            |frame| frame.incoming_args_size = 8,
+        );
+        assert_eq!(stmts(&func, 1), [call(&[RCX]), XvaStatement::Return]);
+    }
+
+    #[test]
+    fn keeps_calls_when_the_frame_escapes() {
+        let body = vec![call(&[RCX]), XvaStatement::Return];
+        assert_eq!(convert_block(body.clone(), |frame| frame.has_local_buffers = true), body);
+    }
+
+    #[test]
+    fn respects_opt_gates() {
+        let body = vec![XvaStatement::OptGate(BarrierKind::MISC_OPTIMIZATION, 0), call(&[RCX]), XvaStatement::Return, XvaStatement::EndOptGate(0)];
+        assert_eq!(convert_block(body.clone(), |_| {}), body);
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use crate::{
-    compiler::Compiler, intern::Symbol, mach::Register, xva::{XvaBasicBlock, XvaBlockBody, XvaCategory, XvaDest, XvaFunction, XvaOperand, XvaRegister, XvaStatement}
+    compiler::Compiler, intern::Symbol, mach::{Machine, Register, Regset}, xva::{XvaAsmConstraint, XvaBasicBlock, XvaBlockBody, XvaCategory, XvaDest, XvaFunction, XvaOperand, XvaRegister, XvaStatement}
 };
 
 #[derive(Default)]
@@ -71,12 +71,15 @@ impl<'a> RegAllocatorState<'a> {
         let mut dirty = false;
         let name = block.label;
         let mut back_prop_state = HashMap::new();
+        let mach = self.compiler.machine();
         let mut block_locations = self.map.entry(name).or_insert_with(BlockLocations::default);
         match &mut block.body {
             super::XvaBlockBody::Statement(stmts) => {
-                for (idx, stmt) in stmts.iter_mut().enumerate().rev() {
-                    match stmt {
-                        XvaStatement::Jump(next) | XvaStatement::Fallthrough(next) => {
+                for idx in (0..stmts.len()).rev() {
+                    // Registers assigned after this statement that cannot be kept across it
+                    let mut unassign = Vec::new();
+                    match &mut stmts[idx] {
+                        XvaStatement::Jump(next) | XvaStatement::JumpIf(_, next) | XvaStatement::Fallthrough(next) => {
                             if let Some(target_block_locations) = self.map.get(&*next) {
                                 for (&reg, loc) in &target_block_locations.regs {
                                     if let Some(start) = loc.starting_location {
@@ -131,8 +134,76 @@ impl<'a> RegAllocatorState<'a> {
                             }
 
                         },
+                        XvaStatement::InlineAsm(asm) => {
+                            let mut output_regs = Vec::with_capacity(asm.outputs.len());
+
+                            for output in &mut asm.outputs {
+                                let preg = match output.constraint {
+                                    XvaAsmConstraint::Register(preg) => Some(preg),
+                                    _ => None,
+                                };
+
+                                if let XvaRegister::Virtual(v) = output.reg {
+                                    let back_prop = back_prop_state.remove(&v).map(|(_, p)| p);
+                                    if let Some(p) = preg.or(back_prop) {
+                                        let block_loc_info = block_locations.regs.entry(v)
+                                            .or_insert_with(BlockLocationInfo::default);
+                                        block_loc_info.change_posses.insert(idx, p);
+                                        output.reg = XvaRegister::Physical(p);
+                                        dirty = true;
+                                    }
+                                }
+
+                                output_regs.push(match output.reg {
+                                    XvaRegister::Physical(p) => Some(p),
+                                    XvaRegister::Virtual(_) => None,
+                                });
+                            }
+
+                            for input in &mut asm.inputs {
+                                let preg = match input.constraint {
+                                    XvaAsmConstraint::Register(preg) => Some(preg),
+                                    XvaAsmConstraint::Tied(n) => output_regs.get(n as usize).copied().flatten(),
+                                    XvaAsmConstraint::Class(_) => None,
+                                };
+
+                                if let (Some(p), XvaRegister::Virtual(v)) = (preg, input.reg) {
+                                    back_prop_state.insert(v, (idx, p));
+                                    input.reg = XvaRegister::Physical(p);
+                                    dirty = true;
+                                }
+                            }
+
+                            let clobbers = asm.clobber_set(mach);
+                            back_prop_state.retain(|&v, &mut (origin, p)| {
+                                let keep = origin == idx || !clobbers.contains_regid(p, mach);
+                                if !keep {
+                                    unassign.push((origin, v, p));
+                                }
+                                keep
+                            });
+                        }
                         _ => {}
                     }
+
+                    // Undo the assignment made at the use, which then moves the value into the register itself
+                    for (origin, v, p) in unassign {
+                        match &mut stmts[origin] {
+                            XvaStatement::Expr(expr) if expr.dest == XvaRegister::Physical(p) && expr.op == super::XvaOpcode::Move(XvaRegister::Physical(p)) => {
+                                expr.op = super::XvaOpcode::Move(XvaRegister::Virtual(v));
+                                dirty = true;
+                            }
+                            XvaStatement::InlineAsm(asm) => {
+                                for input in &mut asm.inputs {
+                                    if input.reg == XvaRegister::Physical(p) {
+                                        input.reg = XvaRegister::Virtual(v);
+                                        dirty = true;
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
                 }
             },
         }
@@ -183,8 +254,25 @@ impl<'a> RegAllocator<'a> {
         dirty
     }
 
+    fn collect_asm_clobbers(stmts: &[XvaStatement], clobbers: &mut Regset, mach: &dyn Machine) {
+        for stmt in stmts {
+            match stmt {
+                XvaStatement::InlineAsm(asm) => clobbers.insert_bits(*asm.clobber_set(mach)),
+                XvaStatement::Elaborated(stmts) => Self::collect_asm_clobbers(stmts, clobbers, mach),
+                _ => {}
+            }
+        }
+    }
+
     pub fn process_function(&mut self) {
-       
+        for block in &self.func.body {
+            match &block.body {
+                XvaBlockBody::Statement(stmts) => {
+                    Self::collect_asm_clobbers(stmts, &mut self.func.clobber_regs, self.state.compiler.machine())
+                }
+            }
+        }
+
         loop {
             let mut dirty = false;
             loop {
@@ -205,3 +293,63 @@ impl<'a> RegAllocator<'a> {
         }
     }
 }
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Opcode, X86Register},
+        instr::{Instruction, Operand},
+        mach::Opcode,
+        traits::IdType,
+        xva::{XvaAsmOperand, XvaConst, XvaExpr, XvaFrameProperties, XvaInlineAsm, XvaOpcode, XvaType},
+    };
+
+    const RAX: X86Register = crate::x86_register!(rax);
+    const RCX: X86Register = crate::x86_register!(rcx);
+    const RDX: X86Register = crate::x86_register!(rdx);
+
+    fn vreg(id: u32) -> XvaRegister {
+        XvaRegister::Virtual(XvaDest { id, ty: XvaType { size: 8, align: 8, category: XvaCategory::Int } })
+    }
+
+    #[test]
+    fn inline_asm_constraints_and_clobbers() {
+        let asm = XvaInlineAsm {
+            template: vec![Instruction::new(Opcode::new(X86Opcode::Mov), vec![Operand::Placeholder(0), Operand::Placeholder(1)])],
+            outputs: vec![XvaAsmOperand { constraint: XvaAsmConstraint::Register(Register::new(RAX)), reg: vreg(1) }],
+            inputs: vec![XvaAsmOperand { constraint: XvaAsmConstraint::Register(Register::new(RCX)), reg: vreg(0) }],
+            clobbers: Regset::from_regids([RDX], &X86),
+        };
+        let mut func = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: vec![XvaBasicBlock {
+                label: Symbol::intern("entry"),
+                live_at_start: Vec::new(),
+                body: XvaBlockBody::Statement(vec![
+                    XvaStatement::Expr(XvaExpr { dest: vreg(0), dest2: None, op: XvaOpcode::Const(XvaConst::Bits(1)) }),
+                    XvaStatement::InlineAsm(asm),
+                    XvaStatement::Return,
+                ]),
+            }],
+            frame_properties: XvaFrameProperties::new(),
+        };
+        RegAllocator::new(&X86, &mut func).process_function();
+
+        let XvaBlockBody::Statement(stmts) = &func.body[0].body;
+        // The input is computed directly into the register it is constrained to
+        let XvaStatement::Expr(def) = &stmts[0] else { panic!() };
+        assert_eq!(def.dest, XvaRegister::Physical(Register::new(RCX)));
+        let XvaStatement::InlineAsm(asm) = &stmts[1] else { panic!() };
+        assert_eq!(asm.outputs[0].reg, XvaRegister::Physical(Register::new(RAX)));
+        assert_eq!(asm.inputs[0].reg, XvaRegister::Physical(Register::new(RCX)));
+        assert!(func.clobber_regs.contains_regid(RDX, &X86));
+        // So are the registers of the operands, which the statement changes
+        assert!(func.clobber_regs.contains_regid(RAX, &X86));
+        assert!(func.clobber_regs.contains_regid(RCX, &X86));
+    }
+}
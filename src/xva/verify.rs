@@ -0,0 +1,619 @@
+//! Checks that XVA is well-formed, so that malformed input is reported with its location instead of panicking during lowering.
+//!
+//! The checks do not depend on the optimization phase, so [`verify`] can be run between any two phases.
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    fmt::PrettyPrinter, intern::Symbol, mach::{Machine, MachineMode, Register}, xva::{cfg::XvaCfg, for_each_stmt, UseKind, XvaCategory, XvaDest, XvaFile, XvaFunction, XvaOpcode, XvaOperand, XvaRegister, XvaStatement, XvaType}
+};
+
+/// A problem found by [`verify`]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct VerifyError {
+    /// The label of the function containing the problem
+    pub function: Symbol,
+    /// The label of the basic block containing the problem
+    pub block: Symbol,
+    /// The index of the statement in the block, or [`None`] if the problem is with the block itself
+    pub stmt: Option<usize>,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    /// A jump or fallthrough targets a label that is not defined in the function
+    UnknownLabel(Symbol),
+    /// More than one block or local label in the function has this label
+    DuplicateLabel(Symbol),
+    /// The virtual register is used (or is live at the start of a block) without being defined on every path to it
+    UndefinedRegister(XvaDest),
+    /// The virtual register has a different type than another use of the same register
+    InconsistentRegister(XvaDest, XvaType),
+    /// The register does not have the size and category required by the operation
+    OperandMismatch { reg: XvaRegister, expected: XvaType },
+    /// The physical register does not belong to the machine, or is not available in the mode with the function's target features
+    InvalidRegister(Register),
+    /// An [`XvaStatement::EndOptGate`] that does not close the innermost open gate
+    UnmatchedGate(u32),
+    /// An [`XvaStatement::OptGate`] that is still open at the end of the function
+    UnclosedGate(u32),
+}
+
+impl<'a> core::fmt::Display for PrettyPrinter<'a, VerifyErrorKind> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            VerifyErrorKind::UnknownLabel(label) => f.write_fmt(format_args!("jump to unknown label {label}")),
+            VerifyErrorKind::DuplicateLabel(label) => f.write_fmt(format_args!("label {label} is defined more than once")),
+            VerifyErrorKind::UndefinedRegister(dest) => f.write_fmt(format_args!("register {dest} is not defined on every path")),
+            VerifyErrorKind::InconsistentRegister(dest, ty) => f.write_fmt(format_args!("register {dest} was previously used with type {ty}")),
+            VerifyErrorKind::OperandMismatch { reg, expected } => f.write_fmt(format_args!(
+                "register {} has type {}, expected {expected}",
+                PrettyPrinter(reg, self.1, self.2),
+                reg.ty(self.1, self.2)
+            )),
+            VerifyErrorKind::InvalidRegister(reg) => f.write_fmt(format_args!("invalid register {reg:?}")),
+            VerifyErrorKind::UnmatchedGate(id) => f.write_fmt(format_args!("end of opt gate {id} does not match the innermost open gate")),
+            VerifyErrorKind::UnclosedGate(id) => f.write_fmt(format_args!("opt gate {id} is never closed")),
+        }
+    }
+}
+
+impl<'a> core::fmt::Display for PrettyPrinter<'a, VerifyError> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("in function {}, block {}", self.0.function, self.0.block))?;
+        if let Some(stmt) = self.0.stmt {
+            f.write_fmt(format_args!(", statement {stmt}"))?;
+        }
+        f.write_str(": ")?;
+        PrettyPrinter(&self.0.kind, self.1, self.2).fmt(f)
+    }
+}
+
+/// Checks every function of `file`, returning all of the problems found.
+///
+/// This checks that:
+/// * Every jump and fallthrough targets a label in the same function,
+/// * Virtual registers are defined before they are used, on every path through the function,
+/// * The operands of each operation have the same size and category as it requires,
+/// * [`XvaStatement::OptGate`] and [`XvaStatement::EndOptGate`] are properly nested, and
+/// * Physical registers are available in `mode`
+pub fn verify(file: &XvaFile, mach: &dyn Machine, mode: MachineMode) -> Result<(), Vec<VerifyError>> {
+    let mut errors = Vec::new();
+
+    for func in &file.functions {
+        let mut verifier = FunctionVerifier { mach, mode, func: &func.body, function: func.label, types: HashMap::new(), errors: &mut errors };
+        verifier.verify();
+    }
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+struct FunctionVerifier<'a> {
+    mach: &'a dyn Machine,
+    mode: MachineMode,
+    func: &'a XvaFunction,
+    function: Symbol,
+    /// The type each virtual register was first seen with
+    types: HashMap<u32, XvaType>,
+    errors: &'a mut Vec<VerifyError>,
+}
+
+fn operand_regs(opr: &XvaOperand, uses: &mut Vec<XvaRegister>) {
+    match opr {
+        XvaOperand::Register(reg) => uses.push(*reg),
+        XvaOperand::Const(_) | XvaOperand::FrameAddr(_) | XvaOperand::IncomingArg(_) | XvaOperand::OutgoingArg(_) => {}
+    }
+}
+
+/// Collects the registers read by `stmt` into `uses`, and the registers it writes into `defs`
+fn stmt_regs(stmt: &XvaStatement, uses: &mut Vec<XvaRegister>, defs: &mut Vec<XvaRegister>) {
+    match stmt {
+        XvaStatement::Expr(expr) => {
+            match &expr.op {
+                XvaOpcode::ZeroInit | XvaOpcode::Const(_) | XvaOpcode::Uninit | XvaOpcode::GetFrameAddr(_) | XvaOpcode::TlsIndex { .. } => {}
+                XvaOpcode::Move(reg) => uses.push(*reg),
+                XvaOpcode::ComputeAddr { base, index, .. } => {
+                    operand_regs(base, uses);
+                    operand_regs(index, uses);
+                }
+                XvaOpcode::BinaryOp { left, right, .. } | XvaOpcode::CheckedBinaryOp { left, right, .. } => {
+                    uses.push(*left);
+                    operand_regs(right, uses);
+                }
+                XvaOpcode::UnaryOp { left, .. } => uses.push(*left),
+                XvaOpcode::Read(opr) => operand_regs(opr, uses),
+                XvaOpcode::UMul { left, right } | XvaOpcode::SMul { left, right } | XvaOpcode::UDiv { left, right } | XvaOpcode::SDiv { left, right } => uses.extend([*left, *right]),
+                XvaOpcode::Select { cond, left, right } => uses.extend([*cond, *left, *right]),
+                XvaOpcode::TlsAddr { module_base, .. } => uses.extend(*module_base),
+            }
+            defs.push(expr.dest);
+            defs.extend(expr.dest2);
+        }
+        XvaStatement::Write(opr, _, reg) => {
+            operand_regs(opr, uses);
+            uses.push(*reg);
+        }
+        XvaStatement::JumpIf(cond, _) => uses.push(*cond),
+        XvaStatement::Tailcall { dest, .. } | XvaStatement::Call { dest, .. } => operand_regs(dest, uses),
+        XvaStatement::Use(regs, UseKind::Read | UseKind::ReadWrite) => uses.extend(regs.iter().copied()),
+        XvaStatement::Use(regs, UseKind::Write) => defs.extend(regs.iter().copied()),
+        XvaStatement::InlineAsm(asm) => {
+            uses.extend(asm.inputs.iter().map(|opr| opr.reg));
+            defs.extend(asm.outputs.iter().map(|opr| opr.reg));
+        }
+        XvaStatement::Elaborated(stmts) => {
+            for stmt in stmts {
+                stmt_regs(stmt, uses, defs);
+            }
+        }
+        XvaStatement::Jump(_)
+        | XvaStatement::Return
+        | XvaStatement::Trap(_)
+        | XvaStatement::RawInstr(_)
+        | XvaStatement::OptGate(_, _)
+        | XvaStatement::EndOptGate(_)
+        | XvaStatement::Noop(_)
+        | XvaStatement::Fallthrough(_)
+        | XvaStatement::Loc(_)
+        | XvaStatement::Cfi(_)
+        | XvaStatement::Label(_) => {}
+    }
+}
+
+impl<'a> FunctionVerifier<'a> {
+    fn error(&mut self, block: Symbol, stmt: Option<usize>, kind: VerifyErrorKind) {
+        self.errors.push(VerifyError { function: self.function, block, stmt, kind });
+    }
+
+    fn verify(&mut self) {
+        self.check_labels();
+        self.check_registers();
+        self.check_definitions();
+        self.check_types();
+        self.check_gates();
+    }
+
+    /// Checks that labels are unique, and that every jump target exists
+    fn check_labels(&mut self) {
+        let func = self.func;
+        let mut labels = HashMap::new();
+
+        for (n, block) in func.body.iter().enumerate() {
+            if labels.insert(block.label, n).is_some() {
+                self.error(block.label, None, VerifyErrorKind::DuplicateLabel(block.label));
+            }
+            for_each_stmt(block.stmts(), &mut |idx, stmt| {
+                if let XvaStatement::Label(label) = stmt
+                    && labels.insert(*label, n).is_some()
+                {
+                    self.errors.push(VerifyError { function: self.function, block: block.label, stmt: Some(idx), kind: VerifyErrorKind::DuplicateLabel(*label) });
+                }
+            });
+        }
+
+        for block in &func.body {
+            for_each_stmt(block.stmts(), &mut |idx, stmt| {
+                if let XvaStatement::Jump(target) | XvaStatement::JumpIf(_, target) | XvaStatement::Fallthrough(target) = stmt
+                    && !labels.contains_key(target)
+                {
+                    self.errors.push(VerifyError { function: self.function, block: block.label, stmt: Some(idx), kind: VerifyErrorKind::UnknownLabel(*target) });
+                }
+            });
+        }
+    }
+
+    /// Checks that every physical register is valid in the mode, and that every virtual register is used with a single type
+    fn check_registers(&mut self) {
+        let func = self.func;
+        let supported = self.mach.registers().supported_registers(&func.frame_properties.features, self.mode);
+
+        for block in &func.body {
+            let mut check = |this: &mut Self, idx: Option<usize>, reg: XvaRegister| match reg {
+                XvaRegister::Physical(r) => {
+                    if !this.is_valid_register(r) || !supported.contains_regid(r, this.mach) {
+                        this.error(block.label, idx, VerifyErrorKind::InvalidRegister(r));
+                    }
+                }
+                XvaRegister::Virtual(dest) => match this.types.get(&dest.id) {
+                    Some(&ty) if ty != dest.ty => this.error(block.label, idx, VerifyErrorKind::InconsistentRegister(dest, ty)),
+                    Some(_) => {}
+                    None => {
+                        this.types.insert(dest.id, dest.ty);
+                    }
+                },
+            };
+
+            for &reg in &block.live_at_start {
+                check(self, None, reg);
+            }
+
+            let mut regs = Vec::new();
+            for_each_stmt(block.stmts(), &mut |idx, stmt| {
+                let mut uses = Vec::new();
+                let mut defs = Vec::new();
+                stmt_regs(stmt, &mut uses, &mut defs);
+                regs.extend(uses.into_iter().chain(defs).map(|reg| (idx, reg)));
+            });
+
+            for (idx, reg) in regs {
+                check(self, Some(idx), reg);
+            }
+        }
+    }
+
+    /// Whether `reg` is a register of the machine. Other queries on the register panic if it is not
+    fn is_valid_register(&self, reg: Register) -> bool {
+        self.mach.registers().list().contains(&reg)
+    }
+
+    /// Checks that every virtual register is defined on every path to its uses, and to the blocks it is live at the start of
+    fn check_definitions(&mut self) {
+        let func = self.func;
+        let nblocks = func.body.len();
+        if nblocks == 0 {
+            return;
+        }
+
+        let virtuals = |regs: &mut dyn Iterator<Item = XvaRegister>| -> HashSet<u32> {
+            regs.filter_map(|reg| match reg {
+                XvaRegister::Virtual(dest) => Some(dest.id),
+                XvaRegister::Physical(_) => None,
+            })
+            .collect()
+        };
+
+        let cfg = XvaCfg::new(func);
+
+        // For each successor of each block, the registers the block defines before it branches there, along with the ones it declares as live at its start.
+        // A block that branches to a successor more than once only defines what comes before the first branch on every path there
+        let edge_sets: Vec<Vec<(usize, HashSet<u32>)>> = func
+            .body
+            .iter()
+            .enumerate()
+            .map(|(n, block)| {
+                let mut so_far = virtuals(&mut block.live_at_start.iter().copied());
+                let mut edges: Vec<(usize, HashSet<u32>)> = Vec::new();
+                for_each_stmt(block.stmts(), &mut |_, stmt| {
+                    if let XvaStatement::Jump(target) | XvaStatement::JumpIf(_, target) | XvaStatement::Fallthrough(target) = stmt
+                        && let Some(target) = cfg.block_of(*target)
+                        && !edges.iter().any(|&(succ, _)| succ == target)
+                    {
+                        edges.push((target, so_far.clone()));
+                    }
+                    let mut uses = Vec::new();
+                    let mut defs = Vec::new();
+                    stmt_regs(stmt, &mut uses, &mut defs);
+                    so_far.extend(virtuals(&mut defs.into_iter()));
+                });
+                // The remaining successor is the next block, which the block falls through into after all of its statements
+                for &succ in cfg.successors(n) {
+                    if !edges.iter().any(|&(target, _)| target == succ) {
+                        edges.push((succ, so_far.clone()));
+                    }
+                }
+                edges
+            })
+            .collect();
+
+        // The registers defined on every path to the start of each block, or `None` if the block has not been reached yet
+        let mut defined: Vec<Option<HashSet<u32>>> = vec![None; nblocks];
+        defined[0] = Some(virtuals(&mut func.body[0].live_at_start.iter().copied()));
+
+        let mut worklist = vec![0];
+        while let Some(n) = worklist.pop() {
+            let at_start = defined[n].clone().unwrap();
+
+            for &(succ, ref gen_set) in &edge_sets[n] {
+                let mut out = at_start.clone();
+                out.extend(gen_set.iter().copied());
+                let changed = match &mut defined[succ] {
+                    Some(set) => {
+                        let len = set.len();
+                        set.retain(|id| out.contains(id));
+                        set.len() != len
+                    }
+                    slot @ None => {
+                        *slot = Some(out);
+                        true
+                    }
+                };
+
+                if changed && !worklist.contains(&succ) {
+                    worklist.push(succ);
+                }
+            }
+        }
+
+        for (n, block) in func.body.iter().enumerate() {
+            // Unreachable blocks are never executed, so they cannot use undefined registers
+            let Some(mut set) = defined[n].clone() else {
+                continue;
+            };
+
+            for &reg in &block.live_at_start {
+                if let XvaRegister::Virtual(dest) = reg
+                    && !set.contains(&dest.id)
+                {
+                    self.errors.push(VerifyError { function: self.function, block: block.label, stmt: None, kind: VerifyErrorKind::UndefinedRegister(dest) });
+                }
+            }
+            set.extend(virtuals(&mut block.live_at_start.iter().copied()));
+
+            for_each_stmt(block.stmts(), &mut |idx, stmt| {
+                let mut uses = Vec::new();
+                let mut defs = Vec::new();
+                stmt_regs(stmt, &mut uses, &mut defs);
+
+                for reg in uses {
+                    if let XvaRegister::Virtual(dest) = reg
+                        && !set.contains(&dest.id)
+                    {
+                        self.errors.push(VerifyError { function: self.function, block: block.label, stmt: Some(idx), kind: VerifyErrorKind::UndefinedRegister(dest) });
+                    }
+                }
+
+                set.extend(virtuals(&mut defs.into_iter()));
+            });
+        }
+    }
+
+    /// The size and category of `reg`, or [`None`] if it is an invalid physical register
+    fn reg_ty(&self, reg: XvaRegister) -> Option<XvaType> {
+        match reg {
+            XvaRegister::Physical(r) if !self.is_valid_register(r) => None,
+            reg => Some(reg.ty(self.mach, self.mode)),
+        }
+    }
+
+    /// Checks that `reg` has the size and category of `expected`
+    fn expect_ty(&mut self, block: Symbol, idx: usize, reg: XvaRegister, expected: XvaType) {
+        if let Some(ty) = self.reg_ty(reg)
+            && (ty.size != expected.size || ty.category != expected.category)
+        {
+            self.error(block, Some(idx), VerifyErrorKind::OperandMismatch { reg, expected });
+        }
+    }
+
+    fn expect_operand_ty(&mut self, block: Symbol, idx: usize, opr: &XvaOperand, expected: XvaType) {
+        if let XvaOperand::Register(reg) = opr {
+            self.expect_ty(block, idx, *reg, expected);
+        }
+    }
+
+    /// Checks that `cond` is an integer or condition register
+    fn expect_cond(&mut self, block: Symbol, idx: usize, cond: XvaRegister) {
+        if let Some(cond_ty) = self.reg_ty(cond)
+            && !matches!(cond_ty.category, XvaCategory::Int | XvaCategory::Condition)
+        {
+            let expected = XvaType { size: cond_ty.size, align: cond_ty.align, category: XvaCategory::Condition };
+            self.error(block, Some(idx), VerifyErrorKind::OperandMismatch { reg: cond, expected });
+        }
+    }
+
+    /// Checks that the operands of each statement agree in size and category with its destination
+    fn check_types(&mut self) {
+        let func = self.func;
+        for block in &func.body {
+            let mut stmts = Vec::new();
+            for_each_stmt(block.stmts(), &mut |idx, stmt| stmts.push((idx, stmt)));
+
+            for (idx, stmt) in stmts {
+                match stmt {
+                    XvaStatement::Expr(expr) => {
+                        let Some(ty) = self.reg_ty(expr.dest) else {
+                            continue;
+                        };
+
+                        match &expr.op {
+                            XvaOpcode::Move(src) => self.expect_ty(block.label, idx, *src, ty),
+                            XvaOpcode::BinaryOp { left, right, .. } | XvaOpcode::CheckedBinaryOp { left, right, .. } => {
+                                self.expect_ty(block.label, idx, *left, ty);
+                                self.expect_operand_ty(block.label, idx, right, ty);
+                            }
+                            XvaOpcode::UnaryOp { left, .. } => self.expect_ty(block.label, idx, *left, ty),
+                            XvaOpcode::UMul { left, right } | XvaOpcode::SMul { left, right } | XvaOpcode::UDiv { left, right } | XvaOpcode::SDiv { left, right } => {
+                                self.expect_ty(block.label, idx, *left, ty);
+                                self.expect_ty(block.label, idx, *right, ty);
+                            }
+                            XvaOpcode::Select { cond, left, right } => {
+                                self.expect_cond(block.label, idx, *cond);
+                                self.expect_ty(block.label, idx, *left, ty);
+                                self.expect_ty(block.label, idx, *right, ty);
+                            }
+                            XvaOpcode::TlsAddr { module_base: Some(base), .. } => self.expect_ty(block.label, idx, *base, ty),
+                            XvaOpcode::ZeroInit
+                            | XvaOpcode::Const(_)
+                            | XvaOpcode::Uninit
+                            | XvaOpcode::ComputeAddr { .. }
+                            | XvaOpcode::GetFrameAddr(_)
+                            | XvaOpcode::Read(_)
+                            | XvaOpcode::TlsAddr { module_base: None, .. }
+                            | XvaOpcode::TlsIndex { .. } => {}
+                        }
+                    }
+                    XvaStatement::Write(_, ty, reg) => self.expect_ty(block.label, idx, *reg, *ty),
+                    XvaStatement::JumpIf(cond, _) => self.expect_cond(block.label, idx, *cond),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Checks that opt gates are closed in the reverse order they are opened, and that none are left open
+    fn check_gates(&mut self) {
+        let func = self.func;
+        let mut open = Vec::new();
+
+        for block in &func.body {
+            for_each_stmt(block.stmts(), &mut |idx, stmt| match stmt {
+                XvaStatement::OptGate(_, id) => open.push((*id, block.label)),
+                XvaStatement::EndOptGate(id) => {
+                    if open.last().is_some_and(|&(top, _)| top == *id) {
+                        open.pop();
+                    } else {
+                        self.errors.push(VerifyError { function: self.function, block: block.label, stmt: Some(idx), kind: VerifyErrorKind::UnmatchedGate(*id) });
+                    }
+                }
+                _ => {}
+            });
+        }
+
+        for (id, block) in open {
+            self.error(block, None, VerifyErrorKind::UnclosedGate(id));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "x86"))]
+mod tests {
+    use super::*;
+    use crate::{
+        archs::x86::{X86, X86Mode},
+        mach::Regset,
+        traits::{IdType, IntoId},
+        xva::{BarrierKind, BinaryOp, Linkage, XvaBasicBlock, XvaConst, XvaExpr, XvaFrameProperties, XvaFunctionDef, XvaSection},
+    };
+
+    const I64: XvaType = XvaType { size: 8, align: 8, category: XvaCategory::Int };
+
+    fn vreg(id: u32) -> XvaRegister {
+        XvaRegister::Virtual(XvaDest { id, ty: I64 })
+    }
+
+    fn konst(dest: XvaRegister) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest, dest2: None, op: XvaOpcode::Const(XvaConst::Bits(1)) })
+    }
+
+    fn mov(dest: XvaRegister, src: XvaRegister) -> XvaStatement {
+        XvaStatement::Expr(XvaExpr { dest, dest2: None, op: XvaOpcode::Move(src) })
+    }
+
+    fn block(label: &str, live_at_start: Vec<XvaRegister>, stmts: Vec<XvaStatement>) -> XvaBasicBlock {
+        XvaBasicBlock { label: Symbol::intern(label), live_at_start, body: XvaBlockBody::Statement(stmts) }
+    }
+
+    /// A file with the single function `f` made of `blocks`
+    fn file(blocks: Vec<XvaBasicBlock>) -> XvaFile {
+        let body = XvaFunction {
+            params: Regset::new(),
+            preserve_regs: Regset::new(),
+            clobber_regs: Regset::new(),
+            return_regs: Regset::new(),
+            prologue: Vec::new(),
+            body: blocks,
+            frame_properties: XvaFrameProperties::new(),
+        };
+        let func = XvaFunctionDef { body, linkage: Linkage::External, label: Symbol::intern("f"), section: XvaSection::Text, debug: None };
+        XvaFile { functions: vec![func], ..XvaFile::default() }
+    }
+
+    fn errors(blocks: Vec<XvaBasicBlock>, mode: X86Mode) -> Vec<VerifyError> {
+        verify(&file(blocks), &X86, mode.into_id()).err().unwrap_or_default()
+    }
+
+    fn error(block: &str, stmt: Option<usize>, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError { function: Symbol::intern("f"), block: Symbol::intern(block), stmt, kind }
+    }
+
+    fn dest(reg: XvaRegister) -> XvaDest {
+        let XvaRegister::Virtual(dest) = reg else { panic!("not a virtual register") };
+        dest
+    }
+
+    #[test]
+    fn accepts_well_formed_function() {
+        let blocks = vec![
+            block("entry", vec![], vec![konst(vreg(0)), XvaStatement::Jump(Symbol::intern("exit"))]),
+            block("exit", vec![vreg(0)], vec![mov(vreg(1), vreg(0)), XvaStatement::Return]),
+        ];
+        assert_eq!(errors(blocks, X86Mode::Long), []);
+    }
+
+    #[test]
+    fn reports_unknown_and_duplicate_labels() {
+        let blocks = vec![
+            block("entry", vec![], vec![XvaStatement::Label(Symbol::intern("exit")), XvaStatement::Jump(Symbol::intern("missing"))]),
+            block("exit", vec![], vec![XvaStatement::Return]),
+        ];
+        assert_eq!(errors(blocks, X86Mode::Long), [
+            error("exit", None, VerifyErrorKind::DuplicateLabel(Symbol::intern("exit"))),
+            error("entry", Some(1), VerifyErrorKind::UnknownLabel(Symbol::intern("missing"))),
+        ]);
+    }
+
+    #[test]
+    fn reports_registers_undefined_on_some_path() {
+        // v1 is only defined by the previous iteration of the loop, so it is undefined on entry
+        let blocks = vec![
+            block("entry", vec![], vec![konst(vreg(0)), XvaStatement::Fallthrough(Symbol::intern("loop"))]),
+            block("loop", vec![], vec![mov(vreg(2), vreg(0)), mov(vreg(3), vreg(1)), konst(vreg(1)), XvaStatement::Jump(Symbol::intern("loop"))]),
+        ];
+        assert_eq!(errors(blocks, X86Mode::Long), [error("loop", Some(1), VerifyErrorKind::UndefinedRegister(dest(vreg(1))))]);
+
+        // Registers live at the start of a block must also be defined on the way there
+        let blocks = vec![
+            block("entry", vec![], vec![XvaStatement::Fallthrough(Symbol::intern("exit"))]),
+            block("exit", vec![vreg(0)], vec![mov(vreg(1), vreg(0)), XvaStatement::Return]),
+        ];
+        assert_eq!(errors(blocks, X86Mode::Long), [error("exit", None, VerifyErrorKind::UndefinedRegister(dest(vreg(0))))]);
+
+        // A conditional jump only carries the registers defined before it
+        let blocks = vec![
+            block("entry", vec![], vec![konst(vreg(0)), XvaStatement::JumpIf(vreg(0), Symbol::intern("exit")), konst(vreg(1)), XvaStatement::Fallthrough(Symbol::intern("exit"))]),
+            block("exit", vec![], vec![mov(vreg(2), vreg(1)), XvaStatement::Return]),
+        ];
+        assert_eq!(errors(blocks, X86Mode::Long), [error("exit", Some(0), VerifyErrorKind::UndefinedRegister(dest(vreg(1))))]);
+    }
+
+    #[test]
+    fn ignores_unreachable_blocks() {
+        let blocks = vec![
+            block("entry", vec![], vec![XvaStatement::Return]),
+            block("dead", vec![], vec![mov(vreg(1), vreg(0)), XvaStatement::Return]),
+        ];
+        assert_eq!(errors(blocks, X86Mode::Long), []);
+    }
+
+    #[test]
+    fn reports_type_mismatches() {
+        let i32 = XvaType { size: 4, align: 4, category: XvaCategory::Int };
+        let narrow = XvaRegister::Virtual(XvaDest { id: 1, ty: i32 });
+        let add = XvaStatement::Expr(XvaExpr { dest: vreg(2), dest2: None, op: XvaOpcode::BinaryOp { op: BinaryOp::Add, left: vreg(0), right: XvaOperand::Register(narrow) } });
+        let blocks = vec![block("entry", vec![], vec![konst(vreg(0)), konst(narrow), add, XvaStatement::Return])];
+        assert_eq!(errors(blocks, X86Mode::Long), [error("entry", Some(2), VerifyErrorKind::OperandMismatch { reg: narrow, expected: I64 })]);
+
+        // The same virtual register cannot be used with two types
+        let wide = vreg(1);
+        let blocks = vec![block("entry", vec![], vec![konst(narrow), konst(wide), XvaStatement::Return])];
+        assert_eq!(errors(blocks, X86Mode::Long), [error("entry", Some(1), VerifyErrorKind::InconsistentRegister(dest(wide), i32))]);
+    }
+
+    #[test]
+    fn reports_registers_missing_from_the_mode() {
+        let rax = XvaRegister::Physical(Register::new(crate::x86_register!(rax)));
+        let blocks = vec![block("entry", vec![], vec![konst(rax), XvaStatement::Return])];
+        assert_eq!(errors(blocks.clone(), X86Mode::Long), []);
+        assert_eq!(errors(blocks, X86Mode::Protected), [error("entry", Some(0), VerifyErrorKind::InvalidRegister(Register::new(crate::x86_register!(rax))))]);
+    }
+
+    #[test]
+    fn reports_misnested_gates() {
+        let blocks = vec![block("entry", vec![], vec![
+            XvaStatement::OptGate(BarrierKind::DO_NOT_OPTIMIZE, 0),
+            XvaStatement::OptGate(BarrierKind::DO_NOT_OPTIMIZE, 1),
+            XvaStatement::EndOptGate(0),
+            XvaStatement::Return,
+        ])];
+        assert_eq!(errors(blocks, X86Mode::Long), [
+            error("entry", Some(2), VerifyErrorKind::UnmatchedGate(0)),
+            error("entry", None, VerifyErrorKind::UnclosedGate(0)),
+            error("entry", None, VerifyErrorKind::UnclosedGate(1)),
+        ]);
+    }
+
+    #[test]
+    fn error_display() {
+        let err = error("entry", Some(1), VerifyErrorKind::UnknownLabel(Symbol::intern("missing")));
+        assert_eq!(PrettyPrinter(&err, &X86, X86Mode::Long.into_id()).to_string(), "in function f, block entry, statement 1: jump to unknown label missing");
+    }
+}